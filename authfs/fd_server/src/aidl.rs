@@ -17,9 +17,9 @@
 use anyhow::Result;
 use log::error;
 use nix::{
-    errno::Errno, fcntl::openat, fcntl::OFlag, sys::stat::fchmod, sys::stat::mkdirat,
-    sys::stat::mode_t, sys::stat::Mode, sys::statvfs::statvfs, sys::statvfs::Statvfs,
-    unistd::unlinkat, unistd::UnlinkatFlags,
+    errno::Errno, fcntl::OFlag, sys::stat::fchmod, sys::stat::mkdirat, sys::stat::mode_t,
+    sys::stat::Mode, sys::statvfs::statvfs, sys::statvfs::Statvfs, unistd::unlinkat,
+    unistd::UnlinkatFlags,
 };
 use std::cmp::min;
 use std::collections::{btree_map, BTreeMap};
@@ -29,12 +29,15 @@ use std::io;
 use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::common::OwnedFd;
+use crate::allowlist::PathAllowlist;
+use crate::common::{openat_beneath, OwnedFd};
 use crate::fsverity;
+use crate::journal::write_journal;
 use authfs_aidl_interface::aidl::com::android::virt::fs::IVirtFdService::{
-    BnVirtFdService, FsStat::FsStat, IVirtFdService, MAX_REQUESTING_DATA,
+    BnVirtFdService, FsStat::FsStat, IVirtFdService, JournalEntry::JournalEntry, JOURNAL_FILE_NAME,
+    MAX_REQUESTING_DATA,
 };
 use authfs_aidl_interface::binder::{
     BinderFeatures, ExceptionCode, Interface, Result as BinderResult, Status, StatusCode, Strong,
@@ -43,6 +46,7 @@ use authfs_fsverity_metadata::{
     get_fsverity_metadata_path, parse_fsverity_metadata, FSVerityMetadata,
 };
 use binder_common::{new_binder_exception, new_binder_service_specific_error};
+use fsverity_digest::build_merkle_tree;
 
 /// Bitflags of forbidden file mode, e.g. setuid, setgid and sticky bit.
 const FORBIDDEN_MODES: Mode = Mode::from_bits_truncate(!0o777);
@@ -57,19 +61,37 @@ pub enum FdConfig {
 
         // Alternative metadata storing merkle tree and signature.
         alt_metadata: Option<Box<FSVerityMetadata>>,
+
+        /// Merkle tree generated on demand, if the file has neither fs-verity enabled nor the
+        /// alternative metadata. This allows the client to verify the file with a digest that it
+        /// learns from elsewhere.
+        generated_merkle_tree: Mutex<Option<Vec<u8>>>,
     },
 
     /// A readable/writable file to serve by this server. This backing file should just be a
     /// regular file and does not have any specific property.
     ReadWrite(File),
 
-    /// A read-only directory to serve by this server.
-    InputDir(OwnedFd),
+    /// A read-only directory to serve by this server. Files and sub-directories can be opened by
+    /// relative paths, as long as they are in the allowlist if there is one.
+    InputDir {
+        /// The directory to serve.
+        dir: OwnedFd,
+
+        /// Paths that can be opened in the directory. Everything can be opened if `None`.
+        allowlist: Option<PathAllowlist>,
+    },
 
     /// A writable directory to serve by this server.
     OutputDir(OwnedFd),
 }
 
+impl FdConfig {
+    pub fn new_readonly(file: File, alt_metadata: Option<Box<FSVerityMetadata>>) -> Self {
+        FdConfig::Readonly { file, alt_metadata, generated_merkle_tree: Mutex::new(None) }
+    }
+}
+
 pub struct FdService {
     /// A pool of opened files and directories, which can be looked up by the FD number.
     fd_pool: Arc<RwLock<BTreeMap<i32, FdConfig>>>,
@@ -129,7 +151,9 @@ impl IVirtFdService for FdService {
                     new_errno_error(Errno::EIO)
                 })
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir { .. } | FdConfig::OutputDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -138,7 +162,7 @@ impl IVirtFdService for FdService {
         let offset: u64 = validate_and_cast_offset(offset)?;
 
         self.handle_fd(id, |config| match config {
-            FdConfig::Readonly { file, alt_metadata, .. } => {
+            FdConfig::Readonly { file, alt_metadata, generated_merkle_tree } => {
                 let mut buf = vec![0; size];
 
                 let s = if let Some(metadata) = &alt_metadata {
@@ -147,10 +171,31 @@ impl IVirtFdService for FdService {
                         new_errno_error(Errno::EIO)
                     })?
                 } else {
-                    fsverity::read_merkle_tree(file.as_raw_fd(), offset, &mut buf).map_err(|e| {
-                        error!("readFsverityMerkleTree: failed to retrieve merkle tree: {}", e);
-                        new_errno_error(Errno::EIO)
-                    })?
+                    match fsverity::read_merkle_tree(file.as_raw_fd(), offset, &mut buf) {
+                        Ok(s) => s,
+                        Err(e)
+                            if matches!(
+                                e.raw_os_error(),
+                                Some(libc::ENODATA | libc::EOPNOTSUPP | libc::ENOTTY)
+                            ) =>
+                        {
+                            // fs-verity is not enabled on the file, or not supported.
+                            read_generated_merkle_tree(
+                                file,
+                                generated_merkle_tree,
+                                offset,
+                                &mut buf,
+                            )
+                            .map_err(|e| {
+                                error!("readFsverityMerkleTree: failed to generate: {}", e);
+                                new_errno_error(Errno::EIO)
+                            })?
+                        }
+                        Err(e) => {
+                            error!("readFsverityMerkleTree: failed to retrieve merkle tree: {}", e);
+                            return Err(new_errno_error(Errno::EIO));
+                        }
+                    }
                 };
                 debug_assert!(s <= buf.len(), "Shouldn't return more bytes than asked");
                 buf.truncate(s);
@@ -162,7 +207,9 @@ impl IVirtFdService for FdService {
                 // use.
                 Err(new_errno_error(Errno::ENOSYS))
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir { .. } | FdConfig::OutputDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -193,7 +240,9 @@ impl IVirtFdService for FdService {
                 // There is no signature for a writable file.
                 Err(new_errno_error(Errno::ENOSYS))
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir { .. } | FdConfig::OutputDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -211,7 +260,9 @@ impl IVirtFdService for FdService {
                     new_errno_error(Errno::EIO)
                 })? as i32)
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir { .. } | FdConfig::OutputDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -227,7 +278,9 @@ impl IVirtFdService for FdService {
                     new_errno_error(Errno::EIO)
                 })
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir { .. } | FdConfig::OutputDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -252,19 +305,21 @@ impl IVirtFdService for FdService {
                 // for a writable file.
                 Err(new_errno_error(Errno::ENOSYS))
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir { .. } | FdConfig::OutputDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
     fn openFileInDirectory(&self, dir_fd: i32, file_path: &str) -> BinderResult<i32> {
         let path_buf = PathBuf::from(file_path);
-        // Checks if the path is a simple, related path.
-        if path_buf.components().any(|c| !matches!(c, Component::Normal(_))) {
-            return Err(new_errno_error(Errno::EINVAL));
-        }
+        validate_relative_path(&path_buf)?;
 
         self.insert_new_fd(dir_fd, |config| match config {
-            FdConfig::InputDir(dir) => {
+            FdConfig::InputDir { dir, allowlist } => {
+                if !allowlist.as_ref().map_or(true, |a| a.is_file_allowed(&path_buf)) {
+                    return Err(new_errno_error(Errno::EACCES));
+                }
                 let file = open_readonly_at(dir.as_raw_fd(), &path_buf).map_err(new_errno_error)?;
 
                 let metadata_path_buf = get_fsverity_metadata_path(&path_buf);
@@ -272,7 +327,40 @@ impl IVirtFdService for FdService {
                     .ok()
                     .and_then(|f| parse_fsverity_metadata(f).ok());
 
-                Ok((file.as_raw_fd(), FdConfig::Readonly { file, alt_metadata: metadata }))
+                Ok((file.as_raw_fd(), FdConfig::new_readonly(file, metadata)))
+            }
+            FdConfig::OutputDir(_) => {
+                Err(new_errno_error(Errno::ENOSYS)) // TODO: Implement when needed
+            }
+            _ => Err(new_errno_error(Errno::ENOTDIR)),
+        })
+    }
+
+    fn openDirectoryInDirectory(&self, dir_fd: i32, dir_path: &str) -> BinderResult<i32> {
+        let path_buf = PathBuf::from(dir_path);
+        validate_relative_path(&path_buf)?;
+
+        self.insert_new_fd(dir_fd, |config| match config {
+            FdConfig::InputDir { dir, allowlist } => {
+                if !allowlist.as_ref().map_or(true, |a| a.is_dir_allowed(&path_buf)) {
+                    return Err(new_errno_error(Errno::EACCES));
+                }
+                let new_dir_fd = openat_beneath(
+                    dir.as_raw_fd(),
+                    &path_buf,
+                    OFlag::O_DIRECTORY | OFlag::O_RDONLY,
+                    Mode::empty(),
+                )
+                .map_err(new_errno_error)?;
+                // SAFETY: new_dir_fd is just created and not an error.
+                let fd_owner = unsafe { OwnedFd::from_raw_fd(new_dir_fd) };
+                Ok((
+                    new_dir_fd,
+                    FdConfig::InputDir {
+                        dir: fd_owner,
+                        allowlist: allowlist.as_ref().map(|a| a.for_subdir(&path_buf)),
+                    },
+                ))
             }
             FdConfig::OutputDir(_) => {
                 Err(new_errno_error(Errno::ENOSYS)) // TODO: Implement when needed
@@ -285,12 +373,12 @@ impl IVirtFdService for FdService {
         validate_basename(basename)?;
 
         self.insert_new_fd(dir_fd, |config| match config {
-            FdConfig::InputDir(_) => Err(new_errno_error(Errno::EACCES)),
+            FdConfig::InputDir { .. } => Err(new_errno_error(Errno::EACCES)),
             FdConfig::OutputDir(dir) => {
                 let mode = validate_file_mode(mode)?;
-                let new_fd = openat(
+                let new_fd = openat_beneath(
                     dir.as_raw_fd(),
-                    basename,
+                    Path::new(basename),
                     // This function is supposed to be only called when FUSE/authfs thinks the file
                     // does not exist. However, if the file does exist from the view of fd_server
                     // (where the execution context is considered untrusted), we prefer to honor
@@ -316,13 +404,17 @@ impl IVirtFdService for FdService {
         validate_basename(basename)?;
 
         self.insert_new_fd(dir_fd, |config| match config {
-            FdConfig::InputDir(_) => Err(new_errno_error(Errno::EACCES)),
+            FdConfig::InputDir { .. } => Err(new_errno_error(Errno::EACCES)),
             FdConfig::OutputDir(_) => {
                 let mode = validate_file_mode(mode)?;
                 mkdirat(dir_fd, basename, mode).map_err(new_errno_error)?;
-                let new_dir_fd =
-                    openat(dir_fd, basename, OFlag::O_DIRECTORY | OFlag::O_RDONLY, Mode::empty())
-                        .map_err(new_errno_error)?;
+                let new_dir_fd = openat_beneath(
+                    dir_fd,
+                    Path::new(basename),
+                    OFlag::O_DIRECTORY | OFlag::O_RDONLY,
+                    Mode::empty(),
+                )
+                .map_err(new_errno_error)?;
                 // SAFETY: new_dir_fd is just created and not an error.
                 let fd_owner = unsafe { OwnedFd::from_raw_fd(new_dir_fd) };
                 Ok((new_dir_fd, FdConfig::OutputDir(fd_owner)))
@@ -340,7 +432,7 @@ impl IVirtFdService for FdService {
                     .map_err(new_errno_error)?;
                 Ok(())
             }
-            FdConfig::InputDir(_) => Err(new_errno_error(Errno::EACCES)),
+            FdConfig::InputDir { .. } => Err(new_errno_error(Errno::EACCES)),
             _ => Err(new_errno_error(Errno::ENOTDIR)),
         })
     }
@@ -354,7 +446,7 @@ impl IVirtFdService for FdService {
                     .map_err(new_errno_error)?;
                 Ok(())
             }
-            FdConfig::InputDir(_) => Err(new_errno_error(Errno::EACCES)),
+            FdConfig::InputDir { .. } => Err(new_errno_error(Errno::EACCES)),
             _ => Err(new_errno_error(Errno::ENOTDIR)),
         })
     }
@@ -369,6 +461,40 @@ impl IVirtFdService for FdService {
         })
     }
 
+    fn commitJournal(&self, dir_fd: i32, entries: &[JournalEntry]) -> BinderResult<()> {
+        self.handle_fd(dir_fd, |config| match config {
+            FdConfig::OutputDir(dir) => {
+                let mut journal = entries
+                    .iter()
+                    .map(|entry| {
+                        let path = Path::new(&entry.path);
+                        validate_relative_path(path)?;
+                        if path == Path::new(JOURNAL_FILE_NAME) {
+                            return Err(new_errno_error(Errno::EINVAL));
+                        }
+                        let file =
+                            open_readonly_at(dir.as_raw_fd(), path).map_err(new_errno_error)?;
+                        let digest = build_merkle_tree(&file)
+                            .map_err(|e| {
+                                error!("commitJournal: failed to calculate digest: {}", e);
+                                new_errno_error(Errno::EIO)
+                            })?
+                            .digest;
+                        if digest[..] != entry.digest[..] {
+                            error!("commitJournal: digest mismatch of {}", entry.path);
+                            return Err(new_errno_error(Errno::EBADMSG));
+                        }
+                        Ok((entry.path.clone(), digest))
+                    })
+                    .collect::<BinderResult<Vec<_>>>()?;
+                journal.sort();
+                write_journal(dir.as_raw_fd(), JOURNAL_FILE_NAME, &journal).map_err(new_errno_error)
+            }
+            FdConfig::InputDir { .. } => Err(new_errno_error(Errno::EACCES)),
+            _ => Err(new_errno_error(Errno::ENOTDIR)),
+        })
+    }
+
     fn statfs(&self) -> BinderResult<FsStat> {
         let st = statvfs("/data").map_err(new_errno_error)?;
         try_into_fs_stat(st).map_err(|_e| new_errno_error(Errno::EINVAL))
@@ -394,12 +520,30 @@ fn read_into_buf(file: &File, max_size: usize, offset: u64) -> io::Result<Vec<u8
     Ok(buf)
 }
 
+/// Reads the Merkle tree generated from the file content, generating it first if needed.
+fn read_generated_merkle_tree(
+    file: &File,
+    generated_merkle_tree: &Mutex<Option<Vec<u8>>>,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut generated_merkle_tree = generated_merkle_tree.lock().unwrap();
+    if generated_merkle_tree.is_none() {
+        *generated_merkle_tree = Some(build_merkle_tree(file)?.tree);
+    }
+    let tree = generated_merkle_tree.as_ref().unwrap();
+    let start = min(offset, tree.len() as u64) as usize;
+    let end = min(start + buf.len(), tree.len());
+    buf[..end - start].copy_from_slice(&tree[start..end]);
+    Ok(end - start)
+}
+
 fn new_errno_error(errno: Errno) -> Status {
     new_binder_service_specific_error(errno as i32, errno.desc())
 }
 
 fn open_readonly_at(dir_fd: RawFd, path: &Path) -> nix::Result<File> {
-    let new_fd = openat(dir_fd, path, OFlag::O_RDONLY, Mode::empty())?;
+    let new_fd = openat_beneath(dir_fd, path, OFlag::O_RDONLY, Mode::empty())?;
     // SAFETY: new_fd is just created successfully and not owned.
     let new_file = unsafe { File::from_raw_fd(new_fd) };
     Ok(new_file)
@@ -417,9 +561,21 @@ fn validate_and_cast_size(size: i32) -> Result<usize, Status> {
     }
 }
 
+/// Checks if the path is a simple, related path.
+fn validate_relative_path(path: &Path) -> BinderResult<()> {
+    if path.components().any(|c| !matches!(c, Component::Normal(_))) {
+        Err(new_errno_error(Errno::EINVAL))
+    } else {
+        Ok(())
+    }
+}
+
 fn validate_basename(name: &str) -> BinderResult<()> {
     if name.contains(MAIN_SEPARATOR) {
         Err(new_errno_error(Errno::EINVAL))
+    } else if name == JOURNAL_FILE_NAME {
+        // The journal can only be changed through commitJournal.
+        Err(new_errno_error(Errno::EACCES))
     } else {
         Ok(())
     }
@@ -0,0 +1,150 @@
+/*
+ * Copyright (C) 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! An allowlist of paths that the client can open in a directory served by fd_server.
+
+use anyhow::{bail, Result};
+use std::path::{Component, Path, PathBuf};
+
+enum Entry {
+    /// A single file.
+    File(PathBuf),
+    /// Everything under a directory. An empty path means the whole served directory.
+    Subtree(PathBuf),
+}
+
+/// A list of paths, relative to a served directory, that the client is allowed to open.
+pub struct PathAllowlist {
+    entries: Vec<Entry>,
+}
+
+impl PathAllowlist {
+    /// Parses the allowlist from its text form: one relative path per line, where a path ending
+    /// with "/" allows everything under the directory. Empty lines and lines starting with "#" are
+    /// ignored.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let path = PathBuf::from(line);
+            if path.components().any(|c| !matches!(c, Component::Normal(_))) {
+                bail!("Invalid path in allowlist: {}", line);
+            }
+            if line.ends_with('/') {
+                entries.push(Entry::Subtree(path));
+            } else {
+                entries.push(Entry::File(path));
+            }
+        }
+        Ok(PathAllowlist { entries })
+    }
+
+    /// Returns whether the file at `path` can be opened.
+    pub fn is_file_allowed(&self, path: &Path) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            Entry::File(file) => file == path,
+            Entry::Subtree(dir) => path.starts_with(dir),
+        })
+    }
+
+    /// Returns whether the directory at `path` can be opened, i.e. whether anything under it is
+    /// allowed.
+    pub fn is_dir_allowed(&self, path: &Path) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            Entry::File(file) => file.starts_with(path) && file != path,
+            Entry::Subtree(dir) => path.starts_with(dir) || dir.starts_with(path),
+        })
+    }
+
+    /// Returns the allowlist for the sub-directory at `path`, with paths relative to it.
+    pub fn for_subdir(&self, path: &Path) -> PathAllowlist {
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::File(file) => match file.strip_prefix(path) {
+                    Ok(rest) if rest != Path::new("") => Some(Entry::File(rest.to_path_buf())),
+                    _ => None,
+                },
+                Entry::Subtree(dir) => {
+                    if path.starts_with(dir) {
+                        Some(Entry::Subtree(PathBuf::new()))
+                    } else {
+                        dir.strip_prefix(path).ok().map(|rest| Entry::Subtree(rest.to_path_buf()))
+                    }
+                }
+            })
+            .collect();
+        PathAllowlist { entries }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOWLIST: &str = "\
+# Comment
+system/framework/
+apex/com.android.art/javalib/core-oj.jar
+
+";
+
+    #[test]
+    fn parse_rejects_non_relative_paths() {
+        assert!(PathAllowlist::parse("/system/").is_err());
+        assert!(PathAllowlist::parse("system/../data/").is_err());
+        assert!(PathAllowlist::parse("./system").is_err());
+    }
+
+    #[test]
+    fn allows_listed_files_and_subtrees() -> Result<()> {
+        let allowlist = PathAllowlist::parse(ALLOWLIST)?;
+
+        assert!(allowlist.is_file_allowed(Path::new("system/framework/framework.jar")));
+        assert!(allowlist.is_file_allowed(Path::new("system/framework/arm64/boot.oat")));
+        assert!(allowlist.is_file_allowed(Path::new("apex/com.android.art/javalib/core-oj.jar")));
+        assert!(!allowlist.is_file_allowed(Path::new("system/build.prop")));
+        assert!(!allowlist.is_file_allowed(Path::new("system/frameworks.jar")));
+        assert!(!allowlist.is_file_allowed(Path::new("apex/com.android.art/javalib/other.jar")));
+
+        assert!(allowlist.is_dir_allowed(Path::new("system")));
+        assert!(allowlist.is_dir_allowed(Path::new("system/framework/arm64")));
+        assert!(allowlist.is_dir_allowed(Path::new("apex/com.android.art")));
+        assert!(!allowlist.is_dir_allowed(Path::new("apex/com.android.art/javalib/core-oj.jar")));
+        assert!(!allowlist.is_dir_allowed(Path::new("data")));
+        Ok(())
+    }
+
+    #[test]
+    fn subdir_allowlist_is_relative() -> Result<()> {
+        let allowlist = PathAllowlist::parse(ALLOWLIST)?;
+
+        let system = allowlist.for_subdir(Path::new("system"));
+        assert!(system.is_file_allowed(Path::new("framework/framework.jar")));
+        assert!(!system.is_file_allowed(Path::new("build.prop")));
+
+        let framework = allowlist.for_subdir(Path::new("system/framework/arm64"));
+        assert!(framework.is_file_allowed(Path::new("boot.oat")));
+
+        let apex = allowlist.for_subdir(Path::new("apex"));
+        assert!(apex.is_file_allowed(Path::new("com.android.art/javalib/core-oj.jar")));
+        assert!(!apex.is_dir_allowed(Path::new("com.android.os.statsd")));
+        Ok(())
+    }
+}
@@ -14,8 +14,13 @@
  * limitations under the License.
  */
 
+use nix::{errno::Errno, fcntl::OFlag, sys::stat::Mode};
+use std::ffi::CString;
 use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
 
 // TODO: Remove if/when std::os::unix::io::OwnedFd is standardized.
 pub struct OwnedFd {
@@ -33,3 +38,66 @@ impl AsRawFd for OwnedFd {
         self.owner.as_raw_fd()
     }
 }
+
+// From include/uapi/linux/openat2.h
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Opens `path` relative to `dir_fd` like openat(2), but with openat2(2) so that the resolution
+/// fails if it would go outside of the directory or through any symlink, including the last
+/// component. The path is chosen by the client, so without this a symlink in the directory could
+/// open a file which the client isn't allowed to, e.g. one outside of the allowlist.
+pub fn openat_beneath(dir_fd: RawFd, path: &Path, flags: OFlag, mode: Mode) -> nix::Result<RawFd> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    let how = OpenHow {
+        flags: (flags | OFlag::O_CLOEXEC).bits() as u64,
+        mode: mode.bits() as u64,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS,
+    };
+    // SAFETY: `path` and `how` outlive the call, and the kernel only reads `size_of::<OpenHow>()`
+    // bytes of `how`.
+    let fd = unsafe {
+        libc::syscall(libc::SYS_openat2, dir_fd, path.as_ptr(), &how, size_of::<OpenHow>())
+    };
+    Errno::result(fd).map(|fd| fd as RawFd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    #[test]
+    fn openat_beneath_rejects_escapes() {
+        let root: PathBuf =
+            std::env::temp_dir().join(format!("fd_server_test_{}", std::process::id()));
+        create_dir(&root).unwrap();
+        create_dir(root.join("dir")).unwrap();
+        write(root.join("dir/file"), b"").unwrap();
+        write(root.join("outside"), b"").unwrap();
+        symlink("file", root.join("dir/link")).unwrap();
+        symlink("../outside", root.join("dir/escape")).unwrap();
+        let dir = File::open(root.join("dir")).unwrap();
+        let open = |path: &str| {
+            openat_beneath(dir.as_raw_fd(), Path::new(path), OFlag::O_RDONLY, Mode::empty())
+                // SAFETY: the fd is just opened and not owned by anything else.
+                .map(|fd| drop(unsafe { File::from_raw_fd(fd) }))
+        };
+
+        assert_eq!(open("file"), Ok(()));
+        assert_eq!(open("../outside"), Err(Errno::EXDEV));
+        assert_eq!(open("/etc/hosts"), Err(Errno::EXDEV));
+        assert_eq!(open("link"), Err(Errno::ELOOP));
+        assert_eq!(open("escape"), Err(Errno::ELOOP));
+
+        remove_dir_all(&root).unwrap();
+    }
+}
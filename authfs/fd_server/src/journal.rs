@@ -0,0 +1,48 @@
+/*
+ * Copyright (C) 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Integrity journal of a writable directory. The journal records the fs-verity digest of every
+//! file that the client (authfs) has written to the directory, after the digests have been checked
+//! against the actual file content.
+
+use fsverity_digest::{to_hex_string, Sha256Hash};
+use nix::{errno::Errno, fcntl::openat, fcntl::OFlag, sys::stat::Mode};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// Writes the journal as `journal_name` in the directory, replacing the existing one. Each line of
+/// the journal is in the format of `fsverity digest`, i.e. "sha256:<hex digest> <path>".
+pub fn write_journal(
+    dir_fd: RawFd,
+    journal_name: &str,
+    entries: &[(String, Sha256Hash)],
+) -> Result<(), Errno> {
+    let fd = openat(
+        dir_fd,
+        journal_name,
+        OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_TRUNC | OFlag::O_NOFOLLOW,
+        Mode::S_IRUSR | Mode::S_IWUSR,
+    )?;
+    // SAFETY: fd is just created successfully and not owned.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut content = String::new();
+    for (path, digest) in entries {
+        content.push_str(&format!("sha256:{} {}\n", to_hex_string(digest), path));
+    }
+    file.write_all(content.as_bytes()).map_err(|_| Errno::EIO)?;
+    file.sync_all().map_err(|_| Errno::EIO)
+}
@@ -21,10 +21,16 @@
 //!
 //! For example, `exec 9</path/to/file fd_server --ro-fds 9` starts the binder service. A client
 //! client can then request the content of file 9 by offset and size.
+//!
+//! A directory can be served too, e.g. `exec 9</system 8<allowlist.txt fd_server --ro-dirs 9:8`,
+//! where the client can open files and sub-directories in it by relative path. The optional
+//! allowlist (see `PathAllowlist`) limits the paths that the client can open.
 
 mod aidl;
+mod allowlist;
 mod common;
 mod fsverity;
+mod journal;
 
 use anyhow::{bail, Result};
 use binder_common::rpc_server::run_rpc_server;
@@ -32,9 +38,11 @@ use log::debug;
 use nix::sys::stat::{umask, Mode};
 use std::collections::BTreeMap;
 use std::fs::File;
+use std::io::Read;
 use std::os::unix::io::FromRawFd;
 
 use aidl::{FdConfig, FdService};
+use allowlist::PathAllowlist;
 use authfs_fsverity_metadata::parse_fsverity_metadata;
 
 const RPC_SERVICE_PORT: u32 = 3264; // TODO: support dynamic port for multiple fd_server instances
@@ -61,15 +69,14 @@ fn parse_arg_ro_fds(arg: &str) -> Result<(i32, FdConfig)> {
     }
     Ok((
         fds[0],
-        FdConfig::Readonly {
-            file: fd_to_owned(fds[0])?,
+        FdConfig::new_readonly(
+            fd_to_owned(fds[0])?,
             // Alternative metadata source, if provided
-            alt_metadata: fds
-                .get(1)
+            fds.get(1)
                 .map(|fd| fd_to_owned(*fd))
                 .transpose()?
                 .and_then(|f| parse_fsverity_metadata(f).ok()),
-        },
+        ),
     ))
 }
 
@@ -83,8 +90,19 @@ fn parse_arg_rw_fds(arg: &str) -> Result<(i32, FdConfig)> {
 }
 
 fn parse_arg_ro_dirs(arg: &str) -> Result<(i32, FdConfig)> {
-    let fd = arg.parse::<i32>()?;
-    Ok((fd, FdConfig::InputDir(fd_to_owned(fd)?)))
+    let result: Result<Vec<i32>, _> = arg.split(':').map(|x| x.parse::<i32>()).collect();
+    let fds = result?;
+    if fds.len() > 2 {
+        bail!("Too many options: {}", arg);
+    }
+    let allowlist = if let Some(fd) = fds.get(1) {
+        let mut content = String::new();
+        fd_to_owned::<File>(*fd)?.read_to_string(&mut content)?;
+        Some(PathAllowlist::parse(&content)?)
+    } else {
+        None
+    };
+    Ok((fds[0], FdConfig::InputDir { dir: fd_to_owned(fds[0])?, allowlist }))
 }
 
 fn parse_arg_rw_dirs(arg: &str) -> Result<(i32, FdConfig)> {
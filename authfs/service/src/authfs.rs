@@ -19,8 +19,10 @@ use log::{debug, error, warn};
 use nix::mount::{umount2, MntFlags};
 use nix::sys::statfs::{statfs, FsType};
 use shared_child::SharedChild;
-use std::ffi::{OsStr, OsString};
+use std::ffi::{CString, OsStr, OsString};
 use std::fs::{remove_dir, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use std::process::Command;
 use std::thread::sleep;
@@ -30,6 +32,9 @@ use authfs_aidl_interface::aidl::com::android::virt::fs::AuthFsConfig::{
     AuthFsConfig, InputDirFdAnnotation::InputDirFdAnnotation, InputFdAnnotation::InputFdAnnotation,
     OutputDirFdAnnotation::OutputDirFdAnnotation, OutputFdAnnotation::OutputFdAnnotation,
 };
+use authfs_aidl_interface::aidl::com::android::virt::fs::AuthFsStats::{
+    AuthFsStats, FileStats::FileStats,
+};
 use authfs_aidl_interface::aidl::com::android::virt::fs::IAuthFs::{BnAuthFs, IAuthFs};
 use authfs_aidl_interface::binder::{
     self, BinderFeatures, ExceptionCode, Interface, ParcelFileDescriptor, Strong,
@@ -40,6 +45,7 @@ const AUTHFS_BIN: &str = "/system/bin/authfs";
 const AUTHFS_SETUP_POLL_INTERVAL_MS: Duration = Duration::from_millis(50);
 const AUTHFS_SETUP_TIMEOUT_SEC: Duration = Duration::from_secs(10);
 const FUSE_SUPER_MAGIC: FsType = FsType(0x65735546);
+const AUTHFS_STATS_XATTR: &str = "authfs.stats";
 
 /// An `AuthFs` instance is supposed to be backed by an `authfs` process. When the lifetime of the
 /// instance is over, it should leave no trace on the system: the process should be terminated, the
@@ -75,6 +81,24 @@ impl IAuthFs for AuthFs {
             Err(new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, "Bad string encoding"))
         }
     }
+
+    fn getStats(&self) -> binder::Result<AuthFsStats> {
+        let to_binder_exception = |e: anyhow::Error| {
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, format!("{:?}", e))
+        };
+        match self.process.try_wait() {
+            Ok(None) => {}
+            Ok(Some(status)) => {
+                return Err(new_binder_exception(
+                    ExceptionCode::ILLEGAL_STATE,
+                    format!("authfs has exited: {}", status),
+                ))
+            }
+            Err(e) => return Err(to_binder_exception(e.into())),
+        }
+        let text = read_stats_xattr(&self.mountpoint).map_err(to_binder_exception)?;
+        parse_stats(&text).map_err(to_binder_exception)
+    }
 }
 
 impl AuthFs {
@@ -141,10 +165,15 @@ fn run_authfs(
     args.push(OsString::from("-o"));
     args.push(OsString::from("fscontext=u:object_r:authfs_fuse:s0"));
     for conf in in_file_fds {
-        // TODO(b/185178698): Many input files need to be signed and verified.
-        // or can we use debug cert for now, which is better than nothing?
-        args.push(OsString::from("--remote-ro-file-unverified"));
-        args.push(OsString::from(conf.fd.to_string()));
+        if let Some(digest) = &conf.fsverityDigest {
+            args.push(OsString::from("--remote-ro-file"));
+            args.push(OsString::from(format!("{}:sha256-{}", conf.fd, to_hex_string(digest))));
+        } else {
+            // TODO(b/185178698): Many input files need to be signed and verified.
+            // or can we use debug cert for now, which is better than nothing?
+            args.push(OsString::from("--remote-ro-file-unverified"));
+            args.push(OsString::from(conf.fd.to_string()));
+        }
     }
     for conf in out_file_fds {
         args.push(OsString::from("--remote-new-rw-file"));
@@ -168,6 +197,73 @@ fn run_authfs(
     SharedChild::spawn(&mut command).context("Spawn authfs")
 }
 
+fn to_hex_string(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads the statistics exposed by authfs through the xattr of the mount point.
+fn read_stats_xattr(mountpoint: &OsStr) -> Result<String> {
+    let path = CString::new(mountpoint.as_bytes())?;
+    let name = CString::new(AUTHFS_STATS_XATTR)?;
+    loop {
+        // SAFETY: `path` and `name` are valid C strings. With a null buffer of size 0, getxattr
+        // only returns the size of the value.
+        let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error()).context("Get size of authfs stats");
+        }
+        let mut buf = vec![0u8; size as usize];
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes.
+        let size = unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+        };
+        if size < 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ERANGE) {
+                // The stats have grown since the size was queried. Try again.
+                continue;
+            }
+            return Err(error).context("Read authfs stats");
+        }
+        buf.truncate(size as usize);
+        return String::from_utf8(buf).context("Decode authfs stats");
+    }
+}
+
+/// Parses the text form of the statistics. See `authfs/src/fusefs/stats.rs` for the format.
+fn parse_stats(text: &str) -> Result<AuthFsStats> {
+    let mut stats = AuthFsStats::default();
+    for line in text.lines() {
+        let mut fields = line.splitn(4, ' ');
+        match fields.next() {
+            Some("cache") => {
+                stats.cacheHitCount = parse_count(fields.next())?;
+                stats.cacheMissCount = parse_count(fields.next())?;
+            }
+            Some("file") => {
+                let read_count = parse_count(fields.next())?;
+                let verification_failure_count = parse_count(fields.next())?;
+                let path = fields.next().context("Missing path")?.to_string();
+                stats.files.push(FileStats {
+                    path,
+                    readCount: read_count,
+                    verificationFailureCount: verification_failure_count,
+                });
+            }
+            Some("omitted") => {
+                stats.omittedFileCount = parse_count(fields.next())?;
+            }
+            _ => bail!("Unrecognized line in authfs stats: {}", line),
+        }
+    }
+    Ok(stats)
+}
+
+fn parse_count(field: Option<&str>) -> Result<i64> {
+    let field = field.context("Missing count")?;
+    field.parse().with_context(|| format!("Invalid count: {}", field))
+}
+
 fn wait_until_authfs_ready(child: &SharedChild, mountpoint: &OsStr) -> Result<()> {
     let start_time = Instant::now();
     loop {
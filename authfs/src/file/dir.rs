@@ -25,8 +25,12 @@ use std::path::{Path, PathBuf};
 use super::attr::Attr;
 use super::remote_file::RemoteFileEditor;
 use super::{validate_basename, VirtFdService, VirtFdServiceStatus};
+use crate::crypto::Sha256Hash;
 use crate::fsverity::VerifiedFileEditor;
 use crate::fusefs::{AuthFsDirEntry, Inode};
+use authfs_aidl_interface::aidl::com::android::virt::fs::IVirtFdService::{
+    JournalEntry::JournalEntry, JOURNAL_FILE_NAME,
+};
 
 const MAX_ENTRIES: u16 = 100; // Arbitrary limit
 
@@ -54,6 +58,9 @@ struct InodeInfo {
 /// created within such a directory, are also maintained within the VM. A compromised fd_server or
 /// malicious client can't affect the view to the files and directories within such a directory in
 /// the VM.
+///
+/// The fs-verity digests of the files can be committed to fd_server as a journal, which fd_server
+/// checks against the content in the backing storage before persisting it along with the files.
 pub struct RemoteDirEditor {
     service: VirtFdService,
     remote_dir_fd: i32,
@@ -167,6 +174,22 @@ impl RemoteDirEditor {
             .collect::<io::Result<Vec<_>>>()
     }
 
+    /// Commits the integrity journal, i.e. `<path, fs-verity digest>` of all files in the directory
+    /// (recursively), to the remote directory. The paths are relative to this directory.
+    pub fn commit_journal(&self, journal: Vec<(PathBuf, Sha256Hash)>) -> io::Result<()> {
+        let entries = journal
+            .into_iter()
+            .map(|(path, digest)| {
+                let path = path
+                    .into_os_string()
+                    .into_string()
+                    .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+                Ok(JournalEntry { path, digest: digest.to_vec() })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.service.commitJournal(self.remote_dir_fd, &entries).map_err(into_io_error)
+    }
+
     fn force_delete_entry(&mut self, basename: &Path, expect_dir: bool) -> io::Result<Inode> {
         // Kernel should only give us a basename.
         debug_assert!(validate_basename(basename).is_ok());
@@ -194,6 +217,11 @@ impl RemoteDirEditor {
             return Err(io::Error::from_raw_os_error(libc::EEXIST));
         }
 
+        // The name is reserved by fd_server for the journal.
+        if basename == Path::new(JOURNAL_FILE_NAME) {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+
         if self.entries.len() >= MAX_ENTRIES.into() {
             return Err(io::Error::from_raw_os_error(libc::EMLINK));
         }
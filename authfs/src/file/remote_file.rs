@@ -18,9 +18,11 @@ use std::cmp::min;
 use std::convert::TryFrom;
 use std::io;
 use std::path::Path;
+use std::sync::Mutex;
 
 use super::{ChunkBuffer, RandomWrite, ReadByChunk, VirtFdService};
 use crate::common::CHUNK_SIZE;
+use authfs_aidl_interface::aidl::com::android::virt::fs::IVirtFdService::MAX_REQUESTING_DATA;
 
 fn remote_read_chunk(
     service: &VirtFdService,
@@ -39,14 +41,28 @@ fn remote_read_chunk(
     Ok(size)
 }
 
+/// State of the read-ahead of a `RemoteFileReader`.
+#[derive(Default)]
+struct ReadAhead {
+    /// The chunk index expected by a sequential read, i.e. the one right after the last read.
+    next_chunk_index: u64,
+
+    /// Content read ahead from the remote, starting at `next_chunk_index`.
+    buffer: Vec<u8>,
+}
+
+/// A reader of a remote file. When the file is read sequentially, the reader requests more chunks
+/// than asked in one request (up to `MAX_REQUESTING_DATA`), and keeps the extra for the following
+/// reads, to save round trips to the remote.
 pub struct RemoteFileReader {
     service: VirtFdService,
     file_fd: i32,
+    read_ahead: Mutex<ReadAhead>,
 }
 
 impl RemoteFileReader {
     pub fn new(service: VirtFdService, file_fd: i32) -> Self {
-        RemoteFileReader { service, file_fd }
+        RemoteFileReader { service, file_fd, read_ahead: Mutex::new(ReadAhead::default()) }
     }
 
     pub fn new_by_path(
@@ -65,7 +81,7 @@ impl RemoteFileReader {
                     ),
                 )
             })?;
-        Ok(RemoteFileReader { service, file_fd })
+        Ok(RemoteFileReader::new(service, file_fd))
     }
 
     pub fn get_remote_fd(&self) -> i32 {
@@ -75,7 +91,38 @@ impl RemoteFileReader {
 
 impl ReadByChunk for RemoteFileReader {
     fn read_chunk(&self, chunk_index: u64, buf: &mut ChunkBuffer) -> io::Result<usize> {
-        remote_read_chunk(&self.service, self.file_fd, chunk_index, buf)
+        {
+            let mut read_ahead = self.read_ahead.lock().unwrap();
+            let is_sequential = chunk_index == read_ahead.next_chunk_index;
+            read_ahead.next_chunk_index = chunk_index + 1;
+            if !is_sequential {
+                read_ahead.buffer.clear();
+                return remote_read_chunk(&self.service, self.file_fd, chunk_index, buf);
+            }
+            if !read_ahead.buffer.is_empty() {
+                let size = min(buf.len(), read_ahead.buffer.len());
+                buf[..size].copy_from_slice(&read_ahead.buffer[..size]);
+                read_ahead.buffer.drain(..size);
+                return Ok(size);
+            }
+        }
+
+        // Read ahead without holding the lock, since it involves a remote request.
+        let offset = i64::try_from(chunk_index * CHUNK_SIZE)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EOVERFLOW))?;
+        let mut data = self
+            .service
+            .readFile(self.file_fd, offset, MAX_REQUESTING_DATA)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.get_description()))?;
+        let size = min(buf.len(), data.len());
+        buf[..size].copy_from_slice(&data[..size]);
+
+        let mut read_ahead = self.read_ahead.lock().unwrap();
+        // Only keep the extra if no other read has happened in the meantime.
+        if read_ahead.next_chunk_index == chunk_index + 1 && size == buf.len() {
+            read_ahead.buffer = data.split_off(size);
+        }
+        Ok(size)
     }
 }
 
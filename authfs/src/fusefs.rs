@@ -14,8 +14,10 @@
  * limitations under the License.
  */
 
+mod cache;
 mod file;
 mod mount;
+mod stats;
 
 use anyhow::{anyhow, bail, Result};
 use fuse::filesystem::{
@@ -37,6 +39,7 @@ use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use crate::common::{divide_roundup, ChunkedSizeIter, CHUNK_SIZE};
+use crate::crypto::Sha256Hash;
 use crate::file::{
     validate_basename, Attr, InMemoryDir, RandomWrite, ReadByChunk, RemoteDirEditor,
     RemoteFileEditor, RemoteFileReader,
@@ -44,9 +47,11 @@ use crate::file::{
 use crate::fsstat::RemoteFsStatsReader;
 use crate::fsverity::VerifiedFileEditor;
 
+use self::cache::ChunkCache;
 pub use self::file::LazyVerifiedReadonlyFile;
 pub use self::mount::mount_and_enter_message_loop;
 use self::mount::MAX_WRITE_BYTES;
+use self::stats::{format_stats, ReadStats, MAX_STATS_SIZE};
 
 pub type Inode = u64;
 type Handle = u64;
@@ -70,7 +75,8 @@ pub enum AuthFsEntry {
     /// integrity is guaranteed with private Merkle tree.
     VerifiedNew { editor: VerifiedFileEditor<RemoteFileEditor>, attr: Attr },
     /// A directory type that is initially empty. One can create new file (`VerifiedNew`) and new
-    /// directory (`VerifiedNewDirectory` itself) with integrity guaranteed within the VM.
+    /// directory (`VerifiedNewDirectory` itself) with integrity guaranteed within the VM. On fsync,
+    /// the digests of all files in the directory are committed to the remote as a journal.
     VerifiedNewDirectory { dir: RemoteDirEditor, attr: Attr },
 }
 
@@ -207,13 +213,21 @@ pub struct AuthFs {
     /// A reader to access the remote filesystem stats, which is supposed to be of "the" output
     /// directory. We assume all output are stored in the same partition.
     remote_fs_stats_reader: RemoteFsStatsReader,
+
+    /// Cache of verified chunks of `VerifiedReadonly` files.
+    chunk_cache: ChunkCache,
+
+    /// Read statistics of files, exposed through the `authfs.stats` xattr of the root directory.
+    read_stats: ReadStats,
 }
 
 // Implementation for preparing an `AuthFs` instance, before starting to serve.
 // TODO(victorhsieh): Consider implement a builder to separate the mutable initialization from the
 // immutable / interiorly mutable serving phase.
 impl AuthFs {
-    pub fn new(remote_fs_stats_reader: RemoteFsStatsReader) -> AuthFs {
+    /// Creates an `AuthFs` instance. `chunk_cache_size` is the maximum number of verified chunks
+    /// to keep in memory, or 0 to disable the cache.
+    pub fn new(remote_fs_stats_reader: RemoteFsStatsReader, chunk_cache_size: usize) -> AuthFs {
         let mut inode_table = BTreeMap::new();
         inode_table.insert(
             ROOT_INODE,
@@ -226,6 +240,8 @@ impl AuthFs {
             dir_handle_table: RwLock::new(BTreeMap::new()),
             next_handle: AtomicU64::new(1),
             remote_fs_stats_reader,
+            chunk_cache: ChunkCache::new(chunk_cache_size),
+            read_stats: ReadStats::default(),
         }
     }
 
@@ -324,6 +340,23 @@ impl AuthFs {
         handle_inode_locked(&inode_table, inode, |inode_state| handle_fn(&inode_state.entry))
     }
 
+    /// Returns the read statistics of the files that are still reachable from the root, in the
+    /// format of `stats::format_stats`.
+    fn get_formatted_stats(&self) -> io::Result<String> {
+        let mut paths = BTreeMap::new();
+        {
+            let inode_table = self.inode_table.read().unwrap();
+            collect_paths_locked(&inode_table, ROOT_INODE, Path::new(""), &mut paths)?;
+        }
+        let files = self
+            .read_stats
+            .snapshot()
+            .into_iter()
+            .filter_map(|(inode, stats)| paths.remove(&inode).map(|path| (path, stats)))
+            .collect();
+        Ok(format_stats(self.chunk_cache.hit_and_miss_counts(), files, MAX_STATS_SIZE))
+    }
+
     /// Adds a new entry `name` created by `create_fn` at `parent_inode`, with an initial ref count
     /// of one.
     ///
@@ -711,10 +744,14 @@ impl FileSystem for AuthFs {
         _flags: u32,
     ) -> io::Result<usize> {
         self.handle_inode(&inode, |config| {
-            match config {
-                AuthFsEntry::VerifiedReadonly { reader } => {
-                    read_chunks(w, reader, reader.file_size()?, offset, size)
-                }
+            let result = match config {
+                AuthFsEntry::VerifiedReadonly { reader } => read_chunks(
+                    w,
+                    &self.chunk_cache.reader(inode, reader),
+                    reader.file_size()?,
+                    offset,
+                    size,
+                ),
                 AuthFsEntry::UnverifiedReadonly { reader, file_size } => {
                     read_chunks(w, reader, *file_size, offset, size)
                 }
@@ -725,9 +762,11 @@ impl FileSystem for AuthFs {
                 }
                 AuthFsEntry::ReadonlyDirectory { .. }
                 | AuthFsEntry::VerifiedNewDirectory { .. } => {
-                    Err(io::Error::from_raw_os_error(libc::EISDIR))
+                    return Err(io::Error::from_raw_os_error(libc::EISDIR));
                 }
-            }
+            };
+            self.read_stats.record_read(inode, &result);
+            result
         })
     }
 
@@ -817,6 +856,19 @@ impl FileSystem for AuthFs {
         name: &CStr,
         size: u32,
     ) -> io::Result<GetxattrReply> {
+        if inode == ROOT_INODE && name == CStr::from_bytes_with_nul(b"authfs.stats\0").unwrap() {
+            // Unlike the digest, the stats can change between the two calls. Just report the
+            // current size when asked, and let the caller retry with a larger buffer on ERANGE.
+            let value = self.get_formatted_stats()?.into_bytes();
+            return if size == 0 {
+                Ok(GetxattrReply::Count(value.len() as u32))
+            } else if value.len() > size as usize {
+                Err(io::Error::from_raw_os_error(libc::ERANGE))
+            } else {
+                Ok(GetxattrReply::Value(value))
+            };
+        }
+
         self.handle_inode(&inode, |config| {
             match config {
                 AuthFsEntry::VerifiedNew { editor, .. } => {
@@ -989,6 +1041,25 @@ impl FileSystem for AuthFs {
         Ok(())
     }
 
+    fn fsyncdir(
+        &self,
+        _ctx: Context,
+        inode: Self::Inode,
+        _datasync: bool,
+        _handle: Self::Handle,
+    ) -> io::Result<()> {
+        let inode_table = self.inode_table.read().unwrap();
+        handle_inode_locked(&inode_table, &inode, |inode_state| match &inode_state.entry {
+            AuthFsEntry::VerifiedNewDirectory { dir, .. } => {
+                let mut journal = Vec::new();
+                collect_journal_entries_locked(&inode_table, dir, Path::new(""), &mut journal)?;
+                dir.commit_journal(journal)
+            }
+            AuthFsEntry::ReadonlyDirectory { .. } => Ok(()),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        })
+    }
+
     fn statfs(&self, _ctx: Context, _inode: Self::Inode) -> io::Result<libc::statvfs64> {
         let remote_stat = self.remote_fs_stats_reader.statfs()?;
 
@@ -1046,6 +1117,55 @@ where
     }
 }
 
+/// Collects the fs-verity digests of all files under `dir` recursively, with paths prefixed by
+/// `prefix`.
+fn collect_journal_entries_locked(
+    inode_table: &BTreeMap<Inode, InodeState>,
+    dir: &RemoteDirEditor,
+    prefix: &Path,
+    journal: &mut Vec<(PathBuf, Sha256Hash)>,
+) -> io::Result<()> {
+    for entry in dir.retrieve_entries()? {
+        let path = prefix.join(cstr_to_path(&entry.name));
+        handle_inode_locked(inode_table, &entry.inode, |inode_state| match &inode_state.entry {
+            AuthFsEntry::VerifiedNew { editor, .. } => {
+                journal.push((path, editor.calculate_fsverity_digest()?));
+                Ok(())
+            }
+            AuthFsEntry::VerifiedNewDirectory { dir, .. } => {
+                collect_journal_entries_locked(inode_table, dir, &path, journal)
+            }
+            _ => unreachable!("Unexpected entry type in VerifiedNewDirectory"),
+        })?;
+    }
+    Ok(())
+}
+
+/// Collects the paths, relative to `prefix`, of all files under the directory `inode`
+/// recursively.
+fn collect_paths_locked(
+    inode_table: &BTreeMap<Inode, InodeState>,
+    inode: Inode,
+    prefix: &Path,
+    paths: &mut BTreeMap<Inode, PathBuf>,
+) -> io::Result<()> {
+    let entries =
+        handle_inode_locked(inode_table, &inode, |inode_state| match &inode_state.entry {
+            AuthFsEntry::ReadonlyDirectory { dir } => dir.retrieve_entries(),
+            AuthFsEntry::VerifiedNewDirectory { dir, .. } => dir.retrieve_entries(),
+            _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+        })?;
+    for entry in entries {
+        let path = prefix.join(cstr_to_path(&entry.name));
+        if entry.is_dir {
+            collect_paths_locked(inode_table, entry.inode, &path, paths)?;
+        } else {
+            paths.insert(entry.inode, path);
+        }
+    }
+    Ok(())
+}
+
 fn check_unsupported_setattr_request(valid: SetattrValid) -> io::Result<()> {
     if valid.contains(SetattrValid::UID) {
         warn!("Changing st_uid is not currently supported");
@@ -0,0 +1,213 @@
+/*
+ * Copyright (C) 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::Inode;
+use crate::file::{ChunkBuffer, ReadByChunk};
+
+type ChunkKey = (Inode, u64);
+
+struct CachedChunk {
+    data: Vec<u8>,
+    last_used: u64,
+}
+
+struct LruState {
+    chunks: HashMap<ChunkKey, CachedChunk>,
+    /// Keys of `chunks` ordered by the last use, the least recently used first.
+    lru_order: BTreeMap<u64, ChunkKey>,
+    /// A monotonic counter to track the recency of use.
+    clock: u64,
+}
+
+/// An LRU cache of file chunks, shared by files in `AuthFs` and keyed by the inode and the chunk
+/// index. It is only supposed to store chunks of immutable files after verification, so that a
+/// cache hit can skip both the remote read and the verification.
+pub struct ChunkCache {
+    /// The maximum number of chunks to keep. 0 disables the cache.
+    capacity: usize,
+    state: Mutex<LruState>,
+    hit_count: AtomicU64,
+    miss_count: AtomicU64,
+}
+
+impl ChunkCache {
+    pub fn new(capacity: usize) -> Self {
+        ChunkCache {
+            capacity,
+            state: Mutex::new(LruState {
+                chunks: HashMap::new(),
+                lru_order: BTreeMap::new(),
+                clock: 0,
+            }),
+            hit_count: AtomicU64::new(0),
+            miss_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of cache hits and misses so far.
+    pub fn hit_and_miss_counts(&self) -> (u64, u64) {
+        (self.hit_count.load(Ordering::Relaxed), self.miss_count.load(Ordering::Relaxed))
+    }
+
+    /// Returns a `ReadByChunk` that reads chunks of `file` at `inode` through the cache.
+    pub fn reader<'a, T: ReadByChunk>(
+        &'a self,
+        inode: Inode,
+        file: &'a T,
+    ) -> CachedChunkReader<'a, T> {
+        CachedChunkReader { cache: self, inode, file }
+    }
+
+    fn get(&self, key: ChunkKey, buf: &mut ChunkBuffer) -> Option<usize> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.clock += 1;
+        let now = state.clock;
+        let chunk = state.chunks.get_mut(&key)?;
+        let size = chunk.data.len();
+        buf[..size].copy_from_slice(&chunk.data);
+        let last_used = std::mem::replace(&mut chunk.last_used, now);
+        state.lru_order.remove(&last_used);
+        state.lru_order.insert(now, key);
+        Some(size)
+    }
+
+    fn insert(&self, key: ChunkKey, data: &[u8]) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.clock += 1;
+        let now = state.clock;
+        let new_chunk = CachedChunk { data: data.to_vec(), last_used: now };
+        if let Some(old_chunk) = state.chunks.insert(key, new_chunk) {
+            state.lru_order.remove(&old_chunk.last_used);
+        }
+        state.lru_order.insert(now, key);
+
+        while state.chunks.len() > self.capacity {
+            let oldest = *state.lru_order.keys().next().expect("keys are in sync with chunks");
+            let evicted = state.lru_order.remove(&oldest).unwrap();
+            state.chunks.remove(&evicted);
+        }
+    }
+}
+
+/// A `ReadByChunk` of a file that reads through a `ChunkCache`.
+pub struct CachedChunkReader<'a, T: ReadByChunk> {
+    cache: &'a ChunkCache,
+    inode: Inode,
+    file: &'a T,
+}
+
+impl<'a, T: ReadByChunk> ReadByChunk for CachedChunkReader<'a, T> {
+    fn read_chunk(&self, chunk_index: u64, buf: &mut ChunkBuffer) -> io::Result<usize> {
+        if self.cache.capacity == 0 {
+            return self.file.read_chunk(chunk_index, buf);
+        }
+        let key = (self.inode, chunk_index);
+        if let Some(size) = self.cache.get(key, buf) {
+            self.cache.hit_count.fetch_add(1, Ordering::Relaxed);
+            return Ok(size);
+        }
+        self.cache.miss_count.fetch_add(1, Ordering::Relaxed);
+        let size = self.file.read_chunk(chunk_index, buf)?;
+        self.cache.insert(key, &buf[..size]);
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// A fake file whose chunk content is filled with the chunk index, and records the reads.
+    struct FakeFile {
+        reads: RefCell<Vec<u64>>,
+    }
+
+    impl FakeFile {
+        fn new() -> Self {
+            FakeFile { reads: RefCell::new(Vec::new()) }
+        }
+    }
+
+    impl ReadByChunk for FakeFile {
+        fn read_chunk(&self, chunk_index: u64, buf: &mut ChunkBuffer) -> io::Result<usize> {
+            self.reads.borrow_mut().push(chunk_index);
+            buf.fill(chunk_index as u8);
+            Ok(buf.len())
+        }
+    }
+
+    fn read(reader: &impl ReadByChunk, chunk_index: u64) -> u8 {
+        let mut buf = [0u8; 4096];
+        assert_eq!(reader.read_chunk(chunk_index, &mut buf).unwrap(), buf.len());
+        buf[0]
+    }
+
+    #[test]
+    fn cache_hit_skips_file_read() {
+        let cache = ChunkCache::new(2);
+        let file = FakeFile::new();
+        let reader = cache.reader(1, &file);
+
+        assert_eq!(read(&reader, 3), 3);
+        assert_eq!(read(&reader, 3), 3);
+        assert_eq!(*file.reads.borrow(), vec![3]);
+        assert_eq!(cache.hit_and_miss_counts(), (1, 1));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ChunkCache::new(2);
+        let file = FakeFile::new();
+        let reader = cache.reader(1, &file);
+
+        read(&reader, 0);
+        read(&reader, 1);
+        read(&reader, 0); // Now 1 is the least recently used.
+        read(&reader, 2); // Evicts 1.
+        read(&reader, 0);
+        read(&reader, 1);
+        assert_eq!(*file.reads.borrow(), vec![0, 1, 2, 1]);
+    }
+
+    #[test]
+    fn chunks_are_keyed_by_inode() {
+        let cache = ChunkCache::new(2);
+        let file = FakeFile::new();
+
+        read(&cache.reader(1, &file), 0);
+        read(&cache.reader(2, &file), 0);
+        assert_eq!(*file.reads.borrow(), vec![0, 0]);
+    }
+
+    #[test]
+    fn zero_capacity_disables_cache() {
+        let cache = ChunkCache::new(0);
+        let file = FakeFile::new();
+        let reader = cache.reader(1, &file);
+
+        read(&reader, 0);
+        read(&reader, 0);
+        assert_eq!(*file.reads.borrow(), vec![0, 0]);
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright (C) 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use super::Inode;
+
+/// Statistics of reads from a file.
+#[derive(Clone, Default)]
+pub struct FileStats {
+    /// Number of read requests.
+    pub reads: u64,
+    /// Number of read requests that failed due to an integrity violation.
+    pub verification_failures: u64,
+}
+
+/// Statistics of reads from the files in `AuthFs`, keyed by inode.
+#[derive(Default)]
+pub struct ReadStats(Mutex<BTreeMap<Inode, FileStats>>);
+
+impl ReadStats {
+    /// Records the result of a read request of the file at `inode`.
+    pub fn record_read<T>(&self, inode: Inode, result: &io::Result<T>) {
+        let mut files = self.0.lock().unwrap();
+        let stats = files.entry(inode).or_default();
+        stats.reads += 1;
+        if matches!(result, Err(e) if is_verification_error(e)) {
+            stats.verification_failures += 1;
+        }
+    }
+
+    /// Returns the statistics of all files that have been read.
+    pub fn snapshot(&self) -> BTreeMap<Inode, FileStats> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Returns whether the error is caused by data that fails the verification. See
+/// `VerifiedFileReader` and `VerifiedFileEditor`.
+fn is_verification_error(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EIO) || error.kind() == io::ErrorKind::InvalidData
+}
+
+/// The maximum size of an xattr value, XATTR_SIZE_MAX from include/uapi/linux/limits.h. A larger
+/// value can't be returned to the caller of getxattr(2), which fails with E2BIG.
+pub const MAX_STATS_SIZE: usize = 65536;
+
+/// Formats the statistics in the text form exposed through the `authfs.stats` xattr of the root
+/// directory. The first line is "cache <hits> <misses>", followed by one line per file in the form
+/// of "file <reads> <verification failures> <path relative to the mount point>". If the text would
+/// be longer than `max_size`, the files with verification failures and then the most reads are
+/// kept, and the last line is "omitted <number of files left out>".
+pub fn format_stats(
+    cache_hit_and_miss_counts: (u64, u64),
+    mut files: Vec<(PathBuf, FileStats)>,
+    max_size: usize,
+) -> String {
+    let (hits, misses) = cache_hit_and_miss_counts;
+    let mut text = format!("cache {} {}\n", hits, misses);
+    files.sort_by(|(path_a, a), (path_b, b)| {
+        (b.verification_failures, b.reads, path_a).cmp(&(a.verification_failures, a.reads, path_b))
+    });
+    let line_of = |(path, stats): &(PathBuf, FileStats)| {
+        format!("file {} {} {}\n", stats.reads, stats.verification_failures, path.display())
+    };
+    let total_size = text.len() + files.iter().map(|file| line_of(file).len()).sum::<usize>();
+    if total_size <= max_size {
+        files.iter().for_each(|file| text.push_str(&line_of(file)));
+        return text;
+    }
+    // Leave room for the "omitted" line, whose count is at most the number of files.
+    let omitted_line_size = format!("omitted {}\n", files.len()).len();
+    let mut omitted = 0;
+    for file in &files {
+        let line = line_of(file);
+        if omitted == 0 && text.len() + line.len() + omitted_line_size <= max_size {
+            text.push_str(&line);
+        } else {
+            omitted += 1;
+        }
+    }
+    text.push_str(&format!("omitted {}\n", omitted));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_reads() {
+        let stats = ReadStats::default();
+        stats.record_read(2, &Ok(4096));
+        stats.record_read(2, &Err::<usize, _>(io::Error::from_raw_os_error(libc::EIO)));
+        stats.record_read(3, &Err::<usize, _>(io::Error::from_raw_os_error(libc::ENODATA)));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot[&2].reads, 2);
+        assert_eq!(snapshot[&2].verification_failures, 1);
+        assert_eq!(snapshot[&3].reads, 1);
+        assert_eq!(snapshot[&3].verification_failures, 0);
+    }
+
+    #[test]
+    fn format() {
+        let files = vec![
+            (PathBuf::from("3"), FileStats { reads: 10, verification_failures: 0 }),
+            (PathBuf::from("dir/some file"), FileStats { reads: 1, verification_failures: 1 }),
+        ];
+        assert_eq!(
+            format_stats((5, 7), files, MAX_STATS_SIZE),
+            "cache 5 7\nfile 1 1 dir/some file\nfile 10 0 3\n"
+        );
+    }
+
+    #[test]
+    fn format_within_max_size() {
+        let files = (0..10000)
+            .map(|i| {
+                let stats = FileStats { reads: i, verification_failures: (i == 42) as u64 };
+                (PathBuf::from(format!("some/long/directory/name/file{}", i)), stats)
+            })
+            .collect();
+        let text = format_stats((0, 0), files, MAX_STATS_SIZE);
+        assert!(text.len() <= MAX_STATS_SIZE);
+
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[1], "file 42 1 some/long/directory/name/file42");
+        assert_eq!(lines[2], "file 9999 0 some/long/directory/name/file9999");
+        let omitted: usize =
+            lines.last().unwrap().strip_prefix("omitted ").unwrap().parse().unwrap();
+        assert_eq!(lines.len() - 2 + omitted, 10000);
+    }
+}
@@ -113,13 +113,19 @@ struct Args {
 
     /// A new directory that is assumed empty in the backing filesystem. New files created in this
     /// directory are integrity-protected in the same way as --remote-new-verified-file. Can be
-    /// multiple.
+    /// multiple. Calling fsync on a directory commits the fs-verity digests of all files in it as a
+    /// journal, which the remote validates and stores.
     ///
     /// For example, `--remote-new-rw-dir 5` tells the filesystem to associate $MOUNTPOINT/5
     /// with a remote dir FD 5.
     #[structopt(long)]
     remote_new_rw_dir: Vec<i32>,
 
+    /// Maximum number of verified chunks (4K each) of read-only files to cache in memory. 0
+    /// disables the cache.
+    #[structopt(long, default_value = "1024")]
+    chunk_cache_size: usize,
+
     /// Enable debugging features.
     #[structopt(long)]
     debug: bool,
@@ -314,7 +320,7 @@ fn try_main() -> Result<()> {
     );
 
     let service = file::get_rpc_binder_service(args.cid)?;
-    let mut authfs = AuthFs::new(RemoteFsStatsReader::new(service.clone()));
+    let mut authfs = AuthFs::new(RemoteFsStatsReader::new(service.clone()), args.chunk_cache_size);
     prepare_root_dir_entries(service, &mut authfs, &args)?;
 
     fusefs::mount_and_enter_message_loop(
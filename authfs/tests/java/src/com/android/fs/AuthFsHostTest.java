@@ -249,6 +249,37 @@ public final class AuthFsHostTest extends VirtualizationTestCaseBase {
         assertEquals("Inconsistent hash from /authfs/6: ", expectedHash4k1, actualHash4k1);
     }
 
+    @Test
+    public void testReadWithFsverityVerification_DigestOnly() throws Exception {
+        // Setup
+        // No fs-verity metadata is given, so fd_server has to generate the Merkle tree.
+        runFdServerOnAndroid("--open-ro 3:input.4m --open-ro 6:input.4k1", "--ro-fds 3 --ro-fds 6");
+        runAuthFsOnMicrodroid(
+                "--remote-ro-file 3:" + DIGEST_4M + " --remote-ro-file 6:" + DIGEST_4K1 + " --cid "
+                        + VMADDR_CID_HOST);
+
+        // Action
+        String actualHash4m = computeFileHashOnMicrodroid(MOUNT_DIR + "/3");
+        String actualHash4k1 = computeFileHashOnMicrodroid(MOUNT_DIR + "/6");
+
+        // Verify
+        String expectedHash4m = computeFileHashOnAndroid(TEST_DIR + "/input.4m");
+        String expectedHash4k1 = computeFileHashOnAndroid(TEST_DIR + "/input.4k1");
+
+        assertEquals("Inconsistent hash from /authfs/3: ", expectedHash4m, actualHash4m);
+        assertEquals("Inconsistent hash from /authfs/6: ", expectedHash4k1, actualHash4k1);
+    }
+
+    @Test
+    public void testReadWithFsverityVerification_DigestOnlyMismatch() throws Exception {
+        // Setup
+        runFdServerOnAndroid("--open-ro 3:input.4k1", "--ro-fds 3");
+        runAuthFsOnMicrodroid("--remote-ro-file 3:" + DIGEST_4K + " --cid " + VMADDR_CID_HOST);
+
+        // Verify
+        assertFalse(copyFileOnMicrodroid(MOUNT_DIR + "/3", "/dev/null"));
+    }
+
     @Test
     public void testReadWithFsverityVerification_TamperedMerkleTree() throws Exception {
         // Setup
@@ -453,6 +484,52 @@ public final class AuthFsHostTest extends VirtualizationTestCaseBase {
                 "684ad25fdc2bbb80cbc910dd1bde6d5499ccf860ca6ee44704b77ec445271353");
     }
 
+    @Test
+    public void testOutputDirectory_CommitJournal() throws Exception {
+        // Setup
+        String androidOutputDir = TEST_OUTPUT_DIR + "/dir";
+        String authfsOutputDir = MOUNT_DIR + "/3";
+        sAndroid.run("mkdir " + androidOutputDir);
+        runFdServerOnAndroid("--open-dir 3:" + androidOutputDir, "--rw-dirs 3");
+        runAuthFsOnMicrodroid("--remote-new-rw-dir 3 --cid " + VMADDR_CID_HOST);
+        sMicrodroid.run("mkdir " + authfsOutputDir + "/new_dir");
+        createFileWithOnesOnMicrodroid(authfsOutputDir + "/new_dir/file1", 10000);
+
+        // Action
+        // fsync on the directory commits the journal.
+        sMicrodroid.run("sync " + authfsOutputDir);
+
+        // Verify
+        String journal = sAndroid.run("cat " + androidOutputDir + "/.authfs_journal");
+        assertEquals(
+                "sha256:684ad25fdc2bbb80cbc910dd1bde6d5499ccf860ca6ee44704b77ec445271353"
+                        + " new_dir/file1",
+                journal);
+        // The journal name is reserved.
+        assertFalse(
+                sMicrodroid.runForResult("touch " + authfsOutputDir + "/.authfs_journal")
+                        .getStatus() == CommandStatus.SUCCESS);
+    }
+
+    @Test
+    public void testOutputDirectory_CommitJournalFailedIfDetectsTampering() throws Exception {
+        // Setup
+        String androidOutputDir = TEST_OUTPUT_DIR + "/dir";
+        String authfsOutputDir = MOUNT_DIR + "/3";
+        sAndroid.run("mkdir " + androidOutputDir);
+        runFdServerOnAndroid("--open-dir 3:" + androidOutputDir, "--rw-dirs 3");
+        runAuthFsOnMicrodroid("--remote-new-rw-dir 3 --cid " + VMADDR_CID_HOST);
+        createFileWithOnesOnMicrodroid(authfsOutputDir + "/file", 10000);
+
+        // Action
+        zeroizeFileOnAndroid(androidOutputDir + "/file", /* size */ 4096, /* offset */ 0);
+
+        // Verify
+        CommandResult result = sMicrodroid.runForResult("sync " + authfsOutputDir);
+        assertFalse(result.getStatus() == CommandStatus.SUCCESS);
+        sAndroid.run("test ! -f " + androidOutputDir + "/.authfs_journal");
+    }
+
     @Test
     public void testOutputDirectory_CreateAndTruncateExistingFile() throws Exception {
         // Setup
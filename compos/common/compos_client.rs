@@ -49,7 +49,6 @@ use std::thread;
 
 /// This owns an instance of the CompOS VM.
 pub struct VmInstance {
-    #[allow(dead_code)] // Keeps the VM alive even if we don`t touch it
     vm: Strong<dyn IVirtualMachine>,
     cid: i32,
 }
@@ -129,10 +128,13 @@ impl VmInstance {
 
         let config_path = parameters.config_path.as_deref().unwrap_or(DEFAULT_VM_CONFIG_PATH);
         let config = VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
+            name: String::from("compos"),
             apk: Some(apk_fd),
             idsig: Some(idsig_fd),
             instanceImage: Some(instance_fd),
             configPath: config_path.to_owned(),
+            payloadBinaryPath: None,
+            payloadBinaryArgs: vec![],
             debugLevel: debug_level,
             extraIdsigs: vec![idsig_manifest_apk_fd],
             protectedVm: protected_vm,
@@ -140,6 +142,8 @@ impl VmInstance {
             numCpus: parameters.cpus.map_or(1, NonZeroU32::get) as i32,
             cpuAffinity: parameters.cpu_set.clone(),
             taskProfiles: parameters.task_profiles.clone(),
+            sharedDirectory: None,
+            swapImage: None,
         });
 
         let vm = service
@@ -195,6 +199,16 @@ impl VmInstance {
         FromIBinder::try_from(ibinder).context("Connecting to CompOS service")
     }
 
+    /// Pauses the VM, so that it doesn't use any CPU until resumed.
+    pub fn pause(&self) -> Result<()> {
+        self.vm.pauseVm().context("Pausing VM")
+    }
+
+    /// Resumes the VM paused by `pause`.
+    pub fn resume(&self) -> Result<()> {
+        self.vm.resumeVm().context("Resuming VM")
+    }
+
     /// Return the CID of the VM.
     pub fn cid(&self) -> i32 {
         // TODO: Do we actually need/use this?
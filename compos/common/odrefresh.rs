@@ -68,6 +68,30 @@ impl ExitCode {
     }
 }
 
+/// Progress of a compilation in CompOS. It is reported to the host by appending lines in the form
+/// of "<percentage> <last module>" to the progress file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Percentage of the modules that have been compiled, from 0 to 100.
+    pub percentage: i32,
+    /// Name of the module whose compilation was most recently seen to finish.
+    pub last_module: String,
+}
+
+impl Progress {
+    /// Returns the line representing the progress in the progress file.
+    pub fn to_line(&self) -> String {
+        format!("{} {}\n", self.percentage, self.last_module)
+    }
+
+    /// Parses a line (without the line break) of the progress file.
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let (percentage, last_module) = line.split_once(' ')?;
+        let percentage = percentage.parse().ok().filter(|p| (0..=100).contains(p))?;
+        Some(Self { percentage, last_module: last_module.to_owned() })
+    }
+}
+
 /// Returns whether the system property name is interesting to odrefresh and dex2oat.
 pub fn is_system_property_interesting(name: &str) -> bool {
     for prefix in ALLOWLIST_SYSTEM_PROPERTY_PREFIXES {
@@ -0,0 +1,249 @@
+/*
+ * Copyright 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A cache of odrefresh outputs. The output of a compilation is fully determined by the APEXes
+//! visible to the CompOS VM and the compiler flags, so when neither has changed since a previous
+//! successful compilation, its artifacts can be copied instead of compiling again.
+//!
+//! An APEX is identified by its version and the metadata of its file rather than by its content,
+//! so computing the key doesn't read the APEXes. This is the same as VirtualizationService does to
+//! tell whether the APEXes of a VM have changed.
+//!
+//! The artifacts are signed by CompOS as a whole (see `compos.info`), so the cache works at the
+//! granularity of a complete odrefresh output rather than individual modules.
+
+use anyhow::{bail, Context, Result};
+use binder::wait_for_interface;
+use compos_common::COMPOS_DATA_ROOT;
+use log::{debug, info, warn};
+use packagemanager_aidl::aidl::android::content::pm::IPackageManagerNative::IPackageManagerNative;
+use ring::digest::{Context as DigestContext, SHA256};
+use serde::Deserialize;
+use serde_xml_rs::from_reader;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const APEX_INFO_LIST_PATH: &str = "/apex/apex-info-list.xml";
+const PACKAGE_MANAGER_NATIVE_SERVICE: &str = "package_native";
+
+/// The sub-directory of `COMPOS_DATA_ROOT` where cached outputs are stored, each in a directory
+/// named by its key.
+const ARTIFACT_CACHE_DIR: &str = "artifact-cache";
+
+/// Suffix of a directory that an output is copied to before being atomically renamed into place.
+const INCOMPLETE_SUFFIX: &str = ".incomplete";
+
+/// Maximum number of cached outputs to keep. The most recent one is the one that matters for the
+/// next staged compilation; one more allows alternating between two APEX sets (e.g. a rollback).
+const MAX_CACHE_ENTRIES: usize = 2;
+
+#[derive(Deserialize)]
+struct ApexInfoList {
+    #[serde(rename = "apex-info")]
+    list: Vec<ApexInfo>,
+}
+
+#[derive(Deserialize)]
+struct ApexInfo {
+    #[serde(rename = "moduleName")]
+    name: String,
+    #[serde(rename = "versionCode")]
+    version: i64,
+    #[serde(rename = "modulePath")]
+    path: PathBuf,
+    #[serde(rename = "isActive")]
+    is_active: bool,
+}
+
+/// What identifies an APEX as seen by the CompOS VM.
+struct ApexIdentity {
+    version: i64,
+    path: PathBuf,
+}
+
+/// Identifies the output of a compilation by a digest of its inputs.
+pub struct ArtifactCacheKey(String);
+
+impl ArtifactCacheKey {
+    /// Computes the key from the active APEXes (overridden by the staged ones if `prefer_staged`),
+    /// and `compiler_flags`, which should contain everything else that affects the compilation
+    /// output. Each APEX contributes its name, version, path, and the size, modification time and
+    /// inode of its file, which change whenever the APEX is replaced even if its version doesn't.
+    pub fn new(prefer_staged: bool, compiler_flags: &[(String, String)]) -> Result<Self> {
+        let mut context = DigestContext::new(&SHA256);
+        for (name, apex) in get_apexes(prefer_staged)? {
+            let metadata = fs::metadata(&apex.path)
+                .with_context(|| format!("Failed to get metadata of {:?}", apex.path))?;
+            debug!(
+                "APEX {} version {} at {:?}: size {}, mtime {}.{:09}, inode {}",
+                name,
+                apex.version,
+                apex.path,
+                metadata.size(),
+                metadata.mtime(),
+                metadata.mtime_nsec(),
+                metadata.ino()
+            );
+            update_with_field(&mut context, name.as_bytes());
+            update_with_field(&mut context, &apex.version.to_le_bytes());
+            update_with_field(&mut context, apex.path.as_os_str().as_bytes());
+            update_with_field(&mut context, &metadata.size().to_le_bytes());
+            update_with_field(&mut context, &metadata.mtime().to_le_bytes());
+            update_with_field(&mut context, &metadata.mtime_nsec().to_le_bytes());
+            update_with_field(&mut context, &metadata.ino().to_le_bytes());
+        }
+        for (name, value) in compiler_flags {
+            update_with_field(&mut context, name.as_bytes());
+            update_with_field(&mut context, value.as_bytes());
+        }
+        Ok(Self(to_hex_string(context.finish().as_ref())))
+    }
+}
+
+/// Copies the cached output for `key` to `target_dir`, which must not exist. Returns false if
+/// there is no such output.
+pub fn restore(key: &ArtifactCacheKey, target_dir: &Path) -> Result<bool> {
+    let cached_dir = cache_root().join(&key.0);
+    if !cached_dir.is_dir() {
+        return Ok(false);
+    }
+    if let Err(e) = copy_dir_all(&cached_dir, target_dir) {
+        // Don't leave a partial output, which could be mistaken as a compilation result.
+        let _ = fs::remove_dir_all(target_dir);
+        return Err(e).context("Failed to restore cached artifacts");
+    }
+    info!("Restored artifacts from cache {}", key.0);
+    Ok(true)
+}
+
+/// Stores a copy of the output at `source_dir` for `key`, evicting the oldest outputs beyond
+/// `MAX_CACHE_ENTRIES`.
+pub fn store(key: &ArtifactCacheKey, source_dir: &Path) -> Result<()> {
+    let cache_root = cache_root();
+    fs::create_dir_all(&cache_root)
+        .with_context(|| format!("Failed to create {}", cache_root.display()))?;
+
+    let cached_dir = cache_root.join(&key.0);
+    let incomplete_dir = cache_root.join(format!("{}{}", key.0, INCOMPLETE_SUFFIX));
+    remove_dir_if_exists(&incomplete_dir)?;
+    copy_dir_all(source_dir, &incomplete_dir)?;
+    remove_dir_if_exists(&cached_dir)?;
+    fs::rename(&incomplete_dir, &cached_dir)
+        .with_context(|| format!("Failed to rename to {}", cached_dir.display()))?;
+    info!("Stored artifacts to cache {}", key.0);
+
+    if let Err(e) = evict_old_entries(&cache_root) {
+        warn!("Failed to evict old cache entries: {:?}", e);
+    }
+    Ok(())
+}
+
+fn cache_root() -> PathBuf {
+    Path::new(COMPOS_DATA_ROOT).join(ARTIFACT_CACHE_DIR)
+}
+
+fn evict_old_entries(cache_root: &Path) -> Result<()> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(cache_root)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.to_string_lossy().ends_with(INCOMPLETE_SUFFIX) {
+            // Left over by an interrupted `store`.
+            remove_dir_if_exists(&path)?;
+            continue;
+        }
+        entries.push((entry.metadata()?.modified()?, path));
+    }
+    entries.sort();
+    let excess = entries.len().saturating_sub(MAX_CACHE_ENTRIES);
+    for (_, path) in entries.into_iter().take(excess) {
+        debug!("Evicting cached artifacts {:?}", path);
+        remove_dir_if_exists(&path)?;
+    }
+    Ok(())
+}
+
+/// Returns the APEXes keyed by name, as they would be seen by the CompOS VM.
+fn get_apexes(prefer_staged: bool) -> Result<BTreeMap<String, ApexIdentity>> {
+    let apex_info_list = File::open(APEX_INFO_LIST_PATH)
+        .with_context(|| format!("Failed to open {}", APEX_INFO_LIST_PATH))?;
+    let apex_info_list: ApexInfoList = from_reader(apex_info_list)
+        .with_context(|| format!("Failed to parse {}", APEX_INFO_LIST_PATH))?;
+    let mut apexes: BTreeMap<_, _> = apex_info_list
+        .list
+        .into_iter()
+        .filter(|apex_info| apex_info.is_active)
+        .map(|apex_info| {
+            (apex_info.name, ApexIdentity { version: apex_info.version, path: apex_info.path })
+        })
+        .collect();
+
+    if prefer_staged {
+        let pm = wait_for_interface::<dyn IPackageManagerNative>(PACKAGE_MANAGER_NATIVE_SERVICE)
+            .context("Failed to get package manager")?;
+        for name in pm.getStagedApexModuleNames().context("getStagedApexModuleNames failed")? {
+            if let Some(staged_apex_info) =
+                pm.getStagedApexInfo(&name).context("getStagedApexInfo failed")?
+            {
+                let apex = ApexIdentity {
+                    version: staged_apex_info.versionCode,
+                    path: PathBuf::from(staged_apex_info.diskImagePath),
+                };
+                apexes.insert(name, apex);
+            }
+        }
+    }
+    Ok(apexes)
+}
+
+/// Updates the digest with a length-prefixed field, so that the boundaries of fields are
+/// unambiguous.
+fn update_with_field(context: &mut DigestContext, field: &[u8]) {
+    context.update(&(field.len() as u64).to_le_bytes());
+    context.update(field);
+}
+
+fn copy_dir_all(source: &Path, target: &Path) -> Result<()> {
+    fs::create_dir(target).with_context(|| format!("Failed to create {}", target.display()))?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target_path = target.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &target_path)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), &target_path)
+                .with_context(|| format!("Failed to copy to {}", target_path.display()))?;
+        } else {
+            bail!("Unexpected file type of {:?}", entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn to_hex_string(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
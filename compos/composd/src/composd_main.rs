@@ -18,10 +18,12 @@
 //! responsible for managing the lifecycle of the CompOS VM instances, providing key management for
 //! them, and orchestrating trusted compilation.
 
+mod artifact_cache;
 mod fd_server_helper;
 mod instance_manager;
 mod instance_starter;
 mod odrefresh_task;
+mod scheduler;
 mod service;
 
 use crate::instance_manager::InstanceManager;
@@ -32,7 +32,6 @@ use std::path::{Path, PathBuf};
 
 pub struct CompOsInstance {
     service: Strong<dyn ICompOsService>,
-    #[allow(dead_code)] // Keeps VirtualizationService & the VM alive
     vm_instance: VmInstance,
     #[allow(dead_code)] // Keeps composd process alive
     lazy_service_guard: LazyServiceGuard,
@@ -42,6 +41,10 @@ impl CompOsInstance {
     pub fn get_service(&self) -> Strong<dyn ICompOsService> {
         self.service.clone()
     }
+
+    pub fn vm_instance(&self) -> &VmInstance {
+        &self.vm_instance
+    }
 }
 
 pub struct InstanceStarter {
@@ -16,11 +16,14 @@
 
 //! Handle running odrefresh in the VM, with an async interface to allow cancellation
 
+use crate::artifact_cache::{self, ArtifactCacheKey};
 use crate::fd_server_helper::FdServerConfig;
 use crate::instance_starter::CompOsInstance;
+use crate::scheduler::Scheduler;
 use android_system_composd::aidl::android::system::composd::{
     ICompilationTask::ICompilationTask,
     ICompilationTaskCallback::{FailureReason::FailureReason, ICompilationTaskCallback},
+    SchedulingPolicy::SchedulingPolicy,
 };
 use android_system_composd::binder::{Interface, Result as BinderResult, Strong};
 use anyhow::{Context, Result};
@@ -28,16 +31,24 @@ use compos_aidl_interface::aidl::com::android::compos::ICompOsService::{
     CompilationMode::CompilationMode, ICompOsService,
 };
 use compos_common::odrefresh::{
-    is_system_property_interesting, ExitCode, ODREFRESH_OUTPUT_ROOT_DIR,
+    is_system_property_interesting, ExitCode, Progress, ODREFRESH_OUTPUT_ROOT_DIR,
 };
 use log::{error, info, warn};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use rustutils::system_properties;
+use std::ffi::CString;
 use std::fs::{remove_dir_all, File, OpenOptions};
+use std::io::Read;
 use std::os::unix::fs::OpenOptionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
+
+/// How often to check the progress file written by CompOS.
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct OdrefreshTask {
@@ -59,6 +70,8 @@ struct RunningTask {
     callback: Strong<dyn ICompilationTaskCallback>,
     #[allow(dead_code)] // Keeps the CompOS VM alive
     comp_os: Arc<CompOsInstance>,
+    #[allow(dead_code)] // Controls the CompOS VM while the task is running
+    scheduler: Scheduler,
 }
 
 impl OdrefreshTask {
@@ -73,11 +86,24 @@ impl OdrefreshTask {
         comp_os: Arc<CompOsInstance>,
         compilation_mode: CompilationMode,
         target_dir_name: String,
+        scheduling_policy: Arc<Mutex<SchedulingPolicy>>,
         callback: &Strong<dyn ICompilationTaskCallback>,
     ) -> Result<OdrefreshTask> {
         let service = comp_os.get_service();
-        let task = RunningTask { comp_os, callback: callback.clone() };
-        let task = OdrefreshTask { running_task: Arc::new(Mutex::new(Some(task))) };
+        let task = OdrefreshTask { running_task: Arc::new(Mutex::new(None)) };
+        let on_pause = {
+            let task = task.clone();
+            // The running task owns the scheduler, so it must be dropped from another thread.
+            Box::new(move || {
+                thread::spawn(move || task.give_up_for_device_conditions());
+            })
+        };
+        {
+            // Hold the lock until the task is stored, in case the scheduler gives up on it at once.
+            let mut running_task = task.running_task.lock().unwrap();
+            let scheduler = Scheduler::start(comp_os.clone(), scheduling_policy, on_pause);
+            *running_task = Some(RunningTask { comp_os, callback: callback.clone(), scheduler });
+        }
 
         task.clone().start_thread(service, compilation_mode, target_dir_name);
 
@@ -91,7 +117,7 @@ impl OdrefreshTask {
         target_dir_name: String,
     ) {
         thread::spawn(move || {
-            let exit_code = run_in_vm(service, compilation_mode, &target_dir_name);
+            let exit_code = run_in_vm(&self, service, compilation_mode, &target_dir_name);
 
             let task = self.take();
             // We don't do the callback if cancel has already happened.
@@ -119,9 +145,65 @@ impl OdrefreshTask {
             }
         });
     }
+
+    /// Ends the task because the device is too hot or its battery too low, so that the client can
+    /// retry it later rather than the VM being paused while odrefresh's timeouts keep running.
+    fn give_up_for_device_conditions(&self) {
+        // Dropping the task drops the VM, which ends compilation.
+        if let Some(task) = self.take() {
+            let message = "Device conditions don't allow compilation";
+            info!("{}", message);
+            if let Err(e) = task.callback.onFailure(FailureReason::DeviceConditions, message) {
+                warn!("Failed to deliver callback: {:?}", e);
+            }
+        }
+    }
+
+    /// Forwards the progress appended to `progress_file` by CompOS to the callback, until `done`
+    /// is signaled (or dropped).
+    fn forward_progress(&self, mut progress_file: File, done: Receiver<()>) {
+        let mut pending = Vec::new();
+        let mut last_progress = None;
+        loop {
+            let finished = !matches!(
+                done.recv_timeout(PROGRESS_POLL_INTERVAL),
+                Err(RecvTimeoutError::Timeout)
+            );
+
+            // The file is only ever appended to, so just continue from where we stopped.
+            if let Err(e) = progress_file.read_to_end(&mut pending) {
+                warn!("Failed to read progress: {}", e);
+                return;
+            }
+            let mut latest = None;
+            while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=pos).collect();
+                match std::str::from_utf8(&line[..pos]).ok().and_then(Progress::parse_line) {
+                    Some(progress) => latest = Some(progress),
+                    None => warn!("Malformed progress: {:?}", line),
+                }
+            }
+
+            if let Some(progress) = latest.filter(|p| Some(p) != last_progress.as_ref()) {
+                // Don't report progress if the task has ended or been canceled.
+                if let Some(task) = self.running_task.lock().unwrap().as_ref() {
+                    if let Err(e) =
+                        task.callback.onProgress(progress.percentage, &progress.last_module)
+                    {
+                        warn!("Failed to deliver progress: {:?}", e);
+                    }
+                }
+                last_progress = Some(progress);
+            }
+            if finished {
+                return;
+            }
+        }
+    }
 }
 
 fn run_in_vm(
+    task: &OdrefreshTask,
     service: Strong<dyn ICompOsService>,
     compilation_mode: CompilationMode,
     target_dir_name: &str,
@@ -136,6 +218,10 @@ fn run_in_vm(
     })?;
     service.initializeSystemProperties(&names, &values).context("initialize system properties")?;
 
+    let zygote_arch = system_properties::read("ro.zygote")?.context("ro.zygote not set")?;
+    let system_server_compiler_filter =
+        system_properties::read("dalvik.vm.systemservercompilerfilter")?.unwrap_or_default();
+
     let output_root = Path::new(ODREFRESH_OUTPUT_ROOT_DIR);
 
     // We need to remove the target directory because odrefresh running in compos will create it
@@ -147,33 +233,92 @@ fn run_in_vm(
             .with_context(|| format!("Failed to delete {}", target_path.display()))?;
     }
 
+    // Only the real compilation makes use of the cache. Test compilations are meant to exercise
+    // the compilation.
+    let cache_key = if compilation_mode == CompilationMode::NORMAL_COMPILE {
+        let mut compiler_flags: Vec<_> = names.into_iter().zip(values).collect();
+        compiler_flags.push(("ro.zygote".to_owned(), zygote_arch.clone()));
+        compiler_flags.push((
+            "dalvik.vm.systemservercompilerfilter".to_owned(),
+            system_server_compiler_filter.clone(),
+        ));
+        // A failure to use the cache shouldn't fail the compilation.
+        match ArtifactCacheKey::new(/* prefer_staged */ true, &compiler_flags) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                warn!("Failed to compute artifact cache key: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(key) = &cache_key {
+        match artifact_cache::restore(key, &target_path) {
+            Ok(true) => return Ok(ExitCode::CompilationSuccess),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to restore cached artifacts: {:?}", e),
+        }
+    }
+
     let staging_dir = open_dir(composd_native::palette_create_odrefresh_staging_directory()?)?;
     let system_dir = open_dir(Path::new("/system"))?;
     let output_dir = open_dir(output_root)?;
 
+    let progress_file = create_progress_file()?;
+
     // Spawn a fd_server to serve the FDs.
     let fd_server_config = FdServerConfig {
+        rw_file_fds: vec![progress_file.as_raw_fd()],
         ro_dir_fds: vec![system_dir.as_raw_fd()],
         rw_dir_fds: vec![staging_dir.as_raw_fd(), output_dir.as_raw_fd()],
         ..Default::default()
     };
     let fd_server_raii = fd_server_config.into_fd_server()?;
 
-    let zygote_arch = system_properties::read("ro.zygote")?.context("ro.zygote not set")?;
-    let system_server_compiler_filter =
-        system_properties::read("dalvik.vm.systemservercompilerfilter")?.unwrap_or_default();
+    let (done_sender, done_receiver) = mpsc::channel();
+    let progress_thread = {
+        let task = task.clone();
+        let progress_file = progress_file.try_clone()?;
+        thread::spawn(move || task.forward_progress(progress_file, done_receiver))
+    };
+
     let exit_code = service.odrefresh(
         compilation_mode,
         system_dir.as_raw_fd(),
         output_dir.as_raw_fd(),
         staging_dir.as_raw_fd(),
+        progress_file.as_raw_fd(),
         target_dir_name,
         &zygote_arch,
         &system_server_compiler_filter,
-    )?;
+    );
+
+    // Deliver the remaining progress before the result.
+    drop(done_sender);
+    if progress_thread.join().is_err() {
+        warn!("Progress forwarding thread panicked");
+    }
+    let exit_code = exit_code?;
 
     drop(fd_server_raii);
-    ExitCode::from_i32(exit_code.into())
+    let exit_code = ExitCode::from_i32(exit_code.into())?;
+
+    if let (ExitCode::CompilationSuccess, Some(key)) = (&exit_code, &cache_key) {
+        if let Err(e) = artifact_cache::store(key, &target_path) {
+            warn!("Failed to cache artifacts: {:?}", e);
+        }
+    }
+    Ok(exit_code)
+}
+
+/// Returns an anonymous file for CompOS to write the progress to.
+fn create_progress_file() -> Result<File> {
+    let name = CString::new("compos_progress").unwrap();
+    let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+        .context("Failed to create progress file")?;
+    // SAFETY: We are the sole owner of the fd as it was just created.
+    Ok(unsafe { File::from_raw_fd(fd) })
 }
 
 /// Returns an owned FD of the directory. It currently returns a `File` as a FD owner, but
@@ -0,0 +1,255 @@
+/*
+ * Copyright 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Runs the CompOS VM at full speed or throttled depending on the thermal and battery status of
+//! the device, according to a `SchedulingPolicy`, or gives up on the compilation so that it is
+//! retried later.
+//!
+//! composd doesn't own the crosvm process, so rather than changing its priority or CPU affinity,
+//! the VM is throttled by periodically pausing and resuming it. It is never paused for longer than
+//! a scheduling period, as the timeouts of odrefresh in the VM and of composd keep running.
+//!
+//! The status is read from sysfs, as IThermalService and the battery properties have no Rust
+//! bindings which composd can use. composd needs sepolicy to read the thermal zones and the power
+//! supplies, which isn't part of this tree; without it the status is unavailable and the VM always
+//! runs at full speed.
+
+use crate::instance_starter::CompOsInstance;
+use android_system_composd::aidl::android::system::composd::SchedulingPolicy::SchedulingPolicy;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::fs::{self, read_dir};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the device status is checked. This is also the period of the throttling.
+const SCHEDULING_PERIOD: Duration = Duration::from_secs(5);
+
+const THERMAL_ROOT: &str = "/sys/class/thermal";
+const POWER_SUPPLY_ROOT: &str = "/sys/class/power_supply";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Run,
+    Throttle,
+    Pause,
+}
+
+#[derive(Debug, Default)]
+struct DeviceStatus {
+    /// The highest temperature of the skin thermal zones, in millidegrees Celsius.
+    skin_temperature_millicelsius: Option<i32>,
+    /// The battery level in percent, if the device is running on battery.
+    discharging_battery_percent: Option<i32>,
+}
+
+/// Checks that the policy is consistent.
+pub fn validate_policy(policy: &SchedulingPolicy) -> Result<()> {
+    if !(1..=100).contains(&policy.throttledDutyCyclePercent) {
+        bail!("Invalid duty cycle {}", policy.throttledDutyCyclePercent);
+    }
+    if policy.pauseTemperatureMillicelsius < policy.throttleTemperatureMillicelsius {
+        bail!("The pause temperature must not be lower than the throttle temperature");
+    }
+    if policy.pauseBatteryPercent > policy.throttleBatteryPercent {
+        bail!("The pause battery level must not be higher than the throttle battery level");
+    }
+    Ok(())
+}
+
+/// A `Scheduler` controls the VM of a `CompOsInstance` until it is dropped, after which the VM is
+/// left running, or until the policy calls for pausing the VM.
+pub struct Scheduler {
+    stop_sender: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    /// Starts controlling the VM of `comp_os` according to `policy`. Changes to `policy` take
+    /// effect from the next scheduling period. If the policy calls for pausing the VM, the VM is
+    /// resumed, the scheduler stops and `on_pause` is called from its thread; `on_pause` must not
+    /// drop the scheduler itself on that thread.
+    pub fn start(
+        comp_os: Arc<CompOsInstance>,
+        policy: Arc<Mutex<SchedulingPolicy>>,
+        on_pause: Box<dyn FnOnce() + Send>,
+    ) -> Self {
+        let (stop_sender, stop_receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if run(&comp_os, &policy, &stop_receiver) {
+                on_pause();
+            }
+        });
+        Scheduler { stop_sender: Some(stop_sender), thread: Some(thread) }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes up the thread.
+        drop(self.stop_sender.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Scheduler thread panicked");
+            }
+        }
+    }
+}
+
+/// Controls the VM until stopped, or until the policy calls for pausing it, in which case this
+/// returns true.
+fn run(comp_os: &CompOsInstance, policy: &Mutex<SchedulingPolicy>, stop: &Receiver<()>) -> bool {
+    let mut last_action = Action::Run;
+    let mut paused = false;
+    let should_pause = loop {
+        let policy = policy.lock().unwrap().clone();
+        let action =
+            if policy.enabled { decide(&policy, &read_device_status()) } else { Action::Run };
+        if action != last_action {
+            info!("CompOS VM scheduling changed from {:?} to {:?}", last_action, action);
+            last_action = action;
+        }
+
+        let running_time = match action {
+            Action::Run => SCHEDULING_PERIOD,
+            Action::Throttle => SCHEDULING_PERIOD * policy.throttledDutyCyclePercent as u32 / 100,
+            Action::Pause => break true,
+        };
+        set_paused(comp_os, &mut paused, false);
+        if wait_for_stop(stop, running_time) {
+            break false;
+        }
+        let paused_time = SCHEDULING_PERIOD - running_time;
+        if !paused_time.is_zero() {
+            set_paused(comp_os, &mut paused, true);
+            if wait_for_stop(stop, paused_time) {
+                break false;
+            }
+        }
+    };
+    set_paused(comp_os, &mut paused, false);
+    should_pause
+}
+
+/// Returns true if stopped, or false if `timeout` has elapsed.
+fn wait_for_stop(stop: &Receiver<()>, timeout: Duration) -> bool {
+    !matches!(stop.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+}
+
+fn set_paused(comp_os: &CompOsInstance, paused: &mut bool, pause: bool) {
+    if *paused == pause {
+        return;
+    }
+    let vm_instance = comp_os.vm_instance();
+    let result = if pause { vm_instance.pause() } else { vm_instance.resume() };
+    match result {
+        Ok(()) => *paused = pause,
+        Err(e) => warn!("Failed to control the CompOS VM: {:?}", e),
+    }
+}
+
+fn decide(policy: &SchedulingPolicy, status: &DeviceStatus) -> Action {
+    let mut action = Action::Run;
+    if let Some(temperature) = status.skin_temperature_millicelsius {
+        if temperature >= policy.pauseTemperatureMillicelsius {
+            return Action::Pause;
+        } else if temperature >= policy.throttleTemperatureMillicelsius {
+            action = Action::Throttle;
+        }
+    }
+    if let Some(battery_percent) = status.discharging_battery_percent {
+        if battery_percent <= policy.pauseBatteryPercent {
+            return Action::Pause;
+        } else if battery_percent <= policy.throttleBatteryPercent {
+            action = Action::Throttle;
+        }
+    }
+    action
+}
+
+/// Reads the device status from sysfs. Unavailable status is ignored, so that the VM is not
+/// throttled on devices that don't report it.
+fn read_device_status() -> DeviceStatus {
+    let skin_temperature_millicelsius = read_skin_temperature().unwrap_or_else(|e| {
+        warn!("Failed to read temperature: {:?}", e);
+        None
+    });
+    let discharging_battery_percent = read_discharging_battery_percent().unwrap_or_else(|e| {
+        warn!("Failed to read battery status: {:?}", e);
+        None
+    });
+    DeviceStatus { skin_temperature_millicelsius, discharging_battery_percent }
+}
+
+/// Reads the temperature of the skin thermal zones, which is what the device throttles on, e.g.
+/// "skin-therm" or the virtual "VIRTUAL-SKIN" zone. The other zones, such as the CPU ones, heat up
+/// quickly during the compilation itself and would keep the VM throttled.
+fn read_skin_temperature() -> Result<Option<i32>> {
+    let mut max_temperature = None;
+    for entry in read_dir(THERMAL_ROOT).context("Failed to read thermal zones")? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with("thermal_zone") {
+            continue;
+        }
+        let zone_type = read_value(&entry.path().join("type")).unwrap_or_default();
+        if !zone_type.to_ascii_lowercase().contains("skin") {
+            continue;
+        }
+        // A zone may be temporarily unavailable, e.g. when its sensor is off.
+        if let Ok(temperature) = read_value(&entry.path().join("temp")) {
+            max_temperature = max_temperature.max(Some(temperature.parse::<i32>()?));
+        }
+    }
+    Ok(max_temperature)
+}
+
+fn read_discharging_battery_percent() -> Result<Option<i32>> {
+    let battery_root = match find_battery()? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let status = read_value(&battery_root.join("status"))?;
+    if matches!(status.as_str(), "Charging" | "Full") {
+        return Ok(None);
+    }
+    Ok(Some(read_value(&battery_root.join("capacity"))?.parse()?))
+}
+
+/// Returns the power supply of type "Battery" that powers the device, as opposed to e.g. the
+/// battery of a connected stylus, which has the "Device" scope.
+fn find_battery() -> Result<Option<PathBuf>> {
+    for entry in read_dir(POWER_SUPPLY_ROOT).context("Failed to read power supplies")? {
+        let path = entry?.path();
+        if read_value(&path.join("type")).ok().as_deref() != Some("Battery") {
+            continue;
+        }
+        if read_value(&path.join("scope")).ok().as_deref() == Some("Device") {
+            continue;
+        }
+        return Ok(Some(path));
+    }
+    Ok(None)
+}
+
+fn read_value(path: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .trim()
+        .to_owned())
+}
@@ -19,31 +19,36 @@
 
 use crate::instance_manager::InstanceManager;
 use crate::odrefresh_task::OdrefreshTask;
+use crate::scheduler;
 use android_system_composd::aidl::android::system::composd::{
     ICompilationTask::{BnCompilationTask, ICompilationTask},
     ICompilationTaskCallback::ICompilationTaskCallback,
     IIsolatedCompilationService::{
         ApexSource::ApexSource, BnIsolatedCompilationService, IIsolatedCompilationService,
     },
+    SchedulingPolicy::SchedulingPolicy,
 };
 use android_system_composd::binder::{
     self, BinderFeatures, ExceptionCode, Interface, Status, Strong, ThreadState,
 };
 use anyhow::{Context, Result};
+use binder_common::new_binder_exception;
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::CompilationMode::CompilationMode;
 use compos_common::binder::to_binder_result;
 use compos_common::odrefresh::{PENDING_ARTIFACTS_SUBDIR, TEST_ARTIFACTS_SUBDIR};
 use rustutils::{users::AID_ROOT, users::AID_SYSTEM};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 pub struct IsolatedCompilationService {
     instance_manager: Arc<InstanceManager>,
+    scheduling_policy: Arc<Mutex<SchedulingPolicy>>,
 }
 
 pub fn new_binder(
     instance_manager: Arc<InstanceManager>,
 ) -> Strong<dyn IIsolatedCompilationService> {
-    let service = IsolatedCompilationService { instance_manager };
+    let service =
+        IsolatedCompilationService { instance_manager, scheduling_policy: Default::default() };
     BnIsolatedCompilationService::new_binder(service, BinderFeatures::default())
 }
 
@@ -71,6 +76,15 @@ impl IIsolatedCompilationService for IsolatedCompilationService {
         };
         to_binder_result(self.do_start_test_compile(prefer_staged, callback))
     }
+
+    fn setSchedulingPolicy(&self, policy: &SchedulingPolicy) -> binder::Result<()> {
+        check_permissions()?;
+        scheduler::validate_policy(policy).map_err(|e| {
+            new_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT, format!("{:?}", e))
+        })?;
+        *self.scheduling_policy.lock().unwrap() = policy.clone();
+        Ok(())
+    }
 }
 
 impl IsolatedCompilationService {
@@ -85,6 +99,7 @@ impl IsolatedCompilationService {
             comp_os,
             CompilationMode::NORMAL_COMPILE,
             target_dir_name,
+            self.scheduling_policy.clone(),
             callback,
         )?;
 
@@ -104,6 +119,7 @@ impl IsolatedCompilationService {
             comp_os,
             CompilationMode::TEST_COMPILE,
             target_dir_name,
+            self.scheduling_policy.clone(),
             callback,
         )?;
 
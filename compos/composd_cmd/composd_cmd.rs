@@ -79,6 +79,11 @@ enum Outcome {
 impl Interface for Callback {}
 
 impl ICompilationTaskCallback for Callback {
+    fn onProgress(&self, percentage: i32, last_module: &str) -> BinderResult<()> {
+        println!("Compiled {} ({}%)", last_module, percentage);
+        Ok(())
+    }
+
     fn onSuccess(&self) -> BinderResult<()> {
         self.0.set_outcome(Outcome::Succeeded);
         Ok(())
@@ -126,8 +126,8 @@ public class IsolatedCompilationJobService extends JobService {
         }
     }
 
-    void onCompletion(JobParameters params, boolean succeeded) {
-        Log.i(TAG, "onCompletion, succeeded=" + succeeded);
+    void onCompletion(JobParameters params, boolean succeeded, boolean wantReschedule) {
+        Log.i(TAG, "onCompletion, succeeded=" + succeeded + ", wantReschedule=" + wantReschedule);
 
         CompilationJob job = mCurrentJob.getAndSet(null);
         if (job == null) {
@@ -136,12 +136,13 @@ public class IsolatedCompilationJobService extends JobService {
         }
         // On success we don't need to reschedule.
         // On failure we could reschedule, but that could just use a lot of resources and still
-        // fail; instead we just let odsign do compilation on reboot if necessary.
-        jobFinished(params, /*wantReschedule=*/ false);
+        // fail; instead we just let odsign do compilation on reboot if necessary. The exception is
+        // when composd gave up because of the device conditions, which should improve.
+        jobFinished(params, wantReschedule);
     }
 
     interface CompilationCallback {
-        void onCompletion(JobParameters params, boolean succeeded);
+        void onCompletion(JobParameters params, boolean succeeded, boolean wantReschedule);
     }
 
     static class CompilationJob extends ICompilationTaskCallback.Stub
@@ -214,17 +215,24 @@ public class IsolatedCompilationJobService extends JobService {
 
         @Override
         public void binderDied() {
-            onCompletion(false, IsolatedCompilationMetrics.RESULT_COMPOSD_DIED);
+            onCompletion(false, IsolatedCompilationMetrics.RESULT_COMPOSD_DIED,
+                    /*wantReschedule=*/ false);
+        }
+
+        @Override
+        public void onProgress(int percentage, String lastModule) {
+            Log.i(TAG, "Compiled " + lastModule + " (" + percentage + "%)");
         }
 
         @Override
         public void onSuccess() {
-            onCompletion(true, IsolatedCompilationMetrics.RESULT_SUCCESS);
+            onCompletion(true, IsolatedCompilationMetrics.RESULT_SUCCESS, /*wantReschedule=*/ false);
         }
 
         @Override
         public void onFailure(byte reason, String message) {
             int result;
+            boolean wantReschedule = false;
             switch (reason) {
                 case ICompilationTaskCallback.FailureReason.CompilationFailed:
                     result = IsolatedCompilationMetrics.RESULT_COMPILATION_FAILED;
@@ -234,19 +242,25 @@ public class IsolatedCompilationJobService extends JobService {
                     result = IsolatedCompilationMetrics.RESULT_UNEXPECTED_COMPILATION_RESULT;
                     break;
 
+                case ICompilationTaskCallback.FailureReason.DeviceConditions:
+                    result = IsolatedCompilationMetrics.RESULT_JOB_CANCELED;
+                    wantReschedule = true;
+                    break;
+
                 default:
                     result = IsolatedCompilationMetrics.RESULT_UNKNOWN_FAILURE;
                     break;
             }
             Log.w(TAG, "Compilation failed: " + message);
-            onCompletion(false, result);
+            onCompletion(false, result, wantReschedule);
         }
 
-        private void onCompletion(boolean succeeded, @CompilationResult int result) {
+        private void onCompletion(boolean succeeded, @CompilationResult int result,
+                boolean wantReschedule) {
             ICompilationTask task = mTask.getAndSet(null);
             if (task != null) {
                 mMetrics.onCompilationEnded(result);
-                mCallback.onCompletion(mParams, succeeded);
+                mCallback.onCompletion(mParams, succeeded, wantReschedule);
                 try {
                     task.asBinder().unlinkToDeath(this, 0);
                 } catch (NoSuchElementException e) {
@@ -19,30 +19,39 @@ use log::{debug, info, warn};
 use minijail::{self, Minijail};
 use regex::Regex;
 use rustutils::system_properties;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
 use std::path::{self, Path, PathBuf};
 use std::process::Command;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 use authfs_aidl_interface::aidl::com::android::virt::fs::{
     AuthFsConfig::{
         AuthFsConfig, InputDirFdAnnotation::InputDirFdAnnotation,
-        OutputDirFdAnnotation::OutputDirFdAnnotation,
+        OutputDirFdAnnotation::OutputDirFdAnnotation, OutputFdAnnotation::OutputFdAnnotation,
     },
     IAuthFsService::IAuthFsService,
 };
 use authfs_aidl_interface::binder::Strong;
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::CompilationMode::CompilationMode;
-use compos_common::odrefresh::ExitCode;
+use compos_common::odrefresh::{ExitCode, Progress};
 
 const FD_SERVER_PORT: i32 = 3264; // TODO: support dynamic port
 
+/// How often to check the artifacts written by odrefresh for the progress of the compilation.
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct OdrefreshContext<'a> {
     compilation_mode: CompilationMode,
     system_dir_fd: i32,
     output_dir_fd: i32,
     staging_dir_fd: i32,
+    progress_fd: i32,
     target_dir_name: &'a str,
     zygote_arch: &'a str,
     system_server_compiler_filter: &'a str,
@@ -54,6 +63,7 @@ impl<'a> OdrefreshContext<'a> {
         system_dir_fd: i32,
         output_dir_fd: i32,
         staging_dir_fd: i32,
+        progress_fd: i32,
         target_dir_name: &'a str,
         zygote_arch: &'a str,
         system_server_compiler_filter: &'a str,
@@ -71,6 +81,9 @@ impl<'a> OdrefreshContext<'a> {
         if system_dir_fd < 0 || output_dir_fd < 0 || staging_dir_fd < 0 {
             bail!("The remote FDs are expected to be non-negative");
         }
+        if progress_fd < -1 {
+            bail!("The progress FD is expected to be non-negative, or -1 if not needed");
+        }
         if !matches!(zygote_arch, "zygote64" | "zygote64_32") {
             bail!("Invalid zygote arch");
         }
@@ -90,6 +103,7 @@ impl<'a> OdrefreshContext<'a> {
             system_dir_fd,
             output_dir_fd,
             staging_dir_fd,
+            progress_fd,
             target_dir_name,
             zygote_arch,
             system_server_compiler_filter,
@@ -120,11 +134,23 @@ where
             OutputDirFdAnnotation { fd: context.output_dir_fd },
             OutputDirFdAnnotation { fd: context.staging_dir_fd },
         ],
+        outputFdAnnotations: if context.progress_fd >= 0 {
+            vec![OutputFdAnnotation { fd: context.progress_fd }]
+        } else {
+            vec![]
+        },
         ..Default::default()
     };
     let authfs = authfs_service.mount(&authfs_config)?;
     let mountpoint = PathBuf::from(authfs.getMountPoint()?);
 
+    let progress_file = if context.progress_fd >= 0 {
+        let path = mountpoint.join(context.progress_fd.to_string());
+        Some(OpenOptions::new().write(true).open(&path).context("Open progress file")?)
+    } else {
+        None
+    };
+
     // Make a copy of our environment as the basis of the one we will give odrefresh
     let mut odrefresh_vars = EnvMap::from_current_env();
 
@@ -165,20 +191,37 @@ where
     };
     args.push(compile_flag.to_string());
 
+    let target_dir = art_apex_data.join(context.target_dir_name);
+    let expected_artifacts = ExpectedArtifacts::new(context.zygote_arch, &odrefresh_vars);
+
     debug!("Running odrefresh with args: {:?}", &args);
     let jail = spawn_jailed_task(odrefresh_path, &args, &odrefresh_vars.into_env())
         .context("Spawn odrefresh")?;
+    let progress_thread = progress_file.map(|progress_file| {
+        let (done_sender, done) = channel();
+        let target_dir = target_dir.clone();
+        let thread = thread::spawn(move || {
+            report_progress(&target_dir, &expected_artifacts, progress_file, done)
+        });
+        (thread, done_sender)
+    });
     let exit_code = match jail.wait() {
         Ok(_) => 0,
         Err(minijail::Error::ReturnCode(exit_code)) => exit_code,
         Err(e) => bail!("Unexpected minijail error: {}", e),
     };
+    if let Some((progress_thread, done_sender)) = progress_thread {
+        // The thread also stops if this fails, as the receiver sees the sender dropped.
+        let _ = done_sender.send(());
+        if progress_thread.join().is_err() {
+            warn!("Progress reporting thread panicked");
+        }
+    }
 
     let exit_code = ExitCode::from_i32(exit_code.into())?;
     info!("odrefresh exited with {:?}", exit_code);
 
     if exit_code == ExitCode::CompilationSuccess {
-        let target_dir = art_apex_data.join(context.target_dir_name);
         success_fn(target_dir)?;
     }
 
@@ -235,6 +278,102 @@ fn load_classpath_vars(odrefresh_vars: &mut EnvMap, export_lines: &str) -> Resul
     Ok(())
 }
 
+/// The artifacts which odrefresh is expected to write to the target directory. odrefresh doesn't
+/// report its progress, but it compiles each module into the staging directory, and moves the
+/// artifacts to the target directory once the module is done. So the progress is worked out from
+/// which of the expected artifacts are in the target directory.
+struct ExpectedArtifacts {
+    /// The number of boot images, one for each ISA of the zygote. Each ISA directory gets a
+    /// "boot*.oat" file when its boot image is done.
+    boot_image_count: usize,
+    /// The system server jars, by the name of the "<jar path with '@' for '/'>@classes.odex" file
+    /// that is written to the ISA directory when the jar is done.
+    system_server_jars: Vec<(String, String)>,
+}
+
+impl ExpectedArtifacts {
+    fn new(zygote_arch: &str, odrefresh_vars: &EnvMap) -> Self {
+        let boot_image_count = if zygote_arch == "zygote64_32" { 2 } else { 1 };
+        let system_server_jars = ["SYSTEMSERVERCLASSPATH", "STANDALONE_SYSTEMSERVER_JARS"]
+            .iter()
+            .filter_map(|name| odrefresh_vars.get(name))
+            .flat_map(|jars| jars.split(':'))
+            .filter(|jar| !jar.is_empty())
+            .map(|jar| {
+                let odex_name = format!("{}@classes.odex", jar.trim_start_matches('/'));
+                (odex_name.replace('/', "@"), jar.to_owned())
+            })
+            .collect();
+        Self { boot_image_count, system_server_jars }
+    }
+
+    fn total(&self) -> usize {
+        self.boot_image_count + self.system_server_jars.len()
+    }
+
+    /// Returns the names of the modules whose artifacts are in `target_dir`. A boot image is
+    /// named by its ISA directory, and a system server jar by its path.
+    fn compiled_modules(&self, target_dir: &Path) -> Vec<String> {
+        let mut modules = Vec::new();
+        let isa_dirs = match fs::read_dir(target_dir) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok()),
+            // odrefresh hasn't created it yet.
+            Err(_) => return modules,
+        };
+        for isa_dir in isa_dirs {
+            let file_names: BTreeSet<_> = match fs::read_dir(isa_dir.path()) {
+                Ok(entries) => {
+                    entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).collect()
+                }
+                Err(_) => continue,
+            };
+            if file_names.iter().any(|name| name.starts_with("boot") && name.ends_with(".oat")) {
+                modules.push(format!("boot image ({})", isa_dir.file_name().to_string_lossy()));
+            }
+            for (odex_name, jar) in &self.system_server_jars {
+                if file_names.contains(odex_name) {
+                    modules.push(jar.clone());
+                }
+            }
+        }
+        modules
+    }
+}
+
+/// Appends the progress of odrefresh to `progress_file` whenever more of the expected artifacts
+/// appear in `target_dir`, until `done` is signaled (or dropped).
+fn report_progress(
+    target_dir: &Path,
+    expected_artifacts: &ExpectedArtifacts,
+    mut progress_file: File,
+    done: Receiver<()>,
+) {
+    let total = expected_artifacts.total();
+    let mut compiled = BTreeSet::new();
+    loop {
+        let finished =
+            !matches!(done.recv_timeout(PROGRESS_POLL_INTERVAL), Err(RecvTimeoutError::Timeout));
+        let mut last_module = None;
+        for module in expected_artifacts.compiled_modules(target_dir) {
+            if compiled.insert(module.clone()) {
+                last_module = Some(module);
+            }
+        }
+        if let Some(last_module) = last_module {
+            // Don't go over 100% if odrefresh writes more than expected.
+            let percentage = (compiled.len() * 100 / total).min(100) as i32;
+            let progress = Progress { percentage, last_module };
+            if let Err(e) = progress_file.write_all(progress.to_line().as_bytes()) {
+                warn!("Failed to report progress: {}", e);
+                return;
+            }
+        }
+        if finished {
+            return;
+        }
+    }
+}
+
 fn spawn_jailed_task(executable: &Path, args: &[String], env_vars: &[String]) -> Result<Minijail> {
     // TODO(b/185175567): Run in a more restricted sandbox.
     let jail = Minijail::new()?;
@@ -251,6 +390,10 @@ impl EnvMap {
         Self(env::vars().collect())
     }
 
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
     fn set(&mut self, key: &str, value: &str) {
         self.0.insert(key.to_owned(), value.to_owned());
     }
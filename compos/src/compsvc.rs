@@ -107,6 +107,7 @@ impl ICompOsService for CompOsService {
         system_dir_fd: i32,
         output_dir_fd: i32,
         staging_dir_fd: i32,
+        progress_fd: i32,
         target_dir_name: &str,
         zygote_arch: &str,
         system_server_compiler_filter: &str,
@@ -124,6 +125,7 @@ impl ICompOsService for CompOsService {
             system_dir_fd,
             output_dir_fd,
             staging_dir_fd,
+            progress_fd,
             target_dir_name,
             zygote_arch,
             system_server_compiler_filter,
@@ -0,0 +1,114 @@
+/*
+ * Copyright 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verification of the artifacts listed in a CompOS info file against the files in a directory.
+
+use anyhow::{bail, Context, Result};
+use fsverity_digest::{build_merkle_tree, to_hex_string, Sha256Hash};
+use odsign_proto::odsign_info::OdsignInfo;
+use protobuf::Message;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{read_dir, File};
+use std::path::{Path, PathBuf};
+
+/// Files in the artifacts directory that are not artifacts themselves.
+pub const INFO_FILE: &str = "compos.info";
+pub const SIGNATURE_FILE: &str = "compos.info.signature";
+
+/// The result of verifying an artifact.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArtifactStatus {
+    /// The file matches the digest in the info file.
+    Verified,
+    /// The file is listed in the info file, but doesn't exist.
+    Missing,
+    /// The file doesn't match the digest in the info file.
+    DigestMismatch,
+    /// The file exists, but is not listed in the info file.
+    Unlisted,
+}
+
+impl fmt::Display for ArtifactStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ArtifactStatus::Verified => "VERIFIED",
+            ArtifactStatus::Missing => "MISSING",
+            ArtifactStatus::DigestMismatch => "DIGEST_MISMATCH",
+            ArtifactStatus::Unlisted => "UNLISTED",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Verifies the files under `artifacts_dir` against `info`, a serialized `OdsignInfo` whose paths
+/// are under `target_dir`, the directory where the artifacts were meant to be installed. Returns
+/// the status of each file keyed by its path relative to `artifacts_dir`.
+pub fn verify_artifacts(
+    info: &[u8],
+    target_dir: &Path,
+    artifacts_dir: &Path,
+) -> Result<BTreeMap<PathBuf, ArtifactStatus>> {
+    let info = OdsignInfo::parse_from_bytes(info).context("Failed to parse info")?;
+
+    let mut results = BTreeMap::new();
+    for (path, expected_digest) in info.get_file_hashes() {
+        let relative_path = Path::new(path)
+            .strip_prefix(target_dir)
+            .with_context(|| format!("Artifact {} is not under {}", path, target_dir.display()))?;
+        let file_path = artifacts_dir.join(relative_path);
+        let status = if !file_path.is_file() {
+            ArtifactStatus::Missing
+        } else if to_hex_string(&compute_fsverity_digest(&file_path)?) == *expected_digest {
+            ArtifactStatus::Verified
+        } else {
+            ArtifactStatus::DigestMismatch
+        };
+        results.insert(relative_path.to_owned(), status);
+    }
+
+    let mut existing_files = Vec::new();
+    list_files(artifacts_dir, Path::new(""), &mut existing_files)?;
+    for relative_path in existing_files {
+        if relative_path == Path::new(INFO_FILE) || relative_path == Path::new(SIGNATURE_FILE) {
+            continue;
+        }
+        results.entry(relative_path).or_insert(ArtifactStatus::Unlisted);
+    }
+    Ok(results)
+}
+
+fn list_files(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let relative_path = prefix.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(&entry.path(), &relative_path, files)?;
+        } else if file_type.is_file() {
+            files.push(relative_path);
+        } else {
+            bail!("Unexpected file type of {:?}", entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Computes the fs-verity digest of the file, as the kernel would if fs-verity were enabled on it.
+fn compute_fsverity_digest(path: &Path) -> Result<Sha256Hash> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    Ok(build_merkle_tree(&file).with_context(|| format!("Failed to read {:?}", path))?.digest)
+}
@@ -16,7 +16,14 @@
 
 //! A tool to verify a CompOS signature. It starts a CompOS VM as part of this to retrieve the
 //!  public key. The tool is intended to be run by odsign during boot.
+//!
+//! Alternatively, given a copy of the artifacts with `--artifacts-dir` and the public key with
+//! `--public-key`, it verifies the signature and every artifact without starting a VM, and prints
+//! the result of each file.
 
+mod artifacts;
+
+use crate::artifacts::{verify_artifacts, ArtifactStatus, INFO_FILE, SIGNATURE_FILE};
 use android_logger::LogId;
 use anyhow::{bail, Context, Result};
 use compos_aidl_interface::binder::ProcessState;
@@ -33,7 +40,7 @@ use log::error;
 use std::fs::File;
 use std::io::Read;
 use std::panic;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 const MAX_FILE_SIZE_BYTES: u64 = 100 * 1024;
 
@@ -62,23 +69,73 @@ fn try_main() -> Result<()> {
             clap::Arg::with_name("instance")
                 .long("instance")
                 .takes_value(true)
-                .required(true)
-                .possible_values(&["current", "pending", "test"]),
+                .possible_values(&["current", "pending", "test"])
+                .required_unless_all(&["artifacts-dir", "public-key"]),
         )
+        .arg(clap::Arg::with_name("artifacts-dir").long("artifacts-dir").takes_value(true))
+        .arg(clap::Arg::with_name("public-key").long("public-key").takes_value(true))
         .arg(clap::Arg::with_name("debug").long("debug"))
         .get_matches();
 
     let debug_mode = matches.is_present("debug");
-    let (instance_dir, artifacts_dir) = match matches.value_of("instance").unwrap() {
+    let instance = matches.value_of("instance").map(|instance| match instance {
         "current" => (CURRENT_INSTANCE_DIR, CURRENT_ARTIFACTS_SUBDIR),
         "pending" => (CURRENT_INSTANCE_DIR, PENDING_ARTIFACTS_SUBDIR),
         "test" => (TEST_INSTANCE_DIR, TEST_ARTIFACTS_SUBDIR),
         _ => unreachable!("Unexpected instance name"),
+    });
+
+    // An explicit artifacts directory, e.g. a copy of the artifacts, is verified in full, since
+    // odsign won't check the files in it.
+    let explicit_artifacts_dir = matches.value_of("artifacts-dir").map(PathBuf::from);
+    let artifacts_dir = match (&explicit_artifacts_dir, instance) {
+        (Some(artifacts_dir), _) => artifacts_dir.clone(),
+        (None, Some((_, artifacts_dir))) => {
+            Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(artifacts_dir)
+        }
+        (None, None) => unreachable!("--instance is required without --artifacts-dir"),
     };
 
-    let instance_dir = Path::new(COMPOS_DATA_ROOT).join(instance_dir);
-    let artifacts_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(artifacts_dir);
+    let info = artifacts_dir.join(INFO_FILE);
+    let signature = artifacts_dir.join(SIGNATURE_FILE);
+
+    let info = read_small_file(&info).context("Failed to read compos.info")?;
+    let signature = read_small_file(&signature).context("Failed to read compos.info signature")?;
+
+    let public_key = match (matches.value_of("public-key"), instance) {
+        (Some(public_key), _) => {
+            read_small_file(Path::new(public_key)).context("Failed to read public key")?
+        }
+        (None, Some((instance_dir, _))) => get_public_key_from_vm(instance_dir, debug_mode)?,
+        (None, None) => unreachable!("--instance is required without --public-key"),
+    };
 
+    let signature_valid = compos_verify_native::verify(&public_key, &signature, &info);
+
+    if let Some(artifacts_dir) = explicit_artifacts_dir {
+        // Print the results of all files, so that the caller can tell what is wrong.
+        let target_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(CURRENT_ARTIFACTS_SUBDIR);
+        let results = verify_artifacts(&info, &target_dir, &artifacts_dir)?;
+        println!("{} {}", if signature_valid { "VERIFIED" } else { "INVALID" }, INFO_FILE);
+        for (path, status) in &results {
+            println!("{} {}", status, path.display());
+        }
+        let failures = results.values().filter(|s| **s != ArtifactStatus::Verified).count();
+        if failures > 0 {
+            bail!("{} artifacts failed verification", failures);
+        }
+    }
+
+    if !signature_valid {
+        bail!("Signature verification failed");
+    }
+
+    Ok(())
+}
+
+/// Starts a CompOS VM of the instance to retrieve its public key.
+fn get_public_key_from_vm(instance_dir: &str, debug_mode: bool) -> Result<Vec<u8>> {
+    let instance_dir = Path::new(COMPOS_DATA_ROOT).join(instance_dir);
     if !instance_dir.is_dir() {
         bail!("{:?} is not a directory", instance_dir);
     }
@@ -89,12 +146,6 @@ fn try_main() -> Result<()> {
 
     let instance_image = File::open(instance_image).context("Failed to open instance image")?;
 
-    let info = artifacts_dir.join("compos.info");
-    let signature = artifacts_dir.join("compos.info.signature");
-
-    let info = read_small_file(&info).context("Failed to read compos.info")?;
-    let signature = read_small_file(&signature).context("Failed to read compos.info signature")?;
-
     // We need to start the thread pool to be able to receive Binder callbacks
     ProcessState::start_thread_pool();
 
@@ -108,13 +159,7 @@ fn try_main() -> Result<()> {
     )?;
     let service = vm_instance.get_service()?;
 
-    let public_key = service.getPublicKey().context("Getting public key")?;
-
-    if !compos_verify_native::verify(&public_key, &signature, &info) {
-        bail!("Signature verification failed");
-    }
-
-    Ok(())
+    service.getPublicKey().context("Getting public key")
 }
 
 fn read_small_file(file: &Path) -> Result<Vec<u8>> {
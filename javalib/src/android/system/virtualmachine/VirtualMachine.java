@@ -22,7 +22,9 @@ import static android.os.ParcelFileDescriptor.MODE_READ_WRITE;
 import android.annotation.CallbackExecutor;
 import android.annotation.NonNull;
 import android.annotation.Nullable;
+import android.content.ComponentCallbacks2;
 import android.content.Context;
+import android.content.res.Configuration;
 import android.os.Binder;
 import android.os.IBinder;
 import android.os.ParcelFileDescriptor;
@@ -31,10 +33,12 @@ import android.os.ServiceManager;
 import android.system.virtualizationservice.IVirtualMachine;
 import android.system.virtualizationservice.IVirtualMachineCallback;
 import android.system.virtualizationservice.IVirtualizationService;
+import android.system.virtualizationservice.MemoryStats;
 import android.system.virtualizationservice.PartitionType;
 import android.system.virtualizationservice.VirtualMachineAppConfig;
 import android.system.virtualizationservice.VirtualMachineState;
 import android.util.JsonReader;
+import android.util.Log;
 
 import com.android.internal.annotations.GuardedBy;
 
@@ -65,6 +69,8 @@ import java.util.zip.ZipFile;
  * @hide
  */
 public class VirtualMachine {
+    private static final String TAG = "VirtualMachine";
+
     /** Name of the directory under the files directory where all VMs created for the app exist. */
     private static final String VM_DIR = "vm";
 
@@ -99,6 +105,9 @@ public class VirtualMachine {
     /** Lock for internal synchronization. */
     private final Object mLock = new Object();
 
+    /** The application context, which receives the memory pressure callbacks of the app. */
+    private final @NonNull Context mContext;
+
     /** The package which owns this VM. */
     private final @NonNull String mPackageName;
 
@@ -119,13 +128,22 @@ public class VirtualMachine {
     private static class ExtraApkSpec {
         public final File apk;
         public final File idsig;
+        /** Whether the VM fails to start if the APK doesn't exist. */
+        public final boolean required;
 
-        ExtraApkSpec(File apk, File idsig) {
+        ExtraApkSpec(File apk, File idsig, boolean required) {
             this.apk = apk;
             this.idsig = idsig;
+            this.required = required;
         }
     }
 
+    /** An entry of "extra_apks" in the payload config. */
+    private static class ExtraApkConfig {
+        public String path;
+        public boolean required = true;
+    }
+
     /**
      * List of extra apks. Apks are specified by the vm config, and corresponding idsigs are to be
      * generated.
@@ -157,6 +175,33 @@ public class VirtualMachine {
 
     private final ExecutorService mExecutorService = Executors.newCachedThreadPool();
 
+    /**
+     * Inflates the memory balloon of the running VM when the host is under memory pressure, so that
+     * memory which the guest can spare is returned to the host. The balloon is deflated again by
+     * the next trim callback which doesn't indicate memory pressure.
+     */
+    private final ComponentCallbacks2 mMemoryPressureCallbacks =
+            new ComponentCallbacks2() {
+                @Override
+                public void onTrimMemory(int level) {
+                    setBalloonPercent(balloonPercentForTrimLevel(level));
+                }
+
+                @Override
+                public void onLowMemory() {
+                    setBalloonPercent(BALLOON_PERCENT_CRITICAL);
+                }
+
+                @Override
+                public void onConfigurationChanged(@NonNull Configuration newConfig) {}
+            };
+
+    /** Percentages of the guest memory taken by the balloon at the various memory pressures. */
+    private static final int BALLOON_PERCENT_NONE = 0;
+    private static final int BALLOON_PERCENT_MODERATE = 10;
+    private static final int BALLOON_PERCENT_LOW = 25;
+    private static final int BALLOON_PERCENT_CRITICAL = 50;
+
     static {
         System.loadLibrary("virtualmachine_jni");
     }
@@ -164,6 +209,7 @@ public class VirtualMachine {
     private VirtualMachine(
             @NonNull Context context, @NonNull String name, @NonNull VirtualMachineConfig config)
             throws VirtualMachineException {
+        mContext = context.getApplicationContext();
         mPackageName = context.getPackageName();
         mName = name;
         mConfig = config;
@@ -379,12 +425,17 @@ public class VirtualMachine {
             }
 
             VirtualMachineAppConfig appConfig = getConfig().toParcel();
+            appConfig.name = mName;
 
             // Fill the idsig file by hashing the apk
             service.createOrUpdateIdsigFile(
                     appConfig.apk, ParcelFileDescriptor.open(mIdsigFilePath, MODE_READ_WRITE));
 
             for (ExtraApkSpec extraApk : mExtraApks) {
+                if (!extraApk.required && !extraApk.apk.exists()) {
+                    // VirtualizationService leaves it out, so the empty idsig isn't used.
+                    continue;
+                }
                 service.createOrUpdateIdsigFile(
                         ParcelFileDescriptor.open(extraApk.apk, MODE_READ_ONLY),
                         ParcelFileDescriptor.open(extraApk.idsig, MODE_READ_WRITE));
@@ -439,6 +490,7 @@ public class VirtualMachine {
                         }
                         @Override
                         public void onDied(int cid, int reason) {
+                            mContext.unregisterComponentCallbacks(mMemoryPressureCallbacks);
                             service.asBinder().unlinkToDeath(deathRecipient, 0);
                             if (onDiedCalled.compareAndSet(false, true)) {
                                 executeCallback((cb) -> cb.onDied(VirtualMachine.this, reason));
@@ -448,6 +500,7 @@ public class VirtualMachine {
             );
             service.asBinder().linkToDeath(deathRecipient, 0);
             mVirtualMachine.start();
+            mContext.registerComponentCallbacks(mMemoryPressureCallbacks);
         } catch (IOException e) {
             throw new VirtualMachineException(e);
         } catch (RemoteException e) {
@@ -478,10 +531,55 @@ public class VirtualMachine {
      * #run()}.
      */
     public void stop() throws VirtualMachineException {
+        mContext.unregisterComponentCallbacks(mMemoryPressureCallbacks);
         // Dropping the IVirtualMachine handle stops the VM
         mVirtualMachine = null;
     }
 
+    /**
+     * Returns the percentage of the guest memory which the balloon should take at the given memory
+     * trim level. The levels at which the app is in the background are treated like the running
+     * levels of similar severity.
+     */
+    private static int balloonPercentForTrimLevel(int level) {
+        if (level >= ComponentCallbacks2.TRIM_MEMORY_COMPLETE
+                || level == ComponentCallbacks2.TRIM_MEMORY_RUNNING_CRITICAL) {
+            return BALLOON_PERCENT_CRITICAL;
+        }
+        if (level >= ComponentCallbacks2.TRIM_MEMORY_MODERATE
+                || level == ComponentCallbacks2.TRIM_MEMORY_RUNNING_LOW) {
+            return BALLOON_PERCENT_LOW;
+        }
+        if (level >= ComponentCallbacks2.TRIM_MEMORY_BACKGROUND
+                || level == ComponentCallbacks2.TRIM_MEMORY_RUNNING_MODERATE) {
+            return BALLOON_PERCENT_MODERATE;
+        }
+        // TRIM_MEMORY_UI_HIDDEN and unknown levels don't indicate memory pressure.
+        return BALLOON_PERCENT_NONE;
+    }
+
+    /** Resizes the balloon of the running VM to the given percentage of its memory. */
+    private void setBalloonPercent(int percent) {
+        IVirtualMachine vm = mVirtualMachine;
+        if (vm == null) {
+            return;
+        }
+        mExecutorService.execute(
+                () -> {
+                    try {
+                        MemoryStats stats = vm.getMemoryStats();
+                        if (stats.totalMemory < 0) {
+                            // The guest hasn't reported its memory size yet.
+                            return;
+                        }
+                        long totalMib = stats.totalMemory / (1024 * 1024);
+                        vm.setMemoryBalloon(totalMib * percent / 100);
+                    } catch (RemoteException | RuntimeException e) {
+                        Log.w(TAG, "Failed to resize the memory balloon of " + this, e);
+                    }
+                });
+    }
+
     /**
      * Deletes this virtual machine. Deleting a virtual machine means deleting any persisted data
      * associated with it including the per-VM secret. This is an irreversable action. A virtual
@@ -567,6 +665,25 @@ public class VirtualMachine {
                 () -> nativeConnectToVsockServer(mVirtualMachine.asBinder(), port));
     }
 
+    /**
+     * Connects to an RPC server which the payload has registered under the given name with the
+     * vm_payload library, and returns a root IBinder object. This is like {@link
+     * #connectToVsockServer}, except that the host app doesn't need to know the port of the server.
+     */
+    public Future<IBinder> connectToService(@NonNull String name) throws VirtualMachineException {
+        if (getStatus() != Status.RUNNING) {
+            throw new VirtualMachineException("VM is not running");
+        }
+        final int port;
+        try {
+            port = mVirtualMachine.getPayloadServicePort(name);
+        } catch (RemoteException | IllegalArgumentException e) {
+            throw new VirtualMachineException(e);
+        }
+        return mExecutorService.submit(
+                () -> nativeConnectToVsockServer(mVirtualMachine.asBinder(), port));
+    }
+
     @Override
     public String toString() {
         StringBuilder sb = new StringBuilder();
@@ -578,29 +695,38 @@ public class VirtualMachine {
         return sb.toString();
     }
 
-    private static List<String> parseExtraApkListFromPayloadConfig(JsonReader reader)
+    private static List<ExtraApkConfig> parseExtraApkListFromPayloadConfig(JsonReader reader)
             throws VirtualMachineException {
         /**
          * JSON schema from packages/modules/Virtualization/microdroid/payload/config/src/lib.rs:
          *
-         * <p>{ "extra_apks": [ { "path": "/system/app/foo.apk", }, ... ], ... }
+         * <p>{ "extra_apks": [ { "path": "/system/app/foo.apk", "required": false, }, ... ], ... }
          */
         try {
-            List<String> apks = new ArrayList<>();
+            List<ExtraApkConfig> apks = new ArrayList<>();
 
             reader.beginObject();
             while (reader.hasNext()) {
                 if (reader.nextName().equals("extra_apks")) {
                     reader.beginArray();
                     while (reader.hasNext()) {
+                        ExtraApkConfig apk = new ExtraApkConfig();
                         reader.beginObject();
-                        String name = reader.nextName();
-                        if (name.equals("path")) {
-                            apks.add(reader.nextString());
-                        } else {
-                            reader.skipValue();
+                        while (reader.hasNext()) {
+                            String name = reader.nextName();
+                            if (name.equals("path")) {
+                                apk.path = reader.nextString();
+                            } else if (name.equals("required")) {
+                                apk.required = reader.nextBoolean();
+                            } else {
+                                reader.skipValue();
+                            }
                         }
                         reader.endObject();
+                        if (apk.path == null) {
+                            throw new VirtualMachineException("extra apk without a path");
+                        }
+                        apks.add(apk);
                     }
                     reader.endArray();
                 } else {
@@ -626,7 +752,7 @@ public class VirtualMachine {
             String payloadPath = config.getPayloadConfigPath();
             InputStream inputStream =
                     zipFile.getInputStream(zipFile.getEntry(config.getPayloadConfigPath()));
-            List<String> apkList =
+            List<ExtraApkConfig> apkList =
                     parseExtraApkListFromPayloadConfig(
                             new JsonReader(new InputStreamReader(inputStream)));
 
@@ -634,8 +760,9 @@ public class VirtualMachine {
             for (int i = 0; i < apkList.size(); ++i) {
                 extraApks.add(
                         new ExtraApkSpec(
-                                new File(apkList.get(i)),
-                                new File(vmDir, EXTRA_IDSIG_FILE_PREFIX + i)));
+                                new File(apkList.get(i).path),
+                                new File(vmDir, EXTRA_IDSIG_FILE_PREFIX + i),
+                                apkList.get(i).required));
             }
 
             return extraApks;
@@ -184,6 +184,9 @@ public final class VirtualMachineConfig {
         if (mMemoryMib > 0) {
             b.putInt(KEY_MEMORY_MIB, mMemoryMib);
         }
+        if (mCpuAffinity != null) {
+            b.putString(KEY_CPU_AFFINITY, mCpuAffinity);
+        }
         b.writeToStream(output);
     }
 
@@ -0,0 +1,82 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Errors of APK/APEX signature verification
+
+use thiserror::Error;
+
+/// Failures of APK/APEX signature verification. They are returned wrapped in `anyhow::Error`, so
+/// callers can tell them apart with `downcast_ref()` (or `root_cause().downcast_ref()` when
+/// context was added).
+#[derive(Error, Debug, PartialEq)]
+pub enum ApkVerificationError {
+    /// The file is not a ZIP file which can carry an APK Signing Block.
+    #[error("Invalid ZIP: {0}")]
+    InvalidZip(String),
+    /// There's no APK Signing Block at all.
+    #[error("No APK Signing Block before ZIP Central Directory")]
+    NoSigningBlock,
+    /// The APK Signing Block or one of its signature scheme blocks can't be parsed.
+    #[error("Malformed APK Signing Block: {0}")]
+    MalformedSigningBlock(String),
+    /// The APK Signing Block has no signature scheme block with the given ID.
+    #[error("No APK Signature Scheme block in APK Signing Block with ID: {0:#x}")]
+    SignatureNotFound(u32),
+    /// No signer (or more than one signer) is applicable to the platform.
+    #[error("No applicable signer: {0}")]
+    NoApplicableSigner(String),
+    /// None of the signatures uses an algorithm which is supported.
+    #[error("No supported signatures found")]
+    NoSupportedSignature,
+    /// The signature algorithm is unknown or unsupported.
+    #[error("Unsupported signature algorithm: {0:#x}")]
+    UnsupportedAlgorithm(u32),
+    /// The signature doesn't verify with the public key.
+    #[error("Signature verification failed: {0:#x}")]
+    SignatureVerificationFailed(u32),
+    /// The lists of signature algorithms in the signatures and the digests differ.
+    #[error("Signature algorithms don't match between digests and signatures records")]
+    AlgorithmMismatch,
+    /// The SDK ranges of the signer and its signed data differ.
+    #[error("SDK versions mismatch between signed and unsigned in v3 signer block.")]
+    SdkVersionMismatch,
+    /// The digest of the APK contents doesn't match the signed one.
+    #[error("Digest mismatch: computed={computed:?} vs expected={expected:?}")]
+    DigestMismatch {
+        /// Computed digest in hex
+        computed: String,
+        /// Signed digest in hex
+        expected: String,
+    },
+    /// No precomputed digest was supplied for the signature algorithm.
+    #[error("No expected digest for signature algorithm: {0:#x}")]
+    MissingDigest(u32),
+    /// The signer has no certificate, or the certificate can't be parsed.
+    #[error("Invalid certificate: {0}")]
+    InvalidCertificate(String),
+    /// The public key of the signer doesn't match its certificate.
+    #[error("Public key mismatch between certificate and signature record")]
+    PublicKeyMismatch,
+    /// A signature of a newer scheme is expected but missing.
+    #[error("Signature stripped: {0}")]
+    SignatureStripped(String),
+    /// The proof-of-rotation attribute is invalid.
+    #[error("Invalid proof-of-rotation: {0}")]
+    InvalidProofOfRotation(String),
+    /// The SourceStamp block is invalid.
+    #[error("Invalid source stamp: {0}")]
+    InvalidSourceStamp(String),
+}
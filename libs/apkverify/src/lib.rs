@@ -17,23 +17,193 @@
 //! Verifies APK/APEX signing with v2/v3 scheme
 
 mod bytes_ext;
+mod error;
 mod sigutil;
+mod stamp;
 #[allow(dead_code)]
 pub mod testing;
+mod v2;
 mod v3;
 mod ziputil;
 
 use anyhow::Result;
+pub use error::ApkVerificationError;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use sigutil::{is_signature_not_found, ApkSections, DigestSource};
+use std::fs::File;
+use std::io::{Read, Seek};
 use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+/// The outcome of a successful APK/APEX signature verification.
+#[derive(Debug)]
+pub struct VerificationResult {
+    /// Version of the APK Signature Scheme used for verification (e.g. 3 for v3).
+    pub scheme_version: u32,
+    /// Signers whose signatures were verified, in the order they appear in the signing block.
+    pub signers: Vec<SignerInfo>,
+    /// Content digests which were verified against the APK/APEX contents.
+    pub digests: Vec<ContentDigest>,
+}
+
+/// Information about a verified signer.
+#[derive(Debug)]
+pub struct SignerInfo {
+    /// The public key (in DER format) of the signer.
+    pub public_key: Box<[u8]>,
+    /// The certificate chain (each in DER format) of the signer. The first certificate is the
+    /// signer's own certificate.
+    pub certificates: Vec<Box<[u8]>>,
+    /// ID of the signature algorithm which was used for verification.
+    pub signature_algorithm_id: u32,
+    /// The additional attributes in the signed data of the signer, in the order they appear.
+    pub attributes: Vec<SignerAttribute>,
+}
+
+/// An additional attribute in the signed data of a signer.
+#[derive(Debug, PartialEq)]
+pub enum SignerAttribute {
+    /// (v2) ID of a newer signature scheme which the APK is also signed with.
+    StrippingProtection(u32),
+    /// (v3) The verified signing certificate lineage (each in DER format), oldest first. The last
+    /// certificate is the signer's own certificate.
+    ProofOfRotation(Vec<Box<[u8]>>),
+    /// (v3) The minimum SDK version on which the rotated signing key is used.
+    RotationMinSdkVersion(u32),
+    /// (v3.1) The rotated signing key targets a development release.
+    RotationOnDevRelease,
+    /// An attribute which isn't known to this library.
+    Unknown {
+        /// ID of the attribute
+        id: u32,
+        /// Value of the attribute
+        value: Box<[u8]>,
+    },
+}
+
+/// A content digest listed in the signed data of a signer.
+#[derive(Debug)]
+pub struct ContentDigest {
+    /// ID of the signature algorithm which the digest is associated with.
+    pub signature_algorithm_id: u32,
+    /// The digest itself.
+    pub digest: Box<[u8]>,
+}
 
 /// Verifies APK/APEX signing with v2/v3 scheme. On success, the public key (in DER format) is
-/// returned.
-pub fn verify<P: AsRef<Path>>(path: P) -> Result<Box<[u8]>> {
-    // TODO(jooyung) fallback to v2 when v3 not found
-    v3::verify(path)
+/// returned. `current_sdk` is the SDK version of the platform: v3 signers whose SDK range doesn't
+/// include it are skipped.
+pub fn verify<P: AsRef<Path>>(path: P, current_sdk: u32) -> Result<Box<[u8]>> {
+    let mut result = verify_and_extract(path, current_sdk)?;
+    Ok(result.signers.remove(0).public_key)
+}
+
+/// Verifies APK/APEX signing with v2/v3 scheme. On success, the details of the verification,
+/// including the signer certificates, are returned. v2 is used only when there's no v3 block.
+pub fn verify_and_extract<P: AsRef<Path>>(path: P, current_sdk: u32) -> Result<VerificationResult> {
+    verify_from(File::open(path.as_ref())?, current_sdk)
+}
+
+/// Same as `verify_and_extract`, but reads the APK/APEX from `reader` so that it doesn't need to
+/// be a file on disk.
+pub fn verify_from<R: Read + Seek>(reader: R, current_sdk: u32) -> Result<VerificationResult> {
+    VerifierBuilder::new(current_sdk).build()?.verify_from(reader)
+}
+
+/// Verifies the signatures over the signed data of the APK/APEX file, but doesn't hash its
+/// contents. Instead, the digests in the signed data are compared with `expected_digests`, which
+/// the caller has to compute or obtain from a trusted source, e.g. when the contents are already
+/// protected by dm-verity.
+pub fn verify_signed_data_only<P: AsRef<Path>>(
+    path: P,
+    expected_digests: &[ContentDigest],
+    current_sdk: u32,
+) -> Result<VerificationResult> {
+    let reader = File::open(path.as_ref())?;
+    VerifierBuilder::new(current_sdk)
+        .build()?
+        .verify_with(reader, &DigestSource::Precomputed(expected_digests))
+}
+
+/// A builder that constructs `Verifier` struct, for verifying APK/APEX signing with non-default
+/// options.
+pub struct VerifierBuilder {
+    current_sdk: u32,
+    digest_threads: usize,
+}
+
+impl VerifierBuilder {
+    /// Creates a builder for the platform of the given SDK version.
+    pub fn new(current_sdk: u32) -> Self {
+        VerifierBuilder { current_sdk, digest_threads: 1 }
+    }
+
+    /// Computes the digests of the 1MB chunks of the APK contents using up to `threads` threads,
+    /// but no more than the available parallelism. By default, they are computed in the calling
+    /// thread.
+    pub fn parallel_digest(&mut self, threads: usize) -> &mut Self {
+        self.digest_threads = threads;
+        self
+    }
+
+    /// Constructs a `Verifier`. Its digest threads are started here, once, and are shared by all
+    /// the verifications it does.
+    pub fn build(&self) -> Result<Verifier> {
+        let available = thread::available_parallelism().map_or(1, |n| n.get());
+        let threads = self.digest_threads.min(available);
+        let digest_pool = if threads > 1 {
+            Some(Arc::new(ThreadPoolBuilder::new().num_threads(threads).build()?))
+        } else {
+            None
+        };
+        Ok(Verifier { current_sdk: self.current_sdk, digest_pool })
+    }
+}
+
+/// Verifies APK/APEX signing with v2/v3 scheme, with the options set by `VerifierBuilder`.
+pub struct Verifier {
+    current_sdk: u32,
+    digest_pool: Option<Arc<ThreadPool>>,
+}
+
+impl Verifier {
+    /// Same as `verify_and_extract`.
+    pub fn verify<P: AsRef<Path>>(&self, path: P) -> Result<VerificationResult> {
+        self.verify_from(File::open(path.as_ref())?)
+    }
+
+    /// Same as `verify_from`.
+    pub fn verify_from<R: Read + Seek>(&self, reader: R) -> Result<VerificationResult> {
+        self.verify_with(reader, &DigestSource::Computed)
+    }
+
+    fn verify_with<R: Read + Seek>(
+        &self,
+        reader: R,
+        digest_source: &DigestSource,
+    ) -> Result<VerificationResult> {
+        let mut sections = ApkSections::new(reader)?;
+        sections.set_digest_pool(self.digest_pool.clone());
+        match v3::verify(&mut sections, self.current_sdk, digest_source) {
+            Err(e) if is_signature_not_found(&e) => v2::verify(&mut sections, digest_source),
+            result => result,
+        }
+    }
+}
+
+/// Verifies the SourceStamp block of the given APK/APEX file, in addition to its v2/v3 signature.
+/// On success, the SHA-256 digest of the stamp certificate is returned.
+pub fn verify_source_stamp<P: AsRef<Path>>(path: P, current_sdk: u32) -> Result<Box<[u8]>> {
+    let result = verify_and_extract(path.as_ref(), current_sdk)?;
+    stamp::verify(path, &result)
 }
 
 /// Gets the public key (in DER format) that was used to sign the given APK/APEX file
-pub fn get_public_key_der<P: AsRef<Path>>(path: P) -> Result<Box<[u8]>> {
-    v3::get_public_key_der(path)
+pub fn get_public_key_der<P: AsRef<Path>>(path: P, current_sdk: u32) -> Result<Box<[u8]>> {
+    let mut sections = ApkSections::new(File::open(path.as_ref())?)?;
+    match v3::get_public_key_der(&mut sections, current_sdk) {
+        Err(e) if is_signature_not_found(&e) => v2::get_public_key_der(&mut sections),
+        result => result,
+    }
 }
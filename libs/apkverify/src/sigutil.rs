@@ -19,11 +19,16 @@
 use anyhow::{anyhow, bail, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rayon::prelude::*;
+use rayon::ThreadPool;
 use ring::digest;
 use std::cmp::min;
 use std::io::{Cursor, Read, Seek, SeekFrom, Take};
+use std::sync::Arc;
 
+use crate::error::ApkVerificationError;
 use crate::ziputil::{set_central_directory_offset, zip_sections};
+use crate::ContentDigest;
 
 const APK_SIG_BLOCK_MIN_SIZE: u32 = 32;
 const APK_SIG_BLOCK_MAGIC: u128 = 0x3234206b636f6c4220676953204b5041;
@@ -49,6 +54,15 @@ const CONTENT_DIGEST_SHA256: u32 = 4;
 
 const CHUNK_SIZE_BYTES: u64 = 1024 * 1024;
 
+/// Where the digest of the APK contents comes from when verifying a signer.
+pub enum DigestSource<'a> {
+    /// The digest is computed over the APK contents.
+    Computed,
+    /// The digests were computed elsewhere. The caller is responsible for the integrity of the
+    /// APK contents, e.g. by reading them through dm-verity.
+    Precomputed(&'a [ContentDigest]),
+}
+
 pub struct ApkSections<R> {
     inner: R,
     signing_block_offset: u32,
@@ -57,6 +71,7 @@ pub struct ApkSections<R> {
     central_directory_size: u32,
     eocd_offset: u32,
     eocd_size: u32,
+    digest_pool: Option<Arc<ThreadPool>>,
 }
 
 impl<R: Read + Seek> ApkSections<R> {
@@ -72,14 +87,21 @@ impl<R: Read + Seek> ApkSections<R> {
             central_directory_size: zip_sections.central_directory_size,
             eocd_offset: zip_sections.eocd_offset,
             eocd_size: zip_sections.eocd_size,
+            digest_pool: None,
         })
     }
 
+    /// Sets the thread pool which computes the digests of chunks in `compute_digest`, rather than
+    /// the calling thread.
+    pub fn set_digest_pool(&mut self, pool: Option<Arc<ThreadPool>>) {
+        self.digest_pool = pool;
+    }
+
     /// Returns the APK Signature Scheme block contained in the provided file for the given ID
     /// and the additional information relevant for verifying the block against the file.
+    /// Fails with `ApkVerificationError::SignatureNotFound` if there's no such block.
     pub fn find_signature(&mut self, block_id: u32) -> Result<Bytes> {
         let signing_block = self.bytes(self.signing_block_offset, self.signing_block_size)?;
-        // TODO(jooyung): propagate NotFound error so that verification can fallback to V2
         find_signature_scheme_block(Bytes::from(signing_block), block_id)
     }
 
@@ -90,12 +112,16 @@ impl<R: Read + Seek> ApkSections<R> {
     ///    chunks (little-endian uint32), and the concatenation of digests of the chunks in the
     ///    order the chunks appear in the APK.
     /// (see https://source.android.com/security/apksigning/v2#integrity-protected-contents)
+    /// When a digest thread pool is set, chunks are read in batches and the digests of the chunks
+    /// in a batch are computed in parallel.
     pub fn compute_digest(&mut self, signature_algorithm_id: u32) -> Result<Vec<u8>> {
         let digester = Digester::new(signature_algorithm_id)?;
+        let pool = self.digest_pool.clone();
+        let batch_size = pool.as_ref().map_or(1, |pool| pool.current_num_threads());
 
         let mut digests_of_chunks = BytesMut::new();
         let mut chunk_count = 0u32;
-        let mut chunk = vec![0u8; CHUNK_SIZE_BYTES as usize];
+        let mut chunks = vec![vec![0u8; CHUNK_SIZE_BYTES as usize]; batch_size];
         for data in &[
             ApkSections::zip_entries,
             ApkSections::central_directory,
@@ -103,18 +129,48 @@ impl<R: Read + Seek> ApkSections<R> {
         ] {
             let mut data = data(self)?;
             while data.limit() > 0 {
-                let chunk_size = min(CHUNK_SIZE_BYTES, data.limit());
-                let slice = &mut chunk[..(chunk_size as usize)];
-                data.read_exact(slice)?;
-                digests_of_chunks.put_slice(
-                    digester.digest(slice, CHUNK_HEADER_MID, chunk_size as u32).as_ref(),
-                );
-                chunk_count += 1;
+                let mut chunk_sizes = vec![];
+                for chunk in chunks.iter_mut() {
+                    if data.limit() == 0 {
+                        break;
+                    }
+                    let chunk_size = min(CHUNK_SIZE_BYTES, data.limit()) as usize;
+                    data.read_exact(&mut chunk[..chunk_size])?;
+                    chunk_sizes.push(chunk_size);
+                }
+                let batch: Vec<&[u8]> =
+                    chunks.iter().zip(chunk_sizes).map(|(chunk, size)| &chunk[..size]).collect();
+                for digest in digester.digest_chunks(&batch, pool.as_deref()) {
+                    digests_of_chunks.put_slice(digest.as_ref());
+                    chunk_count += 1;
+                }
             }
         }
         Ok(digester.digest(&digests_of_chunks, CHUNK_HEADER_TOP, chunk_count).as_ref().into())
     }
 
+    /// Returns the digest of APK contents for the given "signature algorithm" from `source`.
+    /// Precomputed digests are matched by their content digest algorithm.
+    pub fn content_digest(
+        &mut self,
+        signature_algorithm_id: u32,
+        source: &DigestSource,
+    ) -> Result<Vec<u8>> {
+        let digests = match source {
+            DigestSource::Computed => return self.compute_digest(signature_algorithm_id),
+            DigestSource::Precomputed(digests) => digests,
+        };
+        let content_digest_algorithm_id = to_content_digest_algorithm(signature_algorithm_id)?;
+        digests
+            .iter()
+            .find(|dig| {
+                to_content_digest_algorithm(dig.signature_algorithm_id).ok()
+                    == Some(content_digest_algorithm_id)
+            })
+            .map(|dig| dig.digest.to_vec())
+            .ok_or_else(|| ApkVerificationError::MissingDigest(signature_algorithm_id).into())
+    }
+
     fn zip_entries(&mut self) -> Result<Take<Box<dyn Read + '_>>> {
         scoped_read(&mut self.inner, 0, self.signing_block_offset as u64)
     }
@@ -186,6 +242,14 @@ impl Digester {
         ctx.update(data);
         ctx.finish()
     }
+
+    fn digest_chunks(&self, chunks: &[&[u8]], pool: Option<&ThreadPool>) -> Vec<digest::Digest> {
+        let digest_chunk = |chunk: &&[u8]| self.digest(chunk, CHUNK_HEADER_MID, chunk.len() as u32);
+        match pool {
+            Some(pool) => pool.install(|| chunks.par_iter().map(digest_chunk).collect()),
+            None => chunks.iter().map(digest_chunk).collect(),
+        }
+    }
 }
 
 fn find_signing_block<T: Read + Seek>(
@@ -199,28 +263,31 @@ fn find_signing_block<T: Read + Seek>(
     // * @-24 bytes uint64:    size in bytes (same as the one above)
     // * @-16 bytes uint128:   magic
     if central_directory_offset < APK_SIG_BLOCK_MIN_SIZE {
-        bail!(
+        bail!(ApkVerificationError::MalformedSigningBlock(format!(
             "APK too small for APK Signing Block. ZIP Central Directory offset: {}",
             central_directory_offset
-        );
+        )));
     }
     reader.seek(SeekFrom::Start((central_directory_offset - 24) as u64))?;
     let size_in_footer = reader.read_u64::<LittleEndian>()? as u32;
     if reader.read_u128::<LittleEndian>()? != APK_SIG_BLOCK_MAGIC {
-        bail!("No APK Signing Block before ZIP Central Directory")
+        bail!(ApkVerificationError::NoSigningBlock)
     }
     let total_size = size_in_footer + 8;
-    let signing_block_offset = central_directory_offset
-        .checked_sub(total_size)
-        .ok_or_else(|| anyhow!("APK Signing Block size out of range: {}", size_in_footer))?;
+    let signing_block_offset =
+        central_directory_offset.checked_sub(total_size).ok_or_else(|| {
+            ApkVerificationError::MalformedSigningBlock(format!(
+                "APK Signing Block size out of range: {}",
+                size_in_footer
+            ))
+        })?;
     reader.seek(SeekFrom::Start(signing_block_offset as u64))?;
     let size_in_header = reader.read_u64::<LittleEndian>()? as u32;
     if size_in_header != size_in_footer {
-        bail!(
+        bail!(ApkVerificationError::MalformedSigningBlock(format!(
             "APK Signing Block sizes in header and footer do not match: {} vs {}",
-            size_in_header,
-            size_in_footer
-        );
+            size_in_header, size_in_footer
+        )));
     }
     Ok((signing_block_offset, total_size))
 }
@@ -237,7 +304,10 @@ fn find_signature_scheme_block(buf: Bytes, block_id: u32) -> Result<Bytes> {
     while pairs.has_remaining() {
         entry_count += 1;
         if pairs.remaining() < 8 {
-            bail!("Insufficient data to read size of APK Signing Block entry #{}", entry_count);
+            bail!(ApkVerificationError::MalformedSigningBlock(format!(
+                "Insufficient data to read size of APK Signing Block entry #{}",
+                entry_count
+            )));
         }
         let length = pairs.get_u64_le();
         let mut pair = pairs.split_to(length as usize);
@@ -246,8 +316,12 @@ fn find_signature_scheme_block(buf: Bytes, block_id: u32) -> Result<Bytes> {
             return Ok(pair);
         }
     }
-    // TODO(jooyung): return NotFound error
-    bail!("No APK Signature Scheme block in APK Signing Block with ID: {}", block_id)
+    Err(ApkVerificationError::SignatureNotFound(block_id).into())
+}
+
+/// Returns true if `error` was caused by a missing APK Signature Scheme block.
+pub fn is_signature_not_found(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref(), Some(ApkVerificationError::SignatureNotFound(_)))
 }
 
 pub fn is_supported_signature_algorithm(algorithm_id: u32) -> bool {
@@ -266,7 +340,7 @@ pub fn is_supported_signature_algorithm(algorithm_id: u32) -> bool {
     )
 }
 
-fn to_content_digest_algorithm(algorithm_id: u32) -> Result<u32> {
+pub fn to_content_digest_algorithm(algorithm_id: u32) -> Result<u32> {
     match algorithm_id {
         SIGNATURE_RSA_PSS_WITH_SHA256
         | SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256
@@ -278,7 +352,7 @@ fn to_content_digest_algorithm(algorithm_id: u32) -> Result<u32> {
         SIGNATURE_VERITY_RSA_PKCS1_V1_5_WITH_SHA256
         | SIGNATURE_VERITY_ECDSA_WITH_SHA256
         | SIGNATURE_VERITY_DSA_WITH_SHA256 => Ok(CONTENT_DIGEST_VERITY_CHUNKED_SHA256),
-        _ => bail!("Unknown signature algorithm: {}", algorithm_id),
+        _ => bail!(ApkVerificationError::UnsupportedAlgorithm(algorithm_id)),
     }
 }
 
@@ -0,0 +1,193 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verifies SourceStamp V2 block
+
+use anyhow::{bail, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use ring::digest;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use x509_parser::parse_x509_certificate;
+use zip::ZipArchive;
+
+use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+use crate::error::ApkVerificationError;
+use crate::sigutil::*;
+use crate::v3::{to_hex_string, verify_signed_data, Signature, X509Certificate};
+use crate::VerificationResult;
+
+pub const SOURCE_STAMP_V2_BLOCK_ID: u32 = 0x6dff800d;
+
+/// Name of the ZIP entry which holds the SHA-256 digest of the stamp certificate. Being part of
+/// the APK contents, it is protected by the APK signature.
+const STAMP_CERT_SHA256_ENTRY_NAME: &str = "stamp-cert-sha256";
+
+/// Data model for SourceStamp V2 block
+/// (see com.android.apksig.internal.apk.stamp.V2SourceStampVerifier)
+
+type SourceStampBlock = LengthPrefixed<SourceStamp>;
+
+struct SourceStamp {
+    certificate: LengthPrefixed<X509Certificate>,
+    signed_digests: LengthPrefixed<Vec<LengthPrefixed<SchemeSignatures>>>,
+    // Stamp attributes (optional) and their signatures
+    attributes: Option<(LengthPrefixed<Bytes>, Signatures)>,
+}
+
+struct SchemeSignatures {
+    scheme_version: u32,
+    signatures: Signatures,
+}
+
+type Signatures = LengthPrefixed<Vec<LengthPrefixed<Signature>>>;
+
+/// Verifies the SourceStamp block against the digests which were verified with the APK signature
+/// scheme of `result`. Returns the SHA-256 digest of the stamp certificate.
+pub fn verify<P: AsRef<Path>>(path: P, result: &VerificationResult) -> Result<Box<[u8]>> {
+    let f = File::open(path.as_ref())?;
+    let mut sections = ApkSections::new(f)?;
+    let stamp = sections.find_signature(SOURCE_STAMP_V2_BLOCK_ID)?.read::<SourceStampBlock>()?;
+
+    // 1. Verify that the stamp certificate matches the digest recorded in the APK contents.
+    let expected_cert_digest = read_stamp_cert_digest(path.as_ref())?;
+    let cert_digest = digest::digest(&digest::SHA256, &stamp.certificate);
+    if cert_digest.as_ref() != expected_cert_digest.as_slice() {
+        bail!(ApkVerificationError::InvalidSourceStamp(format!(
+            "Stamp certificate digest mismatch: computed={:?} vs expected={:?}",
+            to_hex_string(cert_digest.as_ref()),
+            to_hex_string(&expected_cert_digest),
+        )));
+    }
+    let (_, cert) = parse_x509_certificate(stamp.certificate.as_ref())
+        .map_err(|e| ApkVerificationError::InvalidCertificate(e.to_string()))?;
+    let key_info = &cert.tbs_certificate.subject_pki;
+
+    // 2. Verify the stamp signature over the content digests of the verified signature scheme.
+    let scheme_signatures = stamp
+        .signed_digests
+        .iter()
+        .find(|s| s.scheme_version == result.scheme_version)
+        .ok_or_else(|| {
+            ApkVerificationError::InvalidSourceStamp(format!(
+                "No stamp signature for APK Signature Scheme v{}",
+                result.scheme_version
+            ))
+        })?;
+    let signed_digests = encode_digests(result)?;
+    verify_strongest(&signed_digests, &scheme_signatures.signatures, key_info)
+        .context("Unable to verify stamp signature over content digests")?;
+
+    // 3. Verify the stamp attributes, if any.
+    if let Some((attributes, signatures)) = &stamp.attributes {
+        verify_strongest(attributes, signatures, key_info)
+            .context("Unable to verify stamp signature over stamp attributes")?;
+    }
+
+    Ok(cert_digest.as_ref().to_vec().into_boxed_slice())
+}
+
+fn read_stamp_cert_digest(path: &Path) -> Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut entry = archive.by_name(STAMP_CERT_SHA256_ENTRY_NAME).map_err(|_| {
+        ApkVerificationError::InvalidSourceStamp(format!(
+            "No {} entry found",
+            STAMP_CERT_SHA256_ENTRY_NAME
+        ))
+    })?;
+    let mut buf = vec![];
+    entry.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Encodes the content digests as the stamp signs them: a sequence of length-prefixed pairs of
+/// content digest algorithm ID and length-prefixed digest, sorted by the algorithm ID.
+fn encode_digests(result: &VerificationResult) -> Result<Bytes> {
+    let mut digests = BTreeMap::new();
+    for dig in result.digests.iter() {
+        let content_digest_algorithm_id = to_content_digest_algorithm(dig.signature_algorithm_id)?;
+        digests.entry(content_digest_algorithm_id).or_insert(&dig.digest);
+    }
+    let mut buf = BytesMut::new();
+    for (id, digest) in digests {
+        buf.put_u32_le(8 + digest.len() as u32);
+        buf.put_u32_le(id);
+        buf.put_u32_le(digest.len() as u32);
+        buf.put_slice(digest);
+    }
+    Ok(buf.freeze())
+}
+
+fn verify_strongest(
+    data: &Bytes,
+    signatures: &Signatures,
+    key_info: &x509_parser::x509::SubjectPublicKeyInfo,
+) -> Result<()> {
+    let strongest: &Signature = signatures
+        .iter()
+        .filter(|sig| is_supported_signature_algorithm(sig.signature_algorithm_id))
+        .max_by_key(|sig| rank_signature_algorithm(sig.signature_algorithm_id).unwrap())
+        .ok_or(ApkVerificationError::NoSupportedSignature)?;
+    verify_signed_data(data, strongest, key_info)
+}
+
+// ReadFromBytes implementations
+
+impl ReadFromBytes for SourceStamp {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        let certificate = buf.read()?;
+        let signed_digests = buf.read()?;
+        let attributes = if buf.is_empty() { None } else { Some((buf.read()?, buf.read()?)) };
+        Ok(Self { certificate, signed_digests, attributes })
+    }
+}
+
+impl ReadFromBytes for SchemeSignatures {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self { scheme_version: buf.read()?, signatures: buf.read()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContentDigest;
+
+    #[test]
+    fn test_encode_digests_sorted_by_content_digest_algorithm() {
+        let result = VerificationResult {
+            scheme_version: 3,
+            signers: vec![],
+            digests: vec![
+                ContentDigest {
+                    signature_algorithm_id: SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA512,
+                    digest: Box::new([2u8; 2]),
+                },
+                ContentDigest {
+                    signature_algorithm_id: SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256,
+                    digest: Box::new([1u8; 1]),
+                },
+            ],
+        };
+        let encoded = encode_digests(&result).unwrap();
+        assert_eq!(
+            encoded.as_ref(),
+            &[9, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 10, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 2, 2]
+        );
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verifies APK Signature Scheme V2
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use std::io::{Read, Seek};
+use x509_parser::{parse_x509_certificate, prelude::FromDer, x509::SubjectPublicKeyInfo};
+
+use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+use crate::error::ApkVerificationError;
+use crate::sigutil::*;
+use crate::v3::{
+    read_attribute_u32, split_additional_attribute, to_hex_string, verify_signed_data,
+    AdditionalAttributes, Digest, Signature, X509Certificate,
+};
+use crate::{ContentDigest, SignerAttribute, SignerInfo, VerificationResult};
+
+pub const APK_SIGNATURE_SCHEME_V2_BLOCK_ID: u32 = 0x7109871a;
+
+const SCHEME_VERSION: u32 = 2;
+
+/// ID of the additional attribute which protects against stripping of newer signature schemes.
+/// Its value is the ID of the newer scheme the APK was also signed with.
+const STRIPPING_PROTECTION_ATTR_ID: u32 = 0xbeeff00d;
+const SF_ATTRIBUTE_ANDROID_APK_SIGNED_ID_V3: u32 = 3;
+
+/// Data model for Signature Scheme V2
+/// https://source.android.com/security/apksigning/v2#apk-signature-scheme-v2-block-format
+
+type Signers = LengthPrefixed<Vec<LengthPrefixed<Signer>>>;
+
+struct Signer {
+    signed_data: LengthPrefixed<Bytes>, // not verified yet
+    signatures: LengthPrefixed<Vec<LengthPrefixed<Signature>>>,
+    public_key: LengthPrefixed<Bytes>,
+}
+
+struct SignedData {
+    digests: LengthPrefixed<Vec<LengthPrefixed<Digest>>>,
+    certificates: LengthPrefixed<Vec<LengthPrefixed<X509Certificate>>>,
+    additional_attributes: LengthPrefixed<Vec<LengthPrefixed<AdditionalAttributes>>>,
+}
+
+/// Verifies APK Signature Scheme v2 signatures of the provided APK and returns the details of
+/// the signers and the verified digests.
+/// Unlike v3, every signer listed in the v2 block must be verified.
+pub fn verify<R: Read + Seek>(
+    sections: &mut ApkSections<R>,
+    digest_source: &DigestSource,
+) -> Result<VerificationResult> {
+    let signers = find_signers(sections)?;
+    let mut result =
+        VerificationResult { scheme_version: SCHEME_VERSION, signers: vec![], digests: vec![] };
+    for (i, signer) in signers.iter().enumerate() {
+        let (signer_info, digests) = signer
+            .verify(sections, digest_source)
+            .with_context(|| format!("Failed to verify signer #{}", i))?;
+        result.signers.push(signer_info);
+        result.digests.extend(digests);
+    }
+    Ok(result)
+}
+
+/// Gets the public key (in DER format) that was used to sign the given APK/APEX file
+pub fn get_public_key_der<R: Read + Seek>(sections: &mut ApkSections<R>) -> Result<Box<[u8]>> {
+    let signers = find_signers(sections)?;
+    Ok(signers[0].public_key.to_vec().into_boxed_slice())
+}
+
+fn find_signers<R: Read + Seek>(sections: &mut ApkSections<R>) -> Result<Signers> {
+    let mut block = sections.find_signature(APK_SIGNATURE_SCHEME_V2_BLOCK_ID)?;
+    // parse v2 scheme block
+    let signers = block.read::<Signers>()?;
+    if signers.is_empty() {
+        bail!(ApkVerificationError::NoApplicableSigner("No signers found".to_string()));
+    }
+    Ok(signers)
+}
+
+impl Signer {
+    fn verify<R: Read + Seek>(
+        &self,
+        sections: &mut ApkSections<R>,
+        digest_source: &DigestSource,
+    ) -> Result<(SignerInfo, Vec<ContentDigest>)> {
+        // 1. Choose the strongest supported signature algorithm ID from signatures.
+        let strongest: &Signature = self
+            .signatures
+            .iter()
+            .filter(|sig| is_supported_signature_algorithm(sig.signature_algorithm_id))
+            .max_by_key(|sig| rank_signature_algorithm(sig.signature_algorithm_id).unwrap())
+            .ok_or(ApkVerificationError::NoSupportedSignature)?;
+
+        // 2. Verify the corresponding signature from signatures against signed data using public
+        //    key.
+        let (_, key_info) = SubjectPublicKeyInfo::from_der(self.public_key.as_ref())?;
+        verify_signed_data(&self.signed_data, strongest, &key_info)?;
+
+        // It is now safe to parse signed data.
+        let signed_data: SignedData = self.signed_data.slice(..).read()?;
+
+        // 3. Verify that the ordered list of signature algorithm IDs in digests and signatures is
+        //    identical. (This is to prevent signature stripping/addition.)
+        if !self
+            .signatures
+            .iter()
+            .map(|sig| sig.signature_algorithm_id)
+            .eq(signed_data.digests.iter().map(|dig| dig.signature_algorithm_id))
+        {
+            bail!(ApkVerificationError::AlgorithmMismatch);
+        }
+
+        // 4. Compute the digest of APK contents using the same digest algorithm as the digest
+        //    algorithm used by the signature algorithm. (Or use the precomputed one.)
+        let digest = signed_data
+            .digests
+            .iter()
+            .find(|&dig| dig.signature_algorithm_id == strongest.signature_algorithm_id)
+            .unwrap(); // ok to unwrap since we check if two lists are the same above
+        let computed = sections.content_digest(digest.signature_algorithm_id, digest_source)?;
+
+        // 5. Verify that the computed digest is identical to the corresponding digest from digests.
+        if computed != digest.digest.as_ref() {
+            bail!(ApkVerificationError::DigestMismatch {
+                computed: to_hex_string(&computed),
+                expected: to_hex_string(&digest.digest),
+            });
+        }
+
+        // 6. Verify that SubjectPublicKeyInfo of the first certificate of certificates is identical
+        //    to public key.
+        let cert = signed_data.certificates.first().ok_or_else(|| {
+            ApkVerificationError::InvalidCertificate("No certificates listed".into())
+        })?;
+        let (_, cert) = parse_x509_certificate(cert.as_ref())
+            .map_err(|e| ApkVerificationError::InvalidCertificate(e.to_string()))?;
+        if cert.tbs_certificate.subject_pki != key_info {
+            bail!(ApkVerificationError::PublicKeyMismatch);
+        }
+
+        // 7. v2 is only used when there's no v3 block. Reject if the signer claims that the APK
+        //    was also signed with v3, which means the v3 block has been stripped.
+        let attributes = signed_data.attributes()?;
+        check_stripping_protection(&attributes)?;
+
+        let signer_info = SignerInfo {
+            public_key: self.public_key.to_vec().into_boxed_slice(),
+            certificates: signed_data
+                .certificates
+                .iter()
+                .map(|cert| cert.to_vec().into_boxed_slice())
+                .collect(),
+            signature_algorithm_id: strongest.signature_algorithm_id,
+            attributes,
+        };
+        let digests = signed_data
+            .digests
+            .iter()
+            .map(|dig| ContentDigest {
+                signature_algorithm_id: dig.signature_algorithm_id,
+                digest: dig.digest.to_vec().into_boxed_slice(),
+            })
+            .collect();
+        Ok((signer_info, digests))
+    }
+}
+
+impl SignedData {
+    fn attributes(&self) -> Result<Vec<SignerAttribute>> {
+        self.additional_attributes
+            .iter()
+            .map(|attr| {
+                let (id, value) = split_additional_attribute(attr)?;
+                Ok(match id {
+                    STRIPPING_PROTECTION_ATTR_ID => {
+                        SignerAttribute::StrippingProtection(read_attribute_u32(id, value)?)
+                    }
+                    _ => SignerAttribute::Unknown { id, value: value.to_vec().into_boxed_slice() },
+                })
+            })
+            .collect()
+    }
+}
+
+fn check_stripping_protection(attributes: &[SignerAttribute]) -> Result<()> {
+    if attributes
+        .contains(&SignerAttribute::StrippingProtection(SF_ATTRIBUTE_ANDROID_APK_SIGNED_ID_V3))
+    {
+        bail!(ApkVerificationError::SignatureStripped(
+            "v2 signature indicates APK is signed using APK Signature Scheme v3, but no such \
+            signature was found"
+                .to_string()
+        ));
+    }
+    Ok(())
+}
+
+// ReadFromBytes implementations
+
+impl ReadFromBytes for Signer {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self { signed_data: buf.read()?, signatures: buf.read()?, public_key: buf.read()? })
+    }
+}
+
+impl ReadFromBytes for SignedData {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self {
+            digests: buf.read()?,
+            certificates: buf.read()?,
+            additional_attributes: buf.read()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::assert_contains;
+    use bytes::{BufMut, BytesMut};
+
+    fn signed_data_with_attribute(id: u32, value: u32) -> SignedData {
+        let mut attr = BytesMut::new();
+        attr.put_u32_le(8);
+        attr.put_u32_le(id);
+        attr.put_u32_le(value);
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(0); // digests
+        buf.put_u32_le(0); // certificates
+        buf.put_u32_le(attr.len() as u32);
+        buf.put_slice(&attr);
+        buf.freeze().read().unwrap()
+    }
+
+    #[test]
+    fn test_stripping_protection_rejects_stripped_v3() {
+        let signed_data = signed_data_with_attribute(STRIPPING_PROTECTION_ATTR_ID, 3);
+        let attributes = signed_data.attributes().unwrap();
+        assert_eq!(attributes, vec![SignerAttribute::StrippingProtection(3)]);
+        let res = check_stripping_protection(&attributes);
+        assert!(res.is_err());
+        assert_contains(&res.unwrap_err().to_string(), "Signature stripped");
+    }
+
+    #[test]
+    fn test_stripping_protection_ignores_unknown_attributes() {
+        let signed_data = signed_data_with_attribute(0x12345678, 3);
+        let attributes = signed_data.attributes().unwrap();
+        assert!(matches!(attributes[..], [SignerAttribute::Unknown { id: 0x12345678, .. }]));
+        assert!(check_stripping_protection(&attributes).is_ok());
+    }
+}
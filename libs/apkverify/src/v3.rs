@@ -19,25 +19,36 @@
 // TODO(jooyung) remove this
 #![allow(dead_code)]
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Result};
 use bytes::Bytes;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Padding;
+use openssl::sign::{RsaPssSaltlen, Verifier};
 use ring::signature::{
     UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, RSA_PKCS1_2048_8192_SHA256,
-    RSA_PKCS1_2048_8192_SHA512, RSA_PSS_2048_8192_SHA256, RSA_PSS_2048_8192_SHA512,
+    RSA_PKCS1_2048_8192_SHA512,
 };
-use std::fs::File;
 use std::io::{Read, Seek};
-use std::ops::Range;
-use std::path::Path;
+use std::ops::RangeInclusive;
 use x509_parser::{parse_x509_certificate, prelude::FromDer, x509::SubjectPublicKeyInfo};
 
 use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+use crate::error::ApkVerificationError;
 use crate::sigutil::*;
+use crate::{ContentDigest, SignerAttribute, SignerInfo, VerificationResult};
+
+mod lineage;
 
 pub const APK_SIGNATURE_SCHEME_V3_BLOCK_ID: u32 = 0xf05368c0;
+pub const APK_SIGNATURE_SCHEME_V31_BLOCK_ID: u32 = 0x1b93ad61;
+
+/// IDs of the additional attributes in the signed data of a v3/v3.1 signer.
+const PROOF_OF_ROTATION_ATTR_ID: u32 = 0x3ba06f8c;
+const ROTATION_MIN_SDK_VERSION_ATTR_ID: u32 = 0x559f8b02;
+const ROTATION_ON_DEV_RELEASE_ATTR_ID: u32 = 0xc2a6b3ba;
 
-// TODO(jooyung): get "ro.build.version.sdk"
-const SDK_INT: u32 = 31;
+const SCHEME_VERSION: u32 = 3;
 
 /// Data model for Signature Scheme V3
 /// https://source.android.com/security/apksigning/v3#verification
@@ -53,8 +64,8 @@ struct Signer {
 }
 
 impl Signer {
-    fn sdk_range(&self) -> Range<u32> {
-        self.min_sdk..self.max_sdk
+    fn sdk_range(&self) -> RangeInclusive<u32> {
+        self.min_sdk..=self.max_sdk
     }
 }
 
@@ -67,69 +78,140 @@ struct SignedData {
 }
 
 impl SignedData {
-    fn sdk_range(&self) -> Range<u32> {
-        self.min_sdk..self.max_sdk
+    fn sdk_range(&self) -> RangeInclusive<u32> {
+        self.min_sdk..=self.max_sdk
+    }
+
+    /// Parses the additional attributes. The proof-of-rotation attribute is verified on the way.
+    fn attributes(&self) -> Result<Vec<SignerAttribute>> {
+        self.additional_attributes
+            .iter()
+            .map(|attr| {
+                let (id, value) = split_additional_attribute(attr)?;
+                Ok(match id {
+                    PROOF_OF_ROTATION_ATTR_ID => SignerAttribute::ProofOfRotation(
+                        lineage::verify(value)?
+                            .iter()
+                            .map(|cert| cert.to_vec().into_boxed_slice())
+                            .collect(),
+                    ),
+                    ROTATION_MIN_SDK_VERSION_ATTR_ID => {
+                        SignerAttribute::RotationMinSdkVersion(read_attribute_u32(id, value)?)
+                    }
+                    ROTATION_ON_DEV_RELEASE_ATTR_ID => SignerAttribute::RotationOnDevRelease,
+                    _ => SignerAttribute::Unknown { id, value: value.to_vec().into_boxed_slice() },
+                })
+            })
+            .collect()
     }
 }
 
-#[derive(Debug)]
-struct Signature {
-    signature_algorithm_id: u32,
-    signature: LengthPrefixed<Bytes>,
+/// Splits an additional attribute into its ID and value.
+pub(crate) fn split_additional_attribute(attr: &AdditionalAttributes) -> Result<(u32, Bytes)> {
+    let mut attr = attr.slice(..);
+    if attr.len() < 4 {
+        bail!(ApkVerificationError::MalformedSigningBlock(
+            "Additional attribute too short to contain ID".to_string()
+        ));
+    }
+    let id = attr.read()?;
+    Ok((id, attr))
+}
+
+/// Reads the value of an additional attribute which is a uint32.
+pub(crate) fn read_attribute_u32(id: u32, mut value: Bytes) -> Result<u32> {
+    if value.len() < 4 {
+        bail!(ApkVerificationError::MalformedSigningBlock(format!(
+            "Value of additional attribute {:#x} too short",
+            id
+        )));
+    }
+    value.read()
 }
 
-struct Digest {
-    signature_algorithm_id: u32,
-    digest: LengthPrefixed<Bytes>,
+#[derive(Debug)]
+pub(crate) struct Signature {
+    pub signature_algorithm_id: u32,
+    pub signature: LengthPrefixed<Bytes>,
 }
 
-type X509Certificate = Bytes;
-type AdditionalAttributes = Bytes;
+pub(crate) struct Digest {
+    pub signature_algorithm_id: u32,
+    pub digest: LengthPrefixed<Bytes>,
+}
 
-/// Verifies APK Signature Scheme v3 signatures of the provided APK and returns the public key
-/// associated with the signer.
-pub fn verify<P: AsRef<Path>>(path: P) -> Result<Box<[u8]>> {
-    let f = File::open(path.as_ref())?;
-    let mut sections = ApkSections::new(f)?;
-    find_signer_and_then(&mut sections, |(signer, sections)| signer.verify(sections))
+pub(crate) type X509Certificate = Bytes;
+pub(crate) type AdditionalAttributes = Bytes;
+
+/// Verifies APK Signature Scheme v3 signatures of the provided APK and returns the details of
+/// the signer and the verified digests.
+pub fn verify<R: Read + Seek>(
+    sections: &mut ApkSections<R>,
+    current_sdk: u32,
+    digest_source: &DigestSource,
+) -> Result<VerificationResult> {
+    find_signer_and_then(sections, current_sdk, |(signer, has_v31_block, sections)| {
+        signer.verify(has_v31_block, current_sdk, sections, digest_source)
+    })
 }
 
-/// Finds the supported signer and execute a function on it.
-fn find_signer_and_then<R, U, F>(sections: &mut ApkSections<R>, f: F) -> Result<U>
+/// Finds the supported signer and execute a function on it. The v3.1 block takes precedence over
+/// the v3 block when it has a signer for the platform. The function is also told whether the APK
+/// has a v3.1 block at all.
+fn find_signer_and_then<R, U, F>(sections: &mut ApkSections<R>, current_sdk: u32, f: F) -> Result<U>
 where
     R: Read + Seek,
-    F: FnOnce((&Signer, &mut ApkSections<R>)) -> Result<U>,
+    F: FnOnce((&Signer, bool, &mut ApkSections<R>)) -> Result<U>,
 {
-    let mut block = sections.find_signature(APK_SIGNATURE_SCHEME_V3_BLOCK_ID)?;
-    // parse v3 scheme block
-    let signers = block.read::<Signers>()?;
+    let (has_v31_block, signers) = match find_signers(sections, APK_SIGNATURE_SCHEME_V31_BLOCK_ID) {
+        Ok(signers) if signers.iter().any(|s| s.sdk_range().contains(&current_sdk)) => {
+            (true, signers)
+        }
+        Ok(_) => (true, find_signers(sections, APK_SIGNATURE_SCHEME_V3_BLOCK_ID)?),
+        Err(e) if !is_signature_not_found(&e) => return Err(e),
+        Err(_) => (false, find_signers(sections, APK_SIGNATURE_SCHEME_V3_BLOCK_ID)?),
+    };
 
     // find supported by platform
-    let supported = signers.iter().filter(|s| s.sdk_range().contains(&SDK_INT)).collect::<Vec<_>>();
+    let supported =
+        signers.iter().filter(|s| s.sdk_range().contains(&current_sdk)).collect::<Vec<_>>();
 
     // there should be exactly one
     if supported.len() != 1 {
-        bail!(
+        bail!(ApkVerificationError::NoApplicableSigner(format!(
             "APK Signature Scheme V3 only supports one signer: {} signers found.",
             supported.len()
-        )
+        )))
     }
 
     // Call the supplied function
-    f((supported[0], sections))
+    f((supported[0], has_v31_block, sections))
+}
+
+fn find_signers<R: Read + Seek>(sections: &mut ApkSections<R>, block_id: u32) -> Result<Signers> {
+    let mut block = sections.find_signature(block_id)?;
+    // parse v3/v3.1 scheme block
+    block.read::<Signers>()
 }
 
 /// Gets the public key (in DER format) that was used to sign the given APK/APEX file
-pub fn get_public_key_der<P: AsRef<Path>>(path: P) -> Result<Box<[u8]>> {
-    let f = File::open(path.as_ref())?;
-    let mut sections = ApkSections::new(f)?;
-    find_signer_and_then(&mut sections, |(signer, _)| {
+pub fn get_public_key_der<R: Read + Seek>(
+    sections: &mut ApkSections<R>,
+    current_sdk: u32,
+) -> Result<Box<[u8]>> {
+    find_signer_and_then(sections, current_sdk, |(signer, _, _)| {
         Ok(signer.public_key.to_vec().into_boxed_slice())
     })
 }
 
 impl Signer {
-    fn verify<R: Read + Seek>(&self, sections: &mut ApkSections<R>) -> Result<Box<[u8]>> {
+    fn verify<R: Read + Seek>(
+        &self,
+        has_v31_block: bool,
+        current_sdk: u32,
+        sections: &mut ApkSections<R>,
+        digest_source: &DigestSource,
+    ) -> Result<VerificationResult> {
         // 1. Choose the strongest supported signature algorithm ID from signatures. The strength
         //    ordering is up to each implementation/platform version.
         let strongest: &Signature = self
@@ -137,7 +219,7 @@ impl Signer {
             .iter()
             .filter(|sig| is_supported_signature_algorithm(sig.signature_algorithm_id))
             .max_by_key(|sig| rank_signature_algorithm(sig.signature_algorithm_id).unwrap())
-            .ok_or_else(|| anyhow!("No supported signatures found"))?;
+            .ok_or(ApkVerificationError::NoSupportedSignature)?;
 
         // 2. Verify the corresponding signature from signatures against signed data using public key.
         //    (It is now safe to parse signed data.)
@@ -150,7 +232,7 @@ impl Signer {
         // 3. Verify the min and max SDK versions in the signed data match those specified for the
         //    signer.
         if self.sdk_range() != signed_data.sdk_range() {
-            bail!("SDK versions mismatch between signed and unsigned in v3 signer block.");
+            bail!(ApkVerificationError::SdkVersionMismatch);
         }
 
         // 4. Verify that the ordered list of signature algorithm IDs in digests and signatures is
@@ -161,66 +243,160 @@ impl Signer {
             .map(|sig| sig.signature_algorithm_id)
             .eq(signed_data.digests.iter().map(|dig| dig.signature_algorithm_id))
         {
-            bail!("Signature algorithms don't match between digests and signatures records");
+            bail!(ApkVerificationError::AlgorithmMismatch);
         }
 
         // 5. Compute the digest of APK contents using the same digest algorithm as the digest
-        //    algorithm used by the signature algorithm.
+        //    algorithm used by the signature algorithm. (Or use the precomputed one.)
         let digest = signed_data
             .digests
             .iter()
             .find(|&dig| dig.signature_algorithm_id == strongest.signature_algorithm_id)
             .unwrap(); // ok to unwrap since we check if two lists are the same above
-        let computed = sections.compute_digest(digest.signature_algorithm_id)?;
+        let computed = sections.content_digest(digest.signature_algorithm_id, digest_source)?;
 
         // 6. Verify that the computed digest is identical to the corresponding digest from digests.
         if computed != digest.digest.as_ref() {
-            bail!(
-                "Digest mismatch: computed={:?} vs expected={:?}",
-                to_hex_string(&computed),
-                to_hex_string(&digest.digest),
-            );
+            bail!(ApkVerificationError::DigestMismatch {
+                computed: to_hex_string(&computed),
+                expected: to_hex_string(&digest.digest),
+            });
         }
 
         // 7. Verify that SubjectPublicKeyInfo of the first certificate of certificates is identical
         //    to public key.
-        let cert = signed_data.certificates.first().context("No certificates listed")?;
-        let (_, cert) = parse_x509_certificate(cert.as_ref())?;
+        let cert = signed_data.certificates.first().ok_or_else(|| {
+            ApkVerificationError::InvalidCertificate("No certificates listed".into())
+        })?;
+        let (_, cert) = parse_x509_certificate(cert.as_ref())
+            .map_err(|e| ApkVerificationError::InvalidCertificate(e.to_string()))?;
         if cert.tbs_certificate.subject_pki != key_info {
-            bail!("Public key mismatch between certificate and signature record");
+            bail!(ApkVerificationError::PublicKeyMismatch);
+        }
+
+        // 8. If the proof-of-rotation attribute exists for the signer verify that the struct is
+        //    valid and this signer is the last certificate in the list.
+        let attributes = signed_data.attributes()?;
+        for attr in attributes.iter() {
+            if let SignerAttribute::ProofOfRotation(lineage) = attr {
+                if lineage.last().map(|c| c.as_ref()) != Some(signed_data.certificates[0].as_ref())
+                {
+                    bail!(ApkVerificationError::InvalidProofOfRotation(
+                        "Signer certificate is not the last certificate".to_string()
+                    ));
+                }
+            }
         }
 
-        // TODO(jooyung) 8. If the proof-of-rotation attribute exists for the signer verify that the struct is valid and this signer is the last certificate in the list.
-        Ok(self.public_key.to_vec().into_boxed_slice())
+        // 9. A v3 signer which declares the minimum SDK version of the rotated signer requires a
+        //    v3.1 block on that SDK version. (This is to prevent stripping of the v3.1 block.)
+        check_v31_stripping(&attributes, has_v31_block, current_sdk)?;
+
+        Ok(VerificationResult {
+            scheme_version: SCHEME_VERSION,
+            signers: vec![SignerInfo {
+                public_key: self.public_key.to_vec().into_boxed_slice(),
+                certificates: signed_data
+                    .certificates
+                    .iter()
+                    .map(|cert| cert.to_vec().into_boxed_slice())
+                    .collect(),
+                signature_algorithm_id: strongest.signature_algorithm_id,
+                attributes,
+            }],
+            digests: signed_data
+                .digests
+                .iter()
+                .map(|dig| ContentDigest {
+                    signature_algorithm_id: dig.signature_algorithm_id,
+                    digest: dig.digest.to_vec().into_boxed_slice(),
+                })
+                .collect(),
+        })
     }
 }
 
-fn verify_signed_data(
+pub(crate) fn verify_signed_data(
     data: &Bytes,
     signature: &Signature,
     key_info: &SubjectPublicKeyInfo,
 ) -> Result<()> {
     let verification_alg: &dyn VerificationAlgorithm = match signature.signature_algorithm_id {
-        SIGNATURE_RSA_PSS_WITH_SHA256 => &RSA_PSS_2048_8192_SHA256,
-        SIGNATURE_RSA_PSS_WITH_SHA512 => &RSA_PSS_2048_8192_SHA512,
         SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256 | SIGNATURE_VERITY_RSA_PKCS1_V1_5_WITH_SHA256 => {
             &RSA_PKCS1_2048_8192_SHA256
         }
         SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA512 => &RSA_PKCS1_2048_8192_SHA512,
         SIGNATURE_ECDSA_WITH_SHA256 | SIGNATURE_VERITY_ECDSA_WITH_SHA256 => &ECDSA_P256_SHA256_ASN1,
-        // TODO(b/190343842) not implemented signature algorithm
-        SIGNATURE_ECDSA_WITH_SHA512
+        // ring doesn't support these, and its RSA-PSS verification is limited to 2048-bit keys
+        // or longer. Use BoringSSL instead.
+        SIGNATURE_RSA_PSS_WITH_SHA256
+        | SIGNATURE_RSA_PSS_WITH_SHA512
+        | SIGNATURE_ECDSA_WITH_SHA512
         | SIGNATURE_DSA_WITH_SHA256
         | SIGNATURE_VERITY_DSA_WITH_SHA256 => {
-            bail!(
-                "TODO(b/190343842) not implemented signature algorithm: {:#x}",
-                signature.signature_algorithm_id
-            );
+            return verify_signed_data_with_openssl(data, signature, key_info);
         }
-        _ => bail!("Unsupported signature algorithm: {:#x}", signature.signature_algorithm_id),
+        _ => bail!(ApkVerificationError::UnsupportedAlgorithm(signature.signature_algorithm_id)),
     };
     let key = UnparsedPublicKey::new(verification_alg, &key_info.subject_public_key);
-    key.verify(data.as_ref(), signature.signature.as_ref())?;
+    key.verify(data.as_ref(), signature.signature.as_ref()).map_err(|_| {
+        ApkVerificationError::SignatureVerificationFailed(signature.signature_algorithm_id)
+    })?;
+    Ok(())
+}
+
+fn verify_signed_data_with_openssl(
+    data: &Bytes,
+    signature: &Signature,
+    key_info: &SubjectPublicKeyInfo,
+) -> Result<()> {
+    let (digest, is_pss) = match signature.signature_algorithm_id {
+        SIGNATURE_RSA_PSS_WITH_SHA256 => (MessageDigest::sha256(), true),
+        SIGNATURE_RSA_PSS_WITH_SHA512 => (MessageDigest::sha512(), true),
+        SIGNATURE_ECDSA_WITH_SHA512 => (MessageDigest::sha512(), false),
+        SIGNATURE_DSA_WITH_SHA256 | SIGNATURE_VERITY_DSA_WITH_SHA256 => {
+            (MessageDigest::sha256(), false)
+        }
+        _ => bail!(ApkVerificationError::UnsupportedAlgorithm(signature.signature_algorithm_id)),
+    };
+    let key = PKey::public_key_from_der(key_info.raw)?;
+    let mut verifier = Verifier::new(digest, &key)?;
+    if is_pss {
+        // RSASSA-PSS with MGF1 using the same digest, and salt as long as the digest.
+        verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+        verifier.set_rsa_mgf1_md(digest)?;
+        verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+    }
+    verifier.update(data.as_ref())?;
+    if !verifier.verify(signature.signature.as_ref())? {
+        bail!(ApkVerificationError::SignatureVerificationFailed(signature.signature_algorithm_id));
+    }
+    Ok(())
+}
+
+/// Fails if the attributes of a v3 signer declare a rotated signer for `current_sdk`, but the
+/// APK has no v3.1 block. Whether the v3.1 signer is actually used on the platform doesn't matter,
+/// e.g. it may target a development release.
+fn check_v31_stripping(
+    attributes: &[SignerAttribute],
+    has_v31_block: bool,
+    current_sdk: u32,
+) -> Result<()> {
+    if has_v31_block {
+        return Ok(());
+    }
+    let rotation_min_sdk = attributes.iter().find_map(|attr| match attr {
+        SignerAttribute::RotationMinSdkVersion(sdk) => Some(*sdk),
+        _ => None,
+    });
+    if let Some(rotation_min_sdk) = rotation_min_sdk {
+        if rotation_min_sdk <= current_sdk {
+            bail!(ApkVerificationError::SignatureStripped(format!(
+                "v3 signer targets rotation on SDK {}, but no v3.1 block was found.",
+                rotation_min_sdk
+            )));
+        }
+    }
     Ok(())
 }
 
@@ -264,6 +440,161 @@ impl ReadFromBytes for Digest {
 }
 
 #[inline]
-fn to_hex_string(buf: &[u8]) -> String {
+pub(crate) fn to_hex_string(buf: &[u8]) -> String {
     buf.iter().map(|b| format!("{:02X}", b)).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+    use openssl::dsa::Dsa;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer as OpensslSigner;
+
+    fn sign_and_verify(
+        key: &PKey<Private>,
+        digest: MessageDigest,
+        algorithm_id: u32,
+    ) -> Result<()> {
+        sign_and_verify_with_padding(key, digest, algorithm_id, None)
+    }
+
+    fn sign_and_verify_with_padding(
+        key: &PKey<Private>,
+        digest: MessageDigest,
+        algorithm_id: u32,
+        padding: Option<Padding>,
+    ) -> Result<()> {
+        let data = Bytes::from_static(b"signed data");
+        let mut signer = OpensslSigner::new(digest, key).unwrap();
+        if let Some(padding) = padding {
+            signer.set_rsa_padding(padding).unwrap();
+            if padding == Padding::PKCS1_PSS {
+                signer.set_rsa_mgf1_md(digest).unwrap();
+                signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH).unwrap();
+            }
+        }
+        signer.update(data.as_ref()).unwrap();
+        let sig = signer.sign_to_vec().unwrap();
+
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(algorithm_id);
+        buf.put_u32_le(sig.len() as u32);
+        buf.put_slice(&sig);
+        let signature: Signature = buf.freeze().read().unwrap();
+
+        let public_key = key.public_key_to_der().unwrap();
+        let (_, key_info) = SubjectPublicKeyInfo::from_der(&public_key).unwrap();
+        verify_signed_data(&data, &signature, &key_info)
+    }
+
+    fn signed_data_with_attributes(attrs: &[(u32, &[u8])]) -> SignedData {
+        let mut attributes = BytesMut::new();
+        for (id, value) in attrs {
+            attributes.put_u32_le(4 + value.len() as u32);
+            attributes.put_u32_le(*id);
+            attributes.put_slice(value);
+        }
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(0); // digests
+        buf.put_u32_le(0); // certificates
+        buf.put_u32_le(28); // min_sdk
+        buf.put_u32_le(u32::MAX); // max_sdk
+        buf.put_u32_le(attributes.len() as u32);
+        buf.put_slice(&attributes);
+        buf.freeze().read().unwrap()
+    }
+
+    #[test]
+    fn test_parse_attributes() {
+        let signed_data = signed_data_with_attributes(&[
+            (ROTATION_MIN_SDK_VERSION_ATTR_ID, &33u32.to_le_bytes()),
+            (ROTATION_ON_DEV_RELEASE_ATTR_ID, &[]),
+            (0x12345678, b"value"),
+        ]);
+        assert_eq!(
+            signed_data.attributes().unwrap(),
+            vec![
+                SignerAttribute::RotationMinSdkVersion(33),
+                SignerAttribute::RotationOnDevRelease,
+                SignerAttribute::Unknown { id: 0x12345678, value: Box::new(*b"value") },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reject_truncated_attribute() {
+        let signed_data = signed_data_with_attributes(&[(ROTATION_MIN_SDK_VERSION_ATTR_ID, &[1])]);
+        assert!(matches!(
+            signed_data.attributes().unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+            ApkVerificationError::MalformedSigningBlock(_),
+        ));
+    }
+
+    #[test]
+    fn test_v31_stripping() {
+        let attributes = [SignerAttribute::RotationMinSdkVersion(33)];
+        assert!(check_v31_stripping(&attributes, false, 32).is_ok());
+        assert!(matches!(
+            check_v31_stripping(&attributes, false, 33)
+                .unwrap_err()
+                .downcast_ref::<ApkVerificationError>()
+                .unwrap(),
+            ApkVerificationError::SignatureStripped(_),
+        ));
+        // The v3 signer can be used on the rotation SDK when the v3.1 signer doesn't apply.
+        assert!(check_v31_stripping(&attributes, true, 33).is_ok());
+        assert!(check_v31_stripping(&[], false, 33).is_ok());
+    }
+
+    #[test]
+    fn test_verify_ecdsa_with_sha512() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        assert!(sign_and_verify(&key, MessageDigest::sha512(), SIGNATURE_ECDSA_WITH_SHA512).is_ok());
+    }
+
+    #[test]
+    fn test_verify_dsa_with_sha256() {
+        let key = PKey::from_dsa(Dsa::generate(2048).unwrap()).unwrap();
+        assert!(sign_and_verify(&key, MessageDigest::sha256(), SIGNATURE_DSA_WITH_SHA256).is_ok());
+    }
+
+    #[test]
+    fn test_verify_rsa_pss() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        for (digest, algorithm_id) in [
+            (MessageDigest::sha256(), SIGNATURE_RSA_PSS_WITH_SHA256),
+            (MessageDigest::sha512(), SIGNATURE_RSA_PSS_WITH_SHA512),
+        ] {
+            let res =
+                sign_and_verify_with_padding(&key, digest, algorithm_id, Some(Padding::PKCS1_PSS));
+            assert!(res.is_ok(), "{:#x}: {:?}", algorithm_id, res);
+        }
+    }
+
+    #[test]
+    fn test_reject_pkcs1_signature_as_rsa_pss() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let res = sign_and_verify_with_padding(
+            &key,
+            MessageDigest::sha256(),
+            SIGNATURE_RSA_PSS_WITH_SHA256,
+            Some(Padding::PKCS1),
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_reject_signature_with_wrong_digest() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        assert!(
+            sign_and_verify(&key, MessageDigest::sha256(), SIGNATURE_ECDSA_WITH_SHA512).is_err()
+        );
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Copyright (C) 2021 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verifies the proof-of-rotation attribute (signing certificate lineage) of a v3/v3.1 signer
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use x509_parser::parse_x509_certificate;
+
+use super::{verify_signed_data, Signature, X509Certificate};
+use crate::bytes_ext::{BytesExt, LengthPrefixed, ReadFromBytes};
+use crate::error::ApkVerificationError;
+
+const LINEAGE_VERSION: u32 = 1;
+
+/// Data model for the proof-of-rotation attribute
+/// https://source.android.com/security/apksigning/v3#proof-of-rotation-struct
+
+struct Lineage {
+    version: u32,
+    nodes: LengthPrefixed<Vec<LengthPrefixed<Node>>>,
+}
+
+struct Node {
+    signed_data: LengthPrefixed<Bytes>, // not verified yet
+    flags: u32,
+    // signature over signed_data by the certificate of the previous node
+    signature: Signature,
+}
+
+struct NodeSignedData {
+    certificate: LengthPrefixed<X509Certificate>,
+    // algorithm that this certificate uses to sign the next node
+    signature_algorithm_id: u32,
+}
+
+/// Verifies the value of a proof-of-rotation attribute and returns the certificates in the
+/// lineage, oldest first.
+pub fn verify(mut attr: Bytes) -> Result<Vec<X509Certificate>> {
+    let lineage: Lineage = attr.read()?;
+    if lineage.version != LINEAGE_VERSION {
+        bail!(ApkVerificationError::InvalidProofOfRotation(format!(
+            "Unsupported version: {}",
+            lineage.version
+        )));
+    }
+    if lineage.nodes.is_empty() {
+        bail!(ApkVerificationError::InvalidProofOfRotation("Empty lineage".to_string()));
+    }
+
+    let mut certificates: Vec<X509Certificate> = vec![];
+    let mut last_signature_algorithm_id = None;
+    for (i, node) in lineage.nodes.iter().enumerate() {
+        // Each node except the first is signed by the certificate of the previous node, using
+        // the algorithm that the previous node committed to.
+        if let Some(last_cert) = certificates.last() {
+            if Some(node.signature.signature_algorithm_id) != last_signature_algorithm_id {
+                bail!(ApkVerificationError::InvalidProofOfRotation(format!(
+                    "Signing algorithm ID mismatch for node #{}",
+                    i
+                )));
+            }
+            let (_, last_cert) = parse_x509_certificate(last_cert.as_ref())?;
+            verify_signed_data(
+                &node.signed_data,
+                &node.signature,
+                &last_cert.tbs_certificate.subject_pki,
+            )
+            .with_context(|| format!("Unable to verify signature of node #{}", i))?;
+        }
+
+        // It is now safe to parse signed data.
+        let signed_data: NodeSignedData = node.signed_data.slice(..).read()?;
+        let certificate = signed_data.certificate.slice(..);
+        parse_x509_certificate(certificate.as_ref()).map_err(|e| {
+            ApkVerificationError::InvalidProofOfRotation(format!(
+                "Invalid certificate in node #{}: {}",
+                i, e
+            ))
+        })?;
+        if certificates.contains(&certificate) {
+            bail!(ApkVerificationError::InvalidProofOfRotation(format!(
+                "Duplicate certificate in node #{}",
+                i
+            )));
+        }
+        certificates.push(certificate);
+        last_signature_algorithm_id = Some(signed_data.signature_algorithm_id);
+    }
+    Ok(certificates)
+}
+
+// ReadFromBytes implementations
+
+impl ReadFromBytes for Lineage {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self { version: buf.read()?, nodes: buf.read()? })
+    }
+}
+
+impl ReadFromBytes for Node {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self { signed_data: buf.read()?, flags: buf.read()?, signature: buf.read()? })
+    }
+}
+
+impl ReadFromBytes for NodeSignedData {
+    fn read_from_bytes(buf: &mut Bytes) -> Result<Self> {
+        Ok(Self { certificate: buf.read()?, signature_algorithm_id: buf.read()? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::assert_contains;
+    use bytes::{BufMut, BytesMut};
+
+    #[test]
+    fn test_reject_unknown_version() {
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(2);
+        buf.put_u32_le(0);
+        let res = verify(buf.freeze());
+        assert!(res.is_err());
+        assert_contains(&res.unwrap_err().to_string(), "Unsupported version");
+    }
+
+    #[test]
+    fn test_reject_empty_lineage() {
+        let mut buf = BytesMut::new();
+        buf.put_u32_le(LINEAGE_VERSION);
+        buf.put_u32_le(0);
+        let res = verify(buf.freeze());
+        assert_eq!(
+            res.unwrap_err().downcast_ref::<ApkVerificationError>(),
+            Some(&ApkVerificationError::InvalidProofOfRotation("Empty lineage".to_string()))
+        );
+    }
+}
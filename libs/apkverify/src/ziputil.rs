@@ -21,6 +21,8 @@ use bytes::{Buf, BufMut};
 use std::io::{Read, Seek, SeekFrom};
 use zip::ZipArchive;
 
+use crate::error::ApkVerificationError;
+
 const EOCD_MIN_SIZE: usize = 22;
 const EOCD_CENTRAL_DIRECTORY_SIZE_FIELD_OFFSET: usize = 12;
 const EOCD_CENTRAL_DIRECTORY_OFFSET_FIELD_OFFSET: usize = 16;
@@ -41,7 +43,10 @@ pub fn zip_sections<R: Read + Seek>(mut reader: R) -> Result<(R, ZipSections)> {
     let archive = ZipArchive::new(reader)?;
     let eocd_size = archive.comment().len() + EOCD_MIN_SIZE;
     if archive.offset() != 0 {
-        bail!("Invalid ZIP: offset should be 0, but {}.", archive.offset());
+        bail!(ApkVerificationError::InvalidZip(format!(
+            "offset should be 0, but {}.",
+            archive.offset()
+        )));
     }
     // retrieve reader back
     reader = archive.into_inner();
@@ -50,14 +55,18 @@ pub fn zip_sections<R: Read + Seek>(mut reader: R) -> Result<(R, ZipSections)> {
     let mut eocd = vec![0u8; eocd_size as usize];
     reader.read_exact(&mut eocd)?;
     if (&eocd[0..]).get_u32_le() != EOCD_MAGIC {
-        bail!("Invalid ZIP: ZipArchive::new() should point EOCD after reading.");
+        bail!(ApkVerificationError::InvalidZip(
+            "ZipArchive::new() should point EOCD after reading.".to_string()
+        ));
     }
     let (central_directory_size, central_directory_offset) = get_central_directory(&eocd)?;
     if central_directory_offset == ZIP64_MARK || central_directory_size == ZIP64_MARK {
-        bail!("Unsupported ZIP: ZIP64 is not supported.");
+        bail!(ApkVerificationError::InvalidZip("ZIP64 is not supported.".to_string()));
     }
     if central_directory_offset + central_directory_size != eocd_offset {
-        bail!("Invalid ZIP: EOCD should follow CD with no extra data or overlap.");
+        bail!(ApkVerificationError::InvalidZip(
+            "EOCD should follow CD with no extra data or overlap.".to_string()
+        ));
     }
 
     Ok((
@@ -14,32 +14,211 @@
  * limitations under the License.
  */
 
-use apkverify::{testing::assert_contains, verify};
+use apkverify::{
+    get_public_key_der, verify, verify_and_extract, verify_from, verify_signed_data_only,
+    verify_source_stamp, ApkVerificationError, ContentDigest, SignerAttribute, VerifierBuilder,
+};
+use std::fs::{self, File};
+use std::io::Cursor;
 use std::matches;
 
+const SDK_INT: u32 = 31;
+
 #[test]
 fn test_verify_v3() {
-    assert!(verify("tests/data/test.apex").is_ok());
+    assert!(verify("tests/data/test.apex", SDK_INT).is_ok());
+}
+
+#[test]
+fn test_verify_v3_skips_signers_for_other_sdk() {
+    let res = verify("tests/data/test.apex", 1);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::NoApplicableSigner(_),
+    ));
+}
+
+#[test]
+fn test_verify_and_extract_v3() {
+    let result = verify_and_extract("tests/data/test.apex", SDK_INT).unwrap();
+    assert_eq!(result.scheme_version, 3);
+    assert_eq!(result.signers.len(), 1);
+    let signer = &result.signers[0];
+    assert_eq!(signer.public_key, get_public_key_der("tests/data/test.apex", SDK_INT).unwrap());
+    assert!(!signer.certificates.is_empty());
+    assert!(result
+        .digests
+        .iter()
+        .any(|dig| dig.signature_algorithm_id == signer.signature_algorithm_id));
+}
+
+#[test]
+fn test_verify_from_reader() {
+    let expected = get_public_key_der("tests/data/test.apex", SDK_INT).unwrap();
+
+    let result = verify_from(File::open("tests/data/test.apex").unwrap(), SDK_INT).unwrap();
+    assert_eq!(result.signers[0].public_key, expected);
+
+    let buf = fs::read("tests/data/test.apex").unwrap();
+    let result = verify_from(Cursor::new(buf), SDK_INT).unwrap();
+    assert_eq!(result.signers[0].public_key, expected);
+}
+
+#[test]
+fn test_verify_source_stamp_without_stamp_block() {
+    let res = verify_source_stamp("tests/data/test.apex", SDK_INT);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::SignatureNotFound(_),
+    ));
 }
 
 #[test]
 fn test_verify_v3_digest_mismatch() {
-    let res = verify("tests/data/v3-only-with-rsa-pkcs1-sha512-8192-digest-mismatch.apk");
-    assert!(res.is_err());
-    assert_contains(&res.unwrap_err().to_string(), "Digest mismatch");
+    let res = verify("tests/data/v3-only-with-rsa-pkcs1-sha512-8192-digest-mismatch.apk", SDK_INT);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::DigestMismatch { .. },
+    ));
 }
 
 #[test]
 fn test_verify_v3_cert_and_public_key_mismatch() {
-    let res = verify("tests/data/v3-only-cert-and-public-key-mismatch.apk");
-    assert!(res.is_err());
-    assert_contains(&res.unwrap_err().to_string(), "Public key mismatch");
+    let res = verify("tests/data/v3-only-cert-and-public-key-mismatch.apk", SDK_INT);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::PublicKeyMismatch,
+    ));
+}
+
+#[test]
+fn test_verify_with_parallel_digest() {
+    let expected = verify_and_extract("tests/data/test.apex", SDK_INT).unwrap();
+    for threads in [2, 4, 16] {
+        let result = VerifierBuilder::new(SDK_INT)
+            .parallel_digest(threads)
+            .build()
+            .unwrap()
+            .verify("tests/data/test.apex")
+            .unwrap();
+        assert_eq!(result.signers[0].public_key, expected.signers[0].public_key);
+    }
+
+    let res = VerifierBuilder::new(SDK_INT)
+        .parallel_digest(4)
+        .build()
+        .unwrap()
+        .verify("tests/data/v3-only-with-rsa-pkcs1-sha512-8192-digest-mismatch.apk");
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::DigestMismatch { .. },
+    ));
+}
+
+#[test]
+fn test_verify_signed_data_only() {
+    let result = verify_and_extract("tests/data/test.apex", SDK_INT).unwrap();
+    let only = verify_signed_data_only("tests/data/test.apex", &result.digests, SDK_INT).unwrap();
+    assert_eq!(only.signers[0].public_key, result.signers[0].public_key);
+
+    let res = verify_signed_data_only("tests/data/test.apex", &[], SDK_INT);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::MissingDigest(_),
+    ));
+
+    let wrong_digests: Vec<_> = result
+        .digests
+        .iter()
+        .map(|dig| ContentDigest {
+            signature_algorithm_id: dig.signature_algorithm_id,
+            digest: vec![0u8; dig.digest.len()].into_boxed_slice(),
+        })
+        .collect();
+    let res = verify_signed_data_only("tests/data/test.apex", &wrong_digests, SDK_INT);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::DigestMismatch { .. },
+    ));
+}
+
+#[test]
+fn test_verify_signed_data_only_does_not_hash_contents() {
+    // The contents of this APK don't match its signed digest, which goes unnoticed when the
+    // signed digest is supplied as the expected one.
+    let apk = "tests/data/v3-only-with-rsa-pkcs1-sha512-8192-digest-mismatch.apk";
+    let signed_digest = ContentDigest {
+        signature_algorithm_id: 0x0104, // RSASSA-PKCS1-v1_5 with SHA2-512
+        digest: match verify(apk, SDK_INT).unwrap_err().downcast::<ApkVerificationError>() {
+            Ok(ApkVerificationError::DigestMismatch { expected, .. }) => hex_to_bytes(&expected),
+            e => panic!("unexpected result: {:?}", e),
+        },
+    };
+    assert!(verify_signed_data_only(apk, &[signed_digest], SDK_INT).is_ok());
+}
+
+fn hex_to_bytes(hex: &str) -> Box<[u8]> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn test_verify_v2() {
+    let apk = "tests/data/v2-only-with-rsa-pkcs1-sha256-2048.apk";
+    let result = verify_and_extract(apk, SDK_INT).unwrap();
+    assert_eq!(result.scheme_version, 2);
+    assert_eq!(result.signers.len(), 1);
+    let signer = &result.signers[0];
+    assert_eq!(signer.signature_algorithm_id, 0x0103); // RSA PKCS#1 v1.5 with SHA-256
+    assert_eq!(signer.public_key, get_public_key_der(apk, SDK_INT).unwrap());
+    assert_eq!(signer.certificates.len(), 1);
+    assert!(signer.attributes.is_empty());
+}
+
+#[test]
+fn test_verify_v2_digest_mismatch() {
+    let res = verify("tests/data/v2-only-with-rsa-pkcs1-sha256-2048-digest-mismatch.apk", SDK_INT);
+    assert!(matches!(
+        res.unwrap_err().root_cause().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::DigestMismatch { .. },
+    ));
+}
+
+#[test]
+fn test_verify_v2_with_stripped_v3() {
+    let res = verify("tests/data/v2-with-v3-stripped.apk", SDK_INT);
+    assert!(matches!(
+        res.unwrap_err().root_cause().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::SignatureStripped(_),
+    ));
+}
+
+#[test]
+fn test_verify_v31_with_both_blocks() {
+    let apk = "tests/data/v31-rotated-on-sdk-33.apk";
+    let rotated = verify_and_extract(apk, 33).unwrap();
+    let original = verify_and_extract(apk, 32).unwrap();
+    assert_eq!(rotated.scheme_version, 3);
+    assert_eq!(original.scheme_version, 3);
+    assert_ne!(rotated.signers[0].public_key, original.signers[0].public_key);
+    assert_eq!(rotated.signers[0].public_key, get_public_key_der(apk, 34).unwrap());
+    assert!(original.signers[0].attributes.contains(&SignerAttribute::RotationMinSdkVersion(33)));
+}
+
+#[test]
+fn test_verify_v3_with_stripped_v31() {
+    let apk = "tests/data/v31-rotated-on-sdk-33-with-v31-stripped.apk";
+    assert!(verify(apk, 32).is_ok());
+    let res = verify(apk, 33);
+    assert!(matches!(
+        res.unwrap_err().downcast_ref::<ApkVerificationError>().unwrap(),
+        ApkVerificationError::SignatureStripped(_),
+    ));
 }
 
 #[test]
 fn test_verify_truncated_cd() {
     use zip::result::ZipError;
-    let res = verify("tests/data/v2-only-truncated-cd.apk");
+    let res = verify("tests/data/v2-only-truncated-cd.apk", SDK_INT);
     // TODO(jooyung): consider making a helper for err assertion
     assert!(matches!(
         res.unwrap_err().root_cause().downcast_ref::<ZipError>().unwrap(),
@@ -0,0 +1,221 @@
+#!/usr/bin/env python3
+#
+# Copyright (C) 2022 The Android Open Source Project
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+"""Creates the test APKs in this directory which are signed with generated keys.
+
+The APKs are signed here directly, following https://source.android.com/security/apksigning, so
+that apksigner is not needed. Requires the `cryptography` package.
+
+  v2-only-with-rsa-pkcs1-sha256-2048.apk: signed with v2 only.
+  v2-only-with-rsa-pkcs1-sha256-2048-digest-mismatch.apk: the same APK with a byte of an entry
+      modified after signing.
+  v2-with-v3-stripped.apk: signed with v2 and v3, with its v3 block removed. The v2 signer
+      declares that the APK is also signed with v3.
+  v31-rotated-on-sdk-33.apk: signed with v3 by the original key for SDK 24 to 32, and with v3.1
+      by the rotated key from SDK 33. The v3 signer declares rotation on SDK 33.
+  v31-rotated-on-sdk-33-with-v31-stripped.apk: the same APK with its v3.1 block removed, and
+      with the v3 signer (re-signed) covering SDK 33 as well.
+"""
+
+import datetime
+import hashlib
+import io
+import os
+import struct
+import zipfile
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import padding, rsa
+from cryptography.x509.oid import NameOID
+
+APK_SIG_BLOCK_MAGIC = b'APK Sig Block 42'
+V2_BLOCK_ID = 0x7109871a
+V3_BLOCK_ID = 0xf05368c0
+V31_BLOCK_ID = 0x1b93ad61
+SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256 = 0x0103
+STRIPPING_PROTECTION_ATTR_ID = 0xbeeff00d
+SIGNED_WITH_V3 = 3
+ROTATION_MIN_SDK_VERSION_ATTR_ID = 0x559f8b02
+MAX_SDK = 0x7fffffff
+CHUNK_SIZE = 1024 * 1024
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+
+def length_prefixed(data):
+    return struct.pack('<I', len(data)) + data
+
+
+def sequence(items):
+    return length_prefixed(b''.join(length_prefixed(item) for item in items))
+
+
+def find_eocd(apk):
+    eocd = apk.rfind(b'PK\x05\x06')
+    cd_offset = struct.unpack('<I', apk[eocd + 16:eocd + 20])[0]
+    return eocd, cd_offset
+
+
+def content_digest(entries, central_directory, eocd):
+    """Chunked SHA-256 digest of the APK sections, as in the v2 spec."""
+    chunk_digests = []
+    for section in (entries, central_directory, eocd):
+        for i in range(0, len(section), CHUNK_SIZE):
+            chunk = section[i:i + CHUNK_SIZE]
+            chunk_digests.append(
+                hashlib.sha256(b'\xa5' + struct.pack('<I', len(chunk)) + chunk).digest())
+    return hashlib.sha256(b'\x5a' + struct.pack('<I', len(chunk_digests)) +
+                          b''.join(chunk_digests)).digest()
+
+
+def signing_block(pairs):
+    body = b''.join(struct.pack('<QI', len(value) + 4, id) + value for id, value in pairs)
+    size = len(body) + 8 + 16
+    return struct.pack('<Q', size) + body + struct.pack('<Q', size) + APK_SIG_BLOCK_MAGIC
+
+
+def insert_signing_block(entries, central_directory, eocd, block):
+    eocd = bytearray(eocd)
+    eocd[16:20] = struct.pack('<I', len(entries) + len(block))
+    return entries + block + central_directory + bytes(eocd)
+
+
+def create_unsigned_apk():
+    buf = io.BytesIO()
+    with zipfile.ZipFile(buf, 'w') as apk:
+        for name, content in [
+            ('AndroidManifest.xml', b'<manifest package="com.android.apkverify.test"/>\n'),
+            ('classes.dex', bytes(range(256)) * 16),
+            ('assets/hello.txt', b'Hello!\n'),
+        ]:
+            info = zipfile.ZipInfo(name, date_time=(2022, 1, 1, 0, 0, 0))
+            apk.writestr(info, content, compress_type=zipfile.ZIP_STORED)
+    return buf.getvalue()
+
+
+class Key:
+    """A fresh RSA-2048 key with a self-signed certificate."""
+
+    def __init__(self, name):
+        self.key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+        subject = x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, name)])
+        now = datetime.datetime(2022, 1, 1)
+        self.cert = (x509.CertificateBuilder().subject_name(subject).issuer_name(subject)
+                     .public_key(self.key.public_key()).serial_number(1).not_valid_before(now)
+                     .not_valid_after(now + datetime.timedelta(days=365 * 30))
+                     .sign(self.key, hashes.SHA256()).public_bytes(serialization.Encoding.DER))
+        self.public_key = self.key.public_key().public_bytes(
+            serialization.Encoding.DER, serialization.PublicFormat.SubjectPublicKeyInfo)
+
+    def sign(self, data):
+        return struct.pack('<I', SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256) + length_prefixed(
+            self.key.sign(data, padding.PKCS1v15(), hashes.SHA256()))
+
+
+def attribute(id, value):
+    return struct.pack('<I', id) + value
+
+
+def v2_signer(key, digest, attributes=()):
+    algorithm = struct.pack('<I', SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256)
+    signed_data = (sequence([algorithm + length_prefixed(digest)]) + sequence([key.cert]) +
+                   sequence(attributes))
+    return length_prefixed(signed_data) + sequence([key.sign(signed_data)]) + length_prefixed(
+        key.public_key)
+
+
+def v3_signer(key, digest, min_sdk, max_sdk, attributes=()):
+    algorithm = struct.pack('<I', SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256)
+    sdk_range = struct.pack('<II', min_sdk, max_sdk)
+    signed_data = (sequence([algorithm + length_prefixed(digest)]) + sequence([key.cert]) +
+                   sdk_range + sequence(attributes))
+    return (length_prefixed(signed_data) + sdk_range + sequence([key.sign(signed_data)]) +
+            length_prefixed(key.public_key))
+
+
+def sign(apk, create_blocks):
+    """Signs the unsigned APK with the (block ID, signers) pairs which `create_blocks` returns for
+    the content digest."""
+    eocd_offset, cd_offset = find_eocd(apk)
+    entries, central_directory = apk[:cd_offset], apk[cd_offset:eocd_offset]
+    # The digest covers the EOCD with the central directory offset pointing at the signing block,
+    # which is where the central directory starts before signing.
+    digest = content_digest(entries, central_directory, apk[eocd_offset:])
+    block = signing_block([(id, sequence(signers)) for id, signers in create_blocks(digest)])
+    return insert_signing_block(entries, central_directory, apk[eocd_offset:], block)
+
+
+def strip_block(apk, block_id):
+    eocd_offset, cd_offset = find_eocd(apk)
+    assert apk[cd_offset - 16:cd_offset] == APK_SIG_BLOCK_MAGIC
+    block_size = struct.unpack('<Q', apk[cd_offset - 24:cd_offset - 16])[0]
+    block_offset = cd_offset - block_size - 8
+    pairs = []
+    offset = block_offset + 8
+    while offset < cd_offset - 24:
+        length, id = struct.unpack('<QI', apk[offset:offset + 12])
+        if id != block_id:
+            pairs.append((id, apk[offset + 12:offset + 8 + length]))
+        offset += 8 + length
+    return insert_signing_block(apk[:block_offset], apk[cd_offset:eocd_offset],
+                                apk[eocd_offset:], signing_block(pairs))
+
+
+def write(name, apk):
+    with open(os.path.join(HERE, name), 'wb') as f:
+        f.write(apk)
+
+
+def main():
+    unsigned = create_unsigned_apk()
+
+    key = Key('apkverify v2 test')
+    signed = sign(unsigned, lambda digest: [(V2_BLOCK_ID, [v2_signer(key, digest)])])
+    write('v2-only-with-rsa-pkcs1-sha256-2048.apk', signed)
+
+    # Modify the last byte of the first entry's data.
+    tampered = bytearray(signed)
+    with zipfile.ZipFile(io.BytesIO(signed)) as apk:
+        first = apk.infolist()[0]
+        name_and_extra = struct.unpack('<HH', signed[first.header_offset + 26:
+                                                     first.header_offset + 30])
+        data_offset = first.header_offset + 30 + sum(name_and_extra)
+        tampered[data_offset + first.compress_size - 1] ^= 0xff
+    write('v2-only-with-rsa-pkcs1-sha256-2048-digest-mismatch.apk', tampered)
+
+    stripping_protection = [
+        attribute(STRIPPING_PROTECTION_ATTR_ID, struct.pack('<I', SIGNED_WITH_V3))
+    ]
+    signed_with_v3 = sign(unsigned, lambda digest: [
+        (V2_BLOCK_ID, [v2_signer(key, digest, stripping_protection)]),
+        (V3_BLOCK_ID, [v3_signer(key, digest, 24, MAX_SDK)]),
+    ])
+    write('v2-with-v3-stripped.apk', strip_block(signed_with_v3, V3_BLOCK_ID))
+
+    original, rotated = Key('apkverify original'), Key('apkverify rotated')
+    rotation_attributes = [attribute(ROTATION_MIN_SDK_VERSION_ATTR_ID, struct.pack('<I', 33))]
+    write('v31-rotated-on-sdk-33.apk', sign(unsigned, lambda digest: [
+        (V3_BLOCK_ID, [v3_signer(original, digest, 24, 32, rotation_attributes)]),
+        (V31_BLOCK_ID, [v3_signer(rotated, digest, 33, MAX_SDK)]),
+    ]))
+    # As if the v3.1 block were stripped and the v3 signer extended to the later SDKs.
+    write('v31-rotated-on-sdk-33-with-v31-stripped.apk', sign(unsigned, lambda digest: [
+        (V3_BLOCK_ID, [v3_signer(original, digest, 24, MAX_SDK, rotation_attributes)]),
+    ]))
+
+
+if __name__ == '__main__':
+    main()
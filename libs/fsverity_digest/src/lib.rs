@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2022 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Computes the fs-verity digest and Merkle tree of a file in userspace, in the same way as
+//! `fsverity digest` (SHA-256, 4K blocks, no salt). This is for files that do not have fs-verity
+//! enabled, or when the kernel's view of the file cannot be used.
+
+use ring::digest::{Context, SHA256};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+const CHUNK_SIZE: usize = 4096;
+const HASH_SIZE: usize = 32;
+const HASH_PER_PAGE: usize = CHUNK_SIZE / HASH_SIZE;
+
+// Constants from uapi/linux/fsverity.h
+const FS_VERITY_VERSION: u8 = 1;
+const FS_VERITY_HASH_ALG_SHA256: u8 = 1;
+const FS_VERITY_LOG_BLOCKSIZE: u8 = 12;
+
+/// A SHA-256 hash, e.g. an fs-verity digest.
+pub type Sha256Hash = [u8; HASH_SIZE];
+
+/// The fs-verity Merkle tree of a file.
+pub struct MerkleTree {
+    /// The tree in the same layout as FS_IOC_READ_VERITY_METADATA returns, i.e. levels from the
+    /// root to the leaves, each padded to the block size. Empty if the file fits in one block.
+    pub tree: Vec<u8>,
+
+    /// The fs-verity digest of the file.
+    pub digest: Sha256Hash,
+}
+fn sha256(data: &[&[u8]]) -> Sha256Hash {
+    let mut context = Context::new(&SHA256);
+    for d in data {
+        context.update(d);
+    }
+    let mut hash = [0u8; HASH_SIZE];
+    hash.copy_from_slice(context.finish().as_ref());
+    hash
+}
+
+/// Packs the hashes into blocks, with the last block zero-padded.
+fn pack_hashes(hashes: &[Sha256Hash]) -> Vec<u8> {
+    let mut level = Vec::new();
+    for page in hashes.chunks(HASH_PER_PAGE) {
+        for hash in page {
+            level.extend_from_slice(hash);
+        }
+        level.resize(level.len() + (HASH_PER_PAGE - page.len()) * HASH_SIZE, 0);
+    }
+    level
+}
+
+/// Reads up to a full chunk at `offset`. Returns the size read, which is less than `CHUNK_SIZE`
+/// only at the end of the file.
+fn read_chunk_at(file: &File, buf: &mut [u8; CHUNK_SIZE], offset: u64) -> io::Result<usize> {
+    let mut size = 0;
+    while size < CHUNK_SIZE {
+        match file.read_at(&mut buf[size..], offset + size as u64) {
+            Ok(0) => break,
+            Ok(n) => size += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+/// Builds the Merkle tree of the file content, in the same way as `fsverity digest`.
+pub fn build_merkle_tree(file: &File) -> io::Result<MerkleTree> {
+    let mut leaves = Vec::new();
+    let mut file_size = 0u64;
+    let mut buf = [0u8; CHUNK_SIZE];
+    loop {
+        let size = read_chunk_at(file, &mut buf, file_size)?;
+        if size == 0 {
+            break;
+        }
+        buf[size..].fill(0);
+        leaves.push(sha256(&[&buf]));
+        file_size += size as u64;
+        if size < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    // Build the levels from the bottom up, until a level fits in one block.
+    let mut levels = Vec::new();
+    let mut hashes = leaves;
+    while hashes.len() > 1 {
+        let level = pack_hashes(&hashes);
+        hashes = level.chunks(CHUNK_SIZE).map(|block| sha256(&[block])).collect();
+        levels.push(level);
+    }
+    // Per fs-verity, the root hash of an empty file is all zeros.
+    let root_hash = hashes.first().copied().unwrap_or([0u8; HASH_SIZE]);
+
+    // Little-endian byte representation of fsverity_descriptor from linux/fsverity.h
+    let digest = sha256(&[
+        &[FS_VERITY_VERSION],
+        &[FS_VERITY_HASH_ALG_SHA256],
+        &[FS_VERITY_LOG_BLOCKSIZE],
+        &[0u8],              // salt_size
+        &0u32.to_le_bytes(), // sig_size
+        &file_size.to_le_bytes(),
+        &root_hash,
+        &[0u8; 32],  // root_hash, last 32 bytes, always 0 because we are using sha256.
+        &[0u8; 32],  // salt
+        &[0u8; 144], // reserved
+    ]);
+    Ok(MerkleTree { tree: levels.into_iter().rev().flatten().collect(), digest })
+}
+
+/// Formats the digest in lowercase hex, as `fsverity digest` and odsign do.
+pub fn to_hex_string(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    // Test data below can be generated by:
+    //  $ perl -e 'print "\x{01}" x 4097' > foo
+    //  $ fsverity digest foo
+    use super::*;
+    use std::io::Write;
+
+    fn digest_of(content: &[u8]) -> String {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(content).unwrap();
+        to_hex_string(&build_merkle_tree(&file).unwrap().digest)
+    }
+
+    #[test]
+    fn digest_of_various_sizes() {
+        assert_eq!(
+            digest_of(&[]),
+            "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95"
+        );
+        assert_eq!(
+            digest_of(&[1; 4096]),
+            "cd0875ca59c7d37e962c5e8f5acd3770750ac80225e2df652ce5672fd34500af"
+        );
+        assert_eq!(
+            digest_of(&[1; 4097]),
+            "2901b849fda2d91e3929524561c4a47e77bb64734319759507b2029f18b9cc52"
+        );
+        // The largest file that still fits in 2 levels, and the smallest that requires 3.
+        assert_eq!(
+            digest_of(&[1; 524288]),
+            "26b7c190a34e19f420808ee7ec233b09fa6c34543b5a9d2950530114c205d14f"
+        );
+        assert_eq!(
+            digest_of(&[1; 524289]),
+            "316835d9be1c95b5cd55d07ae7965d651689efad186e26cbf680e40b683a3262"
+        );
+    }
+
+    #[test]
+    fn tree_layout() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        assert!(build_merkle_tree(&file).unwrap().tree.is_empty());
+
+        // 129 blocks need 2 blocks of leaf hashes, plus the root block above them.
+        file.write_all(&[1; 4096 * 128]).unwrap();
+        let tree = build_merkle_tree(&file).unwrap().tree;
+        assert_eq!(tree.len(), 3 * CHUNK_SIZE);
+        let leaf_hash = ring::digest::digest(&SHA256, &[1; CHUNK_SIZE]);
+        assert_eq!(&tree[CHUNK_SIZE..CHUNK_SIZE + HASH_SIZE], leaf_hash.as_ref());
+    }
+}
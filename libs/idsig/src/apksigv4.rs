@@ -18,7 +18,13 @@ use anyhow::{anyhow, bail, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, RSA_PKCS1_SHA256,
+};
+use std::fs::File;
 use std::io::{copy, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
 use crate::hashtree::*;
 
@@ -114,7 +120,7 @@ impl Default for HashAlgorithm {
 }
 
 /// Signature algorithm that can be used for idsig file
-#[derive(Debug, PartialEq, FromPrimitive, ToPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
 #[allow(non_camel_case_types)]
 #[repr(u32)]
 pub enum SignatureAlgorithmId {
@@ -147,9 +153,31 @@ impl Default for SignatureAlgorithmId {
     }
 }
 
+/// `V4SigningKey` holds what is needed to sign an idsig file.
+pub struct V4SigningKey<'a> {
+    /// Private key of the signer in PKCS#8 form.
+    pub private_key_pkcs8: &'a [u8],
+    /// Certificate of the signer. ASN.1 DER form.
+    pub x509_certificate: &'a [u8],
+    /// Signature algorithm to sign with. Only `RSASSA_PKCS1_SHA2_256` and `ECDSA_SHA2_256` are
+    /// supported.
+    pub signature_algorithm_id: SignatureAlgorithmId,
+    /// Digest of the APK, taken from its v2/v3 signature.
+    pub apk_digest: &'a [u8],
+}
+
+impl V4Signature<File> {
+    /// Creates a `V4Signature` struct from the given idsig path.
+    pub fn from_idsig_path<P: AsRef<Path>>(idsig_path: P) -> Result<Self> {
+        let idsig = File::open(idsig_path.as_ref())
+            .with_context(|| format!("Failed to open idsig file {:?}", idsig_path.as_ref()))?;
+        Self::from_idsig(idsig)
+    }
+}
+
 impl<R: Read + Seek> V4Signature<R> {
     /// Consumes a stream for an idsig file into a `V4Signature` struct.
-    pub fn from(mut r: R) -> Result<V4Signature<R>> {
+    pub fn from_idsig(mut r: R) -> Result<V4Signature<R>> {
         Ok(V4Signature {
             version: Version::from(r.read_u32::<LittleEndian>()?)?,
             hashing_info: HashingInfo::from(&mut r)?,
@@ -170,7 +198,7 @@ impl<R: Read + Seek> V4Signature<R> {
     ) -> Result<V4Signature<Cursor<Vec<u8>>>> {
         // Determine the size of the apk
         let start = apk.stream_position()?;
-        let size = apk.seek(SeekFrom::End(0))? as usize;
+        let size = (apk.seek(SeekFrom::End(0))? - start) as usize;
         apk.seek(SeekFrom::Start(start))?;
 
         // Create hash tree (and root hash)
@@ -187,6 +215,7 @@ impl<R: Read + Seek> V4Signature<R> {
             merkle_tree_offset: 0, // merkle tree starts from the beginning of `data`
             data: Cursor::new(hash_tree.tree),
         };
+        ret.hashing_info.salt = salt.to_vec().into_boxed_slice();
         ret.hashing_info.raw_root_hash = hash_tree.root_hash.into_boxed_slice();
         ret.hashing_info.log2_blocksize = log2(block_size);
 
@@ -197,6 +226,57 @@ impl<R: Read + Seek> V4Signature<R> {
         Ok(ret)
     }
 
+    /// Same as `create`, but also signs the result with `key` so that the complete idsig file can
+    /// be written with `write_into`.
+    pub fn create_signed(
+        apk: &mut R,
+        block_size: usize,
+        salt: &[u8],
+        algorithm: HashAlgorithm,
+        key: &V4SigningKey,
+    ) -> Result<V4Signature<Cursor<Vec<u8>>>> {
+        let start = apk.stream_position()?;
+        let file_size = apk.seek(SeekFrom::End(0))? - start;
+        apk.seek(SeekFrom::Start(start))?;
+
+        let mut ret = Self::create(apk, block_size, salt, algorithm)?;
+        ret.signing_info.apk_digest = key.apk_digest.to_vec().into_boxed_slice();
+        ret.signing_info.x509_certificate = key.x509_certificate.to_vec().into_boxed_slice();
+
+        let signed_data = signed_data(file_size, &ret.hashing_info, &ret.signing_info)?;
+        let rng = SystemRandom::new();
+        let (public_key, signature) = match key.signature_algorithm_id {
+            SignatureAlgorithmId::RSASSA_PKCS1_SHA2_256 => {
+                let key_pair = RsaKeyPair::from_pkcs8(key.private_key_pkcs8)
+                    .map_err(|e| anyhow!("Invalid RSA private key: {}", e))?;
+                let mut signature = vec![0; key_pair.public_modulus_len()];
+                key_pair
+                    .sign(&RSA_PKCS1_SHA256, &rng, &signed_data, &mut signature)
+                    .map_err(|_| anyhow!("Failed to sign with RSA key"))?;
+                (rsa_subject_public_key_info(key_pair.public_key().as_ref()), signature)
+            }
+            SignatureAlgorithmId::ECDSA_SHA2_256 => {
+                let key_pair = EcdsaKeyPair::from_pkcs8(
+                    &ECDSA_P256_SHA256_ASN1_SIGNING,
+                    key.private_key_pkcs8,
+                )
+                .map_err(|e| anyhow!("Invalid EC private key: {}", e))?;
+                let signature = key_pair
+                    .sign(&rng, &signed_data)
+                    .map_err(|_| anyhow!("Failed to sign with EC key"))?;
+                (
+                    ec_p256_subject_public_key_info(key_pair.public_key().as_ref()),
+                    signature.as_ref().to_vec(),
+                )
+            }
+            id => bail!("Signing with {:?} is not supported", id),
+        };
+        ret.signing_info.public_key = public_key.into_boxed_slice();
+        ret.signing_info.signature_algorithm_id = key.signature_algorithm_id;
+        ret.signing_info.signature = signature.into_boxed_slice();
+        Ok(ret)
+    }
+
     /// Writes the data into a writer
     pub fn write_into<W: Write + Seek>(&mut self, mut w: &mut W) -> Result<()> {
         // Writes the header part
@@ -301,6 +381,68 @@ impl SigningInfo {
     }
 }
 
+/// Returns the data which the signature in `SigningInfo` is computed over.
+fn signed_data(
+    file_size: u64,
+    hashing_info: &HashingInfo,
+    signing_info: &SigningInfo,
+) -> Result<Vec<u8>> {
+    let mut w = Cursor::new(Vec::new());
+    // Size of the signed data. Will be filled below.
+    w.write_u32::<LittleEndian>(0)?;
+    w.write_u64::<LittleEndian>(file_size)?;
+    w.write_u32::<LittleEndian>(hashing_info.hash_algorithm.to_u32().unwrap())?;
+    w.write_u8(hashing_info.log2_blocksize)?;
+    write_sized_array(&mut w, &hashing_info.salt)?;
+    write_sized_array(&mut w, &hashing_info.raw_root_hash)?;
+    write_sized_array(&mut w, &signing_info.apk_digest)?;
+    write_sized_array(&mut w, &signing_info.x509_certificate)?;
+    write_sized_array(&mut w, &signing_info.additional_data)?;
+
+    let mut data = w.into_inner();
+    let size = data.len() as u32;
+    data[..4].copy_from_slice(&size.to_le_bytes());
+    Ok(data)
+}
+
+/// DER encoding of AlgorithmIdentifier { rsaEncryption, NULL }
+const RSA_ALGORITHM_IDENTIFIER: &[u8] =
+    &[0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00];
+/// DER encoding of AlgorithmIdentifier { id-ecPublicKey, prime256v1 }
+const EC_P256_ALGORITHM_IDENTIFIER: &[u8] = &[
+    0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48,
+    0xce, 0x3d, 0x03, 0x01, 0x07,
+];
+
+fn rsa_subject_public_key_info(rsa_public_key: &[u8]) -> Vec<u8> {
+    subject_public_key_info(RSA_ALGORITHM_IDENTIFIER, rsa_public_key)
+}
+
+fn ec_p256_subject_public_key_info(ec_point: &[u8]) -> Vec<u8> {
+    subject_public_key_info(EC_P256_ALGORITHM_IDENTIFIER, ec_point)
+}
+
+/// Encodes SubjectPublicKeyInfo ::= SEQUENCE { algorithm, BIT STRING subjectPublicKey }
+fn subject_public_key_info(algorithm: &[u8], public_key: &[u8]) -> Vec<u8> {
+    let bit_string = der_tlv(0x03, &[&[0x00], public_key].concat());
+    der_tlv(0x30, &[algorithm, &bit_string].concat())
+}
+
+fn der_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes = len.to_be_bytes();
+        let len_bytes = &len_bytes[len_bytes.iter().position(|&b| b != 0).unwrap()..];
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend_from_slice(len_bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
 fn read_sized_array(r: &mut dyn Read) -> Result<Box<[u8]>> {
     let size = r.read_u32::<LittleEndian>()?;
     let mut data = vec![0; size as usize];
@@ -330,7 +472,7 @@ mod tests {
     #[test]
     fn parse_idsig_file() {
         let idsig = Cursor::new(include_bytes!("../testdata/test.apk.idsig"));
-        let parsed = V4Signature::from(idsig).unwrap();
+        let parsed = V4Signature::from_idsig(idsig).unwrap();
 
         assert_eq!(Version::V2, parsed.version);
 
@@ -360,12 +502,25 @@ mod tests {
         assert_eq!(2251, parsed.merkle_tree_offset);
     }
 
+    #[test]
+    fn parse_idsig_path() {
+        let parsed = V4Signature::from_idsig_path("testdata/test.apk.idsig").unwrap();
+        let golden =
+            V4Signature::from_idsig(Cursor::new(include_bytes!("../testdata/test.apk.idsig")))
+                .unwrap();
+
+        assert_eq!(golden.hashing_info.raw_root_hash, parsed.hashing_info.raw_root_hash);
+        assert_eq!(golden.signing_info.apk_digest, parsed.signing_info.apk_digest);
+        assert_eq!(golden.merkle_tree_size, parsed.merkle_tree_size);
+        assert_eq!(golden.merkle_tree_offset, parsed.merkle_tree_offset);
+    }
+
     /// Parse an idsig file into V4Signature and write it. The written date must be the same as
     /// the input file.
     #[test]
     fn parse_and_compose() {
         let input = Cursor::new(include_bytes!("../testdata/test.apk.idsig"));
-        let mut parsed = V4Signature::from(input.clone()).unwrap();
+        let mut parsed = V4Signature::from_idsig(input.clone()).unwrap();
 
         let mut output = Cursor::new(Vec::new());
         parsed.write_into(&mut output).unwrap();
@@ -382,7 +537,7 @@ mod tests {
             V4Signature::create(&mut input, 4096, &[], HashAlgorithm::SHA256).unwrap();
 
         let golden = Cursor::new(include_bytes!("../testdata/test.apk.idsig"));
-        let mut golden = V4Signature::from(golden).unwrap();
+        let mut golden = V4Signature::from_idsig(golden).unwrap();
 
         // Compare the root hash
         assert_eq!(
@@ -396,4 +551,93 @@ mod tests {
             golden.merkle_tree().unwrap().as_slice()
         );
     }
+
+    /// Create V4Signatures with a salt, with and without signing. Both must record the salt that
+    /// the merkle tree was built with, so that the tree can be verified from the idsig file.
+    #[test]
+    fn digest_from_apk_with_salt() {
+        let apk = include_bytes!("../testdata/test.apk");
+        let salt = [1, 2, 3, 4, 5, 6];
+        let mut created =
+            V4Signature::create(&mut Cursor::new(apk), 4096, &salt, HashAlgorithm::SHA256).unwrap();
+        assert_eq!(&salt, created.hashing_info.salt.as_ref());
+
+        let expected = HashTree::from(
+            &mut Cursor::new(apk),
+            apk.len(),
+            &salt,
+            4096,
+            &ring::digest::SHA256,
+        )
+        .unwrap();
+        assert_eq!(expected.root_hash.as_slice(), created.hashing_info.raw_root_hash.as_ref());
+        assert_eq!(expected.tree, created.merkle_tree().unwrap());
+
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = V4SigningKey {
+            private_key_pkcs8: pkcs8.as_ref(),
+            x509_certificate: b"certificate",
+            signature_algorithm_id: SignatureAlgorithmId::ECDSA_SHA2_256,
+            apk_digest: &[0xaa; 32],
+        };
+        let mut signed = V4Signature::create_signed(
+            &mut Cursor::new(apk),
+            4096,
+            &salt,
+            HashAlgorithm::SHA256,
+            &key,
+        )
+        .unwrap();
+        assert_eq!(created.hashing_info.salt, signed.hashing_info.salt);
+        assert_eq!(created.hashing_info.raw_root_hash, signed.hashing_info.raw_root_hash);
+        assert_eq!(created.merkle_tree().unwrap(), signed.merkle_tree().unwrap());
+    }
+
+    /// Create a signed V4Signature and check that its signature verifies over the signed data,
+    /// and that it survives a round trip through the idsig file format.
+    #[test]
+    fn create_signed_idsig() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = V4SigningKey {
+            private_key_pkcs8: pkcs8.as_ref(),
+            x509_certificate: b"certificate",
+            signature_algorithm_id: SignatureAlgorithmId::ECDSA_SHA2_256,
+            apk_digest: &[0xaa; 32],
+        };
+        let apk = include_bytes!("../testdata/test.apk");
+        let mut created = V4Signature::create_signed(
+            &mut Cursor::new(apk),
+            4096,
+            &[],
+            HashAlgorithm::SHA256,
+            &key,
+        )
+        .unwrap();
+
+        let mut output = Cursor::new(Vec::new());
+        created.write_into(&mut output).unwrap();
+        output.set_position(0);
+        let parsed = V4Signature::from_idsig(output).unwrap();
+
+        let si = &parsed.signing_info;
+        assert_eq!(SignatureAlgorithmId::ECDSA_SHA2_256, si.signature_algorithm_id);
+        assert_eq!(key.apk_digest, si.apk_digest.as_ref());
+        assert_eq!(key.x509_certificate, si.x509_certificate.as_ref());
+        assert_eq!(created.hashing_info.raw_root_hash, parsed.hashing_info.raw_root_hash);
+
+        let signed_data = signed_data(apk.len() as u64, &parsed.hashing_info, si).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        assert_eq!(
+            ec_p256_subject_public_key_info(key_pair.public_key().as_ref()),
+            si.public_key.as_ref()
+        );
+        let public_key = ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_ASN1,
+            key_pair.public_key().as_ref(),
+        );
+        assert!(public_key.verify(&signed_data, &si.signature).is_ok());
+    }
 }
@@ -15,8 +15,13 @@
 //! Struct for VM configuration with JSON (de)serialization and AIDL parcelables
 
 use android_system_virtualizationservice::{
+    aidl::android::system::virtualizationservice::AudioConfig::AudioConfig as AidlAudioConfig,
     aidl::android::system::virtualizationservice::DiskImage::DiskImage as AidlDiskImage,
+    aidl::android::system::virtualizationservice::GpuBackend::GpuBackend as AidlGpuBackend,
     aidl::android::system::virtualizationservice::Partition::Partition as AidlPartition,
+    aidl::android::system::virtualizationservice::PortForwarding::PortForwarding as AidlPortForwarding,
+    aidl::android::system::virtualizationservice::SandboxProfile::SandboxProfile,
+    aidl::android::system::virtualizationservice::SharedPath::SharedPath as AidlSharedPath,
     aidl::android::system::virtualizationservice::VirtualMachineRawConfig::VirtualMachineRawConfig,
     binder::ParcelFileDescriptor,
 };
@@ -27,7 +32,7 @@ use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::io::BufReader;
-use std::num::NonZeroU32;
+use std::num::{NonZeroU16, NonZeroU32};
 use std::path::{Path, PathBuf};
 
 /// Configuration for a particular VM to be started.
@@ -52,6 +57,36 @@ pub struct VmConfig {
     /// The amount of RAM to give the VM, in MiB.
     #[serde(default)]
     pub memory_mib: Option<NonZeroU32>,
+    /// The host CPUs which the vCPUs may run on, either as a comma-separated list of CPUs or CPU
+    /// ranges (e.g. "0,1-3,5"), or as a colon-separated list of vCPU to host CPU assignments (e.g.
+    /// "0=0:1=1:2=2"). If not set, the vCPUs may run on any host CPU.
+    #[serde(default)]
+    pub cpu_affinity: Option<String>,
+    /// Task profiles (see `task_profiles.json`) to apply to the crosvm process, e.g. to restrict
+    /// it to the little cores.
+    #[serde(default)]
+    pub task_profiles: Vec<String>,
+    /// Names of extra virtio-console ports, which appear in the guest as /dev/hvc3 onwards.
+    #[serde(default)]
+    pub console_ports: Vec<String>,
+    /// TCP ports on the host's loopback interface to forward to vsock ports of the VM.
+    #[serde(default)]
+    pub port_forwardings: Vec<PortForwarding>,
+    /// The backend of the virtio-gpu device to give the VM, if any.
+    #[serde(default)]
+    pub gpu: Option<GpuBackend>,
+    /// Whether to run crosvm in its sandbox. Ignored if `gdb_port` is set.
+    #[serde(default)]
+    pub strict_sandbox: bool,
+    /// The TCP port on which crosvm should serve a GDB stub, if any.
+    #[serde(default)]
+    pub gdb_port: Option<NonZeroU16>,
+    /// Host directories to share with the VM through virtio-fs.
+    #[serde(default)]
+    pub shared_paths: Vec<SharedPath>,
+    /// The virtio-snd device to give the VM, if any.
+    #[serde(default)]
+    pub audio: Option<AudioConfig>,
     /// Version or range of versions of the virtual platform that this config is compatible with.
     /// The format follows SemVer (https://semver.org).
     pub platform_version: VersionReq,
@@ -99,6 +134,29 @@ impl VmConfig {
             disks: self.disks.iter().map(DiskImage::to_parcelable).collect::<Result<_, Error>>()?,
             protectedVm: self.protected,
             memoryMib: memory_mib,
+            cpuAffinity: self.cpu_affinity.clone(),
+            taskProfiles: self.task_profiles.clone(),
+            consolePorts: self.console_ports.clone(),
+            portForwardings: self
+                .port_forwardings
+                .iter()
+                .map(PortForwarding::to_parcelable)
+                .collect(),
+            gpuBackend: self.gpu.map_or(AidlGpuBackend::NONE, GpuBackend::to_parcelable),
+            sandboxProfile: if self.gdb_port.is_some() {
+                SandboxProfile::DEBUG_GDB
+            } else if self.strict_sandbox {
+                SandboxProfile::STRICT
+            } else {
+                SandboxProfile::DEFAULT
+            },
+            gdbPort: self.gdb_port.map_or(0, |port| port.get().into()),
+            sharedPaths: self
+                .shared_paths
+                .iter()
+                .map(SharedPath::to_parcelable)
+                .collect::<Result<_>>()?,
+            audioConfig: self.audio.as_ref().map(AudioConfig::to_parcelable),
             platformVersion: self.platform_version.to_string(),
             ..Default::default()
         })
@@ -153,6 +211,87 @@ impl Partition {
     }
 }
 
+/// The backend of a virtio-gpu device.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GpuBackend {
+    /// 2D rendering in software on the host.
+    #[serde(rename = "2d")]
+    TwoD,
+    /// 3D rendering with virglrenderer.
+    Virgl,
+    /// 3D rendering with gfxstream.
+    Gfxstream,
+}
+
+impl GpuBackend {
+    fn to_parcelable(self) -> AidlGpuBackend {
+        match self {
+            GpuBackend::TwoD => AidlGpuBackend::TWO_D,
+            GpuBackend::Virgl => AidlGpuBackend::VIRGL,
+            GpuBackend::Gfxstream => AidlGpuBackend::GFXSTREAM,
+        }
+    }
+}
+
+/// A rule to forward TCP connections on the host to a vsock port of the VM.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PortForwarding {
+    /// The TCP port to listen on.
+    pub host_port: u16,
+    /// The vsock port of the VM to forward the connections to.
+    pub guest_port: u32,
+}
+
+impl PortForwarding {
+    fn to_parcelable(&self) -> AidlPortForwarding {
+        AidlPortForwarding { hostPort: self.host_port.into(), guestPort: self.guest_port as i32 }
+    }
+}
+
+/// A virtio-snd device connected to the host's audio HAL.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AudioConfig {
+    /// Whether the VM can capture audio from the host's microphone.
+    #[serde(default)]
+    pub use_microphone: bool,
+    /// Whether the VM can play audio through the host's speaker.
+    #[serde(default)]
+    pub use_speaker: bool,
+}
+
+impl AudioConfig {
+    fn to_parcelable(&self) -> AidlAudioConfig {
+        AidlAudioConfig { useMicrophone: self.use_microphone, useSpeaker: self.use_speaker }
+    }
+}
+
+/// A host directory to share with the VM through virtio-fs.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SharedPath {
+    /// The directory on the host.
+    pub path: PathBuf,
+    /// The tag which the guest uses to mount the directory.
+    pub tag: String,
+    /// The UID in the guest which owns the files which crosvm's user owns on the host.
+    #[serde(default)]
+    pub guest_uid: u32,
+    /// The GID in the guest which owns the files which crosvm's group owns on the host.
+    #[serde(default)]
+    pub guest_gid: u32,
+}
+
+impl SharedPath {
+    fn to_parcelable(&self) -> Result<AidlSharedPath> {
+        Ok(AidlSharedPath {
+            directory: Some(open_parcel_file(&self.path, false)?),
+            tag: self.tag.clone(),
+            guestUid: self.guest_uid.try_into().context("Invalid guest_uid")?,
+            guestGid: self.guest_gid.try_into().context("Invalid guest_gid")?,
+        })
+    }
+}
+
 /// Try to open the given file and wrap it in a [`ParcelFileDescriptor`].
 pub fn open_parcel_file(filename: &Path, writable: bool) -> Result<ParcelFileDescriptor> {
     Ok(ParcelFileDescriptor::new(
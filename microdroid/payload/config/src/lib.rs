@@ -15,6 +15,10 @@
 //! VM Payload Config
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Component, Path};
+use thiserror::Error;
 
 /// VM payload config
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -44,6 +48,104 @@ pub struct VmPayloadConfig {
     pub export_tombstones: bool,
 }
 
+/// Errors found while loading a VM payload config.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum PayloadConfigError {
+    /// The config isn't well-formed JSON, or doesn't have the expected shape.
+    #[error("line {line}, column {column}: {message}")]
+    Syntax {
+        /// The line of the config at which the error was found, starting from 1.
+        line: usize,
+        /// The column of the config at which the error was found, starting from 1.
+        column: usize,
+        /// What is wrong.
+        message: String,
+    },
+    /// A value in the config is not allowed.
+    #[error("{pointer}: {message}")]
+    InvalidValue {
+        /// The JSON pointer (RFC 6901) to the offending value, e.g. `/task/command`.
+        pointer: String,
+        /// What is wrong.
+        message: String,
+    },
+}
+
+fn invalid(pointer: String, message: impl Into<String>) -> PayloadConfigError {
+    PayloadConfigError::InvalidValue { pointer, message: message.into() }
+}
+
+impl VmPayloadConfig {
+    /// Parses a VM payload config from JSON and validates it.
+    pub fn load<R: Read>(reader: R) -> Result<Self, PayloadConfigError> {
+        let config: Self = serde_json::from_reader(reader).map_err(|e| {
+            // serde_json appends the location to the message, which is reported separately.
+            let message = e.to_string();
+            let suffix = format!(" at line {} column {}", e.line(), e.column());
+            PayloadConfigError::Syntax {
+                line: e.line(),
+                column: e.column(),
+                message: message.strip_suffix(&suffix).unwrap_or(&message).to_owned(),
+            }
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the values in the config which are well-formed but can't be used to run a VM.
+    pub fn validate(&self) -> Result<(), PayloadConfigError> {
+        // For now, the only supported OS is "microdroid".
+        if self.os.name != "microdroid" {
+            return Err(invalid("/os/name".to_owned(), format!("Unknown OS {:?}", self.os.name)));
+        }
+
+        if let Some(task) = &self.task {
+            task.validate("/task")?;
+        }
+
+        let mut apex_names = HashSet::new();
+        for (i, apex) in self.apexes.iter().enumerate() {
+            let pointer = format!("/apexes/{}/name", i);
+            if apex.name.is_empty() || apex.name.contains('/') {
+                return Err(invalid(pointer, format!("Invalid APEX name {:?}", apex.name)));
+            }
+            if !apex_names.insert(&apex.name) {
+                return Err(invalid(pointer, format!("Duplicate APEX {:?}", apex.name)));
+            }
+        }
+
+        let mut mount_points = HashSet::new();
+        for (i, apk) in self.extra_apks.iter().enumerate() {
+            if !Path::new(&apk.path).is_absolute() {
+                return Err(invalid(
+                    format!("/extra_apks/{}/path", i),
+                    format!("Extra APK path {:?} isn't absolute", apk.path),
+                ));
+            }
+            let pointer = format!("/extra_apks/{}/mount_point", i);
+            let mount_point = apk.mount_point_or_default(i);
+            // Extra APKs can only be mounted directly under EXTRA_APK_MOUNT_DIRECTORY, where the
+            // SELinux policy allows it.
+            let name = Path::new(&mount_point).strip_prefix(EXTRA_APK_MOUNT_DIRECTORY).ok();
+            let components = name.map(|name| name.components().collect::<Vec<_>>());
+            if !matches!(components.as_deref(), Some([Component::Normal(_)])) {
+                return Err(invalid(
+                    pointer,
+                    format!(
+                        "Mount point {:?} isn't directly under {}",
+                        mount_point, EXTRA_APK_MOUNT_DIRECTORY
+                    ),
+                ));
+            }
+            if !mount_points.insert(mount_point.clone()) {
+                return Err(invalid(pointer, format!("Duplicate mount point {:?}", mount_point)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// OS config
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct OsConfig {
@@ -84,6 +186,78 @@ pub struct Task {
     /// Args to the command
     #[serde(default)]
     pub args: Vec<String>,
+
+    /// Limits on the resources the task can use. Default: no limits
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+}
+
+/// Limits on the resources a task can use, which are enforced with a cgroup in the VM so that the
+/// task can't starve the rest of the VM. Limits which aren't set aren't enforced.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ResourceLimits {
+    /// Maximum amount of memory the task can use, in MiB. The task is OOM-killed above it.
+    #[serde(default)]
+    pub memory_mib: Option<u32>,
+
+    /// Maximum CPU bandwidth of the task, in percent of a CPU. E.g. 150 means 1.5 CPUs.
+    #[serde(default)]
+    pub cpu_percent: Option<u32>,
+
+    /// Maximum number of processes and threads the task can have at once.
+    #[serde(default)]
+    pub pids: Option<u32>,
+}
+
+impl ResourceLimits {
+    /// Returns whether any limit is set.
+    pub fn is_empty(&self) -> bool {
+        self.memory_mib.is_none() && self.cpu_percent.is_none() && self.pids.is_none()
+    }
+
+    fn validate(&self, pointer: &str) -> Result<(), PayloadConfigError> {
+        let limits = [
+            ("memory_mib", self.memory_mib),
+            ("cpu_percent", self.cpu_percent),
+            ("pids", self.pids),
+        ];
+        for (name, limit) in limits {
+            if limit == Some(0) {
+                return Err(invalid(format!("{}/{}", pointer, name), "Limit must be positive"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Task {
+    fn validate(&self, pointer: &str) -> Result<(), PayloadConfigError> {
+        let command_pointer = format!("{}/command", pointer);
+        if self.command.is_empty() {
+            return Err(invalid(command_pointer, "Command is empty"));
+        }
+        match self.type_ {
+            TaskType::Executable if !Path::new(&self.command).is_absolute() => {
+                return Err(invalid(
+                    command_pointer,
+                    format!("Executable {:?} isn't an absolute path", self.command),
+                ));
+            }
+            TaskType::MicrodroidLauncher if self.command.contains('/') => {
+                return Err(invalid(
+                    command_pointer,
+                    format!("Library {:?} must be a file name, not a path", self.command),
+                ));
+            }
+            _ => {}
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            if arg.contains('\0') {
+                return Err(invalid(format!("{}/args/{}", pointer, i), "Argument contains NUL"));
+            }
+        }
+        self.resource_limits.validate(&format!("{}/resource_limits", pointer))
+    }
 }
 
 impl Default for TaskType {
@@ -100,9 +274,91 @@ pub struct ApexConfig {
     pub name: String,
 }
 
+/// The directory under which extra APKs are mounted in a VM
+pub const EXTRA_APK_MOUNT_DIRECTORY: &str = "/mnt/extra-apk";
+
 /// APK config
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct ApkConfig {
     /// The path of APK
     pub path: String,
+
+    /// Where to mount the APK in a VM. This must be a directory directly under
+    /// EXTRA_APK_MOUNT_DIRECTORY. Default: EXTRA_APK_MOUNT_DIRECTORY/{index of the APK}
+    #[serde(default)]
+    pub mount_point: Option<String>,
+
+    /// Whether the APK must exist. If an APK which isn't required doesn't exist, it is left out of
+    /// the VM and nothing is mounted at its mount point. Default: true
+    #[serde(default = "default_true")]
+    pub required: bool,
+}
+
+impl ApkConfig {
+    /// Returns where to mount the APK, given its index in `extra_apks`.
+    pub fn mount_point_or_default(&self, index: usize) -> String {
+        match &self.mount_point {
+            Some(mount_point) => mount_point.clone(),
+            None => format!("{}/{}", EXTRA_APK_MOUNT_DIRECTORY, index),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_valid_config() {
+        let config = VmPayloadConfig::load(
+            r#"{
+                "task": { "type": "microdroid_launcher", "command": "Lib.so" },
+                "apexes": [ { "name": "com.android.art" } ],
+                "extra_apks": [ { "path": "/system/etc/extra.apk" } ],
+                "export_tombstones": false
+            }"#
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(config.extra_apks[0].mount_point_or_default(0), "/mnt/extra-apk/0");
+    }
+
+    #[test]
+    fn syntax_error_has_location() {
+        let err = VmPayloadConfig::load("{\n  \"task\": 1\n}".as_bytes()).unwrap_err();
+        assert!(matches!(err, PayloadConfigError::Syntax { line: 2, .. }), "{}", err);
+    }
+
+    #[test]
+    fn invalid_value_has_pointer() {
+        let cases = [
+            (r#""os": { "name": "other" }"#, "/os/name"),
+            (r#""task": { "command": "bin/foo" }"#, "/task/command"),
+            (
+                r#""task": { "type": "microdroid_launcher", "command": "lib/a.so" }"#,
+                "/task/command",
+            ),
+            (
+                r#""task": { "command": "/bin/foo", "resource_limits": { "pids": 0 } }"#,
+                "/task/resource_limits/pids",
+            ),
+            (r#""apexes": [ { "name": "a" }, { "name": "a" } ]"#, "/apexes/1/name"),
+            (r#""extra_apks": [ { "path": "a.apk" } ]"#, "/extra_apks/0/path"),
+            (
+                r#""extra_apks": [ { "path": "/a.apk", "mount_point": "/mnt/extra-apk/a/b" } ]"#,
+                "/extra_apks/0/mount_point",
+            ),
+        ];
+        for (fields, pointer) in cases {
+            let json = format!(r#"{{ {}, "export_tombstones": false }}"#, fields);
+            match VmPayloadConfig::load(json.as_bytes()) {
+                Err(PayloadConfigError::InvalidValue { pointer: p, .. }) => assert_eq!(p, pointer),
+                other => panic!("Unexpected result for {}: {:?}", json, other),
+            }
+        }
+    }
 }
@@ -0,0 +1,47 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Timestamps of the phases of the boot of the VM, for reporting to the host.
+
+use anyhow::{Context, Result};
+use log::warn;
+use nix::time::{clock_gettime, ClockId};
+use rustutils::system_properties;
+
+/// The property which init sets to when it started, in nanoseconds since the kernel started.
+const INIT_START_PROP: &str = "ro.boottime.init";
+
+/// Returns the time since the kernel started in milliseconds, or -1 if it can't be read.
+pub fn now_millis() -> i64 {
+    clock_gettime(ClockId::CLOCK_BOOTTIME)
+        .map(|now| now.tv_sec() * 1000 + now.tv_nsec() / 1_000_000)
+        .unwrap_or_else(|e| {
+            warn!("Failed to read CLOCK_BOOTTIME: {}", e);
+            -1
+        })
+}
+
+/// Returns when init started in milliseconds since the kernel started, or -1 if it isn't known.
+pub fn init_start_millis() -> i64 {
+    read_init_start_nanos().map(|nanos| nanos / 1_000_000).unwrap_or_else(|e| {
+        warn!("Failed to read when init started: {:?}", e);
+        -1
+    })
+}
+
+fn read_init_start_nanos() -> Result<i64> {
+    let value = system_properties::read(INIT_START_PROP)?
+        .with_context(|| format!("{} is not set", INIT_START_PROP))?;
+    value.parse().with_context(|| format!("Invalid {}: {:?}", INIT_START_PROP, value))
+}
@@ -0,0 +1,88 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Enforcement of the resource limits of the payload with a cgroup v2.
+
+use anyhow::{Context, Result};
+use log::info;
+use microdroid_payload_config::ResourceLimits;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const PAYLOAD_CGROUP: &str = "/sys/fs/cgroup/payload";
+
+/// The period over which `cpu.max` is enforced, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Makes the given command run in a cgroup which enforces the given limits, so that the payload
+/// can't starve microdroid_manager or the rest of the VM.
+pub fn limit_resources(command: &mut Command, limits: &ResourceLimits) -> Result<()> {
+    let mut controllers = vec![];
+    let mut settings = vec![];
+    if let Some(memory_mib) = limits.memory_mib {
+        controllers.push("+memory");
+        settings.push(("memory.max", (u64::from(memory_mib) << 20).to_string()));
+    }
+    if let Some(cpu_percent) = limits.cpu_percent {
+        controllers.push("+cpu");
+        let quota = u64::from(cpu_percent) * CPU_PERIOD_US / 100;
+        settings.push(("cpu.max", format!("{} {}", quota, CPU_PERIOD_US)));
+    }
+    if let Some(pids) = limits.pids {
+        controllers.push("+pids");
+        settings.push(("pids.max", pids.to_string()));
+    }
+
+    // The root cgroup is exempt from the rule that only leaf cgroups can have processes, so
+    // microdroid_manager itself can stay there.
+    write_cgroup_file(Path::new(CGROUP_ROOT), "cgroup.subtree_control", &controllers.join(" "))?;
+    if !Path::new(PAYLOAD_CGROUP).exists() {
+        fs::create_dir(PAYLOAD_CGROUP)
+            .with_context(|| format!("Failed to create {}", PAYLOAD_CGROUP))?;
+    }
+    for (file, value) in &settings {
+        write_cgroup_file(Path::new(PAYLOAD_CGROUP), file, value)?;
+    }
+
+    // The payload moves itself into the cgroup before it is executed, so that it can't fork any
+    // process outside of it.
+    let procs_path = Path::new(PAYLOAD_CGROUP).join("cgroup.procs");
+    let procs = OpenOptions::new()
+        .write(true)
+        .open(&procs_path)
+        .with_context(|| format!("Failed to open {:?}", procs_path))?;
+    // SAFETY: The closure only makes a write(2) call, which is async-signal-safe, on a file
+    // descriptor which it keeps open.
+    unsafe {
+        command.pre_exec(move || {
+            // Writing 0 moves the writing process.
+            if libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    info!("Limiting payload resources: {:?}", settings);
+    Ok(())
+}
+
+fn write_cgroup_file(cgroup: &Path, file: &str, value: &str) -> Result<()> {
+    let path = cgroup.join(file);
+    fs::write(&path, value).with_context(|| format!("Failed to write {:?} to {:?}", value, path))
+}
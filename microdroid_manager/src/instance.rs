@@ -42,6 +42,7 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
 use ring::hkdf::{Salt, HKDF_SHA256};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use uuid::Uuid;
@@ -319,9 +320,25 @@ fn get_key() -> Result<ZeroOnDropKey> {
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct MicrodroidData {
     pub salt: Vec<u8>, // Should be [u8; 64] but that isn't serializable.
-    pub apk_data: ApkData,
+    /// The main APK, which is `None` if the payload is an executable rather than an APK.
+    pub apk_data: Option<ApkData>,
     pub extra_apks_data: Vec<ApkData>,
     pub apex_data: Vec<ApexData>,
+    /// The executable which is the payload, if it isn't an APK.
+    #[serde(default)]
+    pub binary_data: Option<BinaryData>,
+    /// State which must only move forward across boots, to detect rollbacks.
+    #[serde(default)]
+    pub rollback: RollbackState,
+}
+
+/// State of the instance which must only move forward. Note that it is stored in the instance disk,
+/// so it doesn't protect against the whole instance disk being replaced with an older copy of
+/// itself.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct RollbackState {
+    /// Monotonic counters of the payload, by name.
+    pub counters: BTreeMap<String, u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -332,6 +349,17 @@ pub struct ApkData {
 
 pub type RootHash = [u8];
 
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct BinaryData {
+    pub path: String,
+    /// SHA-512 digest of the executable.
+    pub digest: Vec<u8>,
+    /// Arguments the executable is run with, which come from the host and so must not change
+    /// across boots.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct ApexData {
     pub name: String,
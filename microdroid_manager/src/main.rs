@@ -14,17 +14,22 @@
 
 //! Microdroid Manager
 
+mod boot_times;
+mod cgroup;
 mod instance;
 mod ioutil;
 mod payload;
+mod vm_payload_service;
 
-use crate::instance::{ApkData, InstanceDisk, MicrodroidData, RootHash};
+use crate::instance::{
+    ApexData, ApkData, BinaryData, InstanceDisk, MicrodroidData, RollbackState, RootHash,
+};
 use android_hardware_security_dice::aidl::android::hardware::security::dice::{
     Config::Config, InputValues::InputValues, Mode::Mode,
 };
 use android_security_dice::aidl::android::security::dice::IDiceMaintenance::IDiceMaintenance;
 use anyhow::{anyhow, bail, ensure, Context, Error, Result};
-use apkverify::{get_public_key_der, verify};
+use apkverify::{get_public_key_der, ApkVerificationError, VerifierBuilder};
 use binder::unstable_api::{new_spibinder, AIBinder};
 use binder::{wait_for_interface, FromIBinder, Strong};
 use diced_utils::cbor::encode_header;
@@ -32,22 +37,29 @@ use glob::glob;
 use idsig::V4Signature;
 use itertools::sorted;
 use log::{error, info};
-use microdroid_metadata::{write_metadata, Metadata};
-use microdroid_payload_config::{Task, TaskType, VmPayloadConfig};
+use microdroid_metadata::{write_metadata, BinaryPayload, Metadata};
+use microdroid_payload_config::{Task, TaskType, VmPayloadConfig, EXTRA_APK_MOUNT_DIRECTORY};
+use nix::mount::{mount, MsFlags};
 use payload::{get_apex_data_from_payload, load_metadata, to_metadata};
 use rand::Fill;
 use ring::digest;
 use rustutils::system_properties;
 use rustutils::system_properties::PropertyWatcher;
+use std::collections::HashSet;
 use std::convert::TryInto;
+use std::ffi::CString;
 use std::fs::{self, create_dir, File, OpenOptions};
+use std::io::{self, Read};
 use std::os::unix::io::{FromRawFd, IntoRawFd};
-use std::path::Path;
+use std::path::{Component, Path};
 use std::process::{Child, Command, Stdio};
 use std::str;
+use std::thread;
 use std::time::{Duration, SystemTime};
+use vm_payload_service::{PayloadProperties, RollbackCounters, VmPayloadService};
 use vsock::VsockStream;
 
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::BootTimes::BootTimes;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::{
     ERROR_PAYLOAD_CHANGED, ERROR_PAYLOAD_VERIFICATION_FAILED, ERROR_PAYLOAD_INVALID_CONFIG, ERROR_UNKNOWN, VM_BINDER_SERVICE_PORT, VM_STREAM_SERVICE_PORT, IVirtualMachineService,
 };
@@ -61,6 +73,18 @@ const EXTRA_IDSIG_PATH_PATTERN: &str = "/dev/block/by-name/extra-idsig-*";
 const DM_MOUNTED_APK_PATH: &str = "/dev/block/mapper/microdroid-apk";
 const APKDMVERITY_BIN: &str = "/system/bin/apkdmverity";
 const ZIPFUSE_BIN: &str = "/system/bin/zipfuse";
+const SHARED_DIRECTORY_MOUNT_POINT: &str = "/mnt/shared";
+const SWAP_PATH: &str = "/dev/block/by-name/vm-swap";
+const SWAP_DM_NAME: &str = "vm-swap";
+const SWAP_DM_PATH: &str = "/dev/block/mapper/vm-swap";
+const DMCTL_BIN: &str = "/system/bin/dmctl";
+const MKSWAP_BIN: &str = "/system/bin/mkswap";
+const SWAPON_BIN: &str = "/system/bin/swapon";
+/// Description of the key of the swap in the session keyring. Logon keys need a "<prefix>:".
+const SWAP_KEY_DESCRIPTION: &str = "microdroid:vm-swap";
+// From include/uapi/linux/keyctl.h, which libc doesn't cover.
+const KEY_SPEC_SESSION_KEYRING: i32 = -3;
+const KEYCTL_INVALIDATE: i32 = 21;
 const AVF_STRICT_BOOT: &str = "/sys/firmware/devicetree/base/chosen/avf,strict-boot";
 const AVF_NEW_INSTANCE: &str = "/sys/firmware/devicetree/base/chosen/avf,new-instance";
 
@@ -70,6 +94,8 @@ const VMADDR_CID_HOST: u32 = 2;
 const APEX_CONFIG_DONE_PROP: &str = "apex_config.done";
 const LOGD_ENABLED_PROP: &str = "ro.boot.logd.enabled";
 const APP_DEBUGGABLE_PROP: &str = "ro.boot.microdroid.app_debuggable";
+const DEBUGGABLE_PROP: &str = "ro.boot.microdroid.debuggable";
+const SDK_VERSION_PROP: &str = "ro.build.version.sdk";
 
 #[derive(thiserror::Error, Debug)]
 enum MicrodroidError {
@@ -86,7 +112,12 @@ fn translate_error(err: &Error) -> (i32, String) {
         match e {
             MicrodroidError::PayloadChanged(msg) => (ERROR_PAYLOAD_CHANGED, msg.to_string()),
             MicrodroidError::PayloadVerificationFailed(msg) => {
-                (ERROR_PAYLOAD_VERIFICATION_FAILED, msg.to_string())
+                match err.root_cause().downcast_ref::<ApkVerificationError>() {
+                    Some(cause) => {
+                        (ERROR_PAYLOAD_VERIFICATION_FAILED, format!("{}: {}", msg, cause))
+                    }
+                    None => (ERROR_PAYLOAD_VERIFICATION_FAILED, msg.to_string()),
+                }
             }
             MicrodroidError::InvalidConfig(msg) => (ERROR_PAYLOAD_INVALID_CONFIG, msg.to_string()),
         }
@@ -150,8 +181,14 @@ fn dice_derivation(verified_data: &MicrodroidData, payload_config_path: &str) ->
     // Calculate compound digests of code and authorities
     let mut code_hash_ctx = digest::Context::new(&digest::SHA512);
     let mut authority_hash_ctx = digest::Context::new(&digest::SHA512);
-    code_hash_ctx.update(verified_data.apk_data.root_hash.as_ref());
-    authority_hash_ctx.update(verified_data.apk_data.pubkey.as_ref());
+    if let Some(apk_data) = &verified_data.apk_data {
+        code_hash_ctx.update(apk_data.root_hash.as_ref());
+        authority_hash_ctx.update(apk_data.pubkey.as_ref());
+    }
+    // An executable payload is signed by the APEX which contains it, whose key is covered below.
+    if let Some(binary_data) = &verified_data.binary_data {
+        code_hash_ctx.update(binary_data.digest.as_ref());
+    }
     for extra_apk in &verified_data.extra_apks_data {
         code_hash_ctx.update(extra_apk.root_hash.as_ref());
         authority_hash_ctx.update(extra_apk.pubkey.as_ref());
@@ -204,6 +241,8 @@ fn is_new_instance() -> bool {
 }
 
 fn try_run_payload(service: &Strong<dyn IVirtualMachineService>) -> Result<i32> {
+    let mut boot_times =
+        BootTimes { initStartMillis: boot_times::init_start_millis(), ..Default::default() };
     let metadata = load_metadata().context("Failed to load payload metadata")?;
 
     let mut instance = InstanceDisk::new().context("Failed to load instance.img")?;
@@ -225,8 +264,8 @@ fn try_run_payload(service: &Strong<dyn IVirtualMachineService>) -> Result<i32>
     }
 
     // Verify the payload before using it.
-    let verified_data =
-        verify_payload(&metadata, saved_data.as_ref()).context("Payload verification failed")?;
+    let verified_data = verify_payload(&metadata, saved_data.as_ref(), &mut boot_times)
+        .context("Payload verification failed")?;
     if let Some(saved_data) = saved_data {
         ensure!(
             saved_data == verified_data,
@@ -242,22 +281,28 @@ fn try_run_payload(service: &Strong<dyn IVirtualMachineService>) -> Result<i32>
 
     // To minimize the exposure to untrusted data, derive dice profile as soon as possible.
     info!("DICE derivation for payload");
-    dice_derivation(&verified_data, &metadata.payload_config_path)?;
+    let binary = metadata.binary.as_ref();
+    let config_desc_path = binary.map_or(&metadata.payload_config_path, |binary| &binary.path);
+    dice_derivation(&verified_data, config_desc_path)?;
 
-    // Before reading a file from the APK, start zipfuse
-    run_zipfuse(
-        "fscontext=u:object_r:zipfusefs:s0,context=u:object_r:system_file:s0",
-        Path::new("/dev/block/mapper/microdroid-apk"),
-        Path::new("/mnt/apk"),
-    )
-    .context("Failed to run zipfuse")?;
+    let config = if let Some(binary) = binary {
+        binary_payload_config(binary)
+    } else {
+        // Before reading a file from the APK, start zipfuse
+        run_zipfuse(
+            "fscontext=u:object_r:zipfusefs:s0,context=u:object_r:system_file:s0",
+            Path::new("/dev/block/mapper/microdroid-apk"),
+            Path::new("/mnt/apk"),
+        )
+        .context("Failed to run zipfuse")?;
 
-    ensure!(
-        !metadata.payload_config_path.is_empty(),
-        MicrodroidError::InvalidConfig("No payload_config_path in metadata".to_string())
-    );
+        ensure!(
+            !metadata.payload_config_path.is_empty(),
+            MicrodroidError::InvalidConfig("No payload_config_path in metadata".to_string())
+        );
 
-    let config = load_config(Path::new(&metadata.payload_config_path))?;
+        load_config(Path::new(&metadata.payload_config_path))?
+    };
 
     // Start tombstone_transmit if enabled
     if config.export_tombstones {
@@ -265,15 +310,29 @@ fn try_run_payload(service: &Strong<dyn IVirtualMachineService>) -> Result<i32>
             .context("Failed to start tombstone_transmit")?;
     }
 
-    if config.extra_apks.len() != verified_data.extra_apks_data.len() {
+    if config.extra_apks.len() < verified_data.extra_apks_data.len() {
         return Err(anyhow!(
-            "config expects {} extra apks, but found only {}",
+            "config expects {} extra apks, but found {}",
             config.extra_apks.len(),
             verified_data.extra_apks_data.len()
         ));
     }
     mount_extra_apks(&config)?;
 
+    if !metadata.shared_directory_tag.is_empty() {
+        mount_shared_directory(&metadata.shared_directory_tag)?;
+    }
+
+    if Path::new(SWAP_PATH).exists() {
+        // Swapped out pages aren't authenticated, so the host could corrupt or replay them. Strict
+        // boot is only set by pvmfw, which boots protected VMs.
+        ensure!(
+            !is_strict_boot(),
+            MicrodroidError::InvalidConfig("Swap isn't supported in protected VMs".to_string())
+        );
+        setup_swap().context("Failed to set up swap")?;
+    }
+
     // Wait until apex config is done. (e.g. linker configuration for apexes)
     // TODO(jooyung): wait until sys.boot_completed?
     wait_for_apex_config_done()?;
@@ -282,7 +341,31 @@ fn try_run_payload(service: &Strong<dyn IVirtualMachineService>) -> Result<i32>
         config.task.is_some(),
         MicrodroidError::InvalidConfig("No task in VM config".to_string())
     );
-    exec_task(&config.task.unwrap(), service)
+    let properties = PayloadProperties::new(
+        &metadata.payload_config_path,
+        serde_json::to_vec(&config)?,
+        get_instance_id(&verified_data),
+        get_debug_level()?,
+    );
+    let counters = RollbackCounters::new(instance, verified_data);
+    exec_task(&config.task.unwrap(), service, properties, counters, boot_times)
+}
+
+/// Returns an ID of the VM instance which is stable across boots. It is derived from the salt
+/// rather than being the salt itself, since the salt is a hidden input of the DICE derivation.
+fn get_instance_id(verified_data: &MicrodroidData) -> String {
+    to_hex_string(digest::digest(&digest::SHA256, &verified_data.salt).as_ref())
+}
+
+/// Returns the debug level of the VM, in the form of the `debug_level` payload property.
+fn get_debug_level() -> Result<&'static str> {
+    Ok(if system_properties::read_bool(DEBUGGABLE_PROP, false)? {
+        "full"
+    } else if system_properties::read_bool(APP_DEBUGGABLE_PROP, false)? {
+        "app_only"
+    } else {
+        "none"
+    })
 }
 
 struct ApkDmverityArgument<'a> {
@@ -325,15 +408,20 @@ fn run_zipfuse(option: &str, zip_path: &Path, mount_dir: &Path) -> Result<Child>
 // Verify payload before executing it. For APK payload, Full verification (which is slow) is done
 // when the root_hash values from the idsig file and the instance disk are different. This function
 // returns the verified root hash (for APK payload) and pubkeys (for APEX payloads) that can be
-// saved to the instance disk.
+// saved to the instance disk. When apkdmverity finishes is recorded in `boot_times`.
 fn verify_payload(
     metadata: &Metadata,
     saved_data: Option<&MicrodroidData>,
+    boot_times: &mut BootTimes,
 ) -> Result<MicrodroidData> {
+    if let Some(binary) = metadata.binary.as_ref() {
+        return verify_binary_payload(metadata, binary, saved_data);
+    }
+
     let start_time = SystemTime::now();
 
     // Verify main APK
-    let root_hash = saved_data.map(|d| &d.apk_data.root_hash);
+    let root_hash = saved_data.and_then(|d| d.apk_data.as_ref()).map(|d| &d.root_hash);
     let root_hash_from_idsig = get_apk_root_hash_from_idsig(MAIN_APK_IDSIG_PATH)?;
     let root_hash_trustful = root_hash == Some(&root_hash_from_idsig);
 
@@ -372,12 +460,25 @@ fn verify_payload(
     }
     let extra_apks_count = extra_apks.len();
 
+    // Extra APKs which aren't required may be left out, so the partition names rather than the
+    // positions in the lists give the indices of the APKs in the payload config.
+    for (extra_apk, extra_idsig) in extra_apks.iter().zip(&extra_idsigs) {
+        let apk_index = partition_name(extra_apk)?.strip_prefix("extra-apk-");
+        let idsig_index = partition_name(extra_idsig)?.strip_prefix("extra-idsig-");
+        ensure!(
+            apk_index == idsig_index,
+            "Extra apks/idsigs mismatch: {:?} and {:?}",
+            extra_apk,
+            extra_idsig
+        );
+    }
+
     let (extra_apk_names, extra_root_hashes_from_idsig): (Vec<_>, Vec<_>) = extra_idsigs
         .iter()
         .enumerate()
         .map(|(i, extra_idsig)| {
             (
-                format!("extra-apk-{}", i),
+                partition_name(&extra_apks[i]).unwrap().to_owned(),
                 get_apk_root_hash_from_idsig(extra_idsig.to_str().unwrap())
                     .expect("Can't find root hash from extra idsig"),
             )
@@ -414,35 +515,17 @@ fn verify_payload(
     let mut apkdmverity_child = run_apkdmverity(&apkdmverity_arguments)?;
 
     // While waiting for apkdmverity to mount APK, gathers public keys and root digests from
-    // APEX payload.
-    let apex_data_from_payload = get_apex_data_from_payload(metadata)?;
-    if let Some(saved_data) = saved_data.map(|d| &d.apex_data) {
-        // We don't support APEX updates. (assuming that update will change root digest)
-        ensure!(
-            saved_data == &apex_data_from_payload,
-            MicrodroidError::PayloadChanged(String::from("APEXes have changed."))
-        );
-        let apex_metadata = to_metadata(&apex_data_from_payload);
-        // Pass metadata(with public keys and root digests) to apexd so that it uses the passed
-        // metadata instead of the default one (/dev/block/by-name/payload-metadata)
-        OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open("/apex/vm-payload-metadata")
-            .context("Failed to open /apex/vm-payload-metadata")
-            .and_then(|f| write_metadata(&apex_metadata, f))?;
-    }
-    // Start apexd to activate APEXes
-    system_properties::write("ctl.start", "apexd-vm")?;
+    // APEX payload, and activates the APEXes.
+    let apex_data_from_payload = verify_and_activate_apexes(metadata, saved_data)?;
 
     // TODO(inseob): add timeout
     apkdmverity_child.wait()?;
+    boot_times.apkdmverityDoneMillis = boot_times::now_millis();
 
     // Do the full verification if the root_hash is un-trustful. This requires the full scanning of
     // the APK file and therefore can be very slow if the APK is large. Note that this step is
     // taken only when the root_hash is un-trustful which can be either when this is the first boot
-    // of the VM or APK was updated in the host.
-    // TODO(jooyung): consider multithreading to make this faster
+    // of the VM or APK was updated in the host. The digest is computed on all available CPUs.
     let main_apk_pubkey = get_public_key_from_apk(DM_MOUNTED_APK_PATH, root_hash_trustful)?;
     let extra_apks_data = extra_root_hashes_from_idsig
         .into_iter()
@@ -456,29 +539,157 @@ fn verify_payload(
 
     info!("payload verification successful. took {:#?}", start_time.elapsed().unwrap());
 
-    // Use the salt from a verified instance, or generate a salt for a new instance.
-    let salt = if let Some(saved_data) = saved_data {
-        saved_data.salt.clone()
-    } else {
-        let mut salt = vec![0u8; 64];
-        salt.as_mut_slice().try_fill(&mut rand::thread_rng())?;
-        salt
-    };
-
     // At this point, we can ensure that the root_hash from the idsig file is trusted, either by
     // fully verifying the APK or by comparing it with the saved root_hash.
     Ok(MicrodroidData {
-        salt,
-        apk_data: ApkData { root_hash: root_hash_from_idsig, pubkey: main_apk_pubkey },
+        salt: get_salt(saved_data)?,
+        apk_data: Some(ApkData { root_hash: root_hash_from_idsig, pubkey: main_apk_pubkey }),
         extra_apks_data,
         apex_data: apex_data_from_payload,
+        binary_data: None,
+        rollback: get_rollback_state(saved_data),
+    })
+}
+
+// Verify a payload which is an executable in one of the APEXes rather than an APK. The APEXes are
+// verified as for an APK payload, and activated so that the executable can be digested. The digest
+// is compared with the saved one by the caller, so that the executable can't change.
+fn verify_binary_payload(
+    metadata: &Metadata,
+    binary: &BinaryPayload,
+    saved_data: Option<&MicrodroidData>,
+) -> Result<MicrodroidData> {
+    check_binary_payload_path(&binary.path)?;
+    let apex_data = verify_and_activate_apexes(metadata, saved_data)?;
+    wait_for_apex_config_done()?;
+    let digest = digest_file(Path::new(&binary.path)).context(
+        MicrodroidError::PayloadVerificationFailed(format!("failed to digest {}", binary.path)),
+    )?;
+    info!("payload verification successful.");
+
+    Ok(MicrodroidData {
+        salt: get_salt(saved_data)?,
+        apk_data: None,
+        extra_apks_data: vec![],
+        apex_data,
+        binary_data: Some(BinaryData {
+            path: binary.path.clone(),
+            digest,
+            args: binary.args.clone(),
+        }),
+        rollback: get_rollback_state(saved_data),
     })
 }
 
+// Gathers public keys and root digests from APEX payload, checks them against the saved ones if
+// there are any, and then starts apexd to activate the APEXes.
+fn verify_and_activate_apexes(
+    metadata: &Metadata,
+    saved_data: Option<&MicrodroidData>,
+) -> Result<Vec<ApexData>> {
+    let apex_data_from_payload = get_apex_data_from_payload(metadata)?;
+    if let Some(saved_data) = saved_data.map(|d| &d.apex_data) {
+        // We don't support APEX updates. (assuming that update will change root digest)
+        ensure!(
+            saved_data == &apex_data_from_payload,
+            MicrodroidError::PayloadChanged(String::from("APEXes have changed."))
+        );
+        let apex_metadata = to_metadata(&apex_data_from_payload);
+        // Pass metadata(with public keys and root digests) to apexd so that it uses the passed
+        // metadata instead of the default one (/dev/block/by-name/payload-metadata)
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open("/apex/vm-payload-metadata")
+            .context("Failed to open /apex/vm-payload-metadata")
+            .and_then(|f| write_metadata(&apex_metadata, f))?;
+    }
+    // Start apexd to activate APEXes
+    system_properties::write("ctl.start", "apexd-vm")?;
+    Ok(apex_data_from_payload)
+}
+
+// Use the salt from a verified instance, or generate a salt for a new instance.
+fn get_salt(saved_data: Option<&MicrodroidData>) -> Result<Vec<u8>> {
+    if let Some(saved_data) = saved_data {
+        Ok(saved_data.salt.clone())
+    } else {
+        let mut salt = vec![0u8; 64];
+        salt.as_mut_slice().try_fill(&mut rand::thread_rng())?;
+        Ok(salt)
+    }
+}
+
+// The rollback state isn't part of the payload, so it is carried over rather than verified.
+fn get_rollback_state(saved_data: Option<&MicrodroidData>) -> RollbackState {
+    saved_data.map(|data| data.rollback.clone()).unwrap_or_default()
+}
+
+fn digest_file(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut context = digest::Context::new(&digest::SHA512);
+    let mut buf = [0u8; 4096];
+    loop {
+        let size = file.read(&mut buf)?;
+        if size == 0 {
+            break;
+        }
+        context.update(&buf[..size]);
+    }
+    Ok(context.finish().as_ref().to_vec())
+}
+
+/// Returns the payload config of a payload which is an executable rather than an APK.
+fn binary_payload_config(binary: &BinaryPayload) -> VmPayloadConfig {
+    VmPayloadConfig {
+        os: Default::default(),
+        task: Some(Task {
+            type_: TaskType::Executable,
+            command: binary.path.clone(),
+            args: binary.args.to_vec(),
+            resource_limits: Default::default(),
+        }),
+        apexes: vec![],
+        extra_apks: vec![],
+        prefer_staged: false,
+        export_tombstones: false,
+    }
+}
+
+fn partition_name(path: &Path) -> Result<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Invalid partition path {:?}", path))
+}
+
 fn mount_extra_apks(config: &VmPayloadConfig) -> Result<()> {
-    // For now, only the number of apks is important, as the mount point and dm-verity name is fixed
-    for i in 0..config.extra_apks.len() {
-        let mount_dir = format!("/mnt/extra-apk/{}", i);
+    let mut mount_dirs = HashSet::new();
+    for (i, extra_apk) in config.extra_apks.iter().enumerate() {
+        let mount_dir = extra_apk.mount_point_or_default(i);
+        check_extra_apk_mount_point(&mount_dir)?;
+        ensure!(
+            mount_dirs.insert(mount_dir.clone()),
+            MicrodroidError::InvalidConfig(format!(
+                "Duplicate extra apk mount point {}",
+                mount_dir
+            ))
+        );
+
+        if !Path::new(&format!("/dev/block/by-name/extra-apk-{}", i)).exists() {
+            ensure!(
+                !extra_apk.required,
+                MicrodroidError::InvalidConfig(format!(
+                    "Required extra apk {} is missing",
+                    extra_apk.path
+                ))
+            );
+            info!(
+                "Extra apk {} is missing, so nothing is mounted at {}",
+                extra_apk.path, mount_dir
+            );
+            continue;
+        }
+
         create_dir(Path::new(&mount_dir)).context("Failed to create mount dir for extra apks")?;
 
         // don't wait, just detach
@@ -493,6 +704,132 @@ fn mount_extra_apks(config: &VmPayloadConfig) -> Result<()> {
     Ok(())
 }
 
+// The host directory is shared for development only, so it isn't mounted unless the VM is fully
+// debuggable.
+fn mount_shared_directory(tag: &str) -> Result<()> {
+    ensure!(
+        system_properties::read_bool(DEBUGGABLE_PROP, false)?,
+        MicrodroidError::InvalidConfig("Shared directory in a non-debuggable VM".to_string())
+    );
+    mount(
+        Some(tag),
+        SHARED_DIRECTORY_MOUNT_POINT,
+        Some("virtiofs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        None::<&str>,
+    )
+    .context("Failed to mount shared directory")?;
+    info!("Mounted shared directory {} at {}", tag, SHARED_DIRECTORY_MOUNT_POINT);
+    Ok(())
+}
+
+/// Enables the swap image provided by the host. The image is used through dm-crypt with a key which
+/// is generated on each boot and never leaves the VM, so the host can't read swapped out pages and
+/// they don't outlive the boot. The key is passed to dm-crypt through the session keyring, which
+/// dmctl inherits, rather than on the command line of dmctl.
+fn setup_swap() -> Result<()> {
+    let sectors = block_device_sectors(Path::new(SWAP_PATH))?;
+    // aes-xts-plain64 with AES-256 takes two 256-bit keys.
+    let mut key = [0u8; 64];
+    key.try_fill(&mut rand::thread_rng())?;
+    let key_id = add_logon_key(SWAP_KEY_DESCRIPTION, &key)?;
+    let status = Command::new(DMCTL_BIN)
+        .args(["create", SWAP_DM_NAME, "crypt", "0", &sectors.to_string(), "aes-xts-plain64"])
+        .arg(format!(":{}:logon:{}", key.len(), SWAP_KEY_DESCRIPTION))
+        .args(["0", SWAP_PATH, "0"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .context("Failed to run dmctl");
+    // dm-crypt has its own copy of the key once the device is created.
+    invalidate_key(key_id)?;
+    let status = status?;
+    ensure!(status.success(), "Failed to create dm-crypt device for swap: {}", status);
+
+    ioutil::wait_for_file(SWAP_DM_PATH, WAIT_TIMEOUT)?;
+    for bin in [MKSWAP_BIN, SWAPON_BIN] {
+        let status = Command::new(bin)
+            .arg(SWAP_DM_PATH)
+            .stdin(Stdio::null())
+            .status()
+            .with_context(|| format!("Failed to run {}", bin))?;
+        ensure!(status.success(), "{} failed: {}", bin, status);
+    }
+    info!("Enabled {} sectors of swap", sectors);
+    Ok(())
+}
+
+/// Adds a key of the "logon" type, whose payload can't be read back from userspace, to the session
+/// keyring. Returns the ID of the key.
+fn add_logon_key(description: &str, payload: &[u8]) -> Result<i32> {
+    let key_type = CString::new("logon").unwrap();
+    let description = CString::new(description)?;
+    // SAFETY: The strings are NUL-terminated and the payload is valid for its length. The kernel
+    // doesn't keep any of the pointers.
+    let id = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            payload.as_ptr(),
+            payload.len(),
+            KEY_SPEC_SESSION_KEYRING,
+        )
+    };
+    if id < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to add key");
+    }
+    Ok(id as i32)
+}
+
+/// Invalidates the key, which removes it from all keyrings.
+fn invalidate_key(id: i32) -> Result<()> {
+    // SAFETY: KEYCTL_INVALIDATE only takes the key ID.
+    if unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_INVALIDATE, id) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to invalidate key");
+    }
+    Ok(())
+}
+
+/// Returns the size of the given block device in 512-byte sectors.
+fn block_device_sectors(path: &Path) -> Result<u64> {
+    let device = fs::canonicalize(path).with_context(|| format!("Failed to resolve {:?}", path))?;
+    let name = device.file_name().with_context(|| format!("Invalid block device {:?}", device))?;
+    let size_path = Path::new("/sys/class/block").join(name).join("size");
+    let size = fs::read_to_string(&size_path)
+        .with_context(|| format!("Failed to read {:?}", size_path))?;
+    size.trim().parse().with_context(|| format!("Invalid size of {:?}: {:?}", device, size))
+}
+
+// The payload binary must be in an APEX, so that it is covered by the APEX's signature. Any "." or
+// ".." would let it escape from /apex.
+fn check_binary_payload_path(path: &str) -> Result<()> {
+    let relative_path = Path::new(path).strip_prefix("/apex").ok();
+    ensure!(
+        relative_path.map_or(false, |relative_path| {
+            relative_path.components().next().is_some()
+                && relative_path.components().all(|c| matches!(c, Component::Normal(_)))
+        }),
+        MicrodroidError::InvalidConfig(format!("Payload binary {} isn't in an APEX", path))
+    );
+    Ok(())
+}
+
+// Extra apks can only be mounted directly under EXTRA_APK_MOUNT_DIRECTORY, where the SELinux
+// policy allows it.
+fn check_extra_apk_mount_point(mount_point: &str) -> Result<()> {
+    let name = Path::new(mount_point).strip_prefix(EXTRA_APK_MOUNT_DIRECTORY).ok();
+    let components = name.map(|name| name.components().collect::<Vec<_>>());
+    ensure!(
+        matches!(components.as_deref(), Some([Component::Normal(_)])),
+        MicrodroidError::InvalidConfig(format!(
+            "Extra apk mount point {} isn't directly under {}",
+            mount_point, EXTRA_APK_MOUNT_DIRECTORY
+        ))
+    );
+    Ok(())
+}
+
 // Waits until linker config is generated
 fn wait_for_apex_config_done() -> Result<()> {
     let mut prop = PropertyWatcher::new(APEX_CONFIG_DONE_PROP)?;
@@ -506,33 +843,58 @@ fn wait_for_apex_config_done() -> Result<()> {
 }
 
 fn get_apk_root_hash_from_idsig(path: &str) -> Result<Box<RootHash>> {
-    let mut idsig = File::open(path)?;
-    let idsig = V4Signature::from(&mut idsig)?;
+    let idsig = V4Signature::from_idsig_path(path)?;
     Ok(idsig.hashing_info.raw_root_hash)
 }
 
 fn get_public_key_from_apk(apk: &str, root_hash_trustful: bool) -> Result<Box<[u8]>> {
+    let current_sdk = get_current_sdk()?;
     if !root_hash_trustful {
-        verify(apk).context(MicrodroidError::PayloadVerificationFailed(format!(
-            "failed to verify {}",
-            apk
-        )))
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut result = VerifierBuilder::new(current_sdk)
+            .parallel_digest(threads)
+            .build()?
+            .verify(apk)
+            .context(MicrodroidError::PayloadVerificationFailed(format!(
+                "failed to verify {}",
+                apk
+            )))?;
+        Ok(result.signers.remove(0).public_key)
     } else {
-        get_public_key_der(apk)
+        get_public_key_der(apk, current_sdk)
     }
 }
 
+fn get_current_sdk() -> Result<u32> {
+    let current_sdk = system_properties::read(SDK_VERSION_PROP)?
+        .with_context(|| format!("{} not set", SDK_VERSION_PROP))?;
+    current_sdk.parse().with_context(|| format!("Malformed SDK version: {:?}", current_sdk))
+}
+
 fn load_config(path: &Path) -> Result<VmPayloadConfig> {
     info!("loading config from {:?}...", path);
     let file = ioutil::wait_for_file(path, WAIT_TIMEOUT)?;
-    Ok(serde_json::from_reader(file)?)
+    Ok(VmPayloadConfig::load(file).map_err(|e| MicrodroidError::InvalidConfig(e.to_string()))?)
 }
 
 /// Executes the given task. Stdout of the task is piped into the vsock stream to the
-/// virtualizationservice in the host side.
-fn exec_task(task: &Task, service: &Strong<dyn IVirtualMachineService>) -> Result<i32> {
+/// virtualizationservice in the host side. The task can query the given properties, use the given
+/// rollback counters and register its RPC binder services with the vm_payload library. Once the
+/// task is executed, the given boot times are reported to the host.
+fn exec_task(
+    task: &Task,
+    service: &Strong<dyn IVirtualMachineService>,
+    properties: PayloadProperties,
+    counters: RollbackCounters,
+    mut boot_times: BootTimes,
+) -> Result<i32> {
     info!("executing main task {:?}...", task);
     let mut command = build_command(task)?;
+    if !task.resource_limits.is_empty() {
+        cgroup::limit_resources(&mut command, &task.resource_limits)
+            .context("Failed to limit payload resources")?;
+    }
+    VmPayloadService::register(properties, counters, service.clone())?;
 
     info!("notifying payload started");
     service.notifyPayloadStarted()?;
@@ -543,7 +905,13 @@ fn exec_task(task: &Task, service: &Strong<dyn IVirtualMachineService>) -> Resul
         system_properties::write("ctl.start", "seriallogging")?;
     }
 
-    let exit_status = command.spawn()?.wait()?;
+    boot_times.payloadExecMillis = boot_times::now_millis();
+    let mut child = command.spawn()?;
+    // The boot times are only for performance tracking, so failing to report them isn't fatal.
+    if let Err(e) = service.notifyBootTimes(&boot_times) {
+        error!("failed to report boot times: {:?}", e);
+    }
+    let exit_status = child.wait()?;
     exit_status.code().ok_or_else(|| anyhow!("Failed to get exit_code from the paylaod."))
 }
 
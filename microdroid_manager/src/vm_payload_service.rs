@@ -0,0 +1,238 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serves the requests which the payload makes with the vm_payload library.
+
+use crate::instance::{InstanceDisk, MicrodroidData};
+use android_security_dice::aidl::android::security::dice::IDiceNode::IDiceNode;
+use android_system_virtualization_payload::aidl::android::system::virtualization::payload::IVmPayloadService::{
+    BnVmPayloadService, IVmPayloadService, VM_PAYLOAD_SERVICE_NAME,
+};
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
+use anyhow::{anyhow, ensure, Context, Result};
+use binder::{wait_for_interface, BinderFeatures, ExceptionCode, Interface, ProcessState, Strong};
+use binder_common::new_binder_exception;
+use log::{error, info};
+use ring::hkdf::{KeyType, Salt, HKDF_SHA256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use vm_payload::properties::{
+    KEY_DEBUG_LEVEL, KEY_INSTANCE_ID, KEY_PAYLOAD_CONFIG, KEY_PAYLOAD_CONFIG_PATH,
+};
+use vm_payload::secrets::MAX_SECRET_LENGTH;
+
+/// The salt of the derivation of secrets of the VM instance, as documented in `vm_payload::secrets`.
+const SECRET_SALT: &[u8] = b"vm_payload_secret";
+
+/// The maximum number of rollback counters, which bounds the size of the instance data.
+const MAX_ROLLBACK_COUNTERS: usize = 64;
+
+/// The properties which are served to the payload.
+#[derive(Debug)]
+pub struct PayloadProperties {
+    values: HashMap<String, Vec<u8>>,
+}
+
+impl PayloadProperties {
+    pub fn new(
+        payload_config_path: &str,
+        payload_config: Vec<u8>,
+        instance_id: String,
+        debug_level: &str,
+    ) -> PayloadProperties {
+        let values = vec![
+            (KEY_PAYLOAD_CONFIG_PATH, payload_config_path.as_bytes().to_vec()),
+            (KEY_PAYLOAD_CONFIG, payload_config),
+            (KEY_INSTANCE_ID, instance_id.into_bytes()),
+            (KEY_DEBUG_LEVEL, debug_level.as_bytes().to_vec()),
+        ];
+        PayloadProperties {
+            values: values.into_iter().map(|(key, value)| (key.to_owned(), value)).collect(),
+        }
+    }
+}
+
+/// The rollback counters of the payload, which are stored in the instance disk.
+pub struct RollbackCounters {
+    instance: InstanceDisk,
+    data: MicrodroidData,
+}
+
+impl RollbackCounters {
+    pub fn new(instance: InstanceDisk, data: MicrodroidData) -> RollbackCounters {
+        RollbackCounters { instance, data }
+    }
+
+    fn get(&self, name: &str) -> u64 {
+        self.data.rollback.counters.get(name).copied().unwrap_or(0)
+    }
+
+    /// Increments the given counter and writes it to the instance disk before returning it.
+    fn increment(&mut self, name: &str) -> Result<u64> {
+        let counters = &mut self.data.rollback.counters;
+        ensure!(
+            counters.contains_key(name) || counters.len() < MAX_ROLLBACK_COUNTERS,
+            "Too many rollback counters"
+        );
+        let value = self.get(name).checked_add(1).context("Rollback counter overflow")?;
+        let previous = self.data.rollback.counters.insert(name.to_owned(), value);
+        if let Err(e) = self.instance.write_microdroid_data(&self.data) {
+            // Don't report a value which may not survive a reboot.
+            match previous {
+                Some(previous) => self.data.rollback.counters.insert(name.to_owned(), previous),
+                None => self.data.rollback.counters.remove(name),
+            };
+            return Err(e.context("Failed to write rollback counter"));
+        }
+        Ok(value)
+    }
+}
+
+/// Serves the requests of the payload.
+pub struct VmPayloadService {
+    properties: PayloadProperties,
+    counters: Mutex<RollbackCounters>,
+    /// Used to relay the RPC binder services which the payload registers to the host.
+    vm_service: Strong<dyn IVirtualMachineService>,
+}
+
+impl Interface for VmPayloadService {}
+
+impl IVmPayloadService for VmPayloadService {
+    fn getProperty(&self, key: &str) -> binder::Result<Option<Vec<u8>>> {
+        Ok(self.properties.values.get(key).cloned())
+    }
+
+    fn registerService(&self, name: &str, port: i32) -> binder::Result<()> {
+        info!("Payload registered service {} on port {}", name, port);
+        self.vm_service.notifyServiceRegistered(name, port).map_err(|e| {
+            error!("Failed to relay registration of service {}: {:?}", name, e);
+            e
+        })
+    }
+
+    fn getVmInstanceSecret(
+        &self,
+        purpose: i64,
+        version: i32,
+        context: &[u8],
+        length: i32,
+    ) -> binder::Result<Vec<u8>> {
+        let purpose = purpose as u64;
+        get_sealing_cdi()
+            .and_then(|cdi| derive_secret(&cdi, purpose, version as u32, context, length as usize))
+            .map_err(|e| {
+                error!("Failed to derive secret for purpose {}: {:?}", purpose, e);
+                new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+            })
+    }
+
+    fn getRollbackCounter(&self, name: &str) -> binder::Result<i64> {
+        Ok(self.counters.lock().unwrap().get(name) as i64)
+    }
+
+    fn incrementRollbackCounter(&self, name: &str) -> binder::Result<i64> {
+        let value = self.counters.lock().unwrap().increment(name).map_err(|e| {
+            error!("Failed to increment rollback counter {}: {:?}", name, e);
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+        })?;
+        info!("Payload incremented rollback counter {} to {}", name, value);
+        Ok(value as i64)
+    }
+}
+
+impl VmPayloadService {
+    /// Registers the service with the service manager of Microdroid, so that the payload can find
+    /// it with the vm_payload library, and starts serving its requests.
+    pub fn register(
+        properties: PayloadProperties,
+        counters: RollbackCounters,
+        vm_service: Strong<dyn IVirtualMachineService>,
+    ) -> Result<()> {
+        let service = VmPayloadService { properties, counters: Mutex::new(counters), vm_service };
+        let service = BnVmPayloadService::new_binder(service, BinderFeatures::default());
+        binder::add_service(VM_PAYLOAD_SERVICE_NAME, service.as_binder())
+            .with_context(|| format!("Failed to register {}", VM_PAYLOAD_SERVICE_NAME))?;
+        ProcessState::start_thread_pool();
+        Ok(())
+    }
+}
+
+/// Returns the sealing CDI of the payload. microdroid_manager has already demoted itself to the
+/// payload's DICE node by the time the payload runs.
+fn get_sealing_cdi() -> Result<[u8; 32]> {
+    let diced = wait_for_interface::<dyn IDiceNode>("android.security.dice.IDiceNode")
+        .context("IDiceNode service not found")?;
+    let bcc_handover = diced.derive(&[]).context("Failed to get BccHandover")?;
+    Ok(bcc_handover.cdiSeal)
+}
+
+struct SecretLength(usize);
+
+impl KeyType for SecretLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Derives a secret of the VM instance from the sealing CDI, as documented in `vm_payload::secrets`.
+fn derive_secret(
+    cdi_seal: &[u8],
+    purpose: u64,
+    version: u32,
+    context: &[u8],
+    length: usize,
+) -> Result<Vec<u8>> {
+    ensure!(length > 0 && length <= MAX_SECRET_LENGTH, "Invalid secret length {}", length);
+    let prk = Salt::new(HKDF_SHA256, SECRET_SALT).extract(cdi_seal);
+    let purpose = purpose.to_be_bytes();
+    let version = version.to_be_bytes();
+    let info = [&purpose[..], &version[..], context];
+    let mut secret = vec![0; length];
+    prk.expand(&info, SecretLength(length))
+        .and_then(|okm| okm.fill(&mut secret))
+        .map_err(|_| anyhow!("HKDF failed"))?;
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The derivation must never change, or payloads would lose access to data they sealed.
+    #[test]
+    fn derive_secret_is_stable() -> Result<()> {
+        let cdi_seal: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            to_hex(&derive_secret(&cdi_seal, 1, 2, b"context", 32)?),
+            "8918d16579bc208de95af73baf0cec4971a8455d4243f2ef369bfc35875174e6"
+        );
+        assert_eq!(
+            to_hex(&derive_secret(&cdi_seal, (1 << 32) | 7, 0, b"", 48)?),
+            "cdd018740ded86fe819168d6e6e011b3c6ecbc1713a4665dbdfe12799c90ae93\
+             2448ca9bb58ef94f4ec8ae1c5e02f12d"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn derive_secret_rejects_invalid_length() {
+        assert!(derive_secret(&[0; 32], 1, 1, b"", 0).is_err());
+        assert!(derive_secret(&[0; 32], 1, 1, b"", MAX_SECRET_LENGTH + 1).is_err());
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
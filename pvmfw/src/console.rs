@@ -19,6 +19,8 @@ use core::fmt::{write, Arguments, Write};
 use spin::mutex::SpinMutex;
 
 const BASE_ADDRESS: usize = 0x3f8;
+/// Base address of the UART which the VMM reads the failure reason of the VM from.
+const FAILURE_BASE_ADDRESS: usize = 0x2f8;
 
 static CONSOLE: SpinMutex<Option<Uart>> = SpinMutex::new(None);
 
@@ -68,6 +70,16 @@ pub fn emergency_write_args(format_args: Arguments) {
     let _ = write(&mut uart, format_args);
 }
 
+/// Writes the reason for the VM failing to the dedicated UART, for the VMM to report.
+///
+/// This may be used in any context, including exception and panic handlers.
+pub fn emergency_write_failure_reason(reason: &str) {
+    // Safe because FAILURE_BASE_ADDRESS is the base of the MMIO region for a UART and is mapped as
+    // device memory.
+    let mut uart = unsafe { Uart::new(FAILURE_BASE_ADDRESS) };
+    let _ = uart.write_str(reason);
+}
+
 /// Prints the given string to the console.
 ///
 /// Panics if the console has not yet been initialised. May hang if used in an exception context;
@@ -0,0 +1,75 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exception handlers, called from the vector table in exceptions.S.
+
+use crate::eprintln;
+use crate::gic;
+use crate::psci::system_reset;
+use crate::timer;
+use crate::watchdog;
+
+#[no_mangle]
+extern "C" fn sync_exception_current(elr: u64, spsr: u64) {
+    unexpected_exception("synchronous exception", elr, spsr);
+}
+
+#[no_mangle]
+extern "C" fn irq_current(elr: u64, spsr: u64) {
+    match gic::acknowledge() {
+        gic::SPURIOUS_INTID => {}
+        timer::VIRTUAL_TIMER_INTID => watchdog::expire(),
+        intid => {
+            gic::end_of_interrupt(intid);
+            unexpected_exception("IRQ", elr, spsr);
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn fiq_current(elr: u64, spsr: u64) {
+    unexpected_exception("FIQ", elr, spsr);
+}
+
+#[no_mangle]
+extern "C" fn serr_current(elr: u64, spsr: u64) {
+    unexpected_exception("SError", elr, spsr);
+}
+
+#[no_mangle]
+extern "C" fn sync_lower(elr: u64, spsr: u64) {
+    unexpected_exception("synchronous exception from a lower EL", elr, spsr);
+}
+
+#[no_mangle]
+extern "C" fn irq_lower(elr: u64, spsr: u64) {
+    unexpected_exception("IRQ from a lower EL", elr, spsr);
+}
+
+#[no_mangle]
+extern "C" fn fiq_lower(elr: u64, spsr: u64) {
+    unexpected_exception("FIQ from a lower EL", elr, spsr);
+}
+
+#[no_mangle]
+extern "C" fn serr_lower(elr: u64, spsr: u64) {
+    unexpected_exception("SError from a lower EL", elr, spsr);
+}
+
+fn unexpected_exception(kind: &str, elr: u64, spsr: u64) -> ! {
+    eprintln!("Unexpected {} at {:#x}, SPSR {:#x}", kind, elr, spsr);
+    system_reset();
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
@@ -0,0 +1,121 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal read-only parser for the flattened device tree provided by the VMM.
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// A flattened device tree blob.
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Parses the header of the FDT at the start of `data`, returning `None` if it isn't valid.
+    pub fn from_slice(data: &'a [u8]) -> Option<Self> {
+        if data.len() < FDT_HEADER_SIZE || read_u32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let data = data.get(..read_u32(data, 4)? as usize)?;
+        let structs = slice_at(data, read_u32(data, 8)?, read_u32(data, 36)?)?;
+        let strings = slice_at(data, read_u32(data, 12)?, read_u32(data, 32)?)?;
+        Some(Self { structs, strings })
+    }
+
+    /// Returns the value of the property `name` of the node at `path` (e.g. "/chosen").
+    pub fn property(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        let components = path.split('/').filter(|c| !c.is_empty());
+        let target_depth = components.clone().count() + 1;
+        // Depth of the current node, and how many of its ancestors (including itself) are on the
+        // requested path.
+        let mut depth = 0;
+        let mut matched = 0;
+        let mut offset = 0;
+        loop {
+            let token = read_u32(self.structs, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node_name = read_str(self.structs, offset)?;
+                    offset = align4(offset + node_name.len() + 1);
+                    depth += 1;
+                    if matched == depth - 1
+                        && (depth == 1 || components.clone().nth(depth - 2) == Some(node_name))
+                    {
+                        matched = depth;
+                    }
+                }
+                FDT_END_NODE => {
+                    if matched == depth {
+                        // Left the deepest matching node without finding the property.
+                        return None;
+                    }
+                    depth = depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let len = read_u32(self.structs, offset)? as usize;
+                    let name_offset = read_u32(self.structs, offset + 4)? as usize;
+                    let value = self.structs.get(offset + 8..offset + 8 + len)?;
+                    offset = align4(offset + 8 + len);
+                    if matched == depth
+                        && depth == target_depth
+                        && read_str(self.strings, name_offset)? == name
+                    {
+                        return Some(value);
+                    }
+                }
+                FDT_NOP => {}
+                // FDT_END, or an invalid token.
+                _ => return None,
+            }
+        }
+    }
+
+    /// Returns the value of the property `name` of the node at `path`, as a single cell.
+    pub fn property_u32(&self, path: &str, name: &str) -> Option<u32> {
+        let value = self.property(path, name)?;
+        if value.len() == 4 {
+            read_u32(value, 0)
+        } else {
+            None
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+fn slice_at(data: &[u8], offset: u32, size: u32) -> Option<&[u8]> {
+    let offset = offset as usize;
+    data.get(offset..offset.checked_add(size as usize)?)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
@@ -0,0 +1,148 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal driver for the GICv3 provided by the VMM, enough for pvmfw to take private peripheral
+//! interrupts on the boot CPU.
+
+use crate::fdt::Fdt;
+use core::arch::asm;
+use core::ptr::{read_volatile, write_volatile};
+
+/// The `compatible` string of the GICv3 in the DT.
+const COMPATIBLE: &[u8] = b"arm,gic-v3";
+
+const GICD_CTLR: usize = 0x0;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+const GICR_WAKER: usize = 0x14;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// Offset of the SGI and PPI frame from the base of the redistributor.
+const GICR_SGI_BASE: usize = 0x10000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x80;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x100;
+const GICR_IPRIORITYR: usize = GICR_SGI_BASE + 0x400;
+
+/// Priority given to the interrupts enabled by pvmfw, above the priority mask.
+const PRIORITY: u8 = 0x80;
+
+/// Interrupt ID returned by the CPU interface when there is no pending interrupt.
+pub const SPURIOUS_INTID: u32 = 1023;
+
+/// The distributor and the redistributor of the boot CPU.
+pub struct Gic {
+    distributor: usize,
+    redistributor: usize,
+}
+
+impl Gic {
+    /// Initialises the GIC described by the `/intc` node of the DT, and the CPU interface of the
+    /// current CPU, or returns `None` if the DT doesn't describe a GICv3. The system registers of
+    /// the CPU interface would trap on a GICv2.
+    pub fn init(fdt: &Fdt) -> Option<Self> {
+        let compatible = fdt.property("/intc", "compatible")?;
+        if !compatible.split(|b| *b == 0).any(|c| c == COMPATIBLE) {
+            return None;
+        }
+        let reg = fdt.property("/intc", "reg")?;
+        // With 2 address cells and 2 size cells, the distributor then the redistributors.
+        if reg.len() < 32 {
+            return None;
+        }
+        let cell = |i: usize| u64::from_be_bytes(reg[i * 8..i * 8 + 8].try_into().unwrap());
+        let gic = Self { distributor: cell(0) as usize, redistributor: cell(2) as usize };
+
+        // Safe because the registers are in the MMIO region of the GIC, which is mapped as device
+        // memory, and pvmfw is the only user of the GIC.
+        unsafe {
+            gic.write(gic.distributor + GICD_CTLR, GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1);
+            while gic.read(gic.distributor + GICD_CTLR) & GICD_CTLR_RWP != 0 {}
+
+            let waker = gic.redistributor + GICR_WAKER;
+            gic.write(waker, gic.read(waker) & !GICR_WAKER_PROCESSOR_SLEEP);
+            while gic.read(waker) & GICR_WAKER_CHILDREN_ASLEEP != 0 {}
+
+            // ICC_SRE_EL1.SRE enables the system register interface, ICC_PMR_EL1 lets all
+            // priorities through, and ICC_IGRPEN1_EL1 enables group 1 interrupts.
+            asm!(
+                "mrs {tmp}, S3_0_C12_C12_5",
+                "orr {tmp}, {tmp}, #1",
+                "msr S3_0_C12_C12_5, {tmp}",
+                "isb",
+                "mov {tmp}, #0xff",
+                "msr S3_0_C4_C6_0, {tmp}",
+                "mov {tmp}, #1",
+                "msr S3_0_C12_C12_7, {tmp}",
+                "isb",
+                tmp = out(reg) _,
+                options(nomem, nostack),
+            );
+        }
+        Some(gic)
+    }
+
+    /// Enables the private peripheral interrupt `intid` as a group 1 interrupt.
+    pub fn enable_ppi(&self, intid: u32) {
+        assert!((16..32).contains(&intid));
+        let bit = 1 << intid;
+        // Safe because the registers are in the MMIO region of the GIC, which is mapped as device
+        // memory, and pvmfw is the only user of the GIC.
+        unsafe {
+            let group = self.redistributor + GICR_IGROUPR0;
+            self.write(group, self.read(group) | bit);
+            write_volatile(
+                (self.redistributor + GICR_IPRIORITYR + intid as usize) as *mut u8,
+                PRIORITY,
+            );
+            self.write(self.redistributor + GICR_ISENABLER0, bit);
+        }
+    }
+
+    unsafe fn read(&self, address: usize) -> u32 {
+        read_volatile(address as *const u32)
+    }
+
+    unsafe fn write(&self, address: usize, value: u32) {
+        write_volatile(address as *mut u32, value)
+    }
+}
+
+/// Acknowledges the highest priority pending group 1 interrupt, returning its ID.
+pub fn acknowledge() -> u32 {
+    let intid: u64;
+    // Safe because reading ICC_IAR1_EL1 only changes the state of the interrupt.
+    unsafe {
+        asm!("mrs {}, S3_0_C12_C12_0", out(reg) intid, options(nomem, nostack));
+    }
+    intid as u32
+}
+
+/// Signals the end of the handling of the interrupt `intid`.
+pub fn end_of_interrupt(intid: u32) {
+    // Safe because writing ICC_EOIR1_EL1 only changes the state of the interrupt.
+    unsafe {
+        asm!("msr S3_0_C12_C12_1, {}", in(reg) u64::from(intid), options(nomem, nostack));
+    }
+}
+
+/// Unmasks IRQs on the current CPU.
+pub fn unmask_irqs() {
+    // Safe because the exception vector handles IRQs.
+    unsafe {
+        asm!("msr daifclr, #2", "isb", options(nomem, nostack));
+    }
+}
@@ -18,23 +18,64 @@
 #![no_std]
 
 mod console;
+mod exceptions;
+mod fdt;
+mod gic;
 mod psci;
+mod timer;
 mod uart;
+mod watchdog;
 
 use core::panic::PanicInfo;
+use core::ptr::addr_of;
+use core::slice;
+use fdt::Fdt;
+use gic::Gic;
 use psci::{system_off, system_reset};
+use watchdog::{Watchdog, DEFAULT_BOOT_TIMEOUT_MS};
+
+extern "C" {
+    static dtb_begin: u8;
+    static dtb_end: u8;
+}
 
 /// Entry point for pVM firmware.
 #[no_mangle]
 pub extern "C" fn main() -> ! {
     console::init();
+    let fdt = reference_fdt();
+    let gic = fdt.as_ref().and_then(Gic::init);
+    if gic.is_none() {
+        eprintln!("No GICv3 found, the boot watchdog is only checked at handover");
+    }
+    let watchdog = Watchdog::arm(boot_timeout_ms(fdt.as_ref()), gic.as_ref());
     println!("Hello world");
 
+    watchdog.disarm();
     system_off();
     #[allow(clippy::empty_loop)]
     loop {}
 }
 
+/// Returns the device tree provided by the VMM, if it is valid.
+fn reference_fdt() -> Option<Fdt<'static>> {
+    // Safe because the linker script places dtb_begin and dtb_end around the region reserved for
+    // the DT, which is mapped read-only and never written by pvmfw.
+    let dtb = unsafe {
+        let begin = addr_of!(dtb_begin);
+        slice::from_raw_parts(begin, addr_of!(dtb_end) as usize - begin as usize)
+    };
+    Fdt::from_slice(dtb)
+}
+
+/// Returns the time the VM is given to reach the payload handover, as configured by the
+/// `avf,boot-timeout-ms` property of `/chosen` in the reference DT.
+fn boot_timeout_ms(fdt: Option<&Fdt>) -> u32 {
+    fdt.and_then(|fdt| fdt.property_u32("/chosen", "avf,boot-timeout-ms"))
+        .filter(|&timeout| timeout != 0)
+        .unwrap_or(DEFAULT_BOOT_TIMEOUT_MS)
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     eprintln!("{}", info);
@@ -0,0 +1,73 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access to the ARM generic timer.
+
+use core::arch::asm;
+
+/// Interrupt ID of the EL1 virtual timer, PPI 11.
+pub const VIRTUAL_TIMER_INTID: u32 = 27;
+
+/// CNTV_CTL_EL0.ENABLE, with IMASK clear so that the interrupt is signalled.
+const CNTV_CTL_ENABLE: u64 = 1 << 0;
+
+/// Returns the current value of the virtual counter.
+pub fn counter() -> u64 {
+    let value: u64;
+    // Safe because reading CNTVCT_EL0 has no side effects. The ISB stops the read from being
+    // speculated ahead of earlier instructions.
+    unsafe {
+        asm!("isb", "mrs {}, cntvct_el0", out(reg) value, options(nomem, nostack));
+    }
+    value
+}
+
+/// Returns the frequency of the system counter, in Hz.
+pub fn frequency() -> u64 {
+    let value: u64;
+    // Safe because reading CNTFRQ_EL0 has no side effects.
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg) value, options(nomem, nostack));
+    }
+    value
+}
+
+/// Converts a duration in milliseconds to a number of counter ticks.
+pub fn ms_to_ticks(ms: u32) -> u64 {
+    frequency().saturating_mul(ms.into()) / 1000
+}
+
+/// Enables the virtual timer so that its interrupt fires once the virtual counter reaches
+/// `deadline`.
+pub fn set_deadline(deadline: u64) {
+    // Safe because the virtual timer is only used by pvmfw, for the watchdog.
+    unsafe {
+        asm!(
+            "msr cntv_cval_el0, {}",
+            "msr cntv_ctl_el0, {}",
+            "isb",
+            in(reg) deadline,
+            in(reg) CNTV_CTL_ENABLE,
+            options(nomem, nostack),
+        );
+    }
+}
+
+/// Disables the virtual timer, so that its interrupt is no longer signalled.
+pub fn disable() {
+    // Safe because the virtual timer is only used by pvmfw, for the watchdog.
+    unsafe {
+        asm!("msr cntv_ctl_el0, xzr", "isb", options(nomem, nostack));
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watchdog bounding the time the VM may take to reach the payload handover.
+
+use crate::console;
+use crate::eprintln;
+use crate::gic::{self, Gic};
+use crate::psci::system_reset;
+use crate::timer;
+
+/// Boot timeout used when the reference DT doesn't provide one.
+pub const DEFAULT_BOOT_TIMEOUT_MS: u32 = 30_000;
+
+/// Failure reason reported to the VMM when the watchdog expires. This must match the string
+/// expected by virtualizationservice.
+const BOOT_TIMEOUT_REASON: &str = "PVM_FIRMWARE_BOOT_TIMEOUT";
+
+/// A watchdog based on the virtual timer, which terminates the VM if it is still armed after its
+/// deadline.
+///
+/// The deadline is programmed into the virtual timer, whose interrupt terminates the VM from the
+/// exception handler. Without a GIC to deliver the interrupt, the deadline is only enforced when
+/// the watchdog is disarmed.
+pub struct Watchdog {
+    deadline: u64,
+}
+
+impl Watchdog {
+    /// Arms a new watchdog which expires `timeout_ms` milliseconds from now, with its interrupt
+    /// delivered through `gic` if there is one.
+    pub fn arm(timeout_ms: u32, gic: Option<&Gic>) -> Self {
+        let deadline = timer::counter().saturating_add(timer::ms_to_ticks(timeout_ms));
+        timer::set_deadline(deadline);
+        if let Some(gic) = gic {
+            gic.enable_ppi(timer::VIRTUAL_TIMER_INTID);
+            gic::unmask_irqs();
+        }
+        Self { deadline }
+    }
+
+    /// Disarms the watchdog at the handover checkpoint, after a final check that the deadline
+    /// wasn't missed.
+    pub fn disarm(self) {
+        timer::disable();
+        if timer::counter() >= self.deadline {
+            expire();
+        }
+    }
+}
+
+/// Reports the boot timeout to the VMM and terminates the VM.
+pub fn expire() -> ! {
+    eprintln!("Boot watchdog expired");
+    console::emergency_write_failure_reason(BOOT_TIMEOUT_REASON);
+    system_reset();
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
@@ -15,20 +15,45 @@
 //! Implementation of the AIDL interface of the VirtualizationService.
 
 use crate::composite::make_composite_image;
-use crate::crosvm::{CrosvmConfig, DiskFile, PayloadState, VmInstance, VmState};
-use crate::payload::add_microdroid_images;
-use crate::{Cid, FIRST_GUEST_CID, SYSPROP_LAST_CID};
+use crate::console::{Console, ConsolePort};
+use crate::crash::{read_crash_report, CRASH_DIRECTORY};
+use crate::crosvm::{
+    AudioDevice, CrosvmConfig, DiskFile, Display, InputDeviceFile, PayloadState, SharedDir,
+    VmInstance, VmState,
+};
+use crate::early_vm::{check_early_vm_config, parse_early_vms, EarlyVm, EARLY_VMS_CONFIG_PATH};
+use crate::hypervisor;
+use crate::input::InputDevice;
+use crate::payload::{
+    add_microdroid_images, create_binary_payload_config, SHARED_DIRECTORY_TAG,
+};
+use crate::port_forwarding::PortForwarder;
+use crate::{
+    Cid, FIRST_GUEST_CID, SYSPROP_EARLY_VMS_ENABLED, SYSPROP_EARLY_VMS_STARTED, SYSPROP_LAST_CID,
+};
 use crate::selinux::{SeContext, getfilecon};
 use ::binder::unstable_api::AsNative;
 use android_os_permissions_aidl::aidl::android::os::IPermissionController;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    AudioConfig::AudioConfig,
+    BootTimes::BootTimes,
+    CrashReport::CrashReport,
     DeathReason::DeathReason,
     DiskImage::DiskImage,
+    DisplayConfig::DisplayConfig,
+    HypervisorCapabilities::HypervisorCapabilities,
     IVirtualMachine::{BnVirtualMachine, IVirtualMachine},
     IVirtualMachineCallback::IVirtualMachineCallback,
     IVirtualizationService::IVirtualizationService,
+    InputDevice::{DeviceType::DeviceType, InputDevice as AidlInputDevice},
+    InputEvent::InputEvent,
+    MemoryStats::MemoryStats,
     Partition::Partition,
     PartitionType::PartitionType,
+    PortForwarding::PortForwarding,
+    SandboxProfile::SandboxProfile,
+    SharedPath::SharedPath,
+    VirtualMachineAppConfig::DebugLevel::DebugLevel,
     VirtualMachineAppConfig::VirtualMachineAppConfig,
     VirtualMachineConfig::VirtualMachineConfig,
     VirtualMachineDebugInfo::VirtualMachineDebugInfo,
@@ -54,16 +79,18 @@ use microdroid_payload_config::VmPayloadConfig;
 use rustutils::system_properties;
 use semver::VersionReq;
 use statslog_virtualization_rust::vm_creation_requested::{stats_write, Hypervisor};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::ffi::CStr;
 use std::fs::{create_dir, File, OpenOptions};
 use std::io::{Error, ErrorKind, Write, Read};
-use std::num::NonZeroU32;
+use std::num::{NonZeroU16, NonZeroU32};
 use std::os::raw;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 use tombstoned_client::{TombstonedConnection, DebuggerdDumpType};
 use vmconfig::VmConfig;
 use vsock::{SockAddr, VsockListener, VsockStream};
@@ -89,8 +116,19 @@ const ANDROID_VM_INSTANCE_VERSION: u16 = 1;
 
 const CHUNK_RECV_MAX_LEN: usize = 1024;
 
+/// How long dumpsys waits for the balloon stats of each VM, so that a busy crosvm doesn't hold up
+/// the dump.
+const DUMP_MEMORY_STATS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The maximum number of RPC binder services which the payload of a VM can register, so that a
+/// guest can't make virtualizationservice grow without bound.
+const MAX_PAYLOAD_SERVICES: usize = 64;
+
+/// The maximum length of the name of a service which the payload of a VM registers, in bytes.
+const MAX_PAYLOAD_SERVICE_NAME_LENGTH: usize = 256;
+
 /// Implementation of `IVirtualizationService`, the entry point of the AIDL service.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct VirtualizationService {
     state: Arc<Mutex<State>>,
 }
@@ -108,6 +146,36 @@ impl Interface for VirtualizationService {
             writeln!(file, "\tPayload state {:?}", vm.payload_state())
                 .or(Err(StatusCode::UNKNOWN_ERROR))?;
             writeln!(file, "\tProtected: {}", vm.protected).or(Err(StatusCode::UNKNOWN_ERROR))?;
+            writeln!(file, "\tPaused: {}", vm.is_paused()).or(Err(StatusCode::UNKNOWN_ERROR))?;
+            if let Some(uptime) = vm.uptime() {
+                writeln!(file, "\tUptime: {}s", uptime.as_secs())
+                    .or(Err(StatusCode::UNKNOWN_ERROR))?;
+            }
+            match vm.crosvm_usage() {
+                Some(Ok(usage)) => {
+                    writeln!(file, "\tCPU time: {}ms", usage.cpu_time.as_millis())
+                        .or(Err(StatusCode::UNKNOWN_ERROR))?;
+                    writeln!(file, "\tRSS: {} KiB", usage.rss_kib)
+                        .or(Err(StatusCode::UNKNOWN_ERROR))?;
+                }
+                Some(Err(e)) => writeln!(file, "\tFailed to read crosvm usage: {:?}", e)
+                    .or(Err(StatusCode::UNKNOWN_ERROR))?,
+                None => {}
+            }
+            match vm.memory_stats(Some(DUMP_MEMORY_STATS_TIMEOUT)) {
+                Ok(stats) => writeln!(file, "\tBalloon size: {} bytes", stats.balloonActualBytes)
+                    .or(Err(StatusCode::UNKNOWN_ERROR))?,
+                Err(e) => writeln!(file, "\tFailed to read balloon stats: {:?}", e)
+                    .or(Err(StatusCode::UNKNOWN_ERROR))?,
+            }
+            if let Some(boot_times) = vm.boot_times() {
+                writeln!(file, "\tBoot times: {:?}", boot_times)
+                    .or(Err(StatusCode::UNKNOWN_ERROR))?;
+            }
+            writeln!(file, "\tForwarded connections: {}", vm.forwarded_connections())
+                .or(Err(StatusCode::UNKNOWN_ERROR))?;
+            writeln!(file, "\tPayload services: {:?}", vm.payload_services.lock().unwrap())
+                .or(Err(StatusCode::UNKNOWN_ERROR))?;
             writeln!(file, "\ttemporary_directory: {}", vm.temporary_directory.to_string_lossy())
                 .or(Err(StatusCode::UNKNOWN_ERROR))?;
             writeln!(file, "\trequester_uid: {}", vm.requester_uid)
@@ -230,11 +298,16 @@ impl IVirtualizationService for VirtualizationService {
             .into_iter()
             .map(|vm| VirtualMachineDebugInfo {
                 cid: vm.cid as i32,
+                name: vm.name.clone(),
                 temporaryDirectory: vm.temporary_directory.to_string_lossy().to_string(),
                 requesterUid: vm.requester_uid as i32,
                 requesterSid: vm.requester_sid.clone(),
                 requesterPid: vm.requester_debug_pid,
                 state: get_state(&vm),
+                protectedVm: vm.protected,
+                memoryMib: vm.memory_mib.map_or(0, |m| m.get() as i32),
+                numCpus: vm.cpus.map_or(0, |c| c.get() as i32),
+                bootTimes: vm.boot_times(),
             })
             .collect();
         Ok(cids)
@@ -259,6 +332,49 @@ impl IVirtualizationService for VirtualizationService {
         let state = &mut *self.state.lock().unwrap();
         Ok(state.debug_drop_vm(cid))
     }
+
+    /// Attaches to the console of the VM with the given CID. This method is only intended for
+    /// debug purposes, and as such is only permitted from the shell user.
+    fn debugAttachConsole(&self, cid: i32) -> binder::Result<ParcelFileDescriptor> {
+        check_debug_access()?;
+
+        let vm = self.state.lock().unwrap().get_vm(cid as Cid).ok_or_else(|| {
+            new_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT, format!("No VM with CID {}", cid))
+        })?;
+        let socket = vm.console.attach().map_err(|e| {
+            new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("Failed to attach console: {}", e),
+            )
+        })?;
+        // SAFETY: ownership is transferred from socket to f
+        let f = unsafe { File::from_raw_fd(socket.into_raw_fd()) };
+        Ok(ParcelFileDescriptor::new(f))
+    }
+
+    fn debugGetCrashReport(&self, cid: i32) -> binder::Result<Option<CrashReport>> {
+        check_debug_access()?;
+
+        read_crash_report(Path::new(CRASH_DIRECTORY), cid as Cid).map_err(|e| {
+            error!("Failed to read crash report of VM with CID {}: {:?}", cid, e);
+            new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("Failed to read crash report: {}", e),
+            )
+        })
+    }
+
+    fn getHypervisorCapabilities(&self) -> binder::Result<HypervisorCapabilities> {
+        check_manage_access()?;
+
+        hypervisor::capabilities().map_err(|e| {
+            error!("Failed to get hypervisor capabilities: {:?}", e);
+            new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("Failed to get hypervisor capabilities: {}", e),
+            )
+        })
+    }
 }
 
 fn handle_stream_connection_tombstoned() -> Result<()> {
@@ -362,13 +478,39 @@ impl VirtualizationService {
         is_protected: &mut bool,
     ) -> binder::Result<Strong<dyn IVirtualMachine>> {
         check_manage_access()?;
-        let state = &mut *self.state.lock().unwrap();
-        let console_fd = console_fd.map(clone_file).transpose()?;
-        let log_fd = log_fd.map(clone_file).transpose()?;
         let requester_uid = ThreadState::get_calling_uid();
         let requester_sid = get_calling_sid()?;
         let requester_debug_pid = ThreadState::get_calling_pid();
         let cid = next_cid().or(Err(ExceptionCode::ILLEGAL_STATE))?;
+        self.create_vm_with_cid(
+            config,
+            console_fd,
+            log_fd,
+            is_protected,
+            cid,
+            requester_uid,
+            requester_sid,
+            requester_debug_pid,
+        )
+    }
+
+    /// Creates a VM with the given CID on behalf of the given requester, which has already been
+    /// allowed to do so.
+    #[allow(clippy::too_many_arguments)]
+    fn create_vm_with_cid(
+        &self,
+        config: &VirtualMachineConfig,
+        console_fd: Option<&ParcelFileDescriptor>,
+        log_fd: Option<&ParcelFileDescriptor>,
+        is_protected: &mut bool,
+        cid: Cid,
+        requester_uid: u32,
+        requester_sid: String,
+        requester_debug_pid: i32,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let state = &mut *self.state.lock().unwrap();
+        let console_fd = console_fd.map(clone_file).transpose()?;
+        let log_fd = log_fd.map(clone_file).transpose()?;
 
         // Counter to generate unique IDs for temporary image files.
         let mut next_temporary_image_id = 0;
@@ -395,6 +537,22 @@ impl VirtualizationService {
         })?;
 
         let is_app_config = matches!(config, VirtualMachineConfig::AppConfig(_));
+        if let VirtualMachineConfig::AppConfig(config) = config {
+            // The swap image is encrypted but not authenticated, so the host could corrupt or
+            // replay swapped out pages.
+            if config.protectedVm && config.swapImage.is_some() {
+                return Err(new_binder_exception(
+                    ExceptionCode::ILLEGAL_ARGUMENT,
+                    "Swap isn't supported in protected VMs",
+                ));
+            }
+        }
+        let shared_dirs = match config {
+            VirtualMachineConfig::AppConfig(config) => app_shared_dirs(config)?,
+            VirtualMachineConfig::RawConfig(config) => {
+                config.sharedPaths.iter().map(to_shared_dir).collect::<binder::Result<_>>()?
+            }
+        };
 
         let config = match config {
             VirtualMachineConfig::AppConfig(config) => BorrowedOrOwned::Owned(
@@ -455,9 +613,61 @@ impl VirtualizationService {
             })
             .collect::<Result<Vec<DiskFile>, _>>()?;
 
+        let console_output = console_fd.as_ref().map(File::try_clone).transpose().map_err(|e| {
+            new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("Failed to clone console file: {}", e),
+            )
+        })?;
+        let (console, console_socket) = Console::new(console_output).map_err(|e| {
+            error!("Failed to create console: {}", e);
+            new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("Failed to create console: {}", e),
+            )
+        })?;
+
+        let (console_ports, console_port_sockets) = create_console_ports(&config.consolePorts)
+            .map_err(|e| {
+                error!("Failed to create console ports: {:?}", e);
+                new_binder_exception(
+                    ExceptionCode::ILLEGAL_ARGUMENT,
+                    format!("Failed to create console ports: {}", e),
+                )
+            })?;
+
+        let (input_devices, input_device_files) = create_input_devices(&config.inputDevices)
+            .map_err(|e| {
+                error!("Failed to create input devices: {:?}", e);
+                new_binder_exception(
+                    ExceptionCode::ILLEGAL_ARGUMENT,
+                    format!("Failed to create input devices: {}", e),
+                )
+            })?;
+
+        if !config.portForwardings.is_empty() {
+            check_debug_access()?;
+        }
+        let port_forwarders =
+            create_port_forwarders(cid, &config.portForwardings).map_err(|e| {
+                error!("Failed to set up port forwarding: {:?}", e);
+                new_binder_exception(
+                    ExceptionCode::SERVICE_SPECIFIC,
+                    format!("Failed to set up port forwarding: {}", e),
+                )
+            })?;
+
+        if config.sandboxProfile == SandboxProfile::DEBUG_GDB {
+            check_debug_access()?;
+        }
+
+        let audio = config.audioConfig.as_ref().map(to_audio_device).transpose()?;
+        let display = config.displayConfig.as_ref().map(to_display).transpose()?;
+
         // Actually start the VM.
         let crosvm_config = CrosvmConfig {
             cid,
+            name: config.name.clone(),
             bootloader: maybe_clone_file(&config.bootloader)?,
             kernel: maybe_clone_file(&config.kernel)?,
             initrd: maybe_clone_file(&config.initrd)?,
@@ -469,13 +679,27 @@ impl VirtualizationService {
             cpu_affinity: config.cpuAffinity.clone(),
             task_profiles: config.taskProfiles.clone(),
             console_fd,
+            console_socket,
             log_fd,
+            console_port_sockets,
             indirect_files,
             platform_version: parse_platform_version_req(&config.platformVersion)?,
+            restore_snapshot: maybe_clone_file(&config.restoreSnapshot)?,
+            gpu_backend: config.gpuBackend,
+            sandbox_profile: config.sandboxProfile,
+            gdb_port: config.gdbPort.try_into().ok().and_then(NonZeroU16::new),
+            shared_dirs,
+            audio,
+            input_device_files,
+            display,
         };
         let instance = Arc::new(
             VmInstance::new(
                 crosvm_config,
+                console,
+                console_ports,
+                input_devices,
+                port_forwarders,
                 temporary_directory,
                 requester_uid,
                 requester_sid,
@@ -492,6 +716,136 @@ impl VirtualizationService {
         state.add_vm(Arc::downgrade(&instance));
         Ok(VirtualMachine::create(instance))
     }
+
+    /// Starts the early VMs declared by the device, if it enables them and that wasn't already
+    /// done since boot.
+    /// VirtualizationService holds on to them, so they keep running without any client.
+    pub fn start_early_vms(&self) {
+        if let Err(e) = self.try_start_early_vms() {
+            error!("Failed to start early VMs: {:?}", e);
+        }
+    }
+
+    fn try_start_early_vms(&self) -> Result<()> {
+        if !system_properties::read_bool(SYSPROP_EARLY_VMS_ENABLED, false)?
+            || system_properties::read_bool(SYSPROP_EARLY_VMS_STARTED, false)?
+        {
+            return Ok(());
+        }
+        // Set this first, so that an early VM which crashes VirtualizationService isn't retried
+        // every time the service restarts.
+        system_properties::write(SYSPROP_EARLY_VMS_STARTED, "1")?;
+
+        let vms = match File::open(EARLY_VMS_CONFIG_PATH) {
+            Ok(file) => parse_early_vms(file)
+                .with_context(|| format!("Failed to load {}", EARLY_VMS_CONFIG_PATH))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open {}", EARLY_VMS_CONFIG_PATH))
+            }
+        };
+        for vm in &vms {
+            if let Err(e) = self.start_early_vm(vm) {
+                error!("Failed to start early VM {:?}: {:?}", vm.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn start_early_vm(&self, early_vm: &EarlyVm) -> Result<()> {
+        let config_file = File::open(&early_vm.path)
+            .with_context(|| format!("Failed to open {:?}", early_vm.path))?;
+        let config = VmConfig::load(&config_file)?.to_parcelable()?;
+        check_early_vm_config(&config)?;
+
+        let mut is_protected = false;
+        let vm = self.create_vm_with_cid(
+            &VirtualMachineConfig::RawConfig(config),
+            None,
+            None,
+            &mut is_protected,
+            early_vm.cid,
+            nix::unistd::getuid().as_raw(),
+            format!("early_vm:{}", early_vm.name),
+            std::process::id() as i32,
+        )?;
+        vm.start()?;
+        info!("Started early VM {:?} with CID {}", early_vm.name, early_vm.cid);
+        self.state.lock().unwrap().early_vms.push(vm);
+        Ok(())
+    }
+}
+
+/// Creates the extra console ports with the given names. Returns the ports and the sockets which
+/// crosvm should use as their devices.
+fn create_console_ports(names: &[String]) -> Result<(Vec<ConsolePort>, Vec<File>)> {
+    let mut ports: Vec<ConsolePort> = vec![];
+    let mut sockets = vec![];
+    for name in names {
+        if name.is_empty() {
+            bail!("Console port name must not be empty");
+        }
+        if ports.iter().any(|port| &port.name == name) {
+            bail!("Duplicate console port name {:?}", name);
+        }
+        let (port, socket) = ConsolePort::new(name.clone())?;
+        ports.push(port);
+        sockets.push(socket);
+    }
+    Ok((ports, sockets))
+}
+
+/// Creates the input devices given in the config. Returns the devices into which events can be
+/// injected, in the same order, and the files which crosvm should use as their devices.
+fn create_input_devices(
+    devices: &[AidlInputDevice],
+) -> Result<(Vec<Option<InputDevice>>, Vec<InputDeviceFile>)> {
+    let mut input_devices = vec![];
+    let mut files = vec![];
+    for device in devices {
+        if device.deviceType == DeviceType::EVDEV {
+            let evdev = device.evdev.as_ref().context("No evdev for EVDEV input device")?;
+            input_devices.push(None);
+            files.push(InputDeviceFile::Evdev(evdev.as_ref().try_clone()?));
+            continue;
+        }
+        let (input_device, socket) = InputDevice::new()?;
+        input_devices.push(Some(input_device));
+        files.push(match device.deviceType {
+            DeviceType::KEYBOARD => InputDeviceFile::Keyboard(socket),
+            DeviceType::MOUSE => InputDeviceFile::Mouse(socket),
+            DeviceType::MULTI_TOUCH => InputDeviceFile::MultiTouch {
+                socket,
+                width: device.width.try_into().context("Invalid multi-touch width")?,
+                height: device.height.try_into().context("Invalid multi-touch height")?,
+            },
+            device_type => bail!("Unsupported input device type {:?}", device_type),
+        });
+    }
+    Ok((input_devices, files))
+}
+
+/// Starts forwarding the host TCP ports given in `port_forwardings` to the VM with the given CID.
+fn create_port_forwarders(
+    cid: Cid,
+    port_forwardings: &[PortForwarding],
+) -> Result<Vec<PortForwarder>> {
+    port_forwardings
+        .iter()
+        .map(|forwarding| {
+            let host_port = forwarding
+                .hostPort
+                .try_into()
+                .ok()
+                .filter(|&port| port != 0)
+                .with_context(|| format!("Invalid host port {}", forwarding.hostPort))?;
+            let guest_port = forwarding
+                .guestPort
+                .try_into()
+                .with_context(|| format!("Invalid guest port {}", forwarding.guestPort))?;
+            PortForwarder::new(host_port, cid, guest_port)
+        })
+        .collect()
 }
 
 /// Write the stats of VMCreation to statsd
@@ -606,14 +960,19 @@ fn load_app_config(
     config: &VirtualMachineAppConfig,
     temporary_directory: &Path,
 ) -> Result<VirtualMachineRawConfig> {
-    let apk_file = clone_file(config.apk.as_ref().unwrap())?;
-    let idsig_file = clone_file(config.idsig.as_ref().unwrap())?;
     let instance_file = clone_file(config.instanceImage.as_ref().unwrap())?;
-    let config_path = &config.configPath;
-
-    let mut apk_zip = ZipArchive::new(&apk_file)?;
-    let config_file = apk_zip.by_name(config_path)?;
-    let vm_payload_config: VmPayloadConfig = serde_json::from_reader(config_file)?;
+    let (vm_payload_config, apk_and_idsig) = if let Some(binary_path) = &config.payloadBinaryPath {
+        (create_binary_payload_config(binary_path, &config.payloadBinaryArgs)?, None)
+    } else {
+        let apk_file = clone_file(config.apk.as_ref().context("No APK or payload binary")?)?;
+        let idsig_file = clone_file(config.idsig.as_ref().context("No idsig for the APK")?)?;
+        let config_path = &config.configPath;
+
+        let mut apk_zip = ZipArchive::new(&apk_file)?;
+        let config_file = apk_zip.by_name(config_path)?;
+        let vm_payload_config = VmPayloadConfig::load(config_file)?;
+        (vm_payload_config, Some((apk_file, idsig_file)))
+    };
 
     let os_name = &vm_payload_config.os.name;
 
@@ -632,6 +991,7 @@ fn load_app_config(
         vm_config.memoryMib = config.memoryMib;
     }
 
+    vm_config.name = config.name.clone();
     vm_config.protectedVm = config.protectedVm;
     vm_config.numCpus = config.numCpus;
     vm_config.cpuAffinity = config.cpuAffinity.clone();
@@ -642,8 +1002,7 @@ fn load_app_config(
         add_microdroid_images(
             config,
             temporary_directory,
-            apk_file,
-            idsig_file,
+            apk_and_idsig,
             instance_file,
             &vm_payload_config,
             &mut vm_config,
@@ -653,6 +1012,103 @@ fn load_app_config(
     Ok(vm_config)
 }
 
+/// Returns the host directory to share with a Microdroid VM, if any, after checking that the caller
+/// is allowed to share it.
+fn app_shared_dirs(config: &VirtualMachineAppConfig) -> binder::Result<Vec<SharedDir>> {
+    let path = match &config.sharedDirectory {
+        Some(path) => path,
+        None => return Ok(vec![]),
+    };
+    check_debug_access()?;
+    if config.protectedVm || config.debugLevel != DebugLevel::FULL {
+        return Err(new_binder_exception(
+            ExceptionCode::ILLEGAL_ARGUMENT,
+            "A shared directory needs a non-protected VM with the FULL debug level",
+        ));
+    }
+    let directory = File::open(path).map_err(|e| {
+        new_binder_exception(
+            ExceptionCode::SERVICE_SPECIFIC,
+            format!("Failed to open shared directory {}: {}", path, e),
+        )
+    })?;
+    Ok(vec![SharedDir {
+        directory,
+        tag: SHARED_DIRECTORY_TAG.to_owned(),
+        guest_uid: 0,
+        guest_gid: 0,
+    }])
+}
+
+/// Converts the audio config from a raw config, after checking that the caller may use the host's
+/// audio devices.
+fn to_audio_device(audio_config: &AudioConfig) -> binder::Result<AudioDevice> {
+    check_audio_access()?;
+    if audio_config.useMicrophone {
+        check_permission("android.permission.RECORD_AUDIO")?;
+    }
+    Ok(AudioDevice { capture: audio_config.useMicrophone, playback: audio_config.useSpeaker })
+}
+
+/// Converts the display config from a raw config. The display isn't bound to a compositor yet.
+fn to_display(display_config: &DisplayConfig) -> binder::Result<Display> {
+    let to_size = |size: i32| -> binder::Result<u32> {
+        size.try_into().map_err(|_| {
+            new_binder_exception(
+                ExceptionCode::ILLEGAL_ARGUMENT,
+                format!("Invalid display size {}x{}", display_config.width, display_config.height),
+            )
+        })
+    };
+    Ok(Display {
+        width: to_size(display_config.width)?,
+        height: to_size(display_config.height)?,
+        compositor: None,
+    })
+}
+
+/// Converts a shared path from a raw config, whose directory the client has opened itself, after
+/// checking that the caller is allowed to share it. crosvm lets the guest modify the directory with
+/// its own credentials, so the caller must own the directory and be allowed to debug VMs.
+fn to_shared_dir(shared_path: &SharedPath) -> binder::Result<SharedDir> {
+    check_debug_access()?;
+    let to_id = |id: i32, name: &str| -> binder::Result<u32> {
+        id.try_into().map_err(|_| {
+            new_binder_exception(
+                ExceptionCode::ILLEGAL_ARGUMENT,
+                format!("Invalid {} {} for shared path {:?}", name, id, shared_path.tag),
+            )
+        })
+    };
+    let directory = shared_path.directory.as_ref().ok_or_else(|| {
+        new_binder_exception(
+            ExceptionCode::ILLEGAL_ARGUMENT,
+            format!("No directory for shared path {:?}", shared_path.tag),
+        )
+    })?;
+    let directory = clone_file(directory)?;
+    let owner = directory.metadata().map(|metadata| metadata.uid()).map_err(|e| {
+        new_binder_exception(
+            ExceptionCode::SERVICE_SPECIFIC,
+            format!("Failed to stat shared path {:?}: {}", shared_path.tag, e),
+        )
+    })?;
+    let calling_uid = ThreadState::get_calling_uid();
+    // Root can share anything, as with permissions.
+    if calling_uid != 0 && owner != calling_uid {
+        return Err(new_binder_exception(
+            ExceptionCode::SECURITY,
+            format!("Shared path {:?} isn't owned by the caller", shared_path.tag),
+        ));
+    }
+    Ok(SharedDir {
+        directory,
+        tag: shared_path.tag.clone(),
+        guest_uid: to_id(shared_path.guestUid, "guest UID")?,
+        guest_gid: to_id(shared_path.guestGid, "guest GID")?,
+    })
+}
+
 /// Generates a unique filename to use for a composite disk image.
 fn make_composite_image_filenames(
     temporary_directory: &Path,
@@ -729,6 +1185,11 @@ fn check_manage_access() -> binder::Result<()> {
     check_permission("android.permission.MANAGE_VIRTUAL_MACHINE")
 }
 
+/// Check whether the caller of the current Binder method is allowed to give VMs audio devices
+fn check_audio_access() -> binder::Result<()> {
+    check_permission("android.permission.USE_VIRTUAL_MACHINE_AUDIO")
+}
+
 /// Check if a partition has selinux labels that are not allowed
 fn check_label_for_partition(partition: &Partition) -> Result<()> {
     let ctx = getfilecon(partition.image.as_ref().unwrap().as_ref())?;
@@ -802,6 +1263,115 @@ impl IVirtualMachine for VirtualMachine {
             })?;
         Ok(vsock_stream_to_pfd(stream))
     }
+
+    fn getPayloadServiceNames(&self) -> binder::Result<Vec<String>> {
+        Ok(self.instance.payload_services.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn getPayloadServicePort(&self, name: &str) -> binder::Result<i32> {
+        let port = self.instance.payload_services.lock().unwrap().get(name).copied();
+        port.map(|port| port as i32).ok_or_else(|| {
+            new_binder_exception(
+                ExceptionCode::ILLEGAL_ARGUMENT,
+                format!("The payload hasn't registered a service named {:?}", name),
+            )
+        })
+    }
+
+    fn openConsolePort(&self, name: &str) -> binder::Result<ParcelFileDescriptor> {
+        let port =
+            self.instance.console_ports.iter().find(|port| port.name == name).ok_or_else(|| {
+                new_binder_exception(
+                    ExceptionCode::ILLEGAL_ARGUMENT,
+                    format!("No console port named {:?}", name),
+                )
+            })?;
+        let file = port.open().map_err(|e| {
+            new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("Failed to open console port: {}", e),
+            )
+        })?;
+        Ok(ParcelFileDescriptor::new(file))
+    }
+
+    fn injectInputEvents(&self, device: i32, events: &[InputEvent]) -> binder::Result<()> {
+        check_manage_access()?;
+        let input_device = usize::try_from(device)
+            .ok()
+            .and_then(|device| self.instance.input_devices.get(device))
+            .and_then(Option::as_ref)
+            .ok_or_else(|| {
+                new_binder_exception(
+                    ExceptionCode::ILLEGAL_ARGUMENT,
+                    format!("No input device {} to inject events into", device),
+                )
+            })?;
+        input_device.inject(events).map_err(|e| {
+            error!("Error injecting input events into VM with CID {}: {:?}", self.instance.cid, e);
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+        })
+    }
+
+    fn bindDisplay(
+        &self,
+        compositor_directory: &ParcelFileDescriptor,
+        socket_name: &str,
+    ) -> binder::Result<()> {
+        check_manage_access()?;
+        let directory = clone_file(compositor_directory)?;
+        self.instance.bind_display(directory, socket_name.to_owned()).map_err(|e| {
+            error!("Error binding display of VM with CID {}: {:?}", self.instance.cid, e);
+            new_binder_exception(ExceptionCode::ILLEGAL_STATE, e.to_string())
+        })
+    }
+
+    fn pauseVm(&self) -> binder::Result<()> {
+        check_manage_access()?;
+        self.instance.pause().map_err(|e| {
+            error!("Error pausing VM with CID {}: {:?}", self.instance.cid, e);
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+        })
+    }
+
+    fn resumeVm(&self) -> binder::Result<()> {
+        check_manage_access()?;
+        self.instance.resume().map_err(|e| {
+            error!("Error resuming VM with CID {}: {:?}", self.instance.cid, e);
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+        })
+    }
+
+    fn setMemoryBalloon(&self, size_mib: i64) -> binder::Result<()> {
+        check_manage_access()?;
+        let size_mib = size_mib.try_into().map_err(|_| {
+            new_binder_exception(
+                ExceptionCode::ILLEGAL_ARGUMENT,
+                format!("Invalid balloon size {}", size_mib),
+            )
+        })?;
+        self.instance.set_memory_balloon(size_mib).map_err(|e| {
+            error!("Error setting memory balloon of VM with CID {}: {:?}", self.instance.cid, e);
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+        })
+    }
+
+    fn getMemoryStats(&self) -> binder::Result<MemoryStats> {
+        check_manage_access()?;
+        self.instance.memory_stats(None).map_err(|e| {
+            error!("Error getting memory stats of VM with CID {}: {:?}", self.instance.cid, e);
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+        })
+    }
+
+    fn snapshot(&self, snapshot_fd: &ParcelFileDescriptor) -> binder::Result<()> {
+        check_manage_access()?;
+        let mut output = clone_file(snapshot_fd)?;
+        self.instance.snapshot(&mut output).map_err(|e| {
+            error!("Error taking a snapshot of VM with CID {}: {:?}", self.instance.cid, e);
+            new_binder_exception(ExceptionCode::SERVICE_SPECIFIC, e.to_string())
+        })
+    }
 }
 
 impl Drop for VirtualMachine {
@@ -887,6 +1457,9 @@ struct State {
     /// Vector of strong VM references held on behalf of users that cannot hold them themselves.
     /// This is only used for debugging purposes.
     debug_held_vms: Vec<Strong<dyn IVirtualMachine>>,
+
+    /// The early VMs started by VirtualizationService itself, which have no other client.
+    early_vms: Vec<Strong<dyn IVirtualMachine>>,
 }
 
 impl State {
@@ -1035,6 +1608,21 @@ impl IVirtualMachineService for VirtualMachineService {
         }
     }
 
+    fn notifyBootTimes(&self, boot_times: &BootTimes) -> binder::Result<()> {
+        let cid = self.cid;
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            info!("VM having CID {} reported boot times {:?}", cid, boot_times);
+            vm.set_boot_times(boot_times.clone());
+            Ok(())
+        } else {
+            error!("notifyBootTimes is called from an unknown CID {}", cid);
+            Err(new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("cannot find a VM with CID {}", cid),
+            ))
+        }
+    }
+
     fn notifyPayloadReady(&self) -> binder::Result<()> {
         let cid = self.cid;
         if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
@@ -1085,6 +1673,36 @@ impl IVirtualMachineService for VirtualMachineService {
             ))
         }
     }
+
+    fn notifyServiceRegistered(&self, name: &str, port: i32) -> binder::Result<()> {
+        let cid = self.cid;
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            if name.is_empty() || name.len() > MAX_PAYLOAD_SERVICE_NAME_LENGTH || port <= 0 {
+                return Err(new_binder_exception(
+                    ExceptionCode::ILLEGAL_ARGUMENT,
+                    format!("Invalid service {:?} on port {}", name, port),
+                ));
+            }
+            let mut payload_services = vm.payload_services.lock().unwrap();
+            if !payload_services.contains_key(name)
+                && payload_services.len() >= MAX_PAYLOAD_SERVICES
+            {
+                return Err(new_binder_exception(
+                    ExceptionCode::ILLEGAL_STATE,
+                    format!("Too many services, can't register {}", name),
+                ));
+            }
+            info!("VM having CID {} registered service {} on port {}", cid, name, port);
+            payload_services.insert(name.to_owned(), port as u32);
+            Ok(())
+        } else {
+            error!("notifyServiceRegistered is called from an unknown CID {}", cid);
+            Err(new_binder_exception(
+                ExceptionCode::SERVICE_SPECIFIC,
+                format!("cannot find a VM with CID {}", cid),
+            ))
+        }
+    }
 }
 
 impl VirtualMachineService {
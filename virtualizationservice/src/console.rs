@@ -0,0 +1,193 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relays the console of a VM to its client and to debug consoles attached to it.
+
+use crate::crash::{RingBuffer, MAX_CAPTURED_OUTPUT};
+use anyhow::{Context, Error};
+use log::{debug, error, warn};
+use nix::errno::Errno;
+use nix::sys::socket::{send, MsgFlags};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const BUFFER_SIZE: usize = 4096;
+
+/// The most output which is kept for an attached console that isn't reading it. A console which
+/// falls further behind than this is detached, so that it can't hold up the VM or other consoles.
+const MAX_PENDING_OUTPUT: usize = 64 * 1024;
+
+/// The virtio-console of a VM. The output of the VM is copied to the console file given by the
+/// client, if any, and to all attached consoles. Input from the attached consoles is sent to the
+/// VM. The most recent output is also kept for crash reports.
+#[derive(Debug)]
+pub struct Console {
+    /// Our end of the socket which crosvm uses as the console device.
+    vm_socket: UnixStream,
+    /// The attached consoles.
+    attached: Arc<Mutex<Vec<AttachedConsole>>>,
+    /// The most recent output of the VM.
+    recent_output: Arc<Mutex<RingBuffer>>,
+}
+
+impl Console {
+    /// Creates a console which copies the output of the VM to `output`, if given. Returns the
+    /// console and the file which crosvm should use as the console device.
+    pub fn new(output: Option<File>) -> Result<(Console, File), Error> {
+        let (vm_socket, crosvm_socket) =
+            UnixStream::pair().context("Failed to create console socket")?;
+        let attached = Arc::new(Mutex::new(vec![]));
+        let recent_output = Arc::new(Mutex::new(RingBuffer::new(MAX_CAPTURED_OUTPUT)));
+
+        let reader = vm_socket.try_clone()?;
+        let attached_clone = attached.clone();
+        let recent_output_clone = recent_output.clone();
+        thread::spawn(move || relay_output(reader, output, &attached_clone, &recent_output_clone));
+
+        // SAFETY: ownership is transferred from crosvm_socket to the file
+        let crosvm_socket = unsafe { File::from_raw_fd(crosvm_socket.into_raw_fd()) };
+        Ok((Console { vm_socket, attached, recent_output }, crosvm_socket))
+    }
+
+    /// Returns the most recent output of the VM, up to `MAX_CAPTURED_OUTPUT` bytes.
+    pub fn recent_output(&self) -> Vec<u8> {
+        self.recent_output.lock().unwrap().contents()
+    }
+
+    /// Attaches a new console. Returns the socket from which the output of the VM can be read and
+    /// to which input to the VM can be written.
+    pub fn attach(&self) -> Result<UnixStream, Error> {
+        let (ours, theirs) = UnixStream::pair().context("Failed to create console socket")?;
+        let mut input = ours.try_clone()?;
+        let mut vm_socket = self.vm_socket.try_clone()?;
+        thread::spawn(move || {
+            if let Err(e) = io::copy(&mut input, &mut vm_socket) {
+                debug!("Console detached: {}", e);
+            }
+        });
+        self.attached.lock().unwrap().push(AttachedConsole { socket: ours, pending: vec![] });
+        Ok(theirs)
+    }
+}
+
+/// A console attached to a VM, with the output which it hasn't read yet.
+#[derive(Debug)]
+struct AttachedConsole {
+    socket: UnixStream,
+    pending: Vec<u8>,
+}
+
+impl AttachedConsole {
+    /// Sends as much of the pending output and `data` as the socket takes without blocking.
+    /// Returns false if the console has gone away or has fallen too far behind, in which case it
+    /// should be detached.
+    fn relay(&mut self, data: &[u8]) -> bool {
+        if self.pending.len() + data.len() > MAX_PENDING_OUTPUT {
+            warn!("Detaching a console which isn't reading the output of the VM");
+            return false;
+        }
+        self.pending.extend_from_slice(data);
+        // The socket is shared with the thread which relays the input, so it is left blocking and
+        // each send is made non-blocking instead.
+        while !self.pending.is_empty() {
+            match send(self.socket.as_raw_fd(), &self.pending, MsgFlags::MSG_DONTWAIT) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(Errno::EINTR) => continue,
+                Err(Errno::EAGAIN) => break,
+                Err(e) => {
+                    debug!("Console detached: {}", e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// An extra virtio-console port of a VM, which the client of the VM can open by name.
+#[derive(Debug)]
+pub struct ConsolePort {
+    /// The name by which the client refers to the port.
+    pub name: String,
+    /// Our end of the socket which crosvm uses as the console device.
+    socket: UnixStream,
+}
+
+impl ConsolePort {
+    /// Creates a console port with the given name. Returns the port and the file which crosvm
+    /// should use as the console device.
+    pub fn new(name: String) -> Result<(ConsolePort, File), Error> {
+        let (socket, crosvm_socket) =
+            UnixStream::pair().context("Failed to create console port socket")?;
+        // SAFETY: ownership is transferred from crosvm_socket to the file
+        let crosvm_socket = unsafe { File::from_raw_fd(crosvm_socket.into_raw_fd()) };
+        Ok((ConsolePort { name, socket }, crosvm_socket))
+    }
+
+    /// Returns a file from which the output of the port can be read and to which input can be
+    /// written. All files returned for the same port share the same stream.
+    pub fn open(&self) -> Result<File, Error> {
+        let socket = self.socket.try_clone()?;
+        // SAFETY: ownership is transferred from socket to the file
+        Ok(unsafe { File::from_raw_fd(socket.into_raw_fd()) })
+    }
+}
+
+/// Copies the output of the VM until crosvm closes the console device, then disconnects the
+/// attached consoles.
+fn relay_output(
+    mut reader: UnixStream,
+    mut output: Option<File>,
+    attached: &Mutex<Vec<AttachedConsole>>,
+    recent_output: &Mutex<RingBuffer>,
+) {
+    let mut buf = [0u8; BUFFER_SIZE];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Failed to read from the VM console: {}", e);
+                break;
+            }
+        };
+        recent_output.lock().unwrap().push(&buf[..n]);
+        if let Some(file) = &mut output {
+            if let Err(e) = file.write_all(&buf[..n]) {
+                error!("Failed to write to the console file: {}", e);
+                output = None;
+            }
+        }
+        attached.lock().unwrap().retain_mut(|console| {
+            let keep = console.relay(&buf[..n]);
+            if !keep {
+                // This also stops the thread which relays the input from the console.
+                let _ = console.socket.shutdown(Shutdown::Both);
+            }
+            keep
+        });
+    }
+    for console in attached.lock().unwrap().drain(..) {
+        // This also stops the thread which relays the input from the console.
+        let _ = console.socket.shutdown(Shutdown::Both);
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Collects crash reports of VMs which died unexpectedly, so that they survive the VM.
+
+use crate::Cid;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    CrashReport::CrashReport, DeathReason::DeathReason,
+};
+use anyhow::{Context, Error};
+use std::collections::VecDeque;
+use std::fs::{create_dir_all, read, read_to_string, remove_dir_all, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Directory where crash reports are stored, in a subdirectory per CID. Unlike the temporary
+/// directories of VMs, this is kept when VirtualizationService restarts.
+pub const CRASH_DIRECTORY: &str = "/data/misc/virtualizationservice/crashes";
+
+/// The maximum amount of console output and crosvm stderr which is kept for a crash report.
+pub const MAX_CAPTURED_OUTPUT: usize = 64 * 1024;
+
+const DEATH_REASON_FILE_NAME: &str = "death_reason";
+const CONSOLE_FILE_NAME: &str = "console";
+const CROSVM_STDERR_FILE_NAME: &str = "crosvm_stderr";
+
+/// Keeps the last `capacity` bytes written to it.
+#[derive(Debug)]
+pub struct RingBuffer {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer { buffer: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Appends `data`, dropping the oldest bytes if the buffer is full.
+    pub fn push(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buffer.len() + data.len()).saturating_sub(self.capacity);
+        self.buffer.drain(..overflow);
+        self.buffer.extend(data);
+    }
+
+    /// Returns the bytes currently in the buffer, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.iter().copied().collect()
+    }
+}
+
+/// Returns whether a VM which died for the given reason crashed, rather than being shut down or
+/// killed deliberately.
+pub fn is_crash(reason: DeathReason) -> bool {
+    matches!(
+        reason,
+        DeathReason::UNKNOWN
+            | DeathReason::ERROR
+            | DeathReason::REBOOT
+            | DeathReason::CRASH
+            | DeathReason::PVM_FIRMWARE_BOOT_TIMEOUT
+    )
+}
+
+/// Writes the crash report of the VM with the given CID under `crash_directory`, replacing any
+/// earlier report for the same CID. Returns the directory of the report.
+pub fn write_crash_report(
+    crash_directory: &Path,
+    cid: Cid,
+    reason: DeathReason,
+    console_output: &[u8],
+    crosvm_stderr: &[u8],
+) -> Result<PathBuf, Error> {
+    let directory = report_directory(crash_directory, cid);
+    match remove_dir_all(&directory) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).context("Failed to remove old crash report")
+        }
+        _ => {}
+    }
+    create_dir_all(&directory).context("Failed to create crash report directory")?;
+    write(directory.join(DEATH_REASON_FILE_NAME), reason.0.to_string())?;
+    write(directory.join(CONSOLE_FILE_NAME), console_output)?;
+    write(directory.join(CROSVM_STDERR_FILE_NAME), crosvm_stderr)?;
+    Ok(directory)
+}
+
+/// Reads the crash report of the VM with the given CID from `crash_directory`, if there is one.
+pub fn read_crash_report(crash_directory: &Path, cid: Cid) -> Result<Option<CrashReport>, Error> {
+    let directory = report_directory(crash_directory, cid);
+    let death_reason = match read_to_string(directory.join(DEATH_REASON_FILE_NAME)) {
+        Ok(death_reason) => DeathReason(death_reason.trim().parse()?),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read death reason"),
+    };
+    let console_output = read(directory.join(CONSOLE_FILE_NAME))?;
+    let crosvm_stderr = read(directory.join(CROSVM_STDERR_FILE_NAME))?;
+    Ok(Some(CrashReport {
+        cid: cid as i32,
+        deathReason: death_reason,
+        directory: directory.to_string_lossy().into_owned(),
+        consoleOutput: String::from_utf8_lossy(&console_output).into_owned(),
+        crosvmStderr: String::from_utf8_lossy(&crosvm_stderr).into_owned(),
+    }))
+}
+
+fn report_directory(crash_directory: &Path, cid: Cid) -> PathBuf {
+    crash_directory.join(cid.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer_keeps_last_bytes() {
+        let mut buffer = RingBuffer::new(4);
+        buffer.push(b"ab");
+        assert_eq!(buffer.contents(), b"ab");
+        buffer.push(b"cde");
+        assert_eq!(buffer.contents(), b"bcde");
+        buffer.push(b"0123456789");
+        assert_eq!(buffer.contents(), b"6789");
+    }
+
+    #[test]
+    fn crash_report_round_trip() -> Result<(), Error> {
+        let crash_directory = tempfile::tempdir()?;
+        assert!(read_crash_report(crash_directory.path(), 42)?.is_none());
+
+        write_crash_report(crash_directory.path(), 42, DeathReason::CRASH, b"old", b"")?;
+        write_crash_report(crash_directory.path(), 42, DeathReason::REBOOT, b"panic", b"error")?;
+
+        let report = read_crash_report(crash_directory.path(), 42)?.unwrap();
+        assert_eq!(report.cid, 42);
+        assert_eq!(report.deathReason, DeathReason::REBOOT);
+        assert_eq!(report.consoleOutput, "panic");
+        assert_eq!(report.crosvmStderr, "error");
+        Ok(())
+    }
+}
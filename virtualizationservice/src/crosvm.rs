@@ -15,24 +15,39 @@
 //! Functions for running instances of `crosvm`.
 
 use crate::aidl::VirtualMachineCallbacks;
+use crate::console::{Console, ConsolePort};
+use crate::crash::{is_crash, write_crash_report, RingBuffer, CRASH_DIRECTORY, MAX_CAPTURED_OUTPUT};
+use crate::input::InputDevice;
+use crate::port_forwarding::PortForwarder;
+use crate::resources::{process_usage, ProcessUsage};
 use crate::Cid;
-use anyhow::{bail, Error};
+use anyhow::{bail, Context, Error};
 use command_fds::CommandFdExt;
 use log::{debug, error, info};
 use semver::{Version, VersionReq};
-use nix::{fcntl::OFlag, unistd::pipe2};
+use nix::fcntl::OFlag;
+use nix::unistd::{getgid, getuid, pipe2};
+use regex::Regex;
+use serde::Deserialize;
 use shared_child::SharedChild;
-use std::fs::{remove_dir_all, File};
-use std::io::{self, Read};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{remove_dir_all, remove_file, File};
+use std::io::{self, BufRead, BufReader, Read};
 use std::mem;
-use std::num::NonZeroU32;
+use std::num::{NonZeroU16, NonZeroU32};
 use std::os::unix::io::{AsRawFd, RawFd, FromRawFd};
-use std::path::PathBuf;
-use std::process::{Command, ExitStatus};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStderr, Command, ExitStatus, Stdio};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use vsock::VsockStream;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::BootTimes::BootTimes;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::DeathReason::DeathReason;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::GpuBackend::GpuBackend;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::SandboxProfile::SandboxProfile;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::MemoryStats::MemoryStats;
 use android_system_virtualmachineservice::binder::Strong;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
 
@@ -51,10 +66,20 @@ const CROSVM_REBOOT_STATUS: i32 = 32;
 /// The exit status which crosvm returns when it crashes due to an error.
 const CROSVM_CRASH_STATUS: i32 = 33;
 
+/// Name of the crosvm control socket in the temporary directory of a VM.
+const CROSVM_CONTROL_SOCKET_NAME: &str = "crosvm.sock";
+
+/// How often to check whether a crosvm control command with a timeout has finished.
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Name of the file in the temporary directory of a VM where crosvm writes a snapshot.
+const SNAPSHOT_FILE_NAME: &str = "snapshot";
+
 /// Configuration for a VM to run with crosvm.
 #[derive(Debug)]
 pub struct CrosvmConfig {
     pub cid: Cid,
+    pub name: String,
     pub bootloader: Option<File>,
     pub kernel: Option<File>,
     pub initrd: Option<File>,
@@ -66,9 +91,27 @@ pub struct CrosvmConfig {
     pub cpu_affinity: Option<String>,
     pub task_profiles: Vec<String>,
     pub console_fd: Option<File>,
+    /// The socket which is used as the virtio-console device. See `Console`.
+    pub console_socket: File,
     pub log_fd: Option<File>,
+    /// The sockets which are used as the extra virtio-console devices. See `ConsolePort`.
+    pub console_port_sockets: Vec<File>,
     pub indirect_files: Vec<File>,
     pub platform_version: VersionReq,
+    /// Snapshot to restore the VM from, instead of booting it.
+    pub restore_snapshot: Option<File>,
+    pub gpu_backend: GpuBackend,
+    pub sandbox_profile: SandboxProfile,
+    /// The port to serve a GDB stub on, with `SandboxProfile::DEBUG_GDB`.
+    pub gdb_port: Option<NonZeroU16>,
+    /// Host directories to share with the VM through virtio-fs.
+    pub shared_dirs: Vec<SharedDir>,
+    /// The virtio-snd device to give the VM, if any.
+    pub audio: Option<AudioDevice>,
+    /// The files which are used as the virtio-input devices.
+    pub input_device_files: Vec<InputDeviceFile>,
+    /// The display of the virtio-gpu device, if any.
+    pub display: Option<Display>,
 }
 
 /// A disk image to pass to crosvm for a VM.
@@ -78,6 +121,53 @@ pub struct DiskFile {
     pub writable: bool,
 }
 
+/// A host directory to share with a VM through a virtio-fs device.
+#[derive(Debug)]
+pub struct SharedDir {
+    /// The directory on the host.
+    pub directory: File,
+    /// The tag which the guest uses to mount the device.
+    pub tag: String,
+    /// The UID in the guest which crosvm's user maps to.
+    pub guest_uid: u32,
+    /// The GID in the guest which crosvm's group maps to.
+    pub guest_gid: u32,
+}
+
+/// A virtio-snd device connected to the host's audio HAL through AAudio.
+#[derive(Debug)]
+pub struct AudioDevice {
+    /// Whether the VM can capture audio from the host's microphone.
+    pub capture: bool,
+    /// Whether the VM can play audio through the host's speaker.
+    pub playback: bool,
+}
+
+/// The display of the virtio-gpu device of a VM.
+#[derive(Debug)]
+pub struct Display {
+    /// The width of the display, in pixels.
+    pub width: u32,
+    /// The height of the display, in pixels.
+    pub height: u32,
+    /// The directory of the socket of the Wayland compositor which presents the display, and the
+    /// name of the socket, once the client has bound it.
+    pub compositor: Option<(File, String)>,
+}
+
+/// The file which crosvm reads the events of a virtio-input device from.
+#[derive(Debug)]
+pub enum InputDeviceFile {
+    /// A socket for a keyboard. See `InputDevice`.
+    Keyboard(File),
+    /// A socket for a mouse. See `InputDevice`.
+    Mouse(File),
+    /// A socket for a multi-touch surface of the given size. See `InputDevice`.
+    MultiTouch { socket: File, width: u32, height: u32 },
+    /// A host evdev device to forward.
+    Evdev(File),
+}
+
 /// The lifecycle state which the payload in the VM has reported itself to be in.
 ///
 /// Note that the order of enum variants is significant; only forward transitions are allowed by
@@ -119,11 +209,17 @@ impl VmState {
             let (failure_pipe_read, failure_pipe_write) = create_pipe()?;
 
             // If this fails and returns an error, `self` will be left in the `Failed` state.
-            let child = Arc::new(run_vm(config, failure_pipe_write)?);
+            let control_socket_path = instance.crosvm_control_socket_path();
+            let child = Arc::new(run_vm(config, &control_socket_path, failure_pipe_write)?);
+            *instance.start_time.lock().unwrap() = Some(Instant::now());
 
+            let stderr_relay = child.take_stderr().map(|stderr| {
+                let crosvm_stderr = instance.crosvm_stderr.clone();
+                thread::spawn(move || relay_crosvm_stderr(stderr, &crosvm_stderr))
+            });
             let child_clone = child.clone();
             thread::spawn(move || {
-                instance.monitor(child_clone, failure_pipe_read);
+                instance.monitor(child_clone, failure_pipe_read, stderr_relay);
             });
 
             // If it started correctly, update the state.
@@ -143,8 +239,14 @@ pub struct VmInstance {
     pub vm_state: Mutex<VmState>,
     /// The CID assigned to the VM for vsock communication.
     pub cid: Cid,
+    /// The name given to the VM in its config, which may be empty.
+    pub name: String,
     /// Whether the VM is a protected VM.
     pub protected: bool,
+    /// The amount of RAM given to the VM, if not the default.
+    pub memory_mib: Option<NonZeroU32>,
+    /// Number of vCPUs in the VM, if not the default.
+    pub cpus: Option<NonZeroU32>,
     /// Directory of temporary files used by the VM while it is running.
     pub temporary_directory: PathBuf,
     /// The UID of the process which requested the VM.
@@ -162,12 +264,36 @@ pub struct VmInstance {
     pub vm_service: Mutex<Option<Strong<dyn IVirtualMachineService>>>,
     /// The latest lifecycle state which the payload reported itself to be in.
     payload_state: Mutex<PayloadState>,
+    /// The console of the VM, which debug consoles can be attached to.
+    pub console: Console,
+    /// The extra console ports of the VM, in the order of their devices.
+    pub console_ports: Vec<ConsolePort>,
+    /// The virtio-input devices of the VM, in the order of their devices. Forwarded evdev devices
+    /// are `None`, as events can't be injected into them.
+    pub input_devices: Vec<Option<InputDevice>>,
+    /// Whether the VM has been paused by `pause`.
+    paused: Mutex<bool>,
+    /// When crosvm was started, if it has been.
+    start_time: Mutex<Option<Instant>>,
+    /// The boot time breakdown which the payload reported, if it has.
+    boot_times: Mutex<Option<BootTimes>>,
+    /// The most recent stderr output of crosvm, for crash reports.
+    crosvm_stderr: Arc<Mutex<RingBuffer>>,
+    /// Forwarders of host TCP ports to vsock ports of the VM, which are stopped when it dies.
+    port_forwarders: Mutex<Vec<PortForwarder>>,
+    /// The vsock ports of the RPC binder services which the payload has registered, by name.
+    pub payload_services: Mutex<BTreeMap<String, u32>>,
 }
 
 impl VmInstance {
     /// Validates the given config and creates a new `VmInstance` but doesn't start running it.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: CrosvmConfig,
+        console: Console,
+        console_ports: Vec<ConsolePort>,
+        input_devices: Vec<Option<InputDevice>>,
+        port_forwarders: Vec<PortForwarder>,
         temporary_directory: PathBuf,
         requester_uid: u32,
         requester_sid: String,
@@ -175,11 +301,17 @@ impl VmInstance {
     ) -> Result<VmInstance, Error> {
         validate_config(&config)?;
         let cid = config.cid;
+        let name = config.name.clone();
         let protected = config.protected;
+        let memory_mib = config.memory_mib;
+        let cpus = config.cpus;
         Ok(VmInstance {
             vm_state: Mutex::new(VmState::NotStarted { config }),
             cid,
+            name,
             protected,
+            memory_mib,
+            cpus,
             temporary_directory,
             requester_uid,
             requester_sid,
@@ -188,6 +320,15 @@ impl VmInstance {
             stream: Mutex::new(None),
             vm_service: Mutex::new(None),
             payload_state: Mutex::new(PayloadState::Starting),
+            console,
+            console_ports,
+            input_devices,
+            paused: Mutex::new(false),
+            start_time: Mutex::new(None),
+            boot_times: Mutex::new(None),
+            crosvm_stderr: Arc::new(Mutex::new(RingBuffer::new(MAX_CAPTURED_OUTPUT))),
+            port_forwarders: Mutex::new(port_forwarders),
+            payload_services: Mutex::new(BTreeMap::new()),
         })
     }
 
@@ -197,12 +338,17 @@ impl VmInstance {
         self.vm_state.lock().unwrap().start(self.clone())
     }
 
-    /// Waits for the crosvm child process to finish, then marks the VM as no longer running and
-    /// calls any callbacks.
+    /// Waits for the crosvm child process to finish, then marks the VM as no longer running, writes
+    /// a crash report if it crashed and calls any callbacks.
     ///
     /// This takes a separate reference to the `SharedChild` rather than using the one in
     /// `self.vm_state` to avoid holding the lock on `vm_state` while it is running.
-    fn monitor(&self, child: Arc<SharedChild>, mut failure_pipe_read: File) {
+    fn monitor(
+        &self,
+        child: Arc<SharedChild>,
+        mut failure_pipe_read: File,
+        stderr_relay: Option<JoinHandle<()>>,
+    ) {
         let result = child.wait();
         match &result {
             Err(e) => error!("Error waiting for crosvm({}) instance to die: {}", child.id(), e),
@@ -214,6 +360,8 @@ impl VmInstance {
         // Ensure that the mutex is released before calling the callbacks.
         drop(vm_state);
 
+        self.port_forwarders.lock().unwrap().clear();
+
         let mut failure_string = String::new();
         let failure_read_result = failure_pipe_read.read_to_string(&mut failure_string);
         if let Err(e) = &failure_read_result {
@@ -223,7 +371,17 @@ impl VmInstance {
             info!("VM returned failure reason '{}'", failure_string);
         }
 
-        self.callbacks.callback_on_died(self.cid, death_reason(&result, &failure_string));
+        // crosvm has exited, so the relay finishes once it has read the rest of the output.
+        if let Some(stderr_relay) = stderr_relay {
+            let _ = stderr_relay.join();
+        }
+
+        let reason = death_reason(&result, &failure_string);
+        if is_crash(reason) {
+            self.write_crash_report(reason);
+        }
+
+        self.callbacks.callback_on_died(self.cid, reason);
 
         // Delete temporary files.
         if let Err(e) = remove_dir_all(&self.temporary_directory) {
@@ -231,6 +389,22 @@ impl VmInstance {
         }
     }
 
+    /// Saves the recent console output and crosvm stderr of the VM as its crash report.
+    fn write_crash_report(&self, reason: DeathReason) {
+        let console_output = self.console.recent_output();
+        let crosvm_stderr = self.crosvm_stderr.lock().unwrap().contents();
+        match write_crash_report(
+            Path::new(CRASH_DIRECTORY),
+            self.cid,
+            reason,
+            &console_output,
+            &crosvm_stderr,
+        ) {
+            Ok(directory) => info!("Wrote crash report of VM {} to {:?}", self.cid, directory),
+            Err(e) => error!("Failed to write crash report of VM {}: {:?}", self.cid, e),
+        }
+    }
+
     /// Returns the last reported state of the VM payload.
     pub fn payload_state(&self) -> PayloadState {
         *self.payload_state.lock().unwrap()
@@ -249,6 +423,229 @@ impl VmInstance {
         }
     }
 
+    /// Pauses the vCPUs and devices of the VM, so that it doesn't use any CPU until resumed.
+    pub fn pause(&self) -> Result<(), Error> {
+        let mut paused = self.paused.lock().unwrap();
+        if !*paused {
+            self.control_crosvm(&["suspend"])?;
+            *paused = true;
+        }
+        Ok(())
+    }
+
+    /// Resumes the VM paused by `pause`.
+    pub fn resume(&self) -> Result<(), Error> {
+        let mut paused = self.paused.lock().unwrap();
+        if *paused {
+            self.control_crosvm(&["resume"])?;
+            *paused = false;
+        }
+        Ok(())
+    }
+
+    /// Binds the display of the VM to the Wayland compositor with the given socket. This must be
+    /// done before the VM is started.
+    pub fn bind_display(&self, directory: File, socket_name: String) -> Result<(), Error> {
+        if socket_name.is_empty() || socket_name.contains(&['/', ','][..]) {
+            bail!("Invalid compositor socket name {:?}", socket_name);
+        }
+        match &mut *self.vm_state.lock().unwrap() {
+            VmState::NotStarted { config } => {
+                let display = config.display.as_mut().context("The VM has no display")?;
+                display.compositor = Some((directory, socket_name));
+                Ok(())
+            }
+            _ => bail!("The display can only be bound before the VM is started"),
+        }
+    }
+
+    /// Returns how long the VM has been running, if it is.
+    pub fn uptime(&self) -> Option<Duration> {
+        if !matches!(&*self.vm_state.lock().unwrap(), VmState::Running { .. }) {
+            return None;
+        }
+        self.start_time.lock().unwrap().map(|start_time| start_time.elapsed())
+    }
+
+    /// Records the boot time breakdown reported by the payload, estimating when the guest kernel
+    /// started from when crosvm was started.
+    pub fn set_boot_times(&self, mut boot_times: BootTimes) {
+        boot_times.kernelStartMillis = match *self.start_time.lock().unwrap() {
+            Some(start_time) if boot_times.payloadExecMillis >= 0 => {
+                let since_start = start_time.elapsed().as_millis() as i64;
+                (since_start - boot_times.payloadExecMillis).max(0)
+            }
+            _ => -1,
+        };
+        *self.boot_times.lock().unwrap() = Some(boot_times);
+    }
+
+    /// Returns the boot time breakdown reported by the payload, if it has reported one.
+    pub fn boot_times(&self) -> Option<BootTimes> {
+        self.boot_times.lock().unwrap().clone()
+    }
+
+    /// Returns the host resources used by the crosvm process of the VM, if it is running.
+    pub fn crosvm_usage(&self) -> Option<Result<ProcessUsage, Error>> {
+        match &*self.vm_state.lock().unwrap() {
+            VmState::Running { child } => Some(process_usage(child.id())),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of host TCP connections which are being forwarded to the VM.
+    pub fn forwarded_connections(&self) -> usize {
+        self.port_forwarders.lock().unwrap().iter().map(PortForwarder::connections).sum()
+    }
+
+    /// Returns whether the VM has been paused by `pause`.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// Takes a snapshot of the RAM and device state of the VM and writes it to `output`. The VM is
+    /// suspended while the snapshot is taken.
+    pub fn snapshot(&self, output: &mut File) -> Result<(), Error> {
+        if self.protected {
+            bail!("Can't take a snapshot of a protected VM");
+        }
+        let snapshot_path = self.temporary_directory.join(SNAPSHOT_FILE_NAME);
+        let snapshot_path_str = snapshot_path.to_str().unwrap(); // safe; made from a UTF-8 string
+
+        // Hold the lock so that the VM isn't paused or resumed in the meantime.
+        let paused = self.paused.lock().unwrap();
+        if !*paused {
+            self.control_crosvm(&["suspend"])?;
+        }
+        let result = self.control_crosvm(&["snapshot", "take", snapshot_path_str]);
+        // Resume the VM even if the snapshot failed, unless it was paused already.
+        let resume_result = if *paused { Ok(()) } else { self.control_crosvm(&["resume"]) };
+        drop(paused);
+
+        let result = result.and_then(|_| {
+            io::copy(&mut File::open(&snapshot_path)?, output)?;
+            Ok(())
+        });
+        // The snapshot holds the memory of the VM, so don't leave it behind even on failure.
+        let remove_result = match remove_file(&snapshot_path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            remove_result => remove_result,
+        };
+        if let Err(e) = &remove_result {
+            error!("Failed to remove snapshot {:?}: {}", snapshot_path, e);
+        }
+        match (result, resume_result) {
+            (Err(e), Err(resume_error)) => {
+                Err(e.context(format!("Failed to resume the VM too: {:?}", resume_error)))
+            }
+            (result, resume_result) => {
+                result?;
+                resume_result.context("Failed to resume the VM")?;
+                remove_result.context("Failed to remove the snapshot")?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Inflates or deflates the balloon of the VM to the given size.
+    pub fn set_memory_balloon(&self, size_mib: u64) -> Result<(), Error> {
+        if let Some(memory_mib) = self.memory_mib {
+            if size_mib > memory_mib.get().into() {
+                bail!("Balloon size {} MiB exceeds VM memory of {} MiB", size_mib, memory_mib);
+            }
+        }
+        let size_bytes = size_mib.checked_mul(1024 * 1024).context("Balloon size overflow")?;
+        self.control_crosvm(&["balloon", &size_bytes.to_string()])?;
+        Ok(())
+    }
+
+    /// Returns the memory statistics reported by the balloon device of the VM. If `timeout` is
+    /// given, fails rather than waiting any longer for them.
+    pub fn memory_stats(&self, timeout: Option<Duration>) -> Result<MemoryStats, Error> {
+        let output = match timeout {
+            Some(timeout) => self.control_crosvm_with_timeout(&["balloon_stats"], timeout)?,
+            None => self.control_crosvm(&["balloon_stats"])?,
+        };
+        let response: BalloonStatsResponse =
+            serde_json::from_str(&output).context("Failed to parse balloon stats")?;
+        let BalloonStatsResponse::BalloonStats { stats, balloon_actual } = response;
+        let value = |v: Option<u64>| v.map_or(-1, |v| v.try_into().unwrap_or(i64::MAX));
+        Ok(MemoryStats {
+            balloonActualBytes: value(Some(balloon_actual)),
+            swapIn: value(stats.swap_in),
+            swapOut: value(stats.swap_out),
+            majorFaults: value(stats.major_faults),
+            minorFaults: value(stats.minor_faults),
+            freeMemory: value(stats.free_memory),
+            totalMemory: value(stats.total_memory),
+            availableMemory: value(stats.available_memory),
+            diskCaches: value(stats.disk_caches),
+        })
+    }
+
+    /// Returns the path of the control socket of the crosvm instance.
+    fn crosvm_control_socket_path(&self) -> PathBuf {
+        self.temporary_directory.join(CROSVM_CONTROL_SOCKET_NAME)
+    }
+
+    /// Sends a command to the crosvm instance via its control socket, waits for it to finish, and
+    /// returns its output.
+    fn control_crosvm(&self, command: &[&str]) -> Result<String, Error> {
+        if !matches!(&*self.vm_state.lock().unwrap(), VmState::Running { .. }) {
+            bail!("VM is not running");
+        }
+        let output = Command::new(CROSVM_PATH)
+            .args(command)
+            .arg(self.crosvm_control_socket_path())
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "crosvm {} failed: {}: {}",
+                command.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Like `control_crosvm`, but kills the command and fails if it hasn't finished within
+    /// `timeout`, e.g. because crosvm is busy or wedged.
+    fn control_crosvm_with_timeout(
+        &self,
+        command: &[&str],
+        timeout: Duration,
+    ) -> Result<String, Error> {
+        if !matches!(&*self.vm_state.lock().unwrap(), VmState::Running { .. }) {
+            bail!("VM is not running");
+        }
+        let mut child = Command::new(CROSVM_PATH)
+            .args(command)
+            .arg(self.crosvm_control_socket_path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let deadline = Instant::now() + timeout;
+        while child.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                bail!("crosvm {} timed out after {:?}", command.join(" "), timeout);
+            }
+            thread::sleep(CONTROL_POLL_INTERVAL);
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "crosvm {} failed: {}: {}",
+                command.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
     /// Kills the crosvm instance, if it is running.
     pub fn kill(&self) {
         let vm_state = &*self.vm_state.lock().unwrap();
@@ -263,6 +660,25 @@ impl VmInstance {
     }
 }
 
+/// The response of `crosvm balloon_stats`.
+#[derive(Deserialize)]
+enum BalloonStatsResponse {
+    BalloonStats { stats: BalloonStats, balloon_actual: u64 },
+}
+
+/// Statistics reported by the balloon device. The guest may not report all of them.
+#[derive(Deserialize)]
+struct BalloonStats {
+    swap_in: Option<u64>,
+    swap_out: Option<u64>,
+    major_faults: Option<u64>,
+    minor_faults: Option<u64>,
+    free_memory: Option<u64>,
+    total_memory: Option<u64>,
+    available_memory: Option<u64>,
+    disk_caches: Option<u64>,
+}
+
 fn death_reason(result: &Result<ExitStatus, io::Error>, failure_reason: &str) -> DeathReason {
     if let Ok(status) = result {
         match failure_reason {
@@ -276,6 +692,7 @@ fn death_reason(result: &Result<ExitStatus, io::Error>, failure_reason: &str) ->
             "BOOTLOADER_INSTANCE_IMAGE_CHANGED" => {
                 return DeathReason::BOOTLOADER_INSTANCE_IMAGE_CHANGED
             }
+            "PVM_FIRMWARE_BOOT_TIMEOUT" => return DeathReason::PVM_FIRMWARE_BOOT_TIMEOUT,
             _ => {}
         }
         match status.code() {
@@ -291,24 +708,56 @@ fn death_reason(result: &Result<ExitStatus, io::Error>, failure_reason: &str) ->
     }
 }
 
+/// Logs the stderr output of crosvm line by line, and keeps the most recent output in
+/// `crosvm_stderr`.
+fn relay_crosvm_stderr(stderr: ChildStderr, crosvm_stderr: &Mutex<RingBuffer>) {
+    let mut reader = BufReader::new(stderr);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {
+                info!("crosvm: {}", String::from_utf8_lossy(&line).trim_end());
+                crosvm_stderr.lock().unwrap().push(&line);
+            }
+            Err(e) => {
+                error!("Failed to read crosvm stderr: {}", e);
+                break;
+            }
+        }
+    }
+}
+
 /// Starts an instance of `crosvm` to manage a new VM.
-fn run_vm(config: CrosvmConfig, failure_pipe_write: File) -> Result<SharedChild, Error> {
+fn run_vm(
+    config: CrosvmConfig,
+    control_socket_path: &Path,
+    failure_pipe_write: File,
+) -> Result<SharedChild, Error> {
     validate_config(&config)?;
 
     let mut command = Command::new(CROSVM_PATH);
-    // TODO(qwandor): Remove --disable-sandbox.
-    command
-        .arg("--extended-status")
-        .arg("run")
-        .arg("--disable-sandbox")
-        .arg("--cid")
-        .arg(config.cid.to_string());
+    command.arg("--extended-status").arg("run");
+
+    match config.sandbox_profile {
+        SandboxProfile::STRICT => {}
+        SandboxProfile::DEBUG_GDB => {
+            // validate_config ensures that the port is set.
+            command.arg("--disable-sandbox").arg("--gdb").arg(config.gdb_port.unwrap().to_string());
+        }
+        _ => {
+            command.arg("--disable-sandbox");
+        }
+    }
+
+    command.arg("--cid").arg(config.cid.to_string()).arg("--socket").arg(control_socket_path);
 
     if config.protected {
         command.arg("--protected-vm");
 
-        // 3 virtio-console devices + vsock = 4.
-        let virtio_pci_device_count = 4 + config.disks.len();
+        // 3 virtio-console devices + vsock = 4, plus the extra console ports.
+        let virtio_pci_device_count = 4 + config.console_port_sockets.len() + config.disks.len();
         // crosvm virtio queue has 256 entries, so 2 MiB per device (2 pages per entry) should be
         // enough.
         let swiotlb_size_mib = 2 * virtio_pci_device_count;
@@ -340,10 +789,12 @@ fn run_vm(config: CrosvmConfig, failure_pipe_write: File) -> Result<SharedChild,
     // 3. virtio-console device: used as the console device where kmsg is redirected to
     // 4. virtio-console device: used as the androidboot.console device (not used currently)
     // 5. virtio-console device: used as the logcat output
+    // 6+. virtio-console devices: the extra console ports requested by the client
     //
     // When [console|log]_fd is not specified, the devices are attached to sink, which means what's
     // written there is discarded.
     let console_arg = format_serial_arg(&mut preserved_fds, &config.console_fd);
+    let console_socket_path = add_preserved_fd(&mut preserved_fds, &config.console_socket);
     let log_arg = format_serial_arg(&mut preserved_fds, &config.log_fd);
     let failure_serial_path = add_preserved_fd(&mut preserved_fds, &failure_pipe_write);
 
@@ -355,12 +806,23 @@ fn run_vm(config: CrosvmConfig, failure_pipe_write: File) -> Result<SharedChild,
     command.arg(format!("--serial={},hardware=serial,num=1", &console_arg));
     // /dev/ttyS1
     command.arg(format!("--serial=type=file,path={},hardware=serial,num=2", &failure_serial_path));
-    // /dev/hvc0
-    command.arg(format!("--serial={},hardware=virtio-console,num=1", &console_arg));
+    // /dev/hvc0, which also takes input from the attached consoles
+    command.arg(format!(
+        "--serial=type=file,path={0},input={0},hardware=virtio-console,num=1",
+        &console_socket_path
+    ));
     // /dev/hvc1 (not used currently)
     command.arg("--serial=type=sink,hardware=virtio-console,num=2");
     // /dev/hvc2
     command.arg(format!("--serial={},hardware=virtio-console,num=3", &log_arg));
+    // /dev/hvc3 onwards
+    for (i, socket) in config.console_port_sockets.iter().enumerate() {
+        command.arg(format!(
+            "--serial=type=file,path={0},input={0},hardware=virtio-console,num={1}",
+            add_preserved_fd(&mut preserved_fds, socket),
+            4 + i
+        ));
+    }
 
     if let Some(bootloader) = &config.bootloader {
         command.arg("--bios").arg(add_preserved_fd(&mut preserved_fds, bootloader));
@@ -380,12 +842,77 @@ fn run_vm(config: CrosvmConfig, failure_pipe_write: File) -> Result<SharedChild,
             .arg(add_preserved_fd(&mut preserved_fds, &disk.image));
     }
 
+    if config.gpu_backend != GpuBackend::NONE {
+        let mut gpu_arg = format!("--gpu=backend={}", crosvm_gpu_backend(config.gpu_backend)?);
+        if let Some(display) = &config.display {
+            gpu_arg += &format!(",width={},height={}", display.width, display.height);
+            if let Some((directory, socket_name)) = &display.compositor {
+                // crosvm connects to the socket through the directory, so that the client doesn't
+                // have to give the compositor a path which we can reach.
+                let directory_path = add_preserved_fd(&mut preserved_fds, directory);
+                command.arg("--wayland-sock").arg(format!("{}/{}", directory_path, socket_name));
+            }
+        }
+        command.arg(gpu_arg);
+    }
+
+    if let Some(audio) = &config.audio {
+        command.arg(format!(
+            "--virtio-snd=backend=aaudio,capture={},num_output_devices={},num_input_devices={}",
+            audio.capture,
+            u8::from(audio.playback),
+            u8::from(audio.capture)
+        ));
+    }
+
+    for device in &config.input_device_files {
+        match device {
+            InputDeviceFile::Keyboard(socket) => {
+                command.arg("--keyboard").arg(add_preserved_fd(&mut preserved_fds, socket))
+            }
+            InputDeviceFile::Mouse(socket) => {
+                command.arg("--mouse").arg(add_preserved_fd(&mut preserved_fds, socket))
+            }
+            InputDeviceFile::MultiTouch { socket, width, height } => {
+                command.arg("--multi-touch").arg(format!(
+                    "{}:{}:{}",
+                    add_preserved_fd(&mut preserved_fds, socket),
+                    width,
+                    height
+                ))
+            }
+            InputDeviceFile::Evdev(evdev) => {
+                command.arg("--evdev").arg(add_preserved_fd(&mut preserved_fds, evdev))
+            }
+        };
+    }
+
+    // crosvm serves the virtio-fs devices itself, so only its own user and group can be mapped.
+    for dir in &config.shared_dirs {
+        let path = add_preserved_fd(&mut preserved_fds, &dir.directory);
+        command.arg("--shared-dir").arg(format!(
+            "{}:{}:type=fs:uidmap={} {} 1:gidmap={} {} 1",
+            path,
+            dir.tag,
+            dir.guest_uid,
+            getuid(),
+            dir.guest_gid,
+            getgid()
+        ));
+    }
+
+    if let Some(snapshot) = &config.restore_snapshot {
+        command.arg("--restore").arg(add_preserved_fd(&mut preserved_fds, snapshot));
+    }
+
     if let Some(kernel) = &config.kernel {
         command.arg(add_preserved_fd(&mut preserved_fds, kernel));
     }
 
     debug!("Preserving FDs {:?}", preserved_fds);
     command.preserved_fds(preserved_fds);
+    // Captured for crash reports; see `relay_crosvm_stderr`.
+    command.stderr(Stdio::piped());
 
     info!("Running {:?}", command);
     let result = SharedChild::spawn(&mut command)?;
@@ -401,6 +928,81 @@ fn validate_config(config: &CrosvmConfig) -> Result<(), Error> {
     if config.bootloader.is_some() && (config.kernel.is_some() || config.initrd.is_some()) {
         bail!("Can't have both bootloader and kernel/initrd image.");
     }
+    if config.protected && config.restore_snapshot.is_some() {
+        bail!("Can't restore a protected VM from a snapshot.");
+    }
+    match config.sandbox_profile {
+        SandboxProfile::DEFAULT | SandboxProfile::STRICT => {}
+        SandboxProfile::DEBUG_GDB => {
+            if config.protected {
+                bail!("GDB isn't supported for protected VMs.");
+            }
+            if config.gdb_port.is_none() {
+                bail!("GDB port must be set with the DEBUG_GDB sandbox profile.");
+            }
+        }
+        _ => bail!("Unsupported sandbox profile {:?}", config.sandbox_profile),
+    }
+    if !config.shared_dirs.is_empty() && config.protected {
+        bail!("Shared directories aren't supported for protected VMs.");
+    }
+    if let Some(audio) = &config.audio {
+        if config.protected {
+            bail!("Audio isn't supported for protected VMs.");
+        }
+        if !audio.capture && !audio.playback {
+            bail!("Audio device has neither a microphone nor a speaker.");
+        }
+    }
+    if !config.input_device_files.is_empty() && config.protected {
+        bail!("Input devices aren't supported for protected VMs.");
+    }
+    for device in &config.input_device_files {
+        if let InputDeviceFile::MultiTouch { width, height, .. } = device {
+            if *width == 0 || *height == 0 {
+                bail!("Invalid multi-touch size {}x{}", width, height);
+            }
+        }
+    }
+    if let Some(display) = &config.display {
+        if config.gpu_backend == GpuBackend::NONE {
+            bail!("A display needs a GPU.");
+        }
+        if display.width == 0 || display.height == 0 {
+            bail!("Invalid display size {}x{}", display.width, display.height);
+        }
+    }
+    let mut shared_dir_tags = HashSet::new();
+    for dir in &config.shared_dirs {
+        // crosvm separates the fields of --shared-dir with ':' and options with ','.
+        if dir.tag.is_empty() || dir.tag.contains(&[':', ','][..]) {
+            bail!("Invalid shared directory tag {:?}", dir.tag);
+        }
+        if !shared_dir_tags.insert(&dir.tag) {
+            bail!("Duplicate shared directory tag {:?}", dir.tag);
+        }
+    }
+    if config.gpu_backend != GpuBackend::NONE {
+        if config.protected {
+            bail!("GPU isn't supported for protected VMs.");
+        }
+        crosvm_gpu_backend(config.gpu_backend)?;
+    }
+    if let Some(cpu_affinity) = &config.cpu_affinity {
+        // Either a list of host CPUs or CPU ranges (e.g. 0,1-3,5), or a list of vCPU to host CPU
+        // assignments (e.g. 0=0:1=1:2=2).
+        let cpu_list = Regex::new(r"^\d+(-\d+)?(,\d+(-\d+)?)*$").unwrap();
+        let cpu_assignments = Regex::new(r"^\d+=\d+(:\d+=\d+)*$").unwrap();
+        if !cpu_list.is_match(cpu_affinity) && !cpu_assignments.is_match(cpu_affinity) {
+            bail!("Invalid CPU affinity {:?}", cpu_affinity);
+        }
+    }
+    // Task profiles are joined with commas on the crosvm command line, so they must not contain
+    // any.
+    let task_profile_name = Regex::new(r"^[A-Za-z0-9_]+$").unwrap();
+    if let Some(profile) = config.task_profiles.iter().find(|p| !task_profile_name.is_match(p)) {
+        bail!("Invalid task profile {:?}", profile);
+    }
     let version = Version::parse(CROSVM_PLATFORM_VERSION).unwrap();
     if !config.platform_version.matches(&version) {
         bail!(
@@ -414,6 +1016,16 @@ fn validate_config(config: &CrosvmConfig) -> Result<(), Error> {
     Ok(())
 }
 
+/// Returns the name which crosvm uses for the given GPU backend.
+fn crosvm_gpu_backend(backend: GpuBackend) -> Result<&'static str, Error> {
+    Ok(match backend {
+        GpuBackend::TWO_D => "2d",
+        GpuBackend::VIRGL => "virglrenderer",
+        GpuBackend::GFXSTREAM => "gfxstream",
+        _ => bail!("Unsupported GPU backend {:?}", backend),
+    })
+}
+
 /// Adds the file descriptor for `file` to `preserved_fds`, and returns a string of the form
 /// "/proc/self/fd/N" where N is the file descriptor.
 fn add_preserved_fd(preserved_fds: &mut Vec<RawFd>, file: &File) -> String {
@@ -0,0 +1,134 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Early VMs, which are declared by the device and started by VirtualizationService during early
+//! boot, before the user has unlocked the device.
+
+use crate::Cid;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    SandboxProfile::SandboxProfile, VirtualMachineRawConfig::VirtualMachineRawConfig,
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+use serde_xml_rs::from_reader;
+use std::collections::HashSet;
+use std::io::Read;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Where the device declares its early VMs.
+pub const EARLY_VMS_CONFIG_PATH: &str = "/system/etc/avf/early_vms.xml";
+
+/// CIDs which early VMs may be given. These are below the first CID that is allocated dynamically,
+/// so that early VMs have well-known CIDs which never clash with other VMs.
+pub const EARLY_VM_CIDS: Range<Cid> = 3..crate::FIRST_GUEST_CID;
+
+/// The list of early VMs declared by the device, e.g.
+///
+/// ```xml
+/// <early_vms>
+///     <early_vm name="foo" cid="3" priority="0" path="/system/etc/foo/vm_config.json"/>
+/// </early_vms>
+/// ```
+#[derive(Debug, Deserialize)]
+struct EarlyVms {
+    #[serde(rename = "early_vm", default)]
+    vms: Vec<EarlyVm>,
+}
+
+/// An early VM, run from the VM config JSON file at `path`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct EarlyVm {
+    /// Name of the VM, for logging.
+    pub name: String,
+    /// The CID which the VM is given, from `EARLY_VM_CIDS`.
+    pub cid: Cid,
+    /// VMs with lower priorities are started first.
+    #[serde(default)]
+    pub priority: i32,
+    /// Path to the VM config JSON file.
+    pub path: PathBuf,
+}
+
+/// Parses and validates the list of early VMs. Returns them in the order in which they should be
+/// started.
+pub fn parse_early_vms<R: Read>(reader: R) -> Result<Vec<EarlyVm>> {
+    let mut vms = from_reader::<_, EarlyVms>(reader).context("Failed to parse early VMs")?.vms;
+
+    let mut names = HashSet::new();
+    let mut cids = HashSet::new();
+    for vm in &vms {
+        ensure!(!vm.name.is_empty(), "Early VM name must not be empty");
+        ensure!(names.insert(&vm.name), "Duplicate early VM name {:?}", vm.name);
+        ensure!(
+            EARLY_VM_CIDS.contains(&vm.cid),
+            "CID {} of early VM {:?} is not in {:?}",
+            vm.cid,
+            vm.name,
+            EARLY_VM_CIDS
+        );
+        ensure!(cids.insert(vm.cid), "Duplicate early VM CID {}", vm.cid);
+    }
+    // The sort is stable, so VMs with the same priority are started in the order they are declared.
+    vms.sort_by_key(|vm| vm.priority);
+    Ok(vms)
+}
+
+/// Checks that the given config only uses features which are available to early VMs. Features
+/// which are otherwise gated on a permission are not, as permissions can't be checked this early.
+pub fn check_early_vm_config(config: &VirtualMachineRawConfig) -> Result<()> {
+    if !config.portForwardings.is_empty() {
+        bail!("Early VMs can't have port forwarding");
+    }
+    if config.sandboxProfile == SandboxProfile::DEBUG_GDB || config.gdbPort != 0 {
+        bail!("Early VMs can't be debugged with GDB");
+    }
+    if config.audioConfig.is_some() {
+        bail!("Early VMs can't have audio");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sorts_by_priority() -> Result<()> {
+        let xml = r#"
+            <early_vms>
+                <early_vm name="b" cid="4" priority="2" path="/b.json"/>
+                <early_vm name="a" cid="3" priority="1" path="/a.json"/>
+                <early_vm name="c" cid="5" path="/c.json"/>
+            </early_vms>"#;
+        let vms = parse_early_vms(xml.as_bytes())?;
+        let names: Vec<_> = vms.iter().map(|vm| vm.name.as_str()).collect();
+        assert_eq!(names, ["c", "a", "b"]);
+        assert_eq!(vms[1].path, PathBuf::from("/a.json"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rejects_invalid_cids() {
+        let out_of_range = r#"<early_vms><early_vm name="a" cid="10" path="/a"/></early_vms>"#;
+        assert!(parse_early_vms(out_of_range.as_bytes()).is_err());
+
+        let duplicate = r#"
+            <early_vms>
+                <early_vm name="a" cid="3" path="/a"/>
+                <early_vm name="b" cid="3" path="/b"/>
+            </early_vms>"#;
+        assert!(parse_early_vms(duplicate.as_bytes()).is_err());
+    }
+}
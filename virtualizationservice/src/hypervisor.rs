@@ -0,0 +1,60 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Queries the capabilities of the hypervisor which VMs are run on.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::HypervisorCapabilities::HypervisorCapabilities;
+use anyhow::{Context, Result};
+use log::warn;
+use rustutils::system_properties;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+const KVM_DEVICE_PATH: &str = "/dev/kvm";
+
+// From include/uapi/linux/kvm.h
+const KVMIO: u8 = 0xAE;
+const KVM_CHECK_EXTENSION: u8 = 0x03;
+const KVM_CAP_ARM_VM_IPA_SIZE: i32 = 165;
+nix::ioctl_write_int_bad!(
+    _kvm_check_extension,
+    nix::request_code_none!(KVMIO, KVM_CHECK_EXTENSION)
+);
+
+/// Returns the capabilities of the hypervisor, as reported by the bootloader and KVM.
+pub fn capabilities() -> Result<HypervisorCapabilities> {
+    Ok(HypervisorCapabilities {
+        vmSupported: system_properties::read_bool("ro.boot.hypervisor.vm.supported", false)?,
+        protectedVmSupported: system_properties::read_bool(
+            "ro.boot.hypervisor.protected_vm.supported",
+            false,
+        )?,
+        version: system_properties::read("ro.boot.hypervisor.version")?,
+        maxIpaBits: max_ipa_bits().unwrap_or_else(|e| {
+            warn!("Failed to get the maximum IPA size: {:?}", e);
+            0
+        }),
+    })
+}
+
+/// Returns the largest IPA size which KVM lets a VM be created with. This is 0 where KVM doesn't
+/// support configuring it, e.g. on x86.
+fn max_ipa_bits() -> Result<i32> {
+    let kvm = File::open(KVM_DEVICE_PATH)
+        .with_context(|| format!("Failed to open {}", KVM_DEVICE_PATH))?;
+    // SAFETY: KVM_CHECK_EXTENSION only reads its integer argument, and the file is kept open until
+    // the end of this function.
+    unsafe { _kvm_check_extension(kvm.as_raw_fd(), KVM_CAP_ARM_VM_IPA_SIZE) }
+        .context("Failed to check KVM_CAP_ARM_VM_IPA_SIZE")
+}
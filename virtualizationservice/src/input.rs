@@ -0,0 +1,73 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Virtio-input devices of a VM whose events are injected by the client.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::InputEvent::InputEvent;
+use anyhow::{Context, Error};
+use log::debug;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::thread;
+
+/// A virtio-input device of a VM, whose events are written to the socket which crosvm reads them
+/// from.
+#[derive(Debug)]
+pub struct InputDevice {
+    /// Our end of the socket which crosvm uses as the event source of the device.
+    socket: Mutex<UnixStream>,
+}
+
+impl InputDevice {
+    /// Creates an input device. Returns the device and the file which crosvm should use as its
+    /// event source.
+    pub fn new() -> Result<(InputDevice, File), Error> {
+        let (socket, crosvm_socket) =
+            UnixStream::pair().context("Failed to create input device socket")?;
+
+        // crosvm writes status updates such as keyboard LEDs to the socket. Nothing uses them, but
+        // they must be read so that crosvm doesn't block once the socket buffer is full.
+        let mut status = socket.try_clone()?;
+        thread::spawn(move || {
+            if let Err(e) = io::copy(&mut status, &mut io::sink()) {
+                debug!("Input device closed: {}", e);
+            }
+        });
+
+        // SAFETY: ownership is transferred from crosvm_socket to the file
+        let crosvm_socket = unsafe { File::from_raw_fd(crosvm_socket.into_raw_fd()) };
+        Ok((InputDevice { socket: Mutex::new(socket) }, crosvm_socket))
+    }
+
+    /// Sends the given events to the VM, in order.
+    pub fn inject(&self, events: &[InputEvent]) -> Result<(), Error> {
+        let buf = events.iter().map(encode_event).collect::<Result<Vec<_>, _>>()?.concat();
+        self.socket.lock().unwrap().write_all(&buf).context("Failed to write input events")
+    }
+}
+
+/// Encodes an event as a `struct virtio_input_event`, as crosvm reads them.
+fn encode_event(event: &InputEvent) -> Result<[u8; 8], Error> {
+    let event_type: u16 = event.eventType.try_into().context("Invalid input event type")?;
+    let code: u16 = event.code.try_into().context("Invalid input event code")?;
+    let mut buf = [0; 8];
+    buf[0..2].copy_from_slice(&event_type.to_le_bytes());
+    buf[2..4].copy_from_slice(&code.to_le_bytes());
+    buf[4..8].copy_from_slice(&event.value.to_le_bytes());
+    Ok(buf)
+}
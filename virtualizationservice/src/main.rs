@@ -16,16 +16,26 @@
 
 mod aidl;
 mod composite;
+mod console;
+mod crash;
 mod crosvm;
+mod early_vm;
+mod hypervisor;
+mod input;
 mod payload;
+mod port_forwarding;
+mod resources;
 mod selinux;
 
 use crate::aidl::{VirtualizationService, BINDER_SERVICE_IDENTIFIER, TEMPORARY_DIRECTORY};
+use crate::crash::CRASH_DIRECTORY;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::BnVirtualizationService;
 use android_system_virtualizationservice::binder::{register_lazy_service, BinderFeatures, ProcessState};
 use anyhow::Error;
 use log::{info, Level};
 use std::fs::{remove_dir_all, remove_file, read_dir};
+use std::path::Path;
+use std::thread;
 
 /// The first CID to assign to a guest VM managed by the VirtualizationService. CIDs lower than this
 /// are reserved for the host or other usage.
@@ -33,6 +43,13 @@ const FIRST_GUEST_CID: Cid = 10;
 
 const SYSPROP_LAST_CID: &str = "virtualizationservice.state.last_cid";
 
+/// Set by devices which declare early VMs in `early_vm::EARLY_VMS_CONFIG_PATH`, so that
+/// VirtualizationService is started during boot to run them.
+const SYSPROP_EARLY_VMS_ENABLED: &str = "ro.virtualizationservice.early_vms.enabled";
+
+/// Set once the early VMs have been started since boot.
+const SYSPROP_EARLY_VMS_STARTED: &str = "virtualizationservice.state.early_vms_started";
+
 const LOG_TAG: &str = "VirtualizationService";
 
 /// The unique ID of a VM used (together with a port number) for vsock communication.
@@ -49,20 +66,26 @@ fn main() {
     clear_temporary_files().expect("Failed to delete old temporary files");
 
     let service = VirtualizationService::init();
+    let early_vm_starter = service.clone();
     let service = BnVirtualizationService::new_binder(
         service,
         BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
     );
     register_lazy_service(BINDER_SERVICE_IDENTIFIER, service.as_binder()).unwrap();
     info!("Registered Binder service, joining threadpool.");
+    // Only once registered, so that clients aren't kept waiting while the early VMs boot.
+    thread::spawn(move || early_vm_starter.start_early_vms());
     ProcessState::join_thread_pool();
 }
 
-/// Remove any files under `TEMPORARY_DIRECTORY`.
+/// Remove any files under `TEMPORARY_DIRECTORY`, except for the crash reports.
 fn clear_temporary_files() -> Result<(), Error> {
     for dir_entry in read_dir(TEMPORARY_DIRECTORY)? {
         let dir_entry = dir_entry?;
         let path = dir_entry.path();
+        if path == Path::new(CRASH_DIRECTORY) {
+            continue;
+        }
         if dir_entry.file_type()?.is_dir() {
             remove_dir_all(path)?;
         } else {
@@ -20,11 +20,11 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
     VirtualMachineRawConfig::VirtualMachineRawConfig,
 };
 use android_system_virtualizationservice::binder::ParcelFileDescriptor;
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use binder::wait_for_interface;
 use log::{info, warn};
-use microdroid_metadata::{ApexPayload, ApkPayload, Metadata};
-use microdroid_payload_config::{ApexConfig, VmPayloadConfig};
+use microdroid_metadata::{ApexPayload, ApkPayload, BinaryPayload, Metadata};
+use microdroid_payload_config::{ApexConfig, Task, TaskType, VmPayloadConfig};
 use once_cell::sync::OnceCell;
 use packagemanager_aidl::aidl::android::content::pm::{
     IPackageManagerNative::IPackageManagerNative, StagedApexInfo::StagedApexInfo,
@@ -48,6 +48,9 @@ const APEX_INFO_LIST_PATH: &str = "/apex/apex-info-list.xml";
 
 const PACKAGE_MANAGER_NATIVE_SERVICE: &str = "package_native";
 
+/// The tag of the virtio-fs device for `VirtualMachineAppConfig.sharedDirectory`.
+pub const SHARED_DIRECTORY_TAG: &str = "shared";
+
 /// Represents the list of APEXes
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 struct ApexInfoList {
@@ -186,13 +189,42 @@ impl PackageManager {
     }
 }
 
+/// Creates the payload config of a VM whose payload is the executable at `binary_path` rather than
+/// an APK. The executable must be in an APEX, which is added to the VM.
+pub fn create_binary_payload_config(binary_path: &str, args: &[String]) -> Result<VmPayloadConfig> {
+    let apex_name = binary_path
+        .strip_prefix("/apex/")
+        .and_then(|path| path.split_once('/'))
+        .filter(|(apex_name, path)| !apex_name.is_empty() && !path.is_empty())
+        .map(|(apex_name, _)| apex_name)
+        .ok_or_else(|| anyhow!("Payload binary {} isn't in an APEX", binary_path))?;
+    ensure!(
+        !binary_path.split('/').any(|component| component == ".."),
+        "Payload binary path {} must not contain \"..\"",
+        binary_path
+    );
+    Ok(VmPayloadConfig {
+        os: Default::default(),
+        task: Some(Task {
+            type_: TaskType::Executable,
+            command: binary_path.to_owned(),
+            args: args.to_vec(),
+            resource_limits: Default::default(),
+        }),
+        apexes: vec![ApexConfig { name: apex_name.to_owned() }],
+        extra_apks: vec![],
+        prefer_staged: false,
+        export_tombstones: false,
+    })
+}
+
 fn make_metadata_file(
-    config_path: &str,
+    app_config: &VirtualMachineAppConfig,
     apex_infos: &[&ApexInfo],
     temporary_directory: &Path,
 ) -> Result<ParcelFileDescriptor> {
     let metadata_path = temporary_directory.join("metadata");
-    let metadata = Metadata {
+    let mut metadata = Metadata {
         version: 1,
         apexes: apex_infos
             .iter()
@@ -214,9 +246,22 @@ fn make_metadata_file(
             ..Default::default()
         })
         .into(),
-        payload_config_path: format!("/mnt/apk/{}", config_path),
+        payload_config_path: format!("/mnt/apk/{}", app_config.configPath),
         ..Default::default()
     };
+    if app_config.sharedDirectory.is_some() {
+        metadata.shared_directory_tag = SHARED_DIRECTORY_TAG.to_owned();
+    }
+    if let Some(binary_path) = &app_config.payloadBinaryPath {
+        metadata.apk.clear();
+        metadata.payload_config_path.clear();
+        metadata.binary = Some(BinaryPayload {
+            path: binary_path.clone(),
+            args: app_config.payloadBinaryArgs.clone().into(),
+            ..Default::default()
+        })
+        .into();
+    }
 
     // Write metadata to file.
     let mut metadata_file = OpenOptions::new()
@@ -243,10 +288,11 @@ fn make_metadata_file(
 ///   extra-apk-1:   additional apk 1
 ///   extra-idsig-1: additional idsig 1
 ///   ..
+/// Extra APKs which aren't required and don't exist are left out, without renumbering the rest.
+/// The APK partitions are left out if `apk_and_idsig` is `None`, for a binary payload.
 fn make_payload_disk(
     app_config: &VirtualMachineAppConfig,
-    apk_file: File,
-    idsig_file: File,
+    apk_and_idsig: Option<(File, File)>,
     vm_payload_config: &VmPayloadConfig,
     temporary_directory: &Path,
 ) -> Result<DiskImage> {
@@ -270,9 +316,17 @@ fn make_payload_disk(
     // update.
     apex_infos.sort_by_key(|info| (&info.name, &info.version, &info.last_update_seconds));
     info!("Microdroid payload APEXes: {:?}", apex_infos.iter().map(|ai| &ai.name));
+    if app_config.payloadBinaryPath.is_some() {
+        for apex_config in &vm_payload_config.apexes {
+            ensure!(
+                apex_infos.iter().any(|apex_info| apex_info.name == apex_config.name),
+                "APEX {} of the payload binary isn't active",
+                apex_config.name
+            );
+        }
+    }
 
-    let metadata_file =
-        make_metadata_file(&app_config.configPath, &apex_infos, temporary_directory)?;
+    let metadata_file = make_metadata_file(app_config, &apex_infos, temporary_directory)?;
     // put metadata at the first partition
     let mut partitions = vec![Partition {
         label: "payload-metadata".to_owned(),
@@ -288,21 +342,29 @@ fn make_payload_disk(
             writable: false,
         });
     }
-    partitions.push(Partition {
-        label: "microdroid-apk".to_owned(),
-        image: Some(ParcelFileDescriptor::new(apk_file)),
-        writable: false,
-    });
-    partitions.push(Partition {
-        label: "microdroid-apk-idsig".to_owned(),
-        image: Some(ParcelFileDescriptor::new(idsig_file)),
-        writable: false,
-    });
+    if let Some((apk_file, idsig_file)) = apk_and_idsig {
+        partitions.push(Partition {
+            label: "microdroid-apk".to_owned(),
+            image: Some(ParcelFileDescriptor::new(apk_file)),
+            writable: false,
+        });
+        partitions.push(Partition {
+            label: "microdroid-apk-idsig".to_owned(),
+            image: Some(ParcelFileDescriptor::new(idsig_file)),
+            writable: false,
+        });
+    }
 
     // we've already checked that extra_apks and extraIdsigs are in the same size.
     let extra_apks = &vm_payload_config.extra_apks;
     let extra_idsigs = &app_config.extraIdsigs;
     for (i, (extra_apk, extra_idsig)) in extra_apks.iter().zip(extra_idsigs.iter()).enumerate() {
+        // The partitions keep the index of the APK in the config even if an earlier one is left
+        // out, so that the VM can tell which APKs are present.
+        if !extra_apk.required && !Path::new(&extra_apk.path).exists() {
+            info!("Leaving out extra APK {}, which doesn't exist", extra_apk.path);
+            continue;
+        }
         partitions.push(Partition {
             label: format!("extra-apk-{}", i),
             image: Some(ParcelFileDescriptor::new(File::open(PathBuf::from(&extra_apk.path))?)),
@@ -381,16 +443,14 @@ fn collect_apex_infos<'a>(
 pub fn add_microdroid_images(
     config: &VirtualMachineAppConfig,
     temporary_directory: &Path,
-    apk_file: File,
-    idsig_file: File,
+    apk_and_idsig: Option<(File, File)>,
     instance_file: File,
     vm_payload_config: &VmPayloadConfig,
     vm_config: &mut VirtualMachineRawConfig,
 ) -> Result<()> {
     vm_config.disks.push(make_payload_disk(
         config,
-        apk_file,
-        idsig_file,
+        apk_and_idsig,
         vm_payload_config,
         temporary_directory,
     )?);
@@ -423,6 +483,14 @@ pub fn add_microdroid_images(
         writable: true,
     });
 
+    if let Some(swap_image) = &config.swapImage {
+        vm_config.disks[1].partitions.push(Partition {
+            label: "vm-swap".to_owned(),
+            image: Some(ParcelFileDescriptor::new(swap_image.as_ref().try_clone()?)),
+            writable: true,
+        });
+    }
+
     Ok(())
 }
 
@@ -445,6 +513,22 @@ export OTHER /foo/bar:/baz:/apex/second.valid.apex/:gibberish:"#;
         assert_eq!(find_apex_names_in_classpath(vars).unwrap(), expected);
     }
 
+    #[test]
+    fn test_create_binary_payload_config() {
+        let args = vec!["--foo".to_owned()];
+        let config = create_binary_payload_config("/apex/com.android.foo/bin/foo", &args).unwrap();
+        assert_eq!(config.apexes, vec![ApexConfig { name: "com.android.foo".to_owned() }]);
+        let task = config.task.unwrap();
+        assert_eq!(task.type_, TaskType::Executable);
+        assert_eq!(task.command, "/apex/com.android.foo/bin/foo");
+        assert_eq!(task.args, args);
+
+        for path in ["/system/bin/foo", "/apex/com.android.foo", "/apex//bin/foo", "/apex/a/../b/c"]
+        {
+            assert!(create_binary_payload_config(path, &[]).is_err(), "{}", path);
+        }
+    }
+
     #[test]
     fn test_collect_apexes() {
         let apex_info_list = ApexInfoList {
@@ -0,0 +1,140 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Forwards TCP connections on the host to vsock ports of a VM.
+
+use crate::Cid;
+use anyhow::{Context, Error};
+use log::{debug, error, info};
+use nix::sys::socket::{shutdown, Shutdown};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown as NetShutdown, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use vsock::VsockStream;
+
+/// Listens on a TCP port of the host's loopback interface, and forwards each connection to a vsock
+/// port of a VM. Stops listening when dropped.
+#[derive(Debug)]
+pub struct PortForwarder {
+    listener: TcpListener,
+    /// The number of connections which are being forwarded.
+    connections: Arc<AtomicUsize>,
+}
+
+impl PortForwarder {
+    /// Starts forwarding connections to `localhost:host_port` to `guest_port` of the VM with the
+    /// given CID.
+    pub fn new(host_port: u16, cid: Cid, guest_port: u32) -> Result<PortForwarder, Error> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, host_port))
+            .with_context(|| format!("Failed to listen on port {}", host_port))?;
+        let accepting_listener = listener.try_clone()?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connections_clone = connections.clone();
+        thread::spawn(move || {
+            accept_connections(accepting_listener, cid, guest_port, &connections_clone)
+        });
+        info!("Forwarding localhost:{} to vsock {}:{}", host_port, cid, guest_port);
+        Ok(PortForwarder { listener, connections })
+    }
+
+    /// Returns the number of connections which are being forwarded.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts a forwarded connection until it is dropped.
+struct ConnectionCount(Arc<AtomicUsize>);
+
+impl ConnectionCount {
+    fn new(connections: &Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionCount(connections.clone())
+    }
+}
+
+impl Drop for ConnectionCount {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for PortForwarder {
+    fn drop(&mut self) {
+        // This makes the blocking accept in `accept_connections` fail, so the thread exits.
+        if let Err(e) = shutdown(self.listener.as_raw_fd(), Shutdown::Both) {
+            error!("Failed to shut down port forwarding listener: {}", e);
+        }
+    }
+}
+
+fn accept_connections(
+    listener: TcpListener,
+    cid: Cid,
+    guest_port: u32,
+    connections: &Arc<AtomicUsize>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                debug!("Stopped forwarding to vsock {}:{}: {}", cid, guest_port, e);
+                return;
+            }
+        };
+        match VsockStream::connect_with_cid_port(cid, guest_port) {
+            Ok(vsock) => relay(stream, vsock, ConnectionCount::new(connections)),
+            Err(e) => error!("Failed to connect to vsock {}:{}: {}", cid, guest_port, e),
+        }
+    }
+}
+
+/// Copies data in both directions between the TCP and vsock streams, until both sides have
+/// finished sending. The connection is counted until then.
+fn relay(tcp: TcpStream, vsock: VsockStream, count: ConnectionCount) {
+    let (tcp_reader, vsock_reader) = match (tcp.try_clone(), vsock.try_clone()) {
+        (Ok(tcp_reader), Ok(vsock_reader)) => (tcp_reader, vsock_reader),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to clone stream for port forwarding: {}", e);
+            return;
+        }
+    };
+    let count = Arc::new(count);
+    let count_clone = count.clone();
+    thread::spawn(move || {
+        copy_then_shutdown(tcp_reader, vsock, |vsock| vsock.shutdown(NetShutdown::Write));
+        drop(count_clone);
+    });
+    thread::spawn(move || {
+        copy_then_shutdown(vsock_reader, tcp, |tcp| tcp.shutdown(NetShutdown::Write));
+        drop(count);
+    });
+}
+
+/// Copies `reader` to `writer` until EOF or an error, and then shuts down `writer` for writing so
+/// that the other end sees EOF too.
+fn copy_then_shutdown<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    shutdown_writer: impl FnOnce(&W) -> io::Result<()>,
+) {
+    if let Err(e) = io::copy(&mut reader, &mut writer) {
+        debug!("Port forwarding connection closed: {}", e);
+    }
+    let _ = shutdown_writer(&writer);
+}
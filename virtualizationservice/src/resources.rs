@@ -0,0 +1,83 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measures the host resources which the crosvm processes of VMs use.
+
+use anyhow::{Context, Result};
+use nix::unistd::{sysconf, SysconfVar};
+use std::fs::read_to_string;
+use std::time::Duration;
+
+/// The host resources used by a process.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProcessUsage {
+    /// The user and system CPU time used by all threads of the process.
+    pub cpu_time: Duration,
+    /// The resident set size of the process, in KiB.
+    pub rss_kib: u64,
+}
+
+/// Reads the resources used by the process with the given PID from /proc.
+pub fn process_usage(pid: u32) -> Result<ProcessUsage> {
+    let stat = read_to_string(format!("/proc/{}/stat", pid))?;
+    let status = read_to_string(format!("/proc/{}/status", pid))?;
+    let ticks_per_second =
+        sysconf(SysconfVar::CLK_TCK)?.context("Clock ticks per second not available")? as u64;
+    Ok(ProcessUsage {
+        cpu_time: Duration::from_millis(parse_cpu_ticks(&stat)? * 1000 / ticks_per_second),
+        rss_kib: parse_rss_kib(&status)?,
+    })
+}
+
+/// Returns the user and system CPU time from the contents of /proc/<pid>/stat, in clock ticks.
+fn parse_cpu_ticks(stat: &str) -> Result<u64> {
+    // The command name in the second field may contain spaces and parentheses, so the other fields
+    // are counted from the last ')'. The first of them is the third field, the state.
+    let (_, fields) = stat.rsplit_once(')').context("Malformed stat")?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let field = |n: usize| -> Result<u64> {
+        let value = fields.get(n - 3).with_context(|| format!("Missing stat field {}", n))?;
+        value.parse().with_context(|| format!("Malformed stat field {}: {:?}", n, value))
+    };
+    // utime and stime
+    Ok(field(14)? + field(15)?)
+}
+
+/// Returns the resident set size from the contents of /proc/<pid>/status, in KiB.
+fn parse_rss_kib(status: &str) -> Result<u64> {
+    let line = status.lines().find_map(|line| line.strip_prefix("VmRSS:")).context("No VmRSS")?;
+    let value = line.trim().strip_suffix("kB").context("Malformed VmRSS")?;
+    value.trim().parse().with_context(|| format!("Malformed VmRSS: {:?}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_stat_with_odd_command_name() -> Result<()> {
+        let stat = "1234 (crosvm (vcpu) 1) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 75 0 0 20 0 \
+                    12 0 1000 0 0";
+        assert_eq!(parse_cpu_ticks(stat)?, 325);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_status() -> Result<()> {
+        let status = "Name:\tcrosvm\nVmPeak:\t  300000 kB\nVmRSS:\t  123456 kB\nThreads:\t12\n";
+        assert_eq!(parse_rss_kib(status)?, 123456);
+        assert!(parse_rss_kib("Name:\tkthreadd\n").is_err());
+        Ok(())
+    }
+}
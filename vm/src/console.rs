@@ -0,0 +1,144 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command to attach to the console of a running VM.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::IVirtualizationService;
+use android_system_virtualizationservice::binder::Strong;
+use anyhow::{bail, Context, Error};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+
+/// Ctrl-], which detaches from the console as in telnet.
+const ESCAPE_CHAR: u8 = 0x1d;
+
+/// Attaches to the console of the VM with the given CID or name and relays stdin/stdout to it until
+/// the VM dies or the user presses the escape character.
+pub fn command_attach_console(
+    service: Strong<dyn IVirtualizationService>,
+    vm: &str,
+) -> Result<(), Error> {
+    let cid = find_vm(&service, vm)?;
+    let console = service
+        .debugAttachConsole(cid as i32)
+        .context("Failed to attach to the console of the VM")?;
+    let console = console.as_ref().try_clone()?;
+    eprintln!("Attached to the console of VM {}. Press Ctrl-] to detach.", cid);
+
+    let raw_mode = RawMode::enable(io::stdin().as_raw_fd())?;
+
+    let mut input = console.try_clone()?;
+    thread::spawn(move || relay_input(&mut input));
+    let result = relay_output(console);
+
+    drop(raw_mode);
+    eprintln!();
+    eprintln!("Detached from the console of VM {}.", cid);
+    result
+}
+
+/// Returns the CID of the running VM which `vm` refers to, either by its CID or by its name. A
+/// name must match exactly one VM.
+fn find_vm(service: &Strong<dyn IVirtualizationService>, vm: &str) -> Result<u32, Error> {
+    if let Ok(cid) = vm.parse() {
+        return Ok(cid);
+    }
+    let vms = service.debugListVms().context("Failed to get list of VMs")?;
+    let cids: Vec<_> = vms.iter().filter(|info| info.name == vm).map(|info| info.cid).collect();
+    match cids[..] {
+        [cid] => Ok(cid as u32),
+        [] => bail!("No running VM is named {:?}", vm),
+        _ => bail!("{} running VMs are named {:?}; use a CID instead: {:?}", cids.len(), vm, cids),
+    }
+}
+
+/// Copies stdin to the console until the escape character is read. Then shuts down the console,
+/// which makes `relay_output` return.
+fn relay_input(console: &mut File) {
+    let mut stdin = io::stdin();
+    let mut buf = [0u8; 256];
+    while let Ok(n) = stdin.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        let (data, detach) = match buf[..n].iter().position(|&c| c == ESCAPE_CHAR) {
+            Some(i) => (&buf[..i], true),
+            None => (&buf[..n], false),
+        };
+        if console.write_all(data).is_err() || detach {
+            break;
+        }
+    }
+    // SAFETY: shutdown(2) doesn't take ownership of the file descriptor
+    unsafe { libc::shutdown(console.as_raw_fd(), libc::SHUT_RDWR) };
+}
+
+/// Copies the console output to stdout until the console is closed.
+fn relay_output(mut console: File) -> Result<(), Error> {
+    let mut stdout = io::stdout();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match console.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Failed to read from the console"),
+        };
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+    }
+}
+
+/// Puts a terminal into raw mode, and restores the original mode when dropped. Does nothing if the
+/// file isn't a terminal.
+pub struct RawMode {
+    fd: RawFd,
+    original: Option<libc::termios>,
+}
+
+impl RawMode {
+    pub fn enable(fd: RawFd) -> Result<RawMode, Error> {
+        // SAFETY: isatty only checks the file descriptor
+        if unsafe { libc::isatty(fd) } != 1 {
+            return Ok(RawMode { fd, original: None });
+        }
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr initializes termios when it succeeds, which we check
+        if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to get terminal attributes");
+        }
+        // SAFETY: termios was initialized by tcgetattr above
+        let original = unsafe { termios.assume_init() };
+        let mut raw = original;
+        // SAFETY: cfmakeraw only modifies the given struct
+        unsafe { libc::cfmakeraw(&mut raw) };
+        // SAFETY: tcsetattr only reads the given struct
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to set terminal attributes");
+        }
+        Ok(RawMode { fd, original: Some(original) })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            // SAFETY: tcsetattr only reads the given struct
+            unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, original) };
+        }
+    }
+}
@@ -12,23 +12,45 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Command to create an empty partition
+//! Command to create an empty partition, optionally formatted with a filesystem
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::IVirtualizationService;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::PartitionType::PartitionType;
 use android_system_virtualizationservice::binder::{ParcelFileDescriptor, Strong};
-use anyhow::{Context, Error};
+use anyhow::{bail, Context, Error};
 use std::convert::TryInto;
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::process::Command;
 
-/// Initialise an empty partition image of the given size to be used as a writable partition.
+const MKE2FS_PATH: &str = "/system/bin/mke2fs";
+const NEWFS_MSDOS_PATH: &str = "/system/bin/newfs_msdos";
+
+/// Filesystem to format a raw partition with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FilesystemFormat {
+    Ext4,
+    Fat,
+}
+
+/// Initialise an empty partition image of the given size to be used as a writable partition. If
+/// `format` is given, the partition is formatted with that filesystem, and populated with the
+/// contents of `seed_dir` if that is given too.
 pub fn command_create_partition(
     service: Strong<dyn IVirtualizationService>,
     image_path: &Path,
     size: u64,
     partition_type: PartitionType,
+    format: Option<FilesystemFormat>,
+    seed_dir: Option<&Path>,
 ) -> Result<(), Error> {
+    if format.is_some() && partition_type != PartitionType::RAW {
+        bail!("Only raw partitions can be formatted");
+    }
+    if seed_dir.is_some() && format != Some(FilesystemFormat::Ext4) {
+        bail!("Only ext4 partitions can be seeded from a directory");
+    }
+
     let image = OpenOptions::new()
         .create_new(true)
         .read(true)
@@ -45,5 +67,38 @@ pub fn command_create_partition(
             "Failed to initialize partition type: {:?}, size: {}",
             partition_type, size
         ))?;
+    match format {
+        Some(FilesystemFormat::Ext4) => format_ext4(image_path, seed_dir),
+        Some(FilesystemFormat::Fat) => format_fat(image_path, size),
+        None => Ok(()),
+    }
+}
+
+fn format_ext4(image_path: &Path, seed_dir: Option<&Path>) -> Result<(), Error> {
+    let mut command = Command::new(MKE2FS_PATH);
+    command.arg("-t").arg("ext4").arg("-F");
+    if let Some(seed_dir) = seed_dir {
+        command.arg("-d").arg(seed_dir);
+    }
+    run_mkfs(command.arg(image_path))
+}
+
+fn format_fat(image_path: &Path, size: u64) -> Result<(), Error> {
+    const SECTOR_SIZE: u64 = 512;
+    run_mkfs(
+        Command::new(NEWFS_MSDOS_PATH)
+            .arg("-O")
+            .arg("android")
+            .arg("-s")
+            .arg((size / SECTOR_SIZE).to_string())
+            .arg(image_path),
+    )
+}
+
+fn run_mkfs(command: &mut Command) -> Result<(), Error> {
+    let status = command.status().with_context(|| format!("Failed to run {:?}", command))?;
+    if !status.success() {
+        bail!("{:?} failed: {}", command, status);
+    }
     Ok(())
 }
@@ -14,21 +14,28 @@
 
 //! Android VM control tool.
 
+mod console;
 mod create_idsig;
 mod create_partition;
 mod run;
+mod shell;
 mod sync;
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
-    IVirtualizationService::IVirtualizationService, PartitionType::PartitionType,
-    VirtualMachineAppConfig::DebugLevel::DebugLevel,
+    IVirtualMachine::IVirtualMachine, IVirtualizationService::IVirtualizationService,
+    PartitionType::PartitionType, VirtualMachineAppConfig::DebugLevel::DebugLevel,
+};
+use android_system_virtualizationservice::binder::{
+    wait_for_interface, ParcelFileDescriptor, ProcessState, Strong,
 };
-use android_system_virtualizationservice::binder::{wait_for_interface, ProcessState, Strong};
 use anyhow::{Context, Error};
+use console::command_attach_console;
 use create_idsig::command_create_idsig;
-use create_partition::command_create_partition;
-use run::{command_run, command_run_app};
-use rustutils::system_properties;
+use create_partition::{command_create_partition, FilesystemFormat};
+use run::{command_run, command_run_app, command_run_binary, state_to_str};
+use serde::Serialize;
+use shell::command_shell;
+use std::fs::File;
 use std::path::{Path, PathBuf};
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
@@ -63,6 +70,10 @@ enum Opt {
         #[structopt(short, long)]
         daemonize: bool,
 
+        /// Name of the VM, by which `vm attach-console` can refer to it
+        #[structopt(long)]
+        name: Option<String>,
+
         /// Path to file for VM console output.
         #[structopt(long)]
         console: Option<PathBuf>,
@@ -99,6 +110,69 @@ enum Opt {
         /// Paths to extra idsig files.
         #[structopt(long = "extra-idsig")]
         extra_idsigs: Vec<PathBuf>,
+
+        /// Host directory to share with the VM, which is mounted at /mnt/shared in the VM.
+        /// Requires `--debug full`.
+        #[structopt(long, parse(from_os_str))]
+        mount: Option<PathBuf>,
+
+        /// Path to a raw image to use as swap in the VM, e.g. created with `vm create-partition`.
+        #[structopt(long, parse(from_os_str))]
+        swap: Option<PathBuf>,
+    },
+    /// Run a Microdroid VM whose payload is an executable in one of the APEXes, instead of an APK
+    RunBinary {
+        /// Path of the executable in the VM (e.g. /apex/com.android.foo/bin/foo)
+        binary: String,
+
+        /// Path to the instance image. Created if not exists.
+        #[structopt(parse(from_os_str))]
+        instance: PathBuf,
+
+        /// Arguments for the executable
+        args: Vec<String>,
+
+        /// Detach VM from the terminal and run in the background
+        #[structopt(short, long)]
+        daemonize: bool,
+
+        /// Name of the VM, by which `vm attach-console` can refer to it
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// Path to file for VM console output.
+        #[structopt(long)]
+        console: Option<PathBuf>,
+
+        /// Path to file for VM log output.
+        #[structopt(long)]
+        log: Option<PathBuf>,
+
+        /// Debug level of the VM. Supported values: "none" (default), "app_only", and "full".
+        #[structopt(long, default_value = "none", parse(try_from_str=parse_debug_level))]
+        debug: DebugLevel,
+
+        /// Run VM in protected mode.
+        #[structopt(short, long)]
+        protected: bool,
+
+        /// Memory size (in MiB) of the VM. If unspecified, defaults to the value of `memory_mib`
+        /// in the VM config file.
+        #[structopt(short, long)]
+        mem: Option<u32>,
+
+        /// Number of vCPUs in the VM. If unspecified, defaults to 1.
+        #[structopt(long)]
+        cpus: Option<u32>,
+
+        /// Host directory to share with the VM, which is mounted at /mnt/shared in the VM.
+        /// Requires `--debug full`.
+        #[structopt(long, parse(from_os_str))]
+        mount: Option<PathBuf>,
+
+        /// Path to a raw image to use as swap in the VM, e.g. created with `vm create-partition`.
+        #[structopt(long, parse(from_os_str))]
+        swap: Option<PathBuf>,
     },
     /// Run a virtual machine
     Run {
@@ -110,6 +184,10 @@ enum Opt {
         #[structopt(short, long)]
         daemonize: bool,
 
+        /// Name of the VM, by which `vm attach-console` can refer to it
+        #[structopt(long)]
+        name: Option<String>,
+
         /// Number of vCPUs in the VM. If unspecified, defaults to 1.
         #[structopt(long)]
         cpus: Option<u32>,
@@ -134,15 +212,79 @@ enum Opt {
         #[structopt(long)]
         log: Option<PathBuf>,
     },
+    /// Restore a virtual machine from a snapshot
+    Restore {
+        /// Path to the VM config JSON which the VM was run with
+        #[structopt(parse(from_os_str))]
+        config: PathBuf,
+
+        /// Path to the snapshot taken with `vm snapshot`
+        #[structopt(parse(from_os_str))]
+        snapshot: PathBuf,
+
+        /// Detach VM from the terminal and run in the background
+        #[structopt(short, long)]
+        daemonize: bool,
+
+        /// Path to file for VM console output.
+        #[structopt(long)]
+        console: Option<PathBuf>,
+
+        /// Path to file for VM log output.
+        #[structopt(long)]
+        log: Option<PathBuf>,
+    },
     /// Stop a virtual machine running in the background
     Stop {
         /// CID of the virtual machine
         cid: u32,
     },
+    /// Pause a virtual machine running in the background
+    Pause {
+        /// CID of the virtual machine
+        cid: u32,
+    },
+    /// Resume a paused virtual machine
+    Resume {
+        /// CID of the virtual machine
+        cid: u32,
+    },
+    /// Take a snapshot of a non-protected virtual machine running in the background
+    Snapshot {
+        /// CID of the virtual machine
+        cid: u32,
+
+        /// Path to the snapshot file to create
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
     /// List running virtual machines
-    List,
+    List {
+        /// Print the list in JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Attach to the console of a running virtual machine. Press Ctrl-] to detach.
+    AttachConsole {
+        /// CID or name of the virtual machine
+        vm: String,
+    },
+    /// Open a shell in a debuggable Microdroid virtual machine running in the background
+    Shell {
+        /// CID of the virtual machine
+        cid: u32,
+    },
+    /// Print the crash report of the last virtual machine with the given CID which crashed
+    CrashReport {
+        /// CID of the virtual machine
+        cid: u32,
+    },
     /// Print information about virtual machine support
-    Info,
+    Info {
+        /// Print the information in JSON
+        #[structopt(long)]
+        json: bool,
+    },
     /// Create a new empty partition to be used as a writable partition for a VM
     CreatePartition {
         /// Path at which to create the image file
@@ -155,6 +297,14 @@ enum Opt {
         /// Type of the partition
         #[structopt(short="t", long="type", default_value="raw", parse(try_from_str=parse_partition_type))]
         partition_type: PartitionType,
+
+        /// Filesystem to format a raw partition with: ext4 or fat
+        #[structopt(long, parse(try_from_str=parse_filesystem_format))]
+        format: Option<FilesystemFormat>,
+
+        /// Directory whose contents to copy into the new filesystem. Only supported for ext4.
+        #[structopt(long, parse(from_os_str))]
+        seed_dir: Option<PathBuf>,
     },
     /// Creates or update the idsig file by digesting the input APK file.
     CreateIdsig {
@@ -184,6 +334,14 @@ fn parse_partition_type(s: &str) -> Result<PartitionType, String> {
     }
 }
 
+fn parse_filesystem_format(s: &str) -> Result<FilesystemFormat, String> {
+    match s {
+        "ext4" => Ok(FilesystemFormat::Ext4),
+        "fat" => Ok(FilesystemFormat::Fat),
+        _ => Err(format!("Invalid filesystem format {}", s)),
+    }
+}
+
 fn main() -> Result<(), Error> {
     env_logger::init();
     let opt = Opt::from_args();
@@ -201,6 +359,7 @@ fn main() -> Result<(), Error> {
             instance,
             config_path,
             daemonize,
+            name,
             console,
             log,
             debug,
@@ -210,6 +369,8 @@ fn main() -> Result<(), Error> {
             cpu_affinity,
             task_profiles,
             extra_idsigs,
+            mount,
+            swap,
         } => command_run_app(
             service,
             &apk,
@@ -217,6 +378,7 @@ fn main() -> Result<(), Error> {
             &instance,
             &config_path,
             daemonize,
+            name,
             console.as_deref(),
             log.as_deref(),
             debug,
@@ -226,25 +388,89 @@ fn main() -> Result<(), Error> {
             cpu_affinity,
             task_profiles,
             &extra_idsigs,
+            mount.as_deref(),
+            swap.as_deref(),
         ),
-        Opt::Run { config, daemonize, cpus, cpu_affinity, task_profiles, console, log } => {
+        Opt::RunBinary {
+            binary,
+            instance,
+            args,
+            daemonize,
+            name,
+            console,
+            log,
+            debug,
+            protected,
+            mem,
+            cpus,
+            mount,
+            swap,
+        } => command_run_binary(
+            service,
+            &binary,
+            args,
+            &instance,
+            daemonize,
+            name,
+            console.as_deref(),
+            log.as_deref(),
+            debug,
+            protected,
+            mem,
+            cpus,
+            mount.as_deref(),
+            swap.as_deref(),
+        ),
+        Opt::Run { config, daemonize, name, cpus, cpu_affinity, task_profiles, console, log } => {
             command_run(
                 service,
                 &config,
                 daemonize,
+                name,
                 console.as_deref(),
                 log.as_deref(),
                 /* mem */ None,
                 cpus,
                 cpu_affinity,
                 task_profiles,
+                /* restore_snapshot */ None,
             )
         }
+        Opt::Restore { config, snapshot, daemonize, console, log } => command_run(
+            service,
+            &config,
+            daemonize,
+            /* name */ None,
+            console.as_deref(),
+            log.as_deref(),
+            /* mem */ None,
+            /* cpus */ None,
+            /* cpu_affinity */ None,
+            /* task_profiles */ vec![],
+            Some(&snapshot),
+        ),
         Opt::Stop { cid } => command_stop(service, cid),
-        Opt::List => command_list(service),
-        Opt::Info => command_info(),
-        Opt::CreatePartition { path, size, partition_type } => {
-            command_create_partition(service, &path, size, partition_type)
+        Opt::Pause { cid } => {
+            with_background_vm(&service, cid, |vm| vm.pauseVm().context("Failed to pause VM"))
+        }
+        Opt::Resume { cid } => {
+            with_background_vm(&service, cid, |vm| vm.resumeVm().context("Failed to resume VM"))
+        }
+        Opt::Snapshot { cid, path } => command_snapshot(service, cid, &path),
+        Opt::List { json } => command_list(service, json),
+        Opt::AttachConsole { vm } => command_attach_console(service, &vm),
+        Opt::Shell { cid } => command_shell(service, cid),
+        Opt::CrashReport { cid } => command_crash_report(service, cid),
+        Opt::Info { json } => command_info(service, json),
+        Opt::CreatePartition { path, size, partition_type, format, seed_dir } => {
+            command_create_partition(
+                service,
+                &path,
+                size,
+                partition_type,
+                format,
+                seed_dir.as_deref(),
+            )
         }
         Opt::CreateIdsig { apk, path } => command_create_idsig(service, &apk, &path),
     }
@@ -259,33 +485,156 @@ fn command_stop(service: Strong<dyn IVirtualizationService>, cid: u32) -> Result
     Ok(())
 }
 
+/// Take a snapshot of a VM running in the background.
+fn command_snapshot(
+    service: Strong<dyn IVirtualizationService>,
+    cid: u32,
+    path: &Path,
+) -> Result<(), Error> {
+    let snapshot =
+        File::create(path).with_context(|| format!("Failed to create snapshot file {:?}", path))?;
+    with_background_vm(&service, cid, |vm| {
+        vm.snapshot(&ParcelFileDescriptor::new(snapshot)).context("Failed to take snapshot")
+    })?;
+    println!("Took a snapshot of VM {} to {:?}.", cid, path);
+    Ok(())
+}
+
+/// Calls `f` with the VM with the given CID, which must be held by VirtualizationService because
+/// it was run in the background. The VM is held again afterwards.
+fn with_background_vm<T>(
+    service: &Strong<dyn IVirtualizationService>,
+    cid: u32,
+    f: impl FnOnce(&Strong<dyn IVirtualMachine>) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let vm = service
+        .debugDropVmRef(cid as i32)
+        .context("Failed to get VM from VirtualizationService")?
+        .context("CID does not correspond to a running background VM")?;
+    let result = f(&vm);
+    service.debugHoldVmRef(&vm).context("Failed to pass VM back to VirtualizationService")?;
+    result
+}
+
+/// A running VM, as printed by `vm list --json`.
+#[derive(Serialize)]
+struct VmJson {
+    cid: i32,
+    /// Empty if the VM wasn't given a name.
+    name: String,
+    state: &'static str,
+    protected: bool,
+    /// 0 if the default is used.
+    memory_mib: i32,
+    /// 0 if the default is used.
+    cpus: i32,
+    requester_uid: i32,
+    requester_pid: i32,
+    /// Only set once the payload has reported it.
+    boot_times: Option<BootTimesJson>,
+}
+
+/// The boot time breakdown of a VM, in milliseconds since the guest kernel started. -1 for phases
+/// which the VM didn't go through.
+#[derive(Serialize)]
+struct BootTimesJson {
+    /// Estimated time from crosvm starting to the guest kernel starting.
+    kernel_start: i64,
+    init_start: i64,
+    apkdmverity_done: i64,
+    payload_exec: i64,
+}
+
 /// List the VMs currently running.
-fn command_list(service: Strong<dyn IVirtualizationService>) -> Result<(), Error> {
+fn command_list(service: Strong<dyn IVirtualizationService>, json: bool) -> Result<(), Error> {
     let vms = service.debugListVms().context("Failed to get list of VMs")?;
-    println!("Running VMs: {:#?}", vms);
+    if json {
+        let vms: Vec<_> = vms
+            .iter()
+            .map(|vm| VmJson {
+                cid: vm.cid,
+                name: vm.name.clone(),
+                state: state_to_str(vm.state),
+                protected: vm.protectedVm,
+                memory_mib: vm.memoryMib,
+                cpus: vm.numCpus,
+                requester_uid: vm.requesterUid,
+                requester_pid: vm.requesterPid,
+                boot_times: vm.bootTimes.as_ref().map(|boot_times| BootTimesJson {
+                    kernel_start: boot_times.kernelStartMillis,
+                    init_start: boot_times.initStartMillis,
+                    apkdmverity_done: boot_times.apkdmverityDoneMillis,
+                    payload_exec: boot_times.payloadExecMillis,
+                }),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&vms)?);
+    } else {
+        println!("Running VMs: {:#?}", vms);
+    }
     Ok(())
 }
 
+/// Print the crash report of the VM with the given CID, if any.
+fn command_crash_report(
+    service: Strong<dyn IVirtualizationService>,
+    cid: u32,
+) -> Result<(), Error> {
+    let report = service
+        .debugGetCrashReport(cid as i32)
+        .context("Failed to get crash report")?
+        .with_context(|| format!("No crash report for VM {}", cid))?;
+    println!("Death reason: {:?}", report.deathReason);
+    println!("Directory: {}", report.directory);
+    println!("Console output:\n{}", report.consoleOutput);
+    println!("crosvm stderr:\n{}", report.crosvmStderr);
+    Ok(())
+}
+
+/// Information about VM support, as printed by `vm info --json`.
+#[derive(Serialize)]
+struct InfoJson {
+    unprotected_vm_supported: bool,
+    protected_vm_supported: bool,
+    hypervisor_version: Option<String>,
+    max_ipa_bits: i32,
+    kvm_exists: bool,
+}
+
 /// Print information about supported VM types.
-fn command_info() -> Result<(), Error> {
-    let unprotected_vm_supported =
-        system_properties::read_bool("ro.boot.hypervisor.vm.supported", false)?;
-    let protected_vm_supported =
-        system_properties::read_bool("ro.boot.hypervisor.protected_vm.supported", false)?;
-    match (unprotected_vm_supported, protected_vm_supported) {
+fn command_info(service: Strong<dyn IVirtualizationService>, json: bool) -> Result<(), Error> {
+    let capabilities =
+        service.getHypervisorCapabilities().context("Failed to get hypervisor capabilities")?;
+    let info = InfoJson {
+        unprotected_vm_supported: capabilities.vmSupported,
+        protected_vm_supported: capabilities.protectedVmSupported,
+        hypervisor_version: capabilities.version,
+        max_ipa_bits: capabilities.maxIpaBits,
+        kvm_exists: Path::new("/dev/kvm").exists(),
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    match (info.unprotected_vm_supported, info.protected_vm_supported) {
         (false, false) => println!("VMs are not supported."),
         (false, true) => println!("Only protected VMs are supported."),
         (true, false) => println!("Only unprotected VMs are supported."),
         (true, true) => println!("Both protected and unprotected VMs are supported."),
     }
 
-    if let Some(version) = system_properties::read("ro.boot.hypervisor.version")? {
+    if let Some(version) = &info.hypervisor_version {
         println!("Hypervisor version: {}", version);
     } else {
         println!("Hypervisor version not set.");
     }
 
-    if Path::new("/dev/kvm").exists() {
+    if info.max_ipa_bits > 0 {
+        println!("Maximum guest IPA size: {} bits", info.max_ipa_bits);
+    }
+
+    if info.kvm_exists {
         println!("/dev/kvm exists.");
     } else {
         println!("/dev/kvm does not exist.");
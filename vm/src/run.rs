@@ -29,8 +29,8 @@ use android_system_virtualizationservice::binder::{
     BinderFeatures, DeathRecipient, IBinder, ParcelFileDescriptor, Strong,
 };
 use android_system_virtualizationservice::binder::{Interface, Result as BinderResult};
-use anyhow::{bail, Context, Error};
-use microdroid_payload_config::VmPayloadConfig;
+use anyhow::{anyhow, bail, Context, Error};
+use microdroid_payload_config::{ApkConfig, VmPayloadConfig};
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
 use std::os::unix::io::{AsRawFd, FromRawFd};
@@ -47,6 +47,7 @@ pub fn command_run_app(
     instance: &Path,
     config_path: &str,
     daemonize: bool,
+    name: Option<String>,
     console_path: Option<&Path>,
     log_path: Option<&Path>,
     debug_level: DebugLevel,
@@ -56,6 +57,8 @@ pub fn command_run_app(
     cpu_affinity: Option<String>,
     task_profiles: Vec<String>,
     extra_idsigs: &[PathBuf],
+    mount: Option<&Path>,
+    swap: Option<&Path>,
 ) -> Result<(), Error> {
     let extra_apks = parse_extra_apk_list(apk, config_path)?;
     if extra_apks.len() != extra_idsigs.len() {
@@ -67,8 +70,12 @@ pub fn command_run_app(
     }
 
     for i in 0..extra_apks.len() {
-        let extra_apk_fd = ParcelFileDescriptor::new(File::open(&extra_apks[i])?);
         let extra_idsig_fd = ParcelFileDescriptor::new(File::create(&extra_idsigs[i])?);
+        if !extra_apks[i].required && !Path::new(&extra_apks[i].path).exists() {
+            // VirtualizationService leaves it out, so the empty idsig isn't used.
+            continue;
+        }
+        let extra_apk_fd = ParcelFileDescriptor::new(File::open(&extra_apks[i].path)?);
         service.createOrUpdateIdsigFile(&extra_apk_fd, &extra_idsig_fd)?;
     }
 
@@ -82,31 +89,28 @@ pub fn command_run_app(
     let idsig_file = File::open(idsig).context("Failed to open idsig file")?;
     let idsig_fd = ParcelFileDescriptor::new(idsig_file);
 
-    if !instance.exists() {
-        const INSTANCE_FILE_SIZE: u64 = 10 * 1024 * 1024;
-        command_create_partition(
-            service.clone(),
-            instance,
-            INSTANCE_FILE_SIZE,
-            PartitionType::ANDROID_VM_INSTANCE,
-        )?;
-    }
+    create_instance_image_if_missing(&service, instance)?;
 
     let extra_idsig_files: Result<Vec<File>, _> = extra_idsigs.iter().map(File::open).collect();
     let extra_idsig_fds = extra_idsig_files?.into_iter().map(ParcelFileDescriptor::new).collect();
 
     let config = VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
+        name: name.unwrap_or_default(),
         apk: apk_fd.into(),
         idsig: idsig_fd.into(),
         extraIdsigs: extra_idsig_fds,
         instanceImage: open_parcel_file(instance, true /* writable */)?.into(),
         configPath: config_path.to_owned(),
+        payloadBinaryPath: None,
+        payloadBinaryArgs: vec![],
         debugLevel: debug_level,
         protectedVm: protected,
         memoryMib: mem.unwrap_or(0) as i32, // 0 means use the VM default
         numCpus: cpus.unwrap_or(1) as i32,
         cpuAffinity: cpu_affinity,
         taskProfiles: task_profiles,
+        sharedDirectory: mount.map(shared_directory_path).transpose()?,
+        swapImage: swap.map(|path| open_parcel_file(path, true /* writable */)).transpose()?,
     });
     run(
         service,
@@ -118,22 +122,96 @@ pub fn command_run_app(
     )
 }
 
+/// Run a VM whose payload is the given executable in one of the APEXes, rather than an APK.
+#[allow(clippy::too_many_arguments)]
+pub fn command_run_binary(
+    service: Strong<dyn IVirtualizationService>,
+    binary_path: &str,
+    args: Vec<String>,
+    instance: &Path,
+    daemonize: bool,
+    name: Option<String>,
+    console_path: Option<&Path>,
+    log_path: Option<&Path>,
+    debug_level: DebugLevel,
+    protected: bool,
+    mem: Option<u32>,
+    cpus: Option<u32>,
+    mount: Option<&Path>,
+    swap: Option<&Path>,
+) -> Result<(), Error> {
+    create_instance_image_if_missing(&service, instance)?;
+
+    let config = VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
+        name: name.unwrap_or_default(),
+        apk: None,
+        idsig: None,
+        extraIdsigs: vec![],
+        instanceImage: open_parcel_file(instance, true /* writable */)?.into(),
+        configPath: String::new(),
+        payloadBinaryPath: Some(binary_path.to_owned()),
+        payloadBinaryArgs: args,
+        debugLevel: debug_level,
+        protectedVm: protected,
+        memoryMib: mem.unwrap_or(0) as i32, // 0 means use the VM default
+        numCpus: cpus.unwrap_or(1) as i32,
+        cpuAffinity: None,
+        taskProfiles: vec![],
+        sharedDirectory: mount.map(shared_directory_path).transpose()?,
+        swapImage: swap.map(|path| open_parcel_file(path, true /* writable */)).transpose()?,
+    });
+    run(service, &config, binary_path, daemonize, console_path, log_path)
+}
+
+/// Returns the absolute path of a directory to share with the VM, as VirtualizationService
+/// doesn't share our working directory.
+fn shared_directory_path(path: &Path) -> Result<String, Error> {
+    let path = path.canonicalize().with_context(|| format!("Failed to find {:?}", path))?;
+    if !path.is_dir() {
+        bail!("{:?} isn't a directory", path);
+    }
+    path.into_os_string().into_string().map_err(|path| anyhow!("Invalid path {:?}", path))
+}
+
+fn create_instance_image_if_missing(
+    service: &Strong<dyn IVirtualizationService>,
+    instance: &Path,
+) -> Result<(), Error> {
+    if !instance.exists() {
+        const INSTANCE_FILE_SIZE: u64 = 10 * 1024 * 1024;
+        command_create_partition(
+            service.clone(),
+            instance,
+            INSTANCE_FILE_SIZE,
+            PartitionType::ANDROID_VM_INSTANCE,
+            /* format */ None,
+            /* seed_dir */ None,
+        )?;
+    }
+    Ok(())
+}
+
 /// Run a VM from the given configuration file.
 #[allow(clippy::too_many_arguments)]
 pub fn command_run(
     service: Strong<dyn IVirtualizationService>,
     config_path: &Path,
     daemonize: bool,
+    name: Option<String>,
     console_path: Option<&Path>,
     log_path: Option<&Path>,
     mem: Option<u32>,
     cpus: Option<u32>,
     cpu_affinity: Option<String>,
     task_profiles: Vec<String>,
+    restore_snapshot: Option<&Path>,
 ) -> Result<(), Error> {
     let config_file = File::open(config_path).context("Failed to open config file")?;
     let mut config =
         VmConfig::load(&config_file).context("Failed to parse config file")?.to_parcelable()?;
+    if let Some(name) = name {
+        config.name = name;
+    }
     if let Some(mem) = mem {
         config.memoryMib = mem as i32;
     }
@@ -142,6 +220,10 @@ pub fn command_run(
     }
     config.cpuAffinity = cpu_affinity;
     config.taskProfiles = task_profiles;
+    if let Some(snapshot) = restore_snapshot {
+        let snapshot = File::open(snapshot).context("Failed to open snapshot file")?;
+        config.restoreSnapshot = Some(ParcelFileDescriptor::new(snapshot));
+    }
     run(
         service,
         &VirtualMachineConfig::RawConfig(config),
@@ -152,7 +234,7 @@ pub fn command_run(
     )
 }
 
-fn state_to_str(vm_state: VirtualMachineState) -> &'static str {
+pub fn state_to_str(vm_state: VirtualMachineState) -> &'static str {
     match vm_state {
         VirtualMachineState::NOT_STARTED => "NOT_STARTED",
         VirtualMachineState::STARTING => "STARTING",
@@ -245,11 +327,11 @@ fn wait_for_death(binder: &mut impl IBinder, dead: AtomicFlag) -> Result<DeathRe
     Ok(death_recipient)
 }
 
-fn parse_extra_apk_list(apk: &Path, config_path: &str) -> Result<Vec<String>, Error> {
+fn parse_extra_apk_list(apk: &Path, config_path: &str) -> Result<Vec<ApkConfig>, Error> {
     let mut archive = ZipArchive::new(File::open(apk)?)?;
     let config_file = archive.by_name(config_path)?;
     let config: VmPayloadConfig = serde_json::from_reader(config_file)?;
-    Ok(config.extra_apks.into_iter().map(|x| x.path).collect())
+    Ok(config.extra_apks)
 }
 
 #[derive(Debug)]
@@ -306,6 +388,9 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
             DeathReason::ERROR => println!("Error starting VM."),
             DeathReason::REBOOT => println!("VM tried to reboot, possibly due to a kernel panic."),
             DeathReason::CRASH => println!("VM crashed."),
+            DeathReason::PVM_FIRMWARE_BOOT_TIMEOUT => {
+                println!("VM didn't boot its payload before the pVM firmware timeout.")
+            }
             _ => println!("VM died for an unrecognised reason."),
         }
         Ok(())
@@ -0,0 +1,177 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command to open a shell in a debuggable Microdroid VM, by talking the adb protocol directly to
+//! its adbd over vsock.
+
+use crate::console::RawMode;
+use crate::with_background_vm;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::IVirtualizationService;
+use android_system_virtualizationservice::binder::Strong;
+use anyhow::{bail, Context, Error};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The vsock port which adbd listens on in Microdroid; see `service.adb.listen_addrs`.
+const ADBD_PORT: i32 = 5555;
+
+// From packages/modules/adb/adb.h
+const A_CNXN: u32 = 0x4e584e43;
+const A_OPEN: u32 = 0x4e45504f;
+const A_OKAY: u32 = 0x59414b4f;
+const A_CLSE: u32 = 0x45534c43;
+const A_WRTE: u32 = 0x45545257;
+const A_AUTH: u32 = 0x48545541;
+const A_VERSION: u32 = 0x01000001;
+const MAX_PAYLOAD: u32 = 256 * 1024;
+
+/// Our ID for the shell stream. We only ever open one.
+const LOCAL_ID: u32 = 1;
+
+/// An adb protocol message.
+struct Message {
+    command: u32,
+    arg0: u32,
+    arg1: u32,
+    data: Vec<u8>,
+}
+
+impl Message {
+    fn new(command: u32, arg0: u32, arg1: u32, data: &[u8]) -> Message {
+        Message { command, arg0, arg1, data: data.to_vec() }
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let checksum = self.data.iter().map(|&b| u32::from(b)).fold(0, u32::wrapping_add);
+        let header = [
+            self.command,
+            self.arg0,
+            self.arg1,
+            self.data.len() as u32,
+            checksum,
+            self.command ^ 0xffffffff,
+        ];
+        for word in &header {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+        writer.write_all(&self.data)
+    }
+
+    fn read_from(reader: &mut impl Read) -> Result<Message, Error> {
+        let mut header = [0u32; 6];
+        for word in &mut header {
+            let mut bytes = [0u8; 4];
+            reader.read_exact(&mut bytes)?;
+            *word = u32::from_le_bytes(bytes);
+        }
+        let [command, arg0, arg1, data_length, _checksum, magic] = header;
+        if magic != command ^ 0xffffffff {
+            bail!("Invalid adb message magic {:#x} for command {:#x}", magic, command);
+        }
+        if data_length > MAX_PAYLOAD {
+            bail!("adb message too long: {} bytes", data_length);
+        }
+        let mut data = vec![0; data_length as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Message { command, arg0, arg1, data })
+    }
+}
+
+/// Opens an interactive shell in the debuggable VM with the given CID, which must be running in
+/// the background, and relays stdin/stdout to it until the shell exits.
+pub fn command_shell(service: Strong<dyn IVirtualizationService>, cid: u32) -> Result<(), Error> {
+    let connection = with_background_vm(&service, cid, |vm| {
+        vm.connectVsock(ADBD_PORT).context("Failed to connect to adbd. Is the VM debuggable?")
+    })?;
+    let mut connection = connection.as_ref().try_clone()?;
+
+    let remote_id = open_shell(&mut connection)?;
+
+    let raw_mode = RawMode::enable(io::stdin().as_raw_fd())?;
+    let writer = Arc::new(Mutex::new(connection.try_clone()?));
+    let (okay_sender, okay_receiver) = channel();
+    let input_writer = writer.clone();
+    thread::spawn(move || relay_input(&input_writer, remote_id, &okay_receiver));
+    let result = relay_output(connection, &writer, remote_id, &okay_sender);
+    drop(raw_mode);
+    result
+}
+
+/// Connects to adbd and opens a shell stream. Returns the ID of the stream on the adbd side.
+fn open_shell(connection: &mut File) -> Result<u32, Error> {
+    Message::new(A_CNXN, A_VERSION, MAX_PAYLOAD, b"host::\0").write_to(connection)?;
+    let reply = Message::read_from(connection)?;
+    match reply.command {
+        A_CNXN => {}
+        A_AUTH => bail!("adbd requires authentication, which isn't supported"),
+        command => bail!("Unexpected adb command {:#x} in reply to CNXN", command),
+    }
+
+    Message::new(A_OPEN, LOCAL_ID, 0, b"shell:\0").write_to(connection)?;
+    let reply = Message::read_from(connection)?;
+    match reply.command {
+        A_OKAY if reply.arg1 == LOCAL_ID => Ok(reply.arg0),
+        A_CLSE => bail!("adbd refused to open a shell"),
+        command => bail!("Unexpected adb command {:#x} in reply to OPEN", command),
+    }
+}
+
+/// Sends stdin to the shell. adbd acknowledges each write with an OKAY, which must be received
+/// before the next write.
+fn relay_input(writer: &Mutex<File>, remote_id: u32, okay_receiver: &Receiver<()>) {
+    let mut stdin = io::stdin();
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = stdin.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        let message = Message::new(A_WRTE, LOCAL_ID, remote_id, &buf[..n]);
+        if message.write_to(&mut *writer.lock().unwrap()).is_err() || okay_receiver.recv().is_err()
+        {
+            return;
+        }
+    }
+    let _ = Message::new(A_CLSE, LOCAL_ID, remote_id, &[]).write_to(&mut *writer.lock().unwrap());
+}
+
+/// Copies the output of the shell to stdout until adbd closes the stream.
+fn relay_output(
+    mut reader: File,
+    writer: &Mutex<File>,
+    remote_id: u32,
+    okay_sender: &Sender<()>,
+) -> Result<(), Error> {
+    let mut stdout = io::stdout();
+    loop {
+        let message = Message::read_from(&mut reader)?;
+        match message.command {
+            A_WRTE => {
+                stdout.write_all(&message.data)?;
+                stdout.flush()?;
+                Message::new(A_OKAY, LOCAL_ID, remote_id, &[])
+                    .write_to(&mut *writer.lock().unwrap())?;
+            }
+            A_OKAY => {
+                // The input thread may have exited already, in which case nobody is waiting.
+                let _ = okay_sender.send(());
+            }
+            A_CLSE => return Ok(()),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Library for VM payloads running in Microdroid, to access the services which microdroid_manager
+//! provides to them.
+
+pub mod properties;
+pub mod rollback;
+pub mod secrets;
+pub mod services;
+
+pub use properties::{get_debug_level, get_instance_id, get_payload_config, get_property};
+pub use rollback::{get_rollback_counter, increment_rollback_counter};
+pub use secrets::{get_vm_instance_secret, SecretPurpose};
+pub use services::register_service;
+
+use android_system_virtualization_payload::aidl::android::system::virtualization::payload::IVmPayloadService::{
+    IVmPayloadService, VM_PAYLOAD_SERVICE_NAME,
+};
+use anyhow::{Context, Result};
+use binder::{wait_for_interface, Strong};
+use once_cell::sync::OnceCell;
+
+/// Returns the service of microdroid_manager which serves the requests of the payload, connecting
+/// to it on first use.
+pub(crate) fn get_vm_payload_service() -> Result<&'static Strong<dyn IVmPayloadService>> {
+    static SERVICE: OnceCell<Strong<dyn IVmPayloadService>> = OnceCell::new();
+    SERVICE.get_or_try_init(|| {
+        wait_for_interface(VM_PAYLOAD_SERVICE_NAME)
+            .with_context(|| format!("Failed to connect to {}", VM_PAYLOAD_SERVICE_NAME))
+    })
+}
@@ -0,0 +1,58 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A key-value API with which the payload can query properties of the VM it runs in, such as its
+//! payload config, its instance ID and its debug level.
+
+use crate::get_vm_payload_service;
+use anyhow::{bail, Context, Result};
+
+/// The JSON payload config which the payload was started from.
+pub const KEY_PAYLOAD_CONFIG: &str = "payload_config";
+/// The path of the payload config within the APK.
+pub const KEY_PAYLOAD_CONFIG_PATH: &str = "payload_config_path";
+/// An identifier of the VM instance, as a hex string, which stays the same across boots of the
+/// instance but differs between instances.
+pub const KEY_INSTANCE_ID: &str = "instance_id";
+/// The debug level of the VM: one of `none`, `app_only` or `full`.
+pub const KEY_DEBUG_LEVEL: &str = "debug_level";
+
+/// Returns the value of the given property, or `None` if there is no such property.
+pub fn get_property(key: &str) -> Result<Option<Vec<u8>>> {
+    get_vm_payload_service()?
+        .getProperty(key)
+        .with_context(|| format!("Failed to get property {}", key))
+}
+
+fn get_string_property(key: &str) -> Result<String> {
+    match get_property(key)? {
+        Some(value) => String::from_utf8(value).with_context(|| format!("Invalid {}", key)),
+        None => bail!("Property {} is not available", key),
+    }
+}
+
+/// Returns the JSON payload config which the payload was started from.
+pub fn get_payload_config() -> Result<String> {
+    get_string_property(KEY_PAYLOAD_CONFIG)
+}
+
+/// Returns the ID of the VM instance, as a hex string.
+pub fn get_instance_id() -> Result<String> {
+    get_string_property(KEY_INSTANCE_ID)
+}
+
+/// Returns the debug level of the VM: one of `none`, `app_only` or `full`.
+pub fn get_debug_level() -> Result<String> {
+    get_string_property(KEY_DEBUG_LEVEL)
+}
@@ -0,0 +1,41 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Monotonic counters which the payload can use to detect that its own state has been rolled back,
+//! e.g. by recording the counter in the state and incrementing it whenever the state changes.
+//!
+//! The counters are kept in the instance disk along with the rest of the identity of the VM
+//! instance, so they persist across boots. They only move forward, but this doesn't protect against
+//! the whole instance disk being replaced with an older copy of itself.
+
+use crate::get_vm_payload_service;
+use anyhow::{Context, Result};
+
+/// Returns the value of the rollback counter with the given name, which is 0 until it is first
+/// incremented.
+pub fn get_rollback_counter(name: &str) -> Result<u64> {
+    let value = get_vm_payload_service()?
+        .getRollbackCounter(name)
+        .with_context(|| format!("Failed to get rollback counter {}", name))?;
+    Ok(value as u64)
+}
+
+/// Increments the rollback counter with the given name, and returns its new value. The new value is
+/// stored before this returns.
+pub fn increment_rollback_counter(name: &str) -> Result<u64> {
+    let value = get_vm_payload_service()?
+        .incrementRollbackCounter(name)
+        .with_context(|| format!("Failed to increment rollback counter {}", name))?;
+    Ok(value as u64)
+}
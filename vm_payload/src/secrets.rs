@@ -0,0 +1,79 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Secrets of the VM instance, which stay the same across boots of the instance but differ between
+//! instances and between payloads, e.g. for sealing data which only the instance should read.
+//!
+//! Each secret is derived for a purpose, a version and a context, so that the payload can use
+//! independent secrets for different things and rotate one of them by bumping its version. The
+//! derivation is stable, and is HKDF-SHA256 with:
+//!
+//! - the sealing CDI of the payload, as the input keying material;
+//! - the ASCII string `vm_payload_secret`, as the salt;
+//! - the ID of the purpose as a big-endian u64, followed by the version as a big-endian u32 and the
+//!   context, as the info.
+
+use crate::get_vm_payload_service;
+use anyhow::{ensure, Context, Result};
+use std::convert::TryInto;
+
+/// The longest secret which can be derived, in bytes.
+pub const MAX_SECRET_LENGTH: usize = 64;
+
+/// What a secret is used for. Secrets derived for different purposes are independent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SecretPurpose {
+    /// A key for encrypting data at rest.
+    Encryption,
+    /// A key for authenticating messages, e.g. with HMAC.
+    Authentication,
+    /// A seed for a signing key pair.
+    Signing,
+    /// A purpose defined by the payload itself.
+    Custom(u32),
+}
+
+impl SecretPurpose {
+    /// Returns the ID of the purpose which goes into the derivation. Custom purposes don't collide
+    /// with the predefined ones.
+    pub fn id(self) -> u64 {
+        match self {
+            SecretPurpose::Encryption => 1,
+            SecretPurpose::Authentication => 2,
+            SecretPurpose::Signing => 3,
+            SecretPurpose::Custom(id) => (1 << 32) | u64::from(id),
+        }
+    }
+}
+
+/// Returns a secret of the VM instance of the given length, derived for the given purpose, version
+/// and context.
+pub fn get_vm_instance_secret(
+    purpose: SecretPurpose,
+    version: u32,
+    context: &[u8],
+    length: usize,
+) -> Result<Vec<u8>> {
+    ensure!(
+        length > 0 && length <= MAX_SECRET_LENGTH,
+        "Secret length {} isn't between 1 and {}",
+        length,
+        MAX_SECRET_LENGTH
+    );
+    let secret = get_vm_payload_service()?
+        .getVmInstanceSecret(purpose.id() as i64, version as i32, context, length.try_into()?)
+        .with_context(|| format!("Failed to get secret for {:?}", purpose))?;
+    ensure!(secret.len() == length, "Got a secret of {} bytes instead of {}", secret.len(), length);
+    Ok(secret)
+}
@@ -0,0 +1,28 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lets the payload publish the RPC binder services which it serves, so that clients of the VM on
+//! the host can connect to them by name rather than by vsock port.
+
+use crate::get_vm_payload_service;
+use anyhow::{Context, Result};
+use std::convert::TryInto;
+
+/// Registers the RPC binder service with the given name, which the payload must already be
+/// serving on the given vsock port. Registering a name again replaces the earlier registration.
+pub fn register_service(name: &str, port: u32) -> Result<()> {
+    get_vm_payload_service()?
+        .registerService(name, port.try_into().context("Invalid port")?)
+        .with_context(|| format!("Failed to register service {}", name))
+}
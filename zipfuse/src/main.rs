@@ -24,6 +24,7 @@ use anyhow::Result;
 use clap::{App, Arg};
 use fuse::filesystem::*;
 use fuse::mount::*;
+use ring::digest;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
@@ -34,6 +35,7 @@ use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::inode::{DirectoryEntry, Inode, InodeData, InodeKind, InodeTable};
 
@@ -46,6 +48,16 @@ fn main() -> Result<()> {
                 .required(false)
                 .help("Comma separated list of mount options"),
         )
+        .arg(
+            Arg::with_name("negative-lookup-timeout")
+                .long("negative-lookup-timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help(
+                    "How long the kernel may cache failed lookups. 0 disables caching. By \
+                    default they are cached forever, as the archive can't change",
+                ),
+        )
         .arg(Arg::with_name("ZIPFILE").required(true))
         .arg(Arg::with_name("MOUNTPOINT").required(true))
         .get_matches();
@@ -53,12 +65,22 @@ fn main() -> Result<()> {
     let zip_file = matches.value_of("ZIPFILE").unwrap().as_ref();
     let mount_point = matches.value_of("MOUNTPOINT").unwrap().as_ref();
     let options = matches.value_of("options");
-    run_fuse(zip_file, mount_point, options)?;
+    let negative_lookup_timeout = match matches.value_of("negative-lookup-timeout") {
+        Some(secs) => Duration::from_secs(secs.parse()?),
+        None => timeout_max(),
+    };
+    run_fuse(zip_file, mount_point, options, negative_lookup_timeout)?;
     Ok(())
 }
 
-/// Runs a fuse filesystem by mounting `zip_file` on `mount_point`.
-pub fn run_fuse(zip_file: &Path, mount_point: &Path, extra_options: Option<&str>) -> Result<()> {
+/// Runs a fuse filesystem by mounting `zip_file` on `mount_point`. Lookups of names that don't
+/// exist are cached by the kernel for `negative_lookup_timeout`, unless it is zero.
+pub fn run_fuse(
+    zip_file: &Path,
+    mount_point: &Path,
+    extra_options: Option<&str>,
+    negative_lookup_timeout: Duration,
+) -> Result<()> {
     const MAX_READ: u32 = 1 << 20; // TODO(jiyong): tune this
     const MAX_WRITE: u32 = 1 << 13; // This is a read-only filesystem
 
@@ -84,7 +106,7 @@ pub fn run_fuse(zip_file: &Path, mount_point: &Path, extra_options: Option<&str>
     )?;
     let mut config = fuse::FuseConfig::new();
     config.dev_fuse(dev_fuse).max_write(MAX_WRITE).max_read(MAX_READ);
-    Ok(config.enter_message_loop(ZipFuse::new(zip_file)?)?)
+    Ok(config.enter_message_loop(ZipFuse::new(zip_file, negative_lookup_timeout)?)?)
 }
 
 struct ZipFuse {
@@ -93,6 +115,8 @@ struct ZipFuse {
     inode_table: InodeTable,
     open_files: Mutex<HashMap<Handle, OpenFile>>,
     open_dirs: Mutex<HashMap<Handle, OpenDirBuf>>,
+    negative_lookup_timeout: Duration,
+    digests: Mutex<HashMap<Inode, digest::Digest>>,
 }
 
 /// Represents a [`ZipFile`] that is opened.
@@ -120,12 +144,12 @@ fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
-fn timeout_max() -> std::time::Duration {
-    std::time::Duration::new(u64::MAX, 1_000_000_000 - 1)
+fn timeout_max() -> Duration {
+    Duration::new(u64::MAX, 1_000_000_000 - 1)
 }
 
 impl ZipFuse {
-    fn new(zip_file: &Path) -> Result<ZipFuse> {
+    fn new(zip_file: &Path, negative_lookup_timeout: Duration) -> Result<ZipFuse> {
         // TODO(jiyong): Use O_DIRECT to avoid double caching.
         // `.custom_flags(nix::fcntl::OFlag::O_DIRECT.bits())` currently doesn't work.
         let f = File::open(zip_file)?;
@@ -140,6 +164,8 @@ impl ZipFuse {
             inode_table: it,
             open_files: Mutex::new(HashMap::new()),
             open_dirs: Mutex::new(HashMap::new()),
+            negative_lookup_timeout,
+            digests: Mutex::new(HashMap::new()),
         })
     }
 
@@ -147,6 +173,30 @@ impl ZipFuse {
         self.inode_table.get(inode).ok_or_else(ebadf)
     }
 
+    /// Returns the SHA-256 digest of the content of the file at `inode`, computing it on first use.
+    /// The content is read through the zip archive, so it is as trustworthy as the archive itself
+    /// (e.g. when it is backed by dm-verity).
+    fn sha256_digest(&self, inode: Inode) -> io::Result<digest::Digest> {
+        if let Some(digest) = self.digests.lock().unwrap().get(&inode) {
+            return Ok(*digest);
+        }
+        let zip_index = self.find_inode(inode)?.get_zip_index().ok_or_else(ebadf)?;
+        let mut zip_archive = self.zip_archive.lock().unwrap();
+        let mut zip_file = zip_archive.by_index(zip_index)?;
+        let mut context = digest::Context::new(&digest::SHA256);
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let n = zip_file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            context.update(&buf[..n]);
+        }
+        let digest = context.finish();
+        self.digests.lock().unwrap().insert(inode, digest);
+        Ok(digest)
+    }
+
     // TODO(jiyong) remove this. Right now this is needed to do the nlink_t to u64 conversion below
     // on aosp_x86_64 target. That however is a useless conversion on other targets.
     #[allow(clippy::useless_conversion)]
@@ -191,7 +241,44 @@ impl fuse::filesystem::FileSystem for ZipFuse {
                 attr_timeout: timeout_max(), // this is a read-only fs
                 entry_timeout: timeout_max(),
             }),
-            _ => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            None if !self.negative_lookup_timeout.is_zero() => {
+                // An entry with inode 0 is a negative entry, which the kernel caches for
+                // `entry_timeout` instead of asking us again.
+                Ok(Entry {
+                    inode: 0,
+                    generation: 0,
+                    // SAFETY: stat64 is a plain C struct for which all zeroes is a valid value.
+                    attr: unsafe { std::mem::zeroed() },
+                    attr_timeout: Duration::ZERO,
+                    entry_timeout: self.negative_lookup_timeout,
+                })
+            }
+            None => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    fn getxattr(
+        &self,
+        _ctx: Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        // Lets the payload check which version of a file it is reading without hashing it itself.
+        if name != CStr::from_bytes_with_nul(b"zipfuse.sha256.digest\0").unwrap()
+            || self.find_inode(inode)?.is_dir()
+        {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA));
+        }
+        if size == 0 {
+            // Per protocol, when size is 0, return the value size.
+            return Ok(GetxattrReply::Count(digest::SHA256.output_len as u32));
+        }
+        let digest = self.sha256_digest(inode)?;
+        if digest.as_ref().len() > size as usize {
+            Err(io::Error::from_raw_os_error(libc::ERANGE))
+        } else {
+            Ok(GetxattrReply::Value(digest.as_ref().to_vec()))
         }
     }
 
@@ -200,7 +287,7 @@ impl fuse::filesystem::FileSystem for ZipFuse {
         _ctx: Context,
         inode: Self::Inode,
         _handle: Option<Self::Handle>,
-    ) -> io::Result<(libc::stat64, std::time::Duration)> {
+    ) -> io::Result<(libc::stat64, Duration)> {
         let st = self.stat_from(inode)?;
         Ok((st, timeout_max()))
     }
@@ -427,9 +514,11 @@ mod tests {
     use anyhow::{bail, Result};
     use nix::sys::statfs::{statfs, FsType};
     use std::collections::BTreeSet;
+    use std::ffi::CString;
     use std::fs;
     use std::fs::File;
     use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
     use std::path::{Path, PathBuf};
     use std::time::{Duration, Instant};
     use zip::write::FileOptions;
@@ -439,7 +528,7 @@ mod tests {
         let zip_path = PathBuf::from(zip_path);
         let mnt_path = PathBuf::from(mnt_path);
         std::thread::spawn(move || {
-            crate::run_fuse(&zip_path, &mnt_path, None).unwrap();
+            crate::run_fuse(&zip_path, &mnt_path, None, crate::timeout_max()).unwrap();
         });
     }
 
@@ -576,6 +665,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn nonexistent_file() {
+        run_test(
+            |zip| {
+                zip.start_file("foo", FileOptions::default()).unwrap();
+            },
+            |root| {
+                // Looked up twice so that the second lookup is served by the negative entry.
+                for _ in 0..2 {
+                    assert!(!root.join("bar").exists());
+                    assert!(!root.join("foo/bar").exists());
+                }
+                check_dir(root, "", &["foo"], &[]);
+            },
+        );
+    }
+
+    fn get_sha256_digest_xattr(path: &Path) -> std::io::Result<Vec<u8>> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new("zipfuse.sha256.digest").unwrap();
+        let mut value = vec![0; 64];
+        // SAFETY: the path and name are valid C strings, and the kernel writes at most
+        // `value.len()` bytes into `value`.
+        let size = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if size < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        value.truncate(size as usize);
+        Ok(value)
+    }
+
+    #[test]
+    fn sha256_digest_xattr() {
+        run_test(
+            |zip| {
+                use zip::CompressionMethod::{Deflated, Stored};
+                let opt = FileOptions::default();
+                zip.start_file("stored", opt.compression_method(Stored)).unwrap();
+                zip.write_all(b"0123456789").unwrap();
+                zip.start_file("deflated", opt.compression_method(Deflated)).unwrap();
+                zip.write_all(&[7; 100000]).unwrap();
+                zip.add_directory("dir", FileOptions::default()).unwrap();
+            },
+            |root| {
+                let expected = ring::digest::digest(&ring::digest::SHA256, b"0123456789");
+                let value = get_sha256_digest_xattr(&root.join("stored")).unwrap();
+                assert_eq!(expected.as_ref(), value.as_slice());
+
+                let expected = ring::digest::digest(&ring::digest::SHA256, &[7; 100000]);
+                let value = get_sha256_digest_xattr(&root.join("deflated")).unwrap();
+                assert_eq!(expected.as_ref(), value.as_slice());
+
+                let err = get_sha256_digest_xattr(&root.join("dir")).unwrap_err();
+                assert_eq!(Some(libc::ENODATA), err.raw_os_error());
+            },
+        );
+    }
+
     #[test]
     fn single_dir() {
         run_test(